@@ -1,5 +1,7 @@
 //! CLI argument parsing types
 
+use std::ffi::OsString;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
 use clap::Parser;
@@ -21,6 +23,119 @@ pub struct CliArguments {
     #[clap(long = "root", value_name = "DIR", env = "TYPST_ROOT")]
     pub root: Option<PathBuf>,
 
+    /// Configures the workspace boundary: the search for `typst.toml` stops at a directory
+    /// containing a file or directory of this name. Pass an empty value to search up to the file
+    /// system root.
+    #[clap(long, value_name = "NAME", default_value = ".git")]
+    pub workspace_boundary: OsString,
+
+    /// Prints additional information, such as the detected Typst version
+    #[clap(short, long)]
+    pub verbose: bool,
+
+    /// Treats problems that are normally only warnings as errors: an unsupported Typst version or
+    /// one that can't be determined, a corrupt index that would be rebuilt, and documents that
+    /// don't mention prequery when no query found anything. Warnings about best-effort operations,
+    /// such as the cache or index checkpoints, are not affected
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Fails if the lockfile configured in `typst.toml` is not up to date, instead of updating it
+    #[clap(long)]
+    pub locked: bool,
+
+    /// Authenticates web resource downloads using the credentials in the user's netrc file
+    /// (`$NETRC` or `~/.netrc`)
+    #[clap(long)]
+    pub netrc: bool,
+
+    /// Trusts the certificates in this PEM bundle for web resource downloads, in addition to the
+    /// system's root certificates. This is useful behind a TLS-intercepting proxy, and is
+    /// preferable to disabling certificate verification.
+    #[clap(long, value_name = "PATH", env = "PREQUERY_CA_CERT")]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Shares downloaded web resources between projects using this directory. Files that are not
+    /// present in a project are copied from the cache if it contains a file downloaded from the
+    /// same URL, and freshly downloaded files are added to the cache. Cached files never expire:
+    /// files that are downloaded again because `overwrite`, `max_age` or `check` detected a
+    /// possible change bypass the cache and replace its entry. To invalidate the cache, delete
+    /// the directory or the entries in it.
+    #[clap(long, value_name = "DIR", env = "PREQUERY_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Stops at the first failed job, aborting all other jobs that are still running. Jobs don't
+    /// depend on each other, so aborted jobs may have been partially executed. By default, all
+    /// jobs are run to completion and all failures are reported together.
+    #[clap(long)]
+    pub fail_fast: bool,
+
+    /// The default limit for how many tasks run at the same time: jobs, the downloads and commands
+    /// within each job, and the downloads from each host. A job's own limits, such as
+    /// `max_concurrent` or `per_host_concurrency`, take precedence. If not given, the number of
+    /// available CPUs is used.
+    #[clap(long, value_name = "N")]
+    pub concurrency: Option<NonZeroUsize>,
+
+    /// Prints each job's output in the order the jobs are defined in `typst.toml`, instead of as
+    /// it happens. Jobs still run concurrently; their output is held back until all jobs have
+    /// finished.
+    #[clap(long)]
+    pub ordered_output: bool,
+
+    /// Skips jobs whose inputs haven't changed since their last successful run, without even
+    /// running their queries. A job's inputs are the document it queries and the `typst.toml`
+    /// file, compared by their modification times; files included by the document, other files a
+    /// job reads, and outputs that were changed or removed are not considered. The time of each
+    /// job's last successful run is stored in `.prequery-state.toml` next to `typst.toml`.
+    #[clap(long, conflicts_with_all = ["clean", "diff_index"])]
+    pub incremental: bool,
+
+    /// Ignores the indexes configured for web resource jobs: they are neither read nor written,
+    /// and whether a file is downloaded only depends on whether it exists and on `overwrite`.
+    /// This is meant for debugging, e.g. when an index seems to be out of sync with the files.
+    #[clap(long, conflicts_with_all = ["clean", "diff_index"])]
+    pub no_index: bool,
+
+    /// Treats index files whose content is invalid, e.g. after they were edited by hand, as empty
+    /// instead of failing the jobs using them. A warning is logged, and the indexes are rebuilt
+    /// when they are saved. This is like setting `on_corrupt = "rebuild"` for every job.
+    #[clap(long)]
+    pub repair_index: bool,
+
+    /// Removes the files produced by previous runs instead of running the jobs. Only jobs that
+    /// keep an index know which files they produced; other jobs are skipped with a warning.
+    #[clap(long)]
+    pub clean: bool,
+
+    /// Shows how the jobs' indexes would change instead of running the jobs: which entries would
+    /// be added or changed, and which are no longer queried. Nothing is downloaded or written.
+    #[clap(long, conflicts_with = "clean")]
+    pub diff_index: bool,
+
+    /// Prints the configuration of all jobs as TOML instead of running them, after package-wide
+    /// defaults, selector variables and inputs files have been applied.
+    #[clap(long)]
+    pub print_config: bool,
+
+    /// Development aid: answers queries from this JSON file instead of running Typst, e.g. to work
+    /// on preprocessors without a Typst installation or to reproduce a bug report. The file maps
+    /// each query, written like its `typst query` arguments, to its result; such a file is
+    /// created using `--record-queries`.
+    #[clap(long, value_name = "PATH", help_heading = "Development")]
+    pub query_cache_file: Option<PathBuf>,
+
+    /// Development aid: runs queries as usual, and records their results in the
+    /// `--query-cache-file` for later replay. Existing entries for other queries are kept.
+    #[clap(long, requires = "query_cache_file", help_heading = "Development")]
+    pub record_queries: bool,
+
+    /// Adds an example prequery configuration to the `typst.toml` file instead of running any
+    /// jobs. Existing prequery configuration is never changed; if the file can't be edited, the
+    /// example is printed instead.
+    #[clap(long)]
+    pub init: bool,
+
     /// Path to the input Typst file. `prequery-preprocess` will look for a `typst.toml` file in
     /// directories upwards from that file to determine jobs.
     pub input: PathBuf,
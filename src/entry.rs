@@ -1,11 +1,23 @@
-//! Contains the executable's entry point
+//! Contains the executable's entry point, and an API for running single jobs
 
+use std::collections::{BTreeSet, HashSet};
+use std::env;
+use std::io::Write;
+use std::path::PathBuf;
 use std::process::exit;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::error::{MultiplePreprocessorExecutionError, Result};
+use tokio::sync::Semaphore;
+
+use crate::args::CliArguments;
+use crate::error::{MultiplePreprocessorConfigError, MultiplePreprocessorExecutionError, Result};
+use crate::init;
+use crate::lockfile;
+use crate::manifest;
 use crate::preprocessor::{ExecutionError, Preprocessor};
-use crate::reporting::ErrorExt;
+use crate::query::TypstVersion;
+use crate::reporting::{ErrorExt, JobLog};
 use crate::utils;
 use crate::world::{DefaultWorld, World, WorldExt};
 
@@ -19,45 +31,475 @@ pub async fn main() {
     }
 }
 
+/// An operation that is done for each job instead of running it
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    /// `--clean`: remove the files produced by previous runs
+    Clean,
+    /// `--diff-index`: show how the index would change
+    DiffIndex,
+}
+
+impl Action {
+    fn from_arguments(arguments: &CliArguments) -> Option<Self> {
+        if arguments.clean {
+            Some(Self::Clean)
+        } else if arguments.diff_index {
+            Some(Self::DiffIndex)
+        } else {
+            None
+        }
+    }
+}
+
+/// Formats the time since `started` according to the world's clock, e.g. `2.3s`.
+fn elapsed(world: &impl World, started: SystemTime) -> String {
+    let elapsed = world.now().duration_since(started).unwrap_or_default();
+    format!("{:.1}s", elapsed.as_secs_f64())
+}
+
+/// The outcome of a successful job
+struct FinishedJob {
+    name: String,
+    /// The job's resources for the lockfile, if they were collected
+    resources: Vec<lockfile::Resource>,
+    /// Whether the job's query found nothing to process
+    found_nothing: bool,
+}
+
+/// Executes a configured job, retrying it as often as requested. If `lock` is set, the job's
+/// resources are collected for the lockfile after a successful run.
+async fn execute_job(
+    mut job: Box<dyn Preprocessor<impl World> + Send>,
+    lock: bool,
+    retries: usize,
+) -> Result<FinishedJob, (String, ExecutionError)> {
+    let mut l = job.world().log();
+    log!(l, "{} beginning job...", job.log_prefix());
+    let started = job.world().now();
+    let mut attempt = 0;
+    let (result, resources) = loop {
+        let mut result = job.run().await;
+        let mut resources = Vec::new();
+        if result.is_ok() && lock {
+            // collect the job's resources for the lockfile
+            match job.locked_resources().await {
+                Ok(locked) => resources = locked,
+                Err(error) => result = Err(error),
+            }
+        }
+        match &result {
+            Ok(()) => {
+                let elapsed = elapsed(&**job.world(), started);
+                log!(l, "{} job finished in {elapsed}", job.log_prefix());
+            }
+            Err(error) => {
+                log!(l, "{} job failed: {error}", job.log_prefix());
+            }
+        }
+        if result.is_err() && attempt < retries {
+            attempt += 1;
+            log!(
+                l,
+                "{} retrying job (attempt {} of {})...",
+                job.log_prefix(),
+                attempt + 1,
+                retries + 1,
+            );
+            continue;
+        }
+        break (result, resources);
+    };
+    let name = job.name().to_string();
+    result.map_err(|error| (name.clone(), error.into()))?;
+    let found_nothing = job.found_nothing().await;
+    Ok(FinishedJob {
+        name,
+        resources,
+        found_nothing,
+    })
+}
+
+/// Warns about documents that don't mention the prequery package, after no job's query found
+/// anything. Such a document most likely forgot to import the package, so its queries can't find
+/// anything; a document that does import it may legitimately contain nothing to preprocess.
+/// Documents that can't be read are not reported. In [strict][CliArguments::strict] mode, the
+/// first such document is an error instead.
+async fn check_prequery_import(world: &impl World, documents: &[PathBuf]) -> Result<()> {
+    let documents: BTreeSet<_> = documents.iter().collect();
+    let mut l = world.log();
+    for document in documents {
+        let Ok(source) = world.read_source(document).await else {
+            continue;
+        };
+        if !source.contains("prequery") {
+            let error = crate::error::Error::NoPrequeryImport(document.clone());
+            if world.arguments().strict {
+                return Err(error);
+            }
+            log!(l, "warning: {error}");
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the given Typst executables are recent enough, before any job runs. An outdated
+/// version, or one that can't be determined, is reported as a warning; in
+/// [strict][CliArguments::strict] mode, it is an error instead.
+async fn check_typst_versions(world: &impl World, executables: &BTreeSet<PathBuf>) -> Result<()> {
+    let mut l = world.log();
+    for typst in executables {
+        let error = match world.typst_version(typst).await {
+            Some(version) => {
+                if world.arguments().verbose {
+                    log!(
+                        l,
+                        "detected Typst version {version} at `{}`",
+                        typst.display()
+                    );
+                }
+                if version >= TypstVersion::MINIMUM {
+                    continue;
+                }
+                crate::error::Error::UnsupportedTypstVersion { found: version }
+            }
+            None => crate::error::Error::UnknownTypstVersion(typst.clone()),
+        };
+        if world.arguments().strict {
+            return Err(error);
+        }
+        log!(l, "warning: {error}");
+    }
+    Ok(())
+}
+
 /// Entry point; takes a World and executes preprocessors according to the contained data.
 pub async fn run(world: impl World) -> Result<()> {
-    async fn inner(world: Arc<impl World>) -> Result<()> {
-        let config = world.read_typst_toml().await?;
+    run_impl(world, None).await
+}
+
+/// Like [run], but uses the given manifest instead of reading `typst.toml`. This is meant for
+/// tools that generate manifests. The manifest is [parsed][manifest::PrequeryManifest::parse] as
+/// if it was the content of a `typst.toml` file, but the paths in it are relative to the current
+/// directory, which is also where indexes are kept and shell commands run. Since the manifest has
+/// no modification time, `--incremental` doesn't skip any jobs; the state recording successful
+/// runs is kept in the current directory as well.
+pub async fn run_with_manifest(world: impl World, manifest: &str) -> Result<()> {
+    run_impl(world, Some(manifest)).await
+}
+
+/// Executes preprocessors according to the given manifest, or `typst.toml` if none is given.
+async fn run_impl(world: impl World, manifest: Option<&str>) -> Result<()> {
+    async fn inner(world: Arc<impl World>, manifest: Option<&str>) -> Result<()> {
+        if world.arguments().init {
+            init::run(&*world).await?;
+            return Ok(());
+        }
+
+        let mut config = match manifest {
+            Some(manifest) => {
+                // the manifest's paths are relative to the current directory
+                let dir = env::current_dir().map_err(manifest::Error::from)?;
+                world.set_manifest_dir(dir);
+                let mut config = manifest::PrequeryManifest::parse(manifest)?;
+                config.merge_inputs_files().await?;
+                config
+            }
+            None => world.read_typst_toml().await?,
+        };
+        if world.arguments().print_config {
+            config.resolve_jobs();
+            let config = toml::to_string(&config).expect("the manifest should be serializable");
+            let mut l = world.log();
+            log!(l, "{}", config.trim_end());
+            return Ok(());
+        }
+        let lockfile = config.lockfile.clone();
+        if lockfile.is_none() && world.arguments().locked {
+            return Err(lockfile::Error::NotConfigured.into());
+        }
+        // disabled jobs are dropped once; everything below only sees the enabled ones
+        let disabled = world.retain_enabled_jobs(&mut config);
+        // the jobs are configured in order, so this lines up with them
+        let (retries, documents): (Vec<_>, Vec<_>) = config
+            .jobs
+            .iter()
+            .map(|job| {
+                let document = job.query.document.as_ref();
+                let document = document.unwrap_or(&world.arguments().input).clone();
+                (job.retries.unwrap_or(0), document)
+            })
+            .unzip();
+        let executables: BTreeSet<_> = config
+            .jobs
+            .iter()
+            .map(|job| {
+                job.typst
+                    .as_ref()
+                    .unwrap_or(&world.arguments().typst)
+                    .clone()
+            })
+            .collect();
         let jobs = world.get_preprocessors(config)?;
+        // all jobs are validated before any of them is executed
+        let errors: Vec<_> = jobs
+            .iter()
+            .filter_map(|job| {
+                let error = job.validate().err()?;
+                Some((job.name().to_string(), error))
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(MultiplePreprocessorConfigError::new(errors).into());
+        }
+        let fail_fast = world.arguments().fail_fast;
 
-        async fn run_job(
-            mut job: Box<dyn Preprocessor<impl World> + Send>,
-        ) -> Result<(), (String, ExecutionError)> {
-            let mut l = job.world().log();
-            log!(l, "[{}] beginning job...", job.name());
-            let result = job.run().await;
-            match &result {
-                Ok(()) => {
-                    log!(l, "[{}] job finished", job.name());
+        if let Some(action) = Action::from_arguments(world.arguments()) {
+            async fn job_action(
+                mut job: Box<dyn Preprocessor<impl World> + Send>,
+                action: Action,
+            ) -> Result<(), (String, ExecutionError)> {
+                let mut l = job.world().log();
+                let (beginning, finished, failed) = match action {
+                    Action::Clean => ("cleaning job", "job cleaned", "cleaning job failed"),
+                    Action::DiffIndex => (
+                        "computing index diff",
+                        "index diff computed",
+                        "computing index diff failed",
+                    ),
+                };
+                log!(l, "{} {beginning}...", job.log_prefix());
+                let result = match action {
+                    Action::Clean => job.clean().await,
+                    Action::DiffIndex => job.diff_index().await,
+                };
+                match &result {
+                    Ok(()) => {
+                        log!(l, "{} {finished}", job.log_prefix());
+                    }
+                    Err(error) => {
+                        log!(l, "{} {failed}: {error}", job.log_prefix());
+                    }
                 }
-                Err(error) => {
-                    log!(l, "[{}] job failed: {error}", job.name());
+                result.map_err(|error| (job.name().to_string(), error.into()))
+            }
+
+            let semaphore = Arc::new(Semaphore::new(world.concurrency().get()));
+            let jobs = jobs.into_iter().map(|job| {
+                let name = job.name().to_string();
+                let job = utils::with_permit(Arc::clone(&semaphore), job_action(job, action));
+                (name, job)
+            });
+            let (_, errors) =
+                utils::spawn_set_with_id(jobs, |name, error| (name, error.into()), fail_fast).await;
+            if !errors.is_empty() {
+                let error: crate::error::Error =
+                    MultiplePreprocessorExecutionError::new(errors).into();
+                return Err(error);
+            }
+            return Ok(());
+        }
+
+        // the Typst executables are checked once, before any job queries them. queries answered
+        // from the query cache don't run Typst at all
+        let arguments = world.arguments();
+        if arguments.query_cache_file.is_none() || arguments.record_queries {
+            check_typst_versions(&*world, &executables).await?;
+        }
+
+        // with --incremental, jobs whose inputs are older than their last successful run are
+        // skipped; the jobs of an explicitly given manifest always count as changed, as there's
+        // no modification time. this is also when the total duration starts
+        let started = world.now();
+        let mut state = None;
+        let mut unchanged = HashSet::new();
+        if world.arguments().incremental {
+            // the state is kept where the manifest's paths are relative to; for an explicitly
+            // given manifest, that's the current directory
+            let dir = match manifest {
+                Some(_) => PathBuf::new(),
+                None => world.manifest_dir().await.map_err(manifest::Error::from)?,
+            };
+            let previous = world.read_state(&dir).await?;
+            if manifest.is_none() {
+                let (typst_toml, _) = world
+                    .read_typst_toml_source()
+                    .await
+                    .map_err(manifest::Error::from)?;
+                let mut l = world.log();
+                for (job, document) in jobs.iter().zip(&documents) {
+                    // files that can't be checked count as changed
+                    let modified = [
+                        world.modified(&typst_toml).await,
+                        world.modified(document).await,
+                    ];
+                    let modified: Result<Vec<_>, _> = modified.into_iter().collect();
+                    if modified.is_ok_and(|modified| previous.is_up_to_date(job.name(), &modified))
+                    {
+                        log!(
+                            l,
+                            "{} skipped: unchanged since the last run",
+                            job.log_prefix()
+                        );
+                        unchanged.insert(job.name().to_string());
+                    }
                 }
             }
-            result.map_err(|error| (job.name().to_string(), error.into()))
+            state = Some(previous);
         }
+        let (jobs, (retries, documents)): (Vec<_>, (Vec<_>, Vec<_>)) = jobs
+            .into_iter()
+            .zip(retries.into_iter().zip(documents))
+            .filter(|(job, _)| !unchanged.contains(job.name()))
+            .unzip();
+
+        // with ordered output, every job logs to its own buffer
+        let ordered_output = world.arguments().ordered_output;
+        let job_logs: Vec<_> = jobs
+            .iter()
+            .map(|_| ordered_output.then(JobLog::default))
+            .collect();
 
+        // at most `--concurrency` jobs run at the same time
+        let semaphore = Arc::new(Semaphore::new(world.concurrency().get()));
         let jobs = jobs
             .into_iter()
-            .map(|job| (job.name().to_string(), run_job(job)));
-        let errors = utils::spawn_set_with_id(jobs, |name, error| (name, error.into())).await;
+            .zip(&job_logs)
+            .zip(retries)
+            .map(|((job, job_log), retries)| {
+                let name = job.name().to_string();
+                let job = execute_job(job, lockfile.is_some(), retries);
+                let job = utils::with_permit(Arc::clone(&semaphore), job);
+                let job_log = job_log.clone();
+                let job = async move {
+                    match job_log {
+                        Some(job_log) => job_log.scope(job).await,
+                        None => job.await,
+                    }
+                };
+                (name, job)
+            });
+        let (finished, errors) =
+            utils::spawn_set_with_id(jobs, |name, error| (name, error.into()), fail_fast).await;
+
+        // write the buffered output in the order of the jobs
+        let mut l = world.log();
+        for job_log in job_logs.into_iter().flatten() {
+            l.write_all(&job_log.take())
+                .expect("logging should not fail");
+        }
+        log!(l, "all jobs finished in {}", elapsed(&*world, started));
+
+        // if every job ran but none of them found anything, the documents possibly don't use
+        // prequery at all
+        let import_check = if errors.is_empty()
+            && !finished.is_empty()
+            && finished.iter().all(|job| job.found_nothing)
+        {
+            check_prequery_import(&*world, &documents).await
+        } else {
+            Ok(())
+        };
+
+        // successful jobs are recorded even if other jobs failed
+        if let Some(mut state) = state {
+            let previous = state.clone();
+            for job in &finished {
+                state.record(job.name.clone(), started);
+            }
+            if state != previous {
+                world.write_state(&state).await?;
+            }
+        }
 
         if !errors.is_empty() {
             let error: crate::error::Error = MultiplePreprocessorExecutionError::new(errors).into();
             return Err(error);
         }
+        import_check?;
+
+        if let Some(lockfile) = lockfile {
+            let previous = world.read_lockfile(&lockfile).await?;
+            // skipped and disabled jobs keep their resources from the previous run
+            let kept = previous
+                .entries
+                .iter()
+                .filter(|entry| unchanged.contains(&entry.job) || disabled.contains(&entry.job))
+                .map(|entry| {
+                    let resource = lockfile::Resource {
+                        path: entry.path.clone(),
+                        url: entry.url.clone(),
+                        hash: entry.hash.clone(),
+                    };
+                    (entry.job.clone(), resource)
+                });
+            let resources = finished
+                .into_iter()
+                .flat_map(
+                    |FinishedJob {
+                         name, resources, ..
+                     }| {
+                        resources
+                            .into_iter()
+                            .map(move |resource| (name.clone(), resource))
+                    },
+                )
+                .chain(kept)
+                .collect();
+            let timestamp = world
+                .now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs());
+            let updated = previous.updated(resources, timestamp);
+            if updated != previous {
+                if world.arguments().locked {
+                    return Err(lockfile::Error::Outdated(lockfile).into());
+                }
+                world.write_lockfile(&updated).await?;
+            }
+        }
 
         Ok(())
     }
 
     let world = Arc::new(world);
     let mut l = world.log();
-    inner(world).await.inspect_err(|error| {
+    inner(world, manifest).await.inspect_err(|error| {
         log!(l, "{}", error.error_chain());
     })
 }
+
+/// Configures, validates and runs a single job that is given directly instead of being read from
+/// `typst.toml`. This is meant for tools that construct jobs programmatically; the job is used
+/// as given, apart from the package-wide `defaults`. Its Typst executable is checked like in [run].
+/// Unlike [run], this ignores the command line
+/// options that select jobs or actions, and doesn't read or write the lockfile. Instead, the
+/// job's resources that would be recorded there are returned.
+pub async fn run_job<W: World>(
+    world: &Arc<W>,
+    job: manifest::Job,
+    defaults: manifest::JobDefaults,
+) -> Result<Vec<lockfile::Resource>> {
+    let retries = job.retries.unwrap_or(0);
+    let typst = job
+        .typst
+        .as_ref()
+        .unwrap_or(&world.arguments().typst)
+        .clone();
+    let job = world
+        .preprocessors()
+        .get(world, job, defaults)
+        .map_err(|error| MultiplePreprocessorConfigError::new(vec![error]))?;
+    if let Err(error) = job.validate() {
+        let errors = vec![(job.name().to_string(), error)];
+        return Err(MultiplePreprocessorConfigError::new(errors).into());
+    }
+    let arguments = world.arguments();
+    if arguments.query_cache_file.is_none() || arguments.record_queries {
+        check_typst_versions(&**world, &BTreeSet::from([typst])).await?;
+    }
+    let finished = execute_job(job, true, retries)
+        .await
+        .map_err(|error| MultiplePreprocessorExecutionError::new(vec![error]))?;
+    Ok(finished.resources)
+}
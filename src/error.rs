@@ -1,12 +1,17 @@
 //! Error types for the overall prequery-preprocessor API
 
 use std::fmt;
+use std::path::PathBuf;
 
 use thiserror::Error;
 
+use crate::init;
+use crate::lockfile;
 use crate::manifest;
 use crate::preprocessor;
+use crate::query::TypstVersion;
 use crate::reporting::{ErrorExt, WriteExt};
+use crate::state;
 
 /// Indicates that the query config is not valid for web-resource
 #[derive(Error, Debug)]
@@ -20,6 +25,37 @@ pub enum Error {
     /// A preprocessor's execution failed
     #[error(transparent)]
     PreprocessorExecution(#[from] MultiplePreprocessorExecutionError),
+    /// The lockfile could not be updated
+    #[error(transparent)]
+    Lockfile(#[from] lockfile::Error),
+    /// The state of incremental runs could not be read or updated
+    #[error(transparent)]
+    State(#[from] state::Error),
+    /// The prequery configuration could not be scaffolded
+    #[error(transparent)]
+    Init(#[from] init::Error),
+    /// The Typst executable is too old. This is only an error in
+    /// [strict][crate::args::CliArguments::strict] mode
+    #[error(
+        "Typst {found} is not supported, at least version {} is required",
+        TypstVersion::MINIMUM
+    )]
+    UnsupportedTypstVersion {
+        /// The version of the Typst executable
+        found: TypstVersion,
+    },
+    /// The version of the Typst executable could not be determined. This is only an error in
+    /// [strict][crate::args::CliArguments::strict] mode
+    #[error("the version of `{}` could not be determined", .0.display())]
+    UnknownTypstVersion(PathBuf),
+    /// No job's query found anything, and a document doesn't mention prequery. This is only an
+    /// error in [strict][crate::args::CliArguments::strict] mode
+    #[error(
+        "no job's query found anything, and {} doesn't mention prequery; did you import the \
+         prequery package?",
+        .0.display()
+    )]
+    NoPrequeryImport(PathBuf),
 }
 
 /// One or more preprocessors were not configured correctly
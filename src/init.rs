@@ -0,0 +1,116 @@
+//! Scaffolding of the prequery configuration in `typst.toml`, for `--init`
+
+use std::path::PathBuf;
+
+use crate::world::World;
+
+pub use error::*;
+
+/// The example configuration that is appended to `typst.toml`
+pub const EXAMPLE: &str = r#"
+# prequery configuration, see https://typst.app/universe/package/prequery
+[tool.prequery]
+
+# download the resources declared in the document into the project
+[[tool.prequery.jobs]]
+name = "download"
+kind = "web-resource"
+# remember the URLs of downloaded files, so that changed URLs are downloaded again
+index = true
+
+# run a command for each piece of data declared in the document, e.g. to execute code
+# [[tool.prequery.jobs]]
+# name = "python"
+# kind = "shell"
+# query.selector = "<python>"
+# command = ["python", "exec.py"]
+"#;
+
+/// Returns the content of a `typst.toml` file with the [example][EXAMPLE] configuration appended.
+/// Fails if the file already contains prequery configuration. Returns `None` if the file is not
+/// valid TOML, or would not be after appending the example.
+pub fn scaffold(location: PathBuf, content: &str) -> Result<Option<String>> {
+    let Ok(config) = content.parse::<toml::Table>() else {
+        return Ok(None);
+    };
+    let configured = config
+        .get("tool")
+        .and_then(|tool| tool.get("prequery"))
+        .is_some();
+    if configured {
+        return Err(Error::AlreadyConfigured(location));
+    }
+
+    let mut content = content.to_string();
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(EXAMPLE);
+    Ok(content.parse::<toml::Table>().is_ok().then_some(content))
+}
+
+/// Appends the [example][EXAMPLE] configuration to the `typst.toml` file closest to the input file.
+/// If there is no such file or it can't be edited, the example is logged instead.
+pub async fn run(world: &impl World) -> Result<()> {
+    let mut l = world.log();
+
+    let edited = match world.read_typst_toml_source().await {
+        Ok((location, content)) => match scaffold(location.clone(), &content)? {
+            Some(content) => match world.write_typst_toml_source(&location, &content).await {
+                Ok(()) => Some(location),
+                Err(error) => {
+                    log!(l, "{} could not be written: {error}", location.display());
+                    None
+                }
+            },
+            None => {
+                log!(
+                    l,
+                    "{} could not be edited automatically",
+                    location.display()
+                );
+                None
+            }
+        },
+        Err(error) => {
+            log!(l, "typst.toml could not be read: {error}");
+            None
+        }
+    };
+
+    match edited {
+        Some(location) => {
+            log!(
+                l,
+                "added an example prequery configuration to {}",
+                location.display(),
+            );
+        }
+        None => {
+            log!(l, "add the following prequery configuration to typst.toml:");
+            log!(l, "{EXAMPLE}");
+        }
+    }
+
+    Ok(())
+}
+
+mod error {
+    use std::path::PathBuf;
+
+    use thiserror::Error;
+
+    /// A problem with scaffolding the prequery configuration
+    #[derive(Error, Debug)]
+    pub enum Error {
+        /// The `typst.toml` file already contains prequery configuration
+        #[error(
+            "{} already contains a `tool.prequery` section, it was not changed",
+            .0.display()
+        )]
+        AlreadyConfigured(PathBuf),
+    }
+
+    /// Result type alias that defaults error to [enum@Error].
+    pub type Result<T, E = Error> = std::result::Result<T, E>;
+}
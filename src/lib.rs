@@ -7,16 +7,22 @@ mod reporting;
 pub mod args;
 pub mod entry;
 pub mod error;
+pub mod init;
+pub mod lockfile;
 pub mod manifest;
 pub mod preprocessor;
 mod preprocessors;
 pub mod query;
+pub mod state;
 mod utils;
 pub mod world;
 
 // re-export the actual preprocessors from the top level
 pub use preprocessors::*;
 
+#[doc(hidden)]
+pub use reporting::write_log as __write_log;
+
 #[cfg(feature = "test")]
 pub use test_utils::*;
 
@@ -0,0 +1,192 @@
+//! The lockfile, recording the resources produced by all jobs for reproducibility
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+pub use error::*;
+
+/// The lockfile, recording all resources produced by jobs, with their content hashes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Lockfile {
+    #[serde(skip)]
+    location: PathBuf,
+    /// a file format version number. Should be 1.
+    pub version: usize,
+    /// The entries in the lockfile, ordered by job name and path.
+    #[serde(default, rename = "resource", skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<LockedResource>,
+}
+
+/// A resource as reported by a job, to be recorded in the lockfile
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Resource {
+    /// The path of the resource, relative to the job's root
+    pub path: PathBuf,
+    /// The URL the resource was downloaded from
+    pub url: String,
+    /// The hash of the resource's content, in the form `algorithm:hex-digest`
+    pub hash: String,
+}
+
+/// An algorithm for computing the content hashes recorded in the lockfile
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// SHA-256
+    #[default]
+    Sha256,
+    /// SHA-512
+    Sha512,
+    /// BLAKE3
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// The algorithm's name, as used in the configuration and as the prefix of hashes
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Computes the hash of the given content, in the form `algorithm:hex-digest`.
+    pub fn digest(self, content: &[u8]) -> String {
+        let digest = match self {
+            Self::Sha256 => Sha256::digest(content).to_vec(),
+            Self::Sha512 => Sha512::digest(content).to_vec(),
+            Self::Blake3 => blake3::hash(content).as_bytes().to_vec(),
+        };
+        let mut hash = format!("{}:", self.name());
+        for byte in digest {
+            write!(hash, "{byte:02x}").expect("writing to a string failed");
+        }
+        hash
+    }
+}
+
+/// An entry in the lockfile
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LockedResource {
+    /// The job that produced the resource
+    pub job: String,
+    /// The path of the resource, relative to the job's root
+    pub path: PathBuf,
+    /// The URL the resource was downloaded from
+    pub url: String,
+    /// The hash of the resource's content, in the form `algorithm:hex-digest`
+    pub hash: String,
+    /// When this resource was first recorded with this URL and hash, in seconds since the Unix
+    /// epoch
+    pub timestamp: u64,
+}
+
+impl Lockfile {
+    /// Creates an empty lockfile that will be written to the given location
+    pub fn new(location: PathBuf) -> Self {
+        Self {
+            location,
+            version: 1,
+            entries: Vec::new(),
+        }
+    }
+
+    /// The location this lockfile is read from and written to
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// Reads a lockfile from a file.
+    pub async fn read(location: PathBuf) -> Result<Self> {
+        let lockfile = fs::read_to_string(&location).await?;
+        let mut lockfile: Self = toml::from_str(&lockfile)?;
+        if lockfile.version != 1 {
+            return Err(Error::Version(lockfile.version));
+        }
+        lockfile.location = location;
+        Ok(lockfile)
+    }
+
+    /// Writes the lockfile to a file.
+    pub async fn write(&self) -> Result<()> {
+        let mut file = fs::File::create(&self.location).await?;
+        let lockfile = toml::to_string(self)?;
+        file.write_all(lockfile.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Creates the lockfile for the current run from the resources reported by all jobs. Entries
+    /// whose URL and hash haven't changed keep their timestamp, other entries get the given
+    /// timestamp. Entries that were not reported are removed.
+    pub fn updated(&self, resources: Vec<(String, Resource)>, timestamp: u64) -> Self {
+        let previous: BTreeMap<_, _> = self
+            .entries
+            .iter()
+            .map(|entry| ((&entry.job, &entry.path), entry))
+            .collect();
+
+        let mut entries: Vec<_> = resources
+            .into_iter()
+            .map(|(job, Resource { path, url, hash })| {
+                let timestamp = match previous.get(&(&job, &path)) {
+                    Some(entry) if entry.url == url && entry.hash == hash => entry.timestamp,
+                    _ => timestamp,
+                };
+                LockedResource {
+                    job,
+                    path,
+                    url,
+                    hash,
+                    timestamp,
+                }
+            })
+            .collect();
+        entries.sort();
+
+        Self {
+            location: self.location.clone(),
+            version: 1,
+            entries,
+        }
+    }
+}
+
+mod error {
+    use std::io;
+    use std::path::PathBuf;
+
+    use thiserror::Error;
+
+    /// A problem with using the lockfile
+    #[derive(Error, Debug)]
+    pub enum Error {
+        /// I/O error while accessing the lockfile
+        #[error("lockfile could not be read or written")]
+        Io(#[from] io::Error),
+        /// Unexpected version: must be 1
+        #[error("expected lockfile version 1, was {0}")]
+        Version(usize),
+        /// Error parsing the lockfile's contents
+        #[error("invalid lockfile content")]
+        Parse(#[from] toml::de::Error),
+        /// Error writing new lockfile contents
+        #[error("lockfile: TOML writing error")]
+        Write(#[from] toml::ser::Error),
+        /// `--locked` was given, but no lockfile is configured
+        #[error("--locked was given, but no lockfile is configured in typst.toml")]
+        NotConfigured,
+        /// `--locked` was given, but the lockfile would change
+        #[error("--locked was given, but the lockfile {} is not up to date", .0.display())]
+        Outdated(PathBuf),
+    }
+
+    /// Result type alias that defaults error to [enum@Error].
+    pub type Result<T, E = Error> = std::result::Result<T, E>;
+}
@@ -1,55 +1,251 @@
 //! Configuration types
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::path::{Path, PathBuf};
 
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use serde::de::{self, Visitor};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use toml::Table;
 use typst_syntax::package::PackageManifest;
 
 pub use error::*;
+pub use when::When;
+
+mod when;
 
 /// The complete prequery manifest as found in the `[tool.prequery]` section in `typst.toml`.
 /// Usually, that section will be defined as multiple `[[tool.prequery.jobs]]` entries.
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct PrequeryManifest {
+    /// The oldest version of prequery-preprocess that supports this manifest, e.g. `"0.4.0"`.
+    /// Older versions refuse to run it instead of misinterpreting newer options.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_version: Option<String>,
+    /// Change this to true or a file path given as a string to enable the lockfile, relative to the
+    /// `typst.toml` file. If true, the default path is "prequery.lock". The lockfile records the
+    /// resources downloaded by all jobs, including content hashes.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_lockfile",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub lockfile: Option<PathBuf>,
+    /// The field (`--field`) that jobs query when they don't specify one, instead of the
+    /// preprocessors' own default (usually `value`). Like a job's field, this may be `false` to
+    /// query whole elements.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_field",
+        serialize_with = "serialize_field",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default_field: Option<Option<String>>,
+    /// Whether jobs set the `prequery-fallback=true` input during their queries, unless they
+    /// specify otherwise. Defaults to `true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<bool>,
+    /// Whether jobs set the `prequery-job` input to their name during their queries, unless they
+    /// specify otherwise. Defaults to `false`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pass_job_name: Option<bool>,
+    /// A directory inside the project root that all output paths of jobs are relative to, e.g.
+    /// `generated`. Paths that start with `/` are still relative to the root itself. The default
+    /// index files of jobs are also placed in this directory (relative to the `typst.toml` file,
+    /// like all index files).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<PathBuf>,
+    /// Package-wide settings for `web-resource` jobs, e.g.
+    /// `web_resource.default_index = "assets.index.toml"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub web_resource: Option<KindDefaults>,
+    /// Package-wide settings for `shell` jobs, e.g. `shell.default_index = "shell.index.toml"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<KindDefaults>,
     /// The preprocessing jobs to execute
     pub jobs: Vec<Job>,
 }
 
+impl PrequeryManifest {
+    /// Returns the package-wide settings for jobs of the given kind, if there are any.
+    pub fn kind_defaults(&self, kind: &str) -> Option<&KindDefaults> {
+        match kind {
+            "web-resource" => self.web_resource.as_ref(),
+            "shell" => self.shell.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Package-wide settings for the jobs of a single preprocessor kind
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct KindDefaults {
+    /// The index file that jobs of this kind use if they set `index = true`, instead of the
+    /// preprocessor's own default. Like explicitly given index files, it is relative to the
+    /// `typst.toml` file, and not placed in the [output directory][PrequeryManifest::output_dir].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_index: Option<PathBuf>,
+}
+
 /// A single preprocessing job. A job normally consists of executing the configured query and then
 /// processing the result in some way, usually writing to files in the project root.
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Job {
     /// The job's name (for human consumption, e.g. in logs)
     pub name: String,
     /// Identifier of the preprocessor that should be run
     pub kind: String,
+    /// The project root for this job, relative to the `typst.toml` file. If given, this overrides
+    /// the root given on the command line for all paths this job accesses: its query is run with
+    /// this root, and its output and index paths are relative to it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
     /// The query the preprocessor needs to run
     #[serde(default)]
     pub query: Query,
+    /// The prefix of the job's log lines. `{name}` and `{kind}` are replaced by the job's name and
+    /// kind, e.g. `"[{kind}:{name}]"`. If not given, the prefix is `[name]`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_prefix: Option<String>,
+    /// How often the whole job is run again if it fails. If not given, a failed job is not
+    /// retried.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<usize>,
+    /// A condition on environment variables, e.g. `"env.CI == 'true'"`. If given and not met, the
+    /// job is skipped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when: Option<When>,
+    /// The Typst executable used for this job's queries, overriding the one given on the command
+    /// line. A path with multiple components is relative to the `typst.toml` file; a plain name is
+    /// looked up in the `PATH`, e.g. `"typst-0.11"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typst: Option<PathBuf>,
     /// Arbitrary additional manifest for the job
     #[serde(flatten)]
     pub manifest: Table,
 }
 
+impl Job {
+    /// Whether the job should be run, according to its [condition][Self::when]. Environment
+    /// variables are looked up using the given function.
+    pub fn is_enabled(&self, var: impl Fn(&str) -> Option<String>) -> bool {
+        self.when.as_ref().is_none_or(|when| when.evaluate(var))
+    }
+
+    /// Returns the job's [log prefix][Self::log_prefix] with the placeholders replaced, or `None`
+    /// if the default prefix should be used.
+    pub fn render_log_prefix(&self) -> Option<String> {
+        let template = self.log_prefix.as_ref()?;
+        let prefix = template
+            .replace("{name}", &self.name)
+            .replace("{kind}", &self.kind);
+        Some(prefix)
+    }
+}
+
+/// Settings outside a job's own configuration that preprocessors take into account when
+/// configuring it; mostly package-wide defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobDefaults {
+    /// The [package-wide default][PrequeryManifest::default_field] for the query's field
+    pub field: Option<Option<String>>,
+    /// The [directory][PrequeryManifest::output_dir] that output paths are relative to
+    pub output_dir: Option<PathBuf>,
+    /// The job's [rendered][Job::render_log_prefix] log prefix, if it is not the default
+    pub log_prefix: Option<String>,
+    /// The job's [Typst executable][Job::typst], if it is not the one from the command line
+    pub typst: Option<PathBuf>,
+    /// The [package-wide default index][KindDefaults::default_index] for the job's kind
+    pub index: Option<PathBuf>,
+}
+
+impl JobDefaults {
+    /// Replaces `index = true` in a job's configuration by the path of its default index: the
+    /// [package-wide default][Self::index] if there is one, otherwise the preprocessor's own
+    /// `default`, placed in the [output directory][Self::output_dir].
+    pub fn apply_default_index(&self, config: &mut Table, default: &str) {
+        if config.get("index").and_then(toml::Value::as_bool) != Some(true) {
+            return;
+        }
+        let index = match (&self.index, &self.output_dir) {
+            (Some(index), _) => index.clone(),
+            (None, Some(output_dir)) => output_dir.join(default),
+            (None, None) => PathBuf::from(default),
+        };
+        let index = index.to_string_lossy().into_owned();
+        config.insert("index".to_string(), index.into());
+    }
+}
+
 /// Query configuration. All fields here are optional, as preprocessors can define their own
 /// defaults.
-#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq, Eq)]
 pub struct Query {
-    /// The selector to be queried, e.g. `<label>`
+    /// The selector to be queried, e.g. `<label>`. The selector may contain `${name}` placeholders
+    /// that are replaced by the [variables][Self::vars] of the same name. A label containing `*` or
+    /// `?`, e.g. `<*-data>`, is a [pattern][crate::query::Query::label_pattern] that matches the
+    /// labels of all metadata elements.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub selector: Option<String>,
     /// The field (`--field`) to be queried from the selector (with metadata elements, this is
     /// usually `value`)
-    #[serde(default, deserialize_with = "deserialize_field")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_field",
+        serialize_with = "serialize_field",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub field: Option<Option<String>>,
     /// Whether only one (`--one`) query result is expected and should be returned
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub one: Option<bool>,
     /// Any additional inputs (`--input`) to be given to the queried document. Regardless of these
-    /// settings, `prequery-fallback` is always set to `true` during queries.
-    #[serde(default)]
+    /// settings, `prequery-fallback` is set to `true` during queries, unless
+    /// [fallback][Self::fallback] is `false`. A value of the form `${env:NAME}` is replaced by the
+    /// environment variable `NAME` when the query is executed, which keeps secrets out of the
+    /// manifest. Expanded values are never logged.
+    #[serde(
+        default,
+        serialize_with = "serialize_sorted",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
     pub inputs: HashMap<String, String>,
+    /// The document to query, relative to the `typst.toml` file. If not given, the input file
+    /// given on the command line is queried.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<PathBuf>,
+    /// Whether to set the `prequery-fallback=true` input during the query. Defaults to the
+    /// package-wide [fallback][PrequeryManifest::fallback] setting, or `true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<bool>,
+    /// Whether to set the `prequery-job` input to the job's name during the query, which lets a
+    /// document queried by several jobs tell them apart. An explicitly given
+    /// [input][Self::inputs] of the same name takes precedence. Defaults to the package-wide
+    /// [pass_job_name][PrequeryManifest::pass_job_name] setting, or `false`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pass_job_name: Option<bool>,
+    /// Additional arguments for `typst query`, e.g. `["--features", "html"]`. These are passed
+    /// verbatim before the document and selector, and are not validated in any way: arguments that
+    /// change the output format or conflict with the arguments generated by prequery can break
+    /// the query.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+    /// Variables for the placeholders in the [selector][Self::selector]. This allows jobs that
+    /// only differ in the queried label to be written the same way, e.g.
+    /// `selector = "<${name}-data>"` with `vars.name = "figure"`.
+    #[serde(
+        default,
+        serialize_with = "serialize_sorted",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub vars: HashMap<String, String>,
+    /// A JSON or TOML file, relative to the `typst.toml` file, containing a table of additional
+    /// [inputs][Self::inputs]. This allows sharing inputs between projects. Inputs given directly
+    /// take precedence over those from the file. Values that are not strings are passed as JSON,
+    /// e.g. `true` or `[1, 2]`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inputs_file: Option<PathBuf>,
 }
 
 impl PrequeryManifest {
@@ -60,11 +256,279 @@ impl PrequeryManifest {
             .tool
             .sections
             .remove("prequery")
-            .ok_or(Error::Missing)?
-            .try_into::<Self>()
-            .map_err(Error::from)?;
+            .ok_or(Error::Missing)?;
+        // check the version first, as a newer manifest may not be valid for this version at all
+        if let Some(min_version) = config.get("min_version").and_then(|value| value.as_str()) {
+            check_min_version(min_version, env!("CARGO_PKG_VERSION"))?;
+        }
+        let config = config.try_into::<Self>().map_err(Error::from)?;
+        // job names identify jobs in logs and elsewhere, so they must be unique
+        for (second, job) in config.jobs.iter().enumerate() {
+            if let Some(first) = config.jobs[..second]
+                .iter()
+                .position(|j| j.name == job.name)
+            {
+                return Err(Error::DuplicateJobName {
+                    name: job.name.clone(),
+                    first: first + 1,
+                    second: second + 1,
+                });
+            }
+        }
         Ok(config)
     }
+
+    /// Resolves the paths in the manifest that are relative to the manifest's location, given the
+    /// directory containing the `typst.toml` file.
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        if let Some(lockfile) = &mut self.lockfile {
+            *lockfile = dir.join(&*lockfile);
+        }
+        for job in &mut self.jobs {
+            if let Some(root) = &mut job.root {
+                *root = dir.join(&*root);
+            }
+            if let Some(typst) = &mut job.typst
+                && typst.components().count() > 1
+            {
+                *typst = dir.join(&*typst);
+            }
+            if let Some(document) = &mut job.query.document {
+                *document = dir.join(&*document);
+            }
+            if let Some(inputs_file) = &mut job.query.inputs_file {
+                *inputs_file = dir.join(&*inputs_file);
+            }
+        }
+    }
+
+    /// Applies the package-wide [defaults][JobDefaults] and the [selector
+    /// variables][Query::vars] to the jobs, so that their configuration shows what they will
+    /// actually use. The preprocessors' own defaults are not applied. Selectors with undefined
+    /// variables are left as they are.
+    pub fn resolve_jobs(&mut self) {
+        for job in &mut self.jobs {
+            let query = &mut job.query;
+            query.fallback = query.fallback.or(self.fallback);
+            query.pass_job_name = query.pass_job_name.or(self.pass_job_name);
+            query.field = query.field.take().or_else(|| self.default_field.clone());
+            if let Some(selector) = &mut query.selector
+                && let Ok(expanded) = crate::query::expand_vars(selector, &query.vars)
+            {
+                *selector = expanded;
+            }
+        }
+    }
+
+    /// Reads the jobs' [inputs files][Query::inputs_file] and adds their inputs to the jobs'
+    /// queries. Inputs that are already given are not replaced. Paths should already be
+    /// [resolved][Self::resolve_paths].
+    pub async fn merge_inputs_files(&mut self) -> Result<()> {
+        for job in &mut self.jobs {
+            let Some(path) = &job.query.inputs_file else {
+                continue;
+            };
+            let content = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|error| Error::InputsFileRead(path.clone(), error))?;
+            let inputs = parse_inputs(path, &content)
+                .map_err(|error| Error::InputsFileInvalid(path.clone(), error))?;
+            for (key, value) in inputs {
+                job.query.inputs.entry(key).or_insert(value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the `current` version is at least the `required` one, following semver's
+/// precedence: missing components of the required version are zero, and a pre-release such as
+/// `0.3.0-rc.1` is older than its release `0.3.0`.
+fn check_min_version(required: &str, current: &str) -> Result<()> {
+    let comparator = semver::Comparator::parse(&format!(">={}", required.trim()))
+        .map_err(|_| Error::InvalidMinVersion(required.to_string()))?;
+    let current = semver::Version::parse(current).expect("Cargo package versions are valid semver");
+    // a comparator wouldn't match pre-releases of newer versions, so only its version is used
+    let required_version = semver::Version {
+        major: comparator.major,
+        minor: comparator.minor.unwrap_or(0),
+        patch: comparator.patch.unwrap_or(0),
+        pre: comparator.pre,
+        build: semver::BuildMetadata::EMPTY,
+    };
+    if current < required_version {
+        return Err(Error::MinVersion {
+            required: required.to_string(),
+            current: current.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Parses the content of an inputs file: JSON if the file name ends in `.json`, TOML otherwise.
+/// The file must contain a table; its values are converted to strings.
+fn parse_inputs(path: &Path, content: &str) -> Result<HashMap<String, String>, String> {
+    let table: serde_json::Map<String, serde_json::Value> =
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(content).map_err(|error| error.to_string())?
+        } else {
+            toml::from_str(content).map_err(|error| error.to_string())?
+        };
+    let inputs = table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                value => value.to_string(),
+            };
+            (key, value)
+        })
+        .collect();
+    Ok(inputs)
+}
+
+/// A list of glob patterns for excluding items of a query result by their path. Exclusion happens
+/// after the query and before a preprocessor acts on the items, e.g. by downloading resources.
+#[derive(Debug, Clone, Default)]
+pub struct Exclude {
+    patterns: Vec<String>,
+    set: GlobSet,
+}
+
+impl Exclude {
+    /// Creates an exclusion list from the given glob patterns.
+    pub fn new(patterns: Vec<String>) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &patterns {
+            builder.add(Glob::new(pattern)?);
+        }
+        let set = builder.build()?;
+        Ok(Self { patterns, set })
+    }
+
+    /// Checks whether the given path is excluded. A leading `/` (referring to the project root) is
+    /// ignored for matching.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let path = path.strip_prefix("/").unwrap_or(path);
+        self.set.is_match(path)
+    }
+}
+
+impl PartialEq for Exclude {
+    fn eq(&self, other: &Self) -> bool {
+        self.patterns == other.patterns
+    }
+}
+
+impl Eq for Exclude {}
+
+impl<'de> Deserialize<'de> for Exclude {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let patterns = Vec::deserialize(deserializer)?;
+        Self::new(patterns).map_err(de::Error::custom)
+    }
+}
+
+/// Unix permissions for files written by a preprocessor, e.g. `0o600` to make them readable only
+/// by their owner. In the manifest, this is either an integer (TOML supports octal literals such as
+/// `0o644`) or a string of octal digits such as `"644"`. On other platforms, it has no effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMode(u32);
+
+impl FileMode {
+    /// Creates a file mode from the given permission bits. Returns `None` if any bits besides the
+    /// permissions, setuid, setgid and sticky bits (`0o7777`) are set.
+    pub fn new(mode: u32) -> Option<Self> {
+        (mode <= 0o7777).then_some(Self(mode))
+    }
+
+    /// The permission bits of this file mode.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Sets the permissions of the file at the given location to this mode.
+    pub async fn apply(self, location: &Path) -> std::io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let permissions = std::fs::Permissions::from_mode(self.0);
+            tokio::fs::set_permissions(location, permissions).await
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = location;
+            Ok(())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FileMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct FileModeVisitor;
+
+        impl Visitor<'_> for FileModeVisitor {
+            type Value = FileMode;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter
+                    .write_str("a file mode between 0o0 and 0o7777, as an integer or octal string")
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                u32::try_from(v)
+                    .ok()
+                    .and_then(FileMode::new)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Signed(v), &self))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                u32::try_from(v)
+                    .ok()
+                    .and_then(FileMode::new)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                let digits = v.strip_prefix("0o").unwrap_or(v);
+                u32::from_str_radix(digits, 8)
+                    .ok()
+                    .and_then(FileMode::new)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_any(FileModeVisitor)
+    }
+}
+
+/// What happens if a preprocessor's index file can't be read because its content is invalid, e.g.
+/// after it was edited by hand
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnCorrupt {
+    /// The job fails.
+    #[default]
+    Error,
+    /// A warning is logged and the index is treated as empty. Since the index is only an
+    /// optimization, the job still runs, and the index is rebuilt when it is saved.
+    Rebuild,
 }
 
 /// Deserializes the `field` config: if given, must be either a string or `false`.
@@ -116,8 +580,75 @@ where
     deserializer.deserialize_any(FieldVisitor)
 }
 
+/// Serializes the `field` config like it is deserialized: a string, or `false` for no field.
+fn serialize_field<S>(field: &Option<Option<String>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match field {
+        Some(Some(field)) => serializer.serialize_str(field),
+        Some(None) => serializer.serialize_bool(false),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes a map with its keys in order, so that the output is stable.
+fn serialize_sorted<S>(map: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+/// Deserializes the `lockfile` config: if given, must be either a boolean or string.
+fn deserialize_lockfile<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct LockfileVisitor;
+
+    impl Visitor<'_> for LockfileVisitor {
+        type Value = Option<PathBuf>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a boolean or string")
+        }
+
+        fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v.then(|| "prequery.lock".into()))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            self.visit_string(v.to_owned())
+        }
+
+        fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Some(v.into()))
+        }
+
+        fn visit_none<E>(self) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(None)
+        }
+    }
+
+    deserializer.deserialize_any(LockfileVisitor)
+}
+
 mod error {
     use std::io;
+    use std::path::PathBuf;
 
     use thiserror::Error;
 
@@ -135,6 +666,35 @@ mod error {
             "typst.toml contains `tool.prequery` key, but it's not a valid preprocessor configuration"
         )]
         Invalid(#[from] toml::de::Error),
+        /// The manifest requires a newer version of prequery-preprocess
+        #[error(
+            "this manifest requires prequery-preprocess >= {required}, but this is version {current}"
+        )]
+        MinVersion {
+            /// The manifest's minimum version
+            required: String,
+            /// The version of this executable
+            current: String,
+        },
+        /// Two jobs have the same name
+        #[error("jobs #{first} and #{second} are both named `{name}`; job names must be unique")]
+        DuplicateJobName {
+            /// The jobs' name
+            name: String,
+            /// The position of the first job with that name, starting at 1
+            first: usize,
+            /// The position of the second job with that name, starting at 1
+            second: usize,
+        },
+        /// The manifest's minimum version is not a version number
+        #[error("invalid min_version `{0}`, expected a version such as `0.4.0`")]
+        InvalidMinVersion(String),
+        /// A job's inputs file could not be read
+        #[error("the inputs file {} could not be read", .0.display())]
+        InputsFileRead(PathBuf, #[source] io::Error),
+        /// A job's inputs file does not contain a table of inputs
+        #[error("the inputs file {} is not a valid JSON or TOML table: {}", .0.display(), .1)]
+        InputsFileInvalid(PathBuf, String),
     }
 
     /// Result type alias that defaults error to [enum@Error].
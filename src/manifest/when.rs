@@ -0,0 +1,240 @@
+use std::fmt;
+
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A condition on environment variables that decides whether a job is run, e.g.
+/// `env.CI == 'true' && !env.OFFLINE`.
+///
+/// The grammar is kept minimal:
+/// - `env.NAME` is true if the variable is set;
+/// - `a == b` and `a != b` compare variables and string literals (in single or double quotes); an
+///   unset variable is not equal to any string;
+/// - conditions can be combined using `!`, `&&`, `||` and parentheses, with the usual precedence.
+#[derive(Debug, Clone)]
+pub struct When {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Set(String),
+    Compare(Operand, Operand, bool),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Env(String),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Env(String),
+    Literal(String),
+    Eq,
+    Ne,
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+impl When {
+    /// Parses a condition.
+    pub fn new(source: String) -> Result<Self, String> {
+        let mut tokens = tokenize(&source)?.into_iter().peekable();
+        let expr = parse_or(&mut tokens)?;
+        if let Some(token) = tokens.next() {
+            return Err(format!("unexpected {token} in condition `{source}`"));
+        }
+        Ok(Self { source, expr })
+    }
+
+    /// Evaluates the condition, looking up environment variables using the given function.
+    pub fn evaluate(&self, var: impl Fn(&str) -> Option<String>) -> bool {
+        self.expr.evaluate(&var)
+    }
+}
+
+impl Expr {
+    fn evaluate(&self, var: &impl Fn(&str) -> Option<String>) -> bool {
+        match self {
+            Self::Set(name) => var(name).is_some(),
+            Self::Compare(a, b, equal) => {
+                let (a, b) = (a.evaluate(var), b.evaluate(var));
+                let result = a.is_some() && a == b;
+                result == *equal
+            }
+            Self::Not(expr) => !expr.evaluate(var),
+            Self::And(a, b) => a.evaluate(var) && b.evaluate(var),
+            Self::Or(a, b) => a.evaluate(var) || b.evaluate(var),
+        }
+    }
+}
+
+impl Operand {
+    fn evaluate(&self, var: &impl Fn(&str) -> Option<String>) -> Option<String> {
+        match self {
+            Self::Env(name) => var(name),
+            Self::Literal(value) => Some(value.clone()),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' if chars.next_if(|&(_, c)| c == '=').is_some() => Token::Eq,
+            '!' if chars.next_if(|&(_, c)| c == '=').is_some() => Token::Ne,
+            '!' => Token::Not,
+            '&' if chars.next_if(|&(_, c)| c == '&').is_some() => Token::And,
+            '|' if chars.next_if(|&(_, c)| c == '|').is_some() => Token::Or,
+            '\'' | '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((_, c)) => value.push(c),
+                        None => return Err(format!("unterminated string in condition `{source}`")),
+                    }
+                }
+                Token::Literal(value)
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+                {
+                    end = i + c.len_utf8();
+                }
+                let word = &source[start..end];
+                match word.strip_prefix("env.") {
+                    Some(name) if !name.is_empty() && !name.contains('.') => {
+                        Token::Env(name.to_string())
+                    }
+                    _ => return Err(format!("unknown name `{word}` in condition `{source}`")),
+                }
+            }
+            c => return Err(format!("unexpected `{c}` in condition `{source}`")),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+type Tokens = std::iter::Peekable<std::vec::IntoIter<Token>>;
+
+fn parse_or(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = parse_and(tokens)?;
+    while tokens.next_if_eq(&Token::Or).is_some() {
+        expr = Expr::Or(Box::new(expr), Box::new(parse_and(tokens)?));
+    }
+    Ok(expr)
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = parse_unary(tokens)?;
+    while tokens.next_if_eq(&Token::And).is_some() {
+        expr = Expr::And(Box::new(expr), Box::new(parse_unary(tokens)?));
+    }
+    Ok(expr)
+}
+
+fn parse_unary(tokens: &mut Tokens) -> Result<Expr, String> {
+    if tokens.next_if_eq(&Token::Not).is_some() {
+        return Ok(Expr::Not(Box::new(parse_unary(tokens)?)));
+    }
+    if tokens.next_if_eq(&Token::Open).is_some() {
+        let expr = parse_or(tokens)?;
+        return match tokens.next() {
+            Some(Token::Close) => Ok(expr),
+            Some(token) => Err(format!("expected `)`, found {token}")),
+            None => Err("expected `)`, found the end of the condition".to_string()),
+        };
+    }
+
+    let a = parse_operand(tokens)?;
+    let equal = match tokens.peek() {
+        Some(Token::Eq) => true,
+        Some(Token::Ne) => false,
+        _ => {
+            // a lone variable checks for presence
+            return match a {
+                Operand::Env(name) => Ok(Expr::Set(name)),
+                Operand::Literal(value) => Err(format!("string '{value}' is not a condition")),
+            };
+        }
+    };
+    tokens.next();
+    let b = parse_operand(tokens)?;
+    Ok(Expr::Compare(a, b, equal))
+}
+
+fn parse_operand(tokens: &mut Tokens) -> Result<Operand, String> {
+    match tokens.next() {
+        Some(Token::Env(name)) => Ok(Operand::Env(name)),
+        Some(Token::Literal(value)) => Ok(Operand::Literal(value)),
+        Some(token) => Err(format!("expected a variable or string, found {token}")),
+        None => Err("expected a variable or string, found the end of the condition".to_string()),
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Env(name) => write!(f, "`env.{name}`"),
+            Self::Literal(value) => write!(f, "string '{value}'"),
+            Self::Eq => write!(f, "`==`"),
+            Self::Ne => write!(f, "`!=`"),
+            Self::Not => write!(f, "`!`"),
+            Self::And => write!(f, "`&&`"),
+            Self::Or => write!(f, "`||`"),
+            Self::Open => write!(f, "`(`"),
+            Self::Close => write!(f, "`)`"),
+        }
+    }
+}
+
+impl fmt::Display for When {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl PartialEq for When {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for When {}
+
+impl<'de> Deserialize<'de> for When {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let source = String::deserialize(deserializer)?;
+        Self::new(source).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for When {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.source)
+    }
+}
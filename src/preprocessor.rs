@@ -13,6 +13,7 @@ pub use error::{
 pub use factory::MockPreprocessorDefinition;
 pub use factory::{PreprocessorDefinition, PreprocessorFactory, PreprocessorMap};
 
+use crate::lockfile;
 use crate::world::World;
 
 /// A configured preprocessor that can be executed for its side effect
@@ -25,8 +26,59 @@ pub trait Preprocessor<W: World> {
     /// This preprocessor's name, which normally comes from [Job::name][crate::manifest::Job::name].
     fn name(&self) -> &str;
 
+    /// The prefix of this preprocessor's log lines, normally `[name]`; see
+    /// [Job::log_prefix][crate::manifest::Job::log_prefix].
+    fn log_prefix(&self) -> &str;
+
+    /// Checks invariants that can be derived from this preprocessor's configuration, such as
+    /// output paths escaping the root. This is called for all jobs before any of them is executed,
+    /// and must not have side effects. By default, nothing is checked.
+    fn validate(&self) -> ConfigResult<()> {
+        Ok(())
+    }
+
     /// Executes this preprocessor
     async fn run(&mut self) -> Result<(), DynError>;
+
+    /// Reports the resources this preprocessor has produced, to be recorded in the lockfile. This
+    /// is called after a successful [run][Preprocessor::run] if a lockfile is configured. By
+    /// default, no resources are reported.
+    async fn locked_resources(&mut self) -> Result<Vec<lockfile::Resource>, DynError> {
+        Ok(Vec::new())
+    }
+
+    /// Whether this preprocessor's query found nothing to process during the last
+    /// [run][Preprocessor::run], before any filtering. This is used to detect documents that don't
+    /// use prequery at all. By default, this is not reported.
+    async fn found_nothing(&mut self) -> bool {
+        false
+    }
+
+    /// Removes the files this preprocessor has produced in previous runs. This is called instead
+    /// of [run][Preprocessor::run] for `--clean`. By default, cleaning is not supported, which is
+    /// only reported as a warning.
+    async fn clean(&mut self) -> Result<(), DynError> {
+        let mut l = self.world().log();
+        log!(
+            l,
+            "{} warning: this job does not support cleaning, skipped",
+            self.log_prefix()
+        );
+        Ok(())
+    }
+
+    /// Reports how this preprocessor's index would change if it was run, without changing
+    /// anything. This is called instead of [run][Preprocessor::run] for `--diff-index`. By
+    /// default, index diffs are not supported, which is only reported as a warning.
+    async fn diff_index(&mut self) -> Result<(), DynError> {
+        let mut l = self.world().log();
+        log!(
+            l,
+            "{} warning: this job does not support index diffs, skipped",
+            self.log_prefix()
+        );
+        Ok(())
+    }
 }
 
 /// A dynamically dispatched, boxed preprocessor
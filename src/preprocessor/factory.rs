@@ -3,6 +3,7 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::error::Error;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use super::{BoxedPreprocessor, ConfigError, ConfigResult, ManifestError};
@@ -18,13 +19,19 @@ pub trait PreprocessorDefinition<W: World> {
     /// The identifier of the preprocessor, referenced by the [Job::kind][manifest::Job::kind] field
     fn name(&self) -> Cow<'static, str>;
 
-    /// Creates the preprocessor; implementation part.
+    /// Creates the preprocessor; implementation part. If `root` is given, the preprocessor should
+    /// use it instead of [WorldExt::resolve_root][crate::world::WorldExt::resolve_root]. The
+    /// preprocessor should also respect the package-wide `defaults`: e.g., if a default field is
+    /// given, it should be used as the query's field when the job doesn't specify one, instead of
+    /// the preprocessor's own default.
     fn configure(
         &self,
         world: &Arc<W>,
         name: String,
+        root: Option<PathBuf>,
         manifest: toml::Table,
         query: manifest::Query,
+        defaults: manifest::JobDefaults,
     ) -> Result<BoxedPreprocessor<W>, Self::Error>;
 }
 
@@ -40,8 +47,10 @@ pub trait PreprocessorFactory<W: World> {
         &self,
         world: &Arc<W>,
         name: String,
+        root: Option<PathBuf>,
         manifest: toml::Table,
         query: manifest::Query,
+        defaults: manifest::JobDefaults,
     ) -> ConfigResult<BoxedPreprocessor<W>>;
 }
 
@@ -57,11 +66,13 @@ where
         &self,
         world: &Arc<W>,
         name: String,
+        root: Option<PathBuf>,
         manifest: toml::Table,
         query: manifest::Query,
+        defaults: manifest::JobDefaults,
     ) -> ConfigResult<BoxedPreprocessor<W>> {
         let preprocessor = self
-            .configure(world, name, manifest, query)
+            .configure(world, name, root, manifest, query, defaults)
             .map_err(|error| ManifestError::new(self.name(), error))?;
         Ok(preprocessor)
     }
@@ -96,23 +107,46 @@ impl<W: World> PreprocessorMap<W> {
 
     /// Looks up the preprocessor according to [Job::kind][manifest::Job::kind] and returns the name
     /// and result of creating the preprocessor. The creation may fail if the kind is not
-    /// recognized, or some part of the manifest was not valid for that kind.
+    /// recognized, or some part of the manifest was not valid for that kind. The `defaults` are
+    /// the package-wide settings from the [manifest][manifest::PrequeryManifest]; the job's log
+    /// prefix is added to them. If the job [passes its name][manifest::Query::pass_job_name], the
+    /// `prequery-job` input is added to its query.
     pub fn get(
         &self,
         world: &Arc<W>,
         job: manifest::Job,
+        defaults: manifest::JobDefaults,
     ) -> Result<BoxedPreprocessor<W>, (String, ConfigError)> {
+        let defaults = manifest::JobDefaults {
+            log_prefix: job.render_log_prefix(),
+            typst: job.typst.clone(),
+            ..defaults
+        };
         let manifest::Job {
             name,
             kind,
+            root,
             query,
+            log_prefix: _,
+            retries: _,
+            when: _,
+            typst: _,
             manifest,
         } = job;
+        let mut query = query;
+        if query.pass_job_name == Some(true) {
+            // explicitly given inputs take precedence
+            query
+                .inputs
+                .entry("prequery-job".to_string())
+                .or_insert_with(|| name.clone());
+        }
         let inner = || {
             let Some(preprocessor) = self.map.get(kind.as_str()) else {
                 return Err(ConfigError::Unknown(kind));
             };
-            let preprocessor = preprocessor.configure(world, name.clone(), manifest, query)?;
+            let preprocessor =
+                preprocessor.configure(world, name.clone(), root, manifest, query, defaults)?;
             Ok(preprocessor)
         };
         inner().map_err(|error| (name, error))
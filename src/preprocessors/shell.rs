@@ -1,15 +1,18 @@
 //! The `shell` preprocessor
 
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::io;
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use derive_more::Debug;
 use itertools::{Either, Itertools};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 
-use crate::preprocessor::{DynError, Preprocessor};
+use crate::preprocessor::{self, ConfigResult, DynError, Preprocessor};
 use crate::query::{self, Query};
 use crate::world::{World as _, WorldExt as _};
 
@@ -26,7 +29,7 @@ mod world;
 use index::*;
 use manifest::*;
 use query_data::*;
-use world::World;
+use world::{World, WorldExt as _};
 
 pub use error::*;
 pub use factory::ShellFactory;
@@ -39,51 +42,166 @@ pub struct Shell<W: World> {
     #[debug(skip)]
     world: Arc<W>,
     name: String,
+    log_prefix: String,
+    root: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
     manifest: Manifest,
-    index: Option<Mutex<Index>>,
+    /// The index, if one is in use. This is populated when the job starts running.
+    index: Mutex<Option<Index>>,
     query: Query,
+    /// Whether the query found no inputs at all, before any were excluded
+    found_nothing: Mutex<bool>,
+}
+
+/// The output of a command. Plain stdout that is also saved as plain text is kept as raw bytes, so
+/// that binary output is saved verbatim; all other output is data.
+enum CommandOutput {
+    Raw(Vec<u8>),
+    Data(serde_json::Value),
+}
+
+impl CommandOutput {
+    /// Returns the output's data. Raw output is only produced when saving plain text, so this must
+    /// not be called in other cases.
+    fn into_data(self) -> serde_json::Value {
+        match self {
+            Self::Data(data) => data,
+            Self::Raw(_) => unreachable!("raw output is only produced for plain output files"),
+        }
+    }
+}
+
+/// Serializes JSON to be saved, with the keys of all objects sorted. This doesn't rely on how
+/// `serde_json` orders maps, which changes if its `preserve_order` feature is enabled anywhere in
+/// the dependency graph.
+fn to_sorted_json(mut output: serde_json::Value) -> serde_json::Result<Vec<u8>> {
+    output.sort_all_objects();
+    serde_json::to_vec(&output)
 }
 
 impl<W: World> Shell<W> {
     pub(crate) fn new(
         world: Arc<W>,
         name: String,
+        root: Option<PathBuf>,
+        output_dir: Option<PathBuf>,
         manifest: Manifest,
-        index: Option<Mutex<Index>>,
+        index: Option<Index>,
         query: Query,
     ) -> Self {
         Self {
             world,
+            log_prefix: format!("[{name}]"),
             name,
-            index,
+            root,
+            output_dir,
+            index: Mutex::new(index),
             manifest,
             query,
+            found_nothing: Mutex::default(),
+        }
+    }
+
+    /// Replaces the default `[name]` log prefix.
+    pub(crate) fn with_log_prefix(mut self, log_prefix: String) -> Self {
+        self.log_prefix = log_prefix;
+        self
+    }
+
+    /// Resolves an output path relative to this job's root, or the output directory within it if
+    /// the path is not absolute. Fails if the path escapes the root.
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let main = self.world.main();
+        let root = self.root.as_deref().unwrap_or_else(|| main.resolve_root());
+        let path = match &self.output_dir {
+            Some(output_dir) if !path.has_root() => Cow::Owned(output_dir.join(path)),
+            _ => Cow::Borrowed(path),
+        };
+        main.try_resolve_in(root, &path)
+    }
+
+    /// Resolves the path of a command's input file relative to this job's root. The output
+    /// directory does not apply to input files. Fails if the path escapes the root.
+    fn resolve_input(&self, path: &Path) -> io::Result<PathBuf> {
+        let main = self.world.main();
+        let root = self.root.as_deref().unwrap_or_else(|| main.resolve_root());
+        main.try_resolve_in(root, path)
+    }
+
+    /// Resolves the path a command result should be saved to. Fails (and logs the problem) if the
+    /// path escapes the root. [Stdout][STDOUT] is not a path and is kept as is.
+    fn resolve_output(&self, path: &Path) -> Result<PathBuf, FileError> {
+        if path == Path::new(STDOUT) {
+            return Ok(path.to_path_buf());
         }
+        self.resolve(path).inspect_err(|error| {
+            let mut l = self.world.main().log();
+            let prefix = &self.log_prefix;
+            let path_str = path.to_string_lossy();
+            log!(
+                l,
+                "{prefix} Can't store command results in {path_str}: {error}"
+            );
+        })
     }
 
-    async fn populate_index(&mut self) -> Result<(), IndexError> {
-        if let Some(path) = self.manifest.index.as_ref() {
+    async fn populate_index(&self) -> Result<(), IndexError> {
+        let index = if let Some(path) = self.manifest.index.as_ref() {
             // an index is in use
-            let index = self.world.read_index(path).await?;
-            self.index = Some(Mutex::new(index));
+            Some(self.world.read_index(path).await?)
         } else {
             // no index is in use
-            self.index = None;
-        }
+            None
+        };
+        *self.index.lock().await = index;
+
+        Ok(())
+    }
 
+    /// Saves the index that is in use. Entries that other jobs or processes sharing the index
+    /// have added since it was read are kept.
+    async fn save_index(&self, index: &Index) -> Result<(), IndexError> {
+        let path = self.manifest.index.as_ref();
+        let path = path.expect("an index is only in use if it is configured");
+        self.world
+            .with_index(path, |written| written.merge(index))
+            .await?;
         Ok(())
     }
 
-    async fn query(&self) -> query::Result<QueryData> {
-        let data = self.world.main().query(&self.query).await?;
-        Ok(data)
+    /// Queries the items to process. With a path template, the items' output paths are rendered
+    /// from it.
+    async fn query(&self) -> ExecutionResult<QueryData> {
+        let main = self.world.main().as_ref();
+        let Some(template) = &self.manifest.path_template else {
+            return Ok(self.query.execute(main).await?);
+        };
+        let items: Vec<TemplatedItem> = self.query.execute(main).await?;
+        QueryData::from_template(items, template)
+            .map_err(|(item, field)| ExecutionError::PathTemplate { item, field })
     }
 
     async fn run_command(
         self: Arc<Self>,
         input: serde_json::Value,
-    ) -> Result<serde_json::Value, CommandError> {
-        let command = &self.manifest.command;
+    ) -> Result<CommandOutput, CommandError> {
+        let command = &self.manifest.command.0;
+        if self.manifest.input_as == InputAs::Args {
+            let args = match input {
+                serde_json::Value::String(arg) => vec![arg],
+                serde_json::Value::Array(args) => args
+                    .into_iter()
+                    .map(|arg| match arg {
+                        serde_json::Value::String(arg) => arg,
+                        _ => unreachable!("inputs were already checked to be strings"),
+                    })
+                    .collect(),
+                _ => unreachable!("inputs were already checked to be strings"),
+            };
+            let command: Vec<_> = command.iter().cloned().chain(args).collect();
+            return self.run_command_raw(&command, &[]).await;
+        }
+
         let input = match self.manifest.format.stdin {
             Format::Plain => {
                 let serde_json::Value::String(input) = input else {
@@ -92,51 +210,298 @@ impl<W: World> Shell<W> {
                 input.into_bytes()
             }
             Format::Json => serde_json::to_vec(&input)?,
+            Format::Split => {
+                unreachable!("split is only an output format, as ensured by the factory")
+            }
         };
 
-        let output = self.world.run_command(&command.0, &input).await?;
+        self.run_command_raw(command, &input).await
+    }
+
+    /// Runs the command through the world, treating exactly the configured exit codes as success.
+    async fn run_world_command(
+        &self,
+        command: &[String],
+        input: &[u8],
+    ) -> Result<Vec<u8>, CommandError> {
+        let success_codes = &self.manifest.success_codes;
+        match self.world.run_command(command, input).await {
+            Ok(output) if !success_codes.contains(&0) => {
+                Err(CommandError::UnexpectedSuccess(output))
+            }
+            Err(CommandError::ExitStatus(status, output))
+                if status
+                    .code()
+                    .is_some_and(|code| success_codes.contains(&code)) =>
+            {
+                Ok(output)
+            }
+            result => result,
+        }
+    }
+
+    /// Runs the command with the given, already encoded, input. If inputs are passed in files, the
+    /// input is written to a temporary file for the command instead of its stdin.
+    async fn run_command_raw(
+        &self,
+        command: &[String],
+        input: &[u8],
+    ) -> Result<CommandOutput, CommandError> {
+        let output = if self.manifest.input_as == InputAs::File {
+            let location = self
+                .world
+                .create_input_file(input)
+                .await
+                .map_err(CommandError::InputFile)?;
+            let location_str = location.to_string_lossy();
+            let command: Vec<_> = command
+                .iter()
+                .map(|arg| arg.replace(INPUT_FILE, &location_str))
+                .collect();
+            let output = self.run_world_command(&command, &[]).await;
+            // the file is removed even if the command failed
+            if let Err(error) = self.world.remove_input_file(&location).await {
+                let mut l = self.world.main().log();
+                let prefix = &self.log_prefix;
+                log!(
+                    l,
+                    "{prefix} warning: removing input file {location_str} failed: {error}"
+                );
+            }
+            output?
+        } else {
+            self.run_world_command(command, input).await?
+        };
         let output = match self.manifest.format.stdout {
+            Format::Plain if self.manifest.format.output == Format::Plain => {
+                // the bytes are saved verbatim, so they don't need to be text
+                return Ok(CommandOutput::Raw(output));
+            }
             Format::Plain => {
                 let output = String::from_utf8(output).map_err(|_| CommandError::NonStringPlain)?;
                 serde_json::Value::String(output)
             }
+            Format::Json if output.trim_ascii().is_empty() => {
+                if !self.manifest.allow_empty {
+                    return Err(CommandError::EmptyOutput);
+                }
+                serde_json::Value::Null
+            }
             Format::Json => serde_json::from_slice(&output)?,
+            Format::Split => {
+                unreachable!("split is only an output format, as ensured by the factory")
+            }
         };
 
-        Ok(output)
+        Ok(CommandOutput::Data(output))
     }
 
     async fn write_output(
         self: Arc<Self>,
         location: PathBuf,
-        output: serde_json::Value,
+        output: CommandOutput,
     ) -> Result<(), FileError> {
         let output = match self.manifest.format.output {
             Format::Plain => {
-                let serde_json::Value::String(output) = output else {
-                    unreachable!("outputs were already checked to be strings");
+                let output = match output {
+                    CommandOutput::Raw(output) => output,
+                    CommandOutput::Data(serde_json::Value::String(output)) => output.into_bytes(),
+                    CommandOutput::Data(_) => {
+                        unreachable!("outputs were already checked to be strings")
+                    }
                 };
-                output.into_bytes()
+                self.manifest.format.trim.apply(output)
             }
-            Format::Json => serde_json::to_vec(&output)?,
+            Format::Json => to_sorted_json(output.into_data())?,
+            Format::Split => match output.into_data() {
+                // the split files can't be plain or JSON as a whole, so each decides for itself
+                serde_json::Value::String(output) => {
+                    self.manifest.format.trim.apply(output.into_bytes())
+                }
+                output => to_sorted_json(output)?,
+            },
         };
-        self.world.write_output(&location, &output).await?;
+        self.world
+            .write_output(&location, &output)
+            .await
+            .inspect_err(|error| {
+                let mut l = self.world.main().log();
+                let prefix = &self.log_prefix;
+                let path_str = location.to_string_lossy();
+                log!(l, "{prefix} Saving to {path_str} failed: {error}");
+            })?;
         Ok(())
     }
 
-    async fn run_impl(self: &mut Arc<Self>) -> ExecutionResult<()> {
-        Arc::get_mut(self)
-            .expect("shell ref count should be one before starting the processing")
-            .populate_index()
+    async fn run_impl(self: &Arc<Self>) -> ExecutionResult<()> {
+        self.populate_index().await?;
+
+        match self.manifest.mode {
+            Mode::Split => self.run_split().await?,
+            Mode::Whole | Mode::File => self.run_whole().await?,
+        }
+
+        if let Some(index) = &*self.index.lock().await {
+            self.save_index(index).await?;
+        }
+
+        Ok::<_, ExecutionError>(())
+    }
+
+    /// Runs a single command on the whole query result or the input file, and saves its output to
+    /// the configured file
+    async fn run_whole(self: &Arc<Self>) -> ExecutionResult<()> {
+        let mut l = self.world.main().log();
+        let prefix = &self.log_prefix;
+
+        let path = if self.manifest.format.output == Format::Split {
+            // the command output determines the files
+            None
+        } else {
+            let path = self.manifest.output.as_ref().expect(
+                "whole and file mode require an output without split output, as ensured by the factory",
+            );
+            Some(self.resolve_output(path)?)
+        };
+
+        let (input, source) = if self.manifest.mode == Mode::File {
+            // the input file is passed on verbatim
+            let input_path = self
+                .manifest
+                .input
+                .as_ref()
+                .expect("file mode requires an input, as ensured by the factory");
+            let read_input = async {
+                let location = self.resolve_input(input_path)?;
+                self.world.read_input(&location).await
+            };
+            let input = read_input
+                .await
+                .map_err(|error| ExecutionError::Input(input_path.clone(), error))?;
+            (input, format!("input file {}", input_path.display()))
+        } else {
+            // the query result is passed on verbatim, unless it needs to be converted to plain text
+            let data = self.world.main().query_raw(&self.query).await?;
+            let input = match self.manifest.format.stdin {
+                Format::Plain => {
+                    let data = serde_json::from_slice(&data).map_err(query::Error::from)?;
+                    let serde_json::Value::String(input) = data else {
+                        return Err(CommandError::NonStringPlain.into());
+                    };
+                    input.into_bytes()
+                }
+                Format::Json => data,
+                Format::Split => {
+                    unreachable!("split is only an output format, as ensured by the factory")
+                }
+            };
+            (input, "the whole query result".to_string())
+        };
+
+        log!(
+            l,
+            "{prefix} executing command \"{}\" with {source}...",
+            self.manifest.command,
+        );
+
+        let output = self
+            .run_command_raw(&self.manifest.command.0, &input)
             .await?;
+        if let CommandOutput::Data(data) = &output
+            && self.manifest.format.output == Format::Plain
+            && !data.is_string()
+        {
+            return Err(CommandError::NonStringPlain.into());
+        }
+
+        let Some(path) = path else {
+            return self.write_split_output(output.into_data()).await;
+        };
+
+        log!(
+            l,
+            "{prefix} execution finished, saving to {}...",
+            path.display(),
+        );
+        Arc::clone(self).write_output(path, output).await?;
+
+        log!(l, "{prefix} command results saved",);
+
+        Ok(())
+    }
 
+    /// Saves a command output that maps file paths to contents to these files. All paths are
+    /// checked before saving any file.
+    async fn write_split_output(
+        self: &Arc<Self>,
+        output: serde_json::Value,
+    ) -> ExecutionResult<()> {
+        let mut l = self.world.main().log();
+        let prefix = &self.log_prefix;
+
+        let serde_json::Value::Object(files) = output else {
+            return Err(CommandError::NonObjectSplit.into());
+        };
+
+        let mut outputs = Vec::with_capacity(files.len());
+        let mut locations = HashSet::with_capacity(files.len());
+        for (path, output) in files {
+            if path == STDOUT {
+                return Err(ExecutionError::StdoutWithMultipleOutputs);
+            }
+            let location = self.resolve_output(Path::new(&path))?;
+            if !locations.insert(location.clone()) {
+                return Err(ExecutionError::SplitCollision(location));
+            }
+            outputs.push((location, CommandOutput::Data(output)));
+        }
+
+        log!(
+            l,
+            "{prefix} execution finished, saving to {} files...",
+            outputs.len(),
+        );
+
+        let writes = outputs
+            .into_iter()
+            .map(|(path, output)| Arc::clone(self).write_output(path, output));
+        let results = futures::future::join_all(writes).await;
+        let errors: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+        if !errors.is_empty() {
+            // failed writes were already logged
+            return Err(error::MultipleFileError::new(errors).into());
+        }
+
+        log!(l, "{prefix} command results saved",);
+
+        Ok(())
+    }
+
+    /// Splits the query result into items and runs commands on them, saving the outputs in the
+    /// files specified by the items
+    async fn run_split(self: &Arc<Self>) -> ExecutionResult<()> {
         let mut l = self.world.main().log();
-        let name = self.name();
+        let prefix = &self.log_prefix;
 
         let query_data = self.query().await?;
+        *self.found_nothing.lock().await = query_data.is_empty();
+        let query_data = query_data.exclude(&self.manifest.exclude);
         let (outputs, inputs) = query_data.split();
 
-        if self.manifest.format.stdin == Format::Plain {
+        if self.manifest.input_as == InputAs::Args {
+            // (the factory ensures that inputs are not joined)
+            // all inputs must be strings or arrays of strings
+            for input in &inputs {
+                let valid = match input {
+                    serde_json::Value::String(_) => true,
+                    serde_json::Value::Array(args) => args.iter().all(|arg| arg.is_string()),
+                    _ => false,
+                };
+                if !valid {
+                    return Err(CommandError::NonStringArgs.into());
+                }
+            }
+        } else if self.manifest.format.stdin == Format::Plain {
             // (we already know that we're not processing a joined query; that's ensured by the factory)
             // all inputs must be strings
             for input in &inputs {
@@ -152,56 +517,26 @@ impl<W: World> Shell<W> {
             }
         }
 
+        // check all output paths before running any commands
         let outputs = match outputs {
-            Output::SharedOutput(path) => {
-                let path_str = path.to_string_lossy();
-                let path = self
-                    .world
-                    .main()
-                    .resolve(&path)
-                    .ok_or_else(|| {
-                        let msg = format!("{path_str} is outside the project root");
-                        io::Error::new(io::ErrorKind::PermissionDenied, msg)
-                    })
-                    .inspect_err(|error| {
-                        log!(
-                            l,
-                            "[{name}] Can't store command results in {path_str}: {error}"
-                        );
-                    })?;
-                Output::SharedOutput(path)
-            }
+            Output::SharedOutput(path) => Output::SharedOutput(self.resolve_output(&path)?),
             Output::IndividualOutput(paths) => {
+                if paths.iter().any(|path| path == Path::new(STDOUT)) {
+                    return Err(ExecutionError::StdoutWithMultipleOutputs);
+                }
                 let paths = paths
-                    .into_iter()
-                    .map(|path| {
-                        let path_str = path.to_string_lossy();
-                        let path = self
-                            .world
-                            .main()
-                            .resolve(&path)
-                            .ok_or_else(|| {
-                                let msg = format!("{path_str} is outside the project root");
-                                io::Error::new(io::ErrorKind::PermissionDenied, msg)
-                            })
-                            .inspect_err(|error| {
-                                log!(
-                                    l,
-                                    "[{name}] Can't store command results in {path_str}: {error}"
-                                );
-                            })?;
-                        Ok::<_, io::Error>(path)
-                    })
+                    .iter()
+                    .map(|path| self.resolve_output(path))
                     .try_collect()?;
                 Output::IndividualOutput(paths)
             }
         };
 
-        let output = if self.manifest.joined {
+        let results = if self.manifest.joined {
             // run one command
             log!(
                 l,
-                "[{name}] executing command \"{}\" with {} joined inputs...",
+                "{prefix} executing command \"{}\" with {} joined inputs...",
                 self.manifest.command,
                 inputs.len(),
             );
@@ -212,74 +547,115 @@ impl<W: World> Shell<W> {
             let output = Arc::clone(self).run_command(input).await?;
 
             // output must be an array as long as the input
-            match output {
-                serde_json::Value::Array(outputs) if outputs.len() == length => outputs,
+            match output.into_data() {
+                serde_json::Value::Array(outputs) if outputs.len() == length => outputs
+                    .into_iter()
+                    .map(CommandOutput::Data)
+                    .map(Ok)
+                    .collect(),
                 _ => return Err(CommandError::Array.into()),
             }
         } else {
             // run many commands
             log!(
                 l,
-                "[{name}] executing command \"{}\" for {} inputs...",
+                "{prefix} executing command \"{}\" for {} inputs...",
                 self.manifest.command,
                 inputs.len(),
             );
 
-            let commands = inputs
-                .into_iter()
-                .map(|input| Arc::clone(self).run_command(input));
-            let results = futures::future::join_all(commands).await;
-
-            // collect
-            let (outputs, errors): (Vec<_>, Vec<_>) =
-                results.into_iter().partition_map(|result| match result {
-                    Ok(output) => Either::Left(output),
-                    Err(error) => Either::Right(error),
-                });
-            if !errors.is_empty() {
-                return Err(error::MultipleCommandError::new(errors).into());
+            // without `concurrent`, commands are run one after the other
+            let limit = if self.manifest.concurrent {
+                let max_concurrent = self.manifest.max_concurrent;
+                max_concurrent.unwrap_or_else(|| self.world.main().concurrency())
+            } else {
+                NonZeroUsize::MIN
+            };
+            let semaphore = Arc::new(Semaphore::new(limit.get()));
+            let commands = inputs.into_iter().map(|input| {
+                let this = Arc::clone(self);
+                let semaphore = Arc::clone(&semaphore);
+                async move {
+                    let _permit = semaphore
+                        .acquire()
+                        .await
+                        .expect("semaphore is never closed");
+                    this.run_command(input).await
+                }
+            });
+            if self.manifest.fail_fast {
+                // the first error aborts all other commands; no outputs are written in that case,
+                // including those of commands that already finished
+                let outputs = futures::future::try_join_all(commands).await?;
+                outputs.into_iter().map(Ok).collect()
+            } else {
+                futures::future::join_all(commands).await
             }
-
-            outputs
         };
 
         match outputs {
             Output::SharedOutput(path) => {
+                // all commands must have succeeded to save to one file
+                let (output, errors): (Vec<_>, Vec<_>) =
+                    results.into_iter().partition_map(|result| match result {
+                        Ok(output) => Either::Left(output.into_data()),
+                        Err(error) => Either::Right(error),
+                    });
+                if !errors.is_empty() {
+                    return Err(error::MultipleCommandError::new(errors).into());
+                }
+
                 // save to one file
                 log!(
                     l,
-                    "[{name}] execution finished, saving to {}...",
+                    "{prefix} execution finished, saving to {}...",
                     path.display(),
                 );
 
                 let output = serde_json::Value::Array(output);
-                let output = serde_json::to_vec(&output).map_err(CommandError::from)?;
+                let output = to_sorted_json(output).map_err(CommandError::from)?;
                 self.world.write_output(&path, &output).await?;
             }
             Output::IndividualOutput(paths) => {
-                // save to many files
-                log!(l, "[{name}] execution finished, saving...",);
+                // save the successful results to many files
+                let (outputs, errors): (Vec<_>, Vec<_>) = paths
+                    .into_iter()
+                    .zip(results)
+                    .partition_map(|(path, result)| match result {
+                        Ok(output) => Either::Left((path, output)),
+                        Err(error) => Either::Right(error),
+                    });
+                if outputs.is_empty() && !errors.is_empty() {
+                    // nothing to save
+                    return Err(error::MultipleCommandError::new(errors).into());
+                } else if errors.is_empty() {
+                    log!(l, "{prefix} execution finished, saving...",);
+                } else {
+                    log!(
+                        l,
+                        "{prefix} execution finished with {} failed commands, saving successful results...",
+                        errors.len(),
+                    );
+                }
 
-                let writes = paths
+                let writes = outputs
                     .into_iter()
-                    .zip(output)
                     .map(|(path, output)| Arc::clone(self).write_output(path, output));
                 let results = futures::future::join_all(writes).await;
-                let errors: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+                let file_errors: Vec<_> = results.into_iter().filter_map(Result::err).collect();
                 if !errors.is_empty() {
-                    return Err(error::MultipleFileError::new(errors).into());
+                    // failed writes were already logged
+                    return Err(error::MultipleCommandError::new(errors).into());
+                }
+                if !file_errors.is_empty() {
+                    return Err(error::MultipleFileError::new(file_errors).into());
                 }
             }
         }
 
-        log!(l, "[{name}] command results saved",);
-
-        if let Some(index) = &self.index {
-            let index = index.lock().await;
-            self.world.write_index(&index).await?;
-        }
+        log!(l, "{prefix} command results saved",);
 
-        Ok::<_, ExecutionError>(())
+        Ok(())
     }
 }
 
@@ -293,8 +669,32 @@ impl<W: World> Preprocessor<W::MainWorld> for Arc<Shell<W>> {
         &self.name
     }
 
+    fn log_prefix(&self) -> &str {
+        &self.log_prefix
+    }
+
+    fn validate(&self) -> ConfigResult<()> {
+        let error = |option, error| {
+            let error = ManifestError::OutsideRoot(option, error);
+            preprocessor::ManifestError::new("shell".into(), error)
+        };
+        if let Some(path) = &self.manifest.output
+            && path != Path::new(STDOUT)
+        {
+            self.resolve(path).map_err(|e| error("output", e))?;
+        }
+        if let Some(path) = &self.manifest.input {
+            self.resolve_input(path).map_err(|e| error("input", e))?;
+        }
+        Ok(())
+    }
+
     async fn run(&mut self) -> Result<(), DynError> {
         self.run_impl().await.map_err(Box::new)?;
         Ok(())
     }
+
+    async fn found_nothing(&mut self) -> bool {
+        *self.found_nothing.lock().await
+    }
 }
@@ -1,12 +1,13 @@
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::process;
 
 use thiserror::Error;
 use tokio::task::JoinError;
 
 use crate::query;
-use crate::reporting::{ErrorExt, WriteExt};
+use crate::reporting::write_error_list;
 
 /// An error in the configuration of the job's query
 #[derive(Error, Debug)]
@@ -30,6 +31,47 @@ pub enum ManifestError {
         "the plain data format can't be used to input to/output from commands processing joined inputs"
     )]
     PlainWithJoined,
+    /// Inputs can't be joined when processing the whole query result
+    #[error("whole mode processes the query result as a whole, it can't be combined with joined")]
+    WholeWithJoined,
+    /// No output file was configured for processing the whole query result
+    #[error("whole mode requires an output file to be configured")]
+    WholeWithoutOutput,
+    /// Inputs can't be joined when processing an input file
+    #[error("file mode processes a single input file, it can't be combined with joined")]
+    FileWithJoined,
+    /// No input or output file was configured for processing an input file
+    #[error("file mode requires an input and an output file to be configured")]
+    FileWithoutInputOrOutput,
+    /// An input file was configured, but is only used in file mode
+    #[error("an input file can only be configured in file mode")]
+    InputWithoutFile,
+    /// The split format was used for the command's stdin or stdout
+    #[error("the split data format can only be used for the output")]
+    SplitNotOutput,
+    /// The split output format was used for a command per item
+    #[error("the split output format requires whole or file mode")]
+    SplitOutputInSplitMode,
+    /// An output file was configured, although the split output format determines the files
+    #[error(
+        "the split output format saves to the files named by the command, it can't be combined with an output file"
+    )]
+    SplitOutputWithOutput,
+    /// A path template was configured, but the query result is not split into items
+    #[error("a path template can only be used in split mode")]
+    PathTemplateWithoutSplit,
+    /// Inputs can only be passed as arguments to one command invocation per input
+    #[error("inputs can only be passed as arguments in split mode, and not when they are joined")]
+    ArgsWithoutSplit,
+    /// No exit code was configured as successful
+    #[error("success_codes must contain at least one exit code")]
+    EmptySuccessCodes,
+    /// Inputs are passed in files, but the command doesn't refer to them
+    #[error("inputs are passed in files, but the command does not contain `{{input_file}}`")]
+    FileWithoutPlaceholder,
+    /// A configured path escapes the job's root
+    #[error("shell {0} is invalid")]
+    OutsideRoot(&'static str, #[source] io::Error),
     /// An error in the configuration of the job's query
     #[error(transparent)]
     Query(#[from] QueryConfigError),
@@ -52,15 +94,34 @@ pub enum IndexError {
     Write(#[from] toml::ser::Error),
 }
 
+impl IndexError {
+    /// Checks whether this error means that the index file exists but its content is invalid.
+    /// An index of an unsupported version is not considered corrupt.
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            Self::Io(error) => error.kind() == io::ErrorKind::InvalidData,
+            Self::Parse(_) => true,
+            Self::Version(_) | Self::Write(_) => false,
+        }
+    }
+}
+
 /// An error while executing a shell command
 #[derive(Error, Debug)]
 pub enum CommandError {
     /// An error running or communication with a child process
     #[error(transparent)]
     Process(#[from] io::Error),
-    /// An unsuccessful child exit code
+    /// The command's executable could not be found
+    #[error("the `{0}` executable could not be found")]
+    NotFound(String),
+    /// An unsuccessful child exit code, along with the output the command produced
     #[error("the command failed: {0}")]
-    ExitStatus(process::ExitStatus),
+    ExitStatus(process::ExitStatus, Vec<u8>),
+    /// A successful child exit while `success_codes` doesn't include 0, along with the output the
+    /// command produced
+    #[error("the command failed: exit code 0 is not in success_codes")]
+    UnexpectedSuccess(Vec<u8>),
     /// The command input or output was not valid
     #[error("command input or output was not valid JSON or did not fit the expected format")]
     Json(#[from] serde_json::Error),
@@ -72,6 +133,18 @@ pub enum CommandError {
     /// array of joined outputs contained non-text data)
     #[error("the command got or returned non-string data")]
     NonStringPlain,
+    /// The command's stdout was empty, but JSON was expected
+    #[error("the command produced no output")]
+    EmptyOutput,
+    /// The command output to be split into files was not an object
+    #[error("the command did not return an object mapping file paths to contents")]
+    NonObjectSplit,
+    /// The command input to be passed as arguments was not a string or array of strings
+    #[error("the command input to be passed as arguments was not a string or array of strings")]
+    NonStringArgs,
+    /// The file to pass the input in could not be created
+    #[error("the command's input file could not be created")]
+    InputFile(#[source] io::Error),
     /// An error while waiting for the command to finish
     #[error("waiting for a command task failed")]
     Join(#[from] JoinError),
@@ -92,15 +165,7 @@ impl MultipleCommandError {
 
 impl fmt::Display for MultipleCommandError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use fmt::Write;
-
-        let mut w = f.hanging_indent("  ");
-        write!(w, "at least one command failed:")?;
-        for error in &self.errors {
-            writeln!(w)?;
-            write!(w, "{}", error.error_chain())?;
-        }
-        Ok(())
+        write_error_list(f, "at least one command failed:", &self.errors)
     }
 }
 
@@ -122,15 +187,7 @@ impl MultipleFileError {
 
 impl fmt::Display for MultipleFileError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use fmt::Write;
-
-        let mut w = f.hanging_indent("  ");
-        write!(w, "at least one result could not be written:")?;
-        for error in &self.errors {
-            writeln!(w)?;
-            write!(w, "{}", error.error_chain())?;
-        }
-        Ok(())
+        write_error_list(f, "at least one result could not be written:", &self.errors)
     }
 }
 
@@ -152,6 +209,25 @@ pub enum ExecutionError {
     /// An error while writing a command result
     #[error(transparent)]
     File(#[from] MultipleFileError),
+    /// Stdout was given as one of multiple outputs
+    #[error("only a single output can be written to stdout (`-`)")]
+    StdoutWithMultipleOutputs,
+    /// Multiple paths of a split command output refer to the same file
+    #[error("the command output contains multiple paths referring to {}", .0.display())]
+    SplitCollision(PathBuf),
+    /// An item's data doesn't have a field referenced by the path template
+    #[error(
+        "the path template's field `{field}` is not a string, number or boolean in item {item}"
+    )]
+    PathTemplate {
+        /// The index of the item in the query result
+        item: usize,
+        /// The placeholder's field, e.g. `data.id`
+        field: String,
+    },
+    /// An error while reading the command input file
+    #[error("the input file {} could not be read", .0.display())]
+    Input(PathBuf, #[source] io::Error),
 }
 
 impl From<CommandError> for ExecutionError {
@@ -1,5 +1,6 @@
 use std::borrow::Cow;
 use std::marker::PhantomData;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use crate::manifest;
@@ -7,7 +8,10 @@ use crate::preprocessor::{BoxedPreprocessor, PreprocessorDefinition};
 use crate::query::Query;
 
 use super::world::{DefaultWorld, World};
-use super::{Format, Manifest, ManifestError, ManifestResult, QueryConfigError, Shell};
+use super::{
+    DEFAULT_INDEX, Format, INPUT_FILE, InputAs, Manifest, ManifestError, ManifestResult, Mode,
+    QueryConfigError, Shell,
+};
 
 /// The `shell` preprocessor factory
 #[derive(Debug, Clone, Copy)]
@@ -34,15 +38,68 @@ impl<W: World> ShellFactory<W> {
         {
             return Err(ManifestError::PlainWithJoined);
         }
+        if config.format.stdin == Format::Split || config.format.stdout == Format::Split {
+            return Err(ManifestError::SplitNotOutput);
+        }
+        // with split output, the output files are given by the command instead of the manifest
+        let split_output = config.format.output == Format::Split;
+        if split_output {
+            if config.mode == Mode::Split {
+                return Err(ManifestError::SplitOutputInSplitMode);
+            }
+            if config.output.is_some() {
+                return Err(ManifestError::SplitOutputWithOutput);
+            }
+        }
+        if config.mode == Mode::Whole {
+            if config.joined {
+                return Err(ManifestError::WholeWithJoined);
+            }
+            if config.output.is_none() && !split_output {
+                return Err(ManifestError::WholeWithoutOutput);
+            }
+        }
+        if config.mode == Mode::File {
+            if config.joined {
+                return Err(ManifestError::FileWithJoined);
+            }
+            if config.input.is_none() || (config.output.is_none() && !split_output) {
+                return Err(ManifestError::FileWithoutInputOrOutput);
+            }
+        } else if config.input.is_some() {
+            return Err(ManifestError::InputWithoutFile);
+        }
+        if config.path_template.is_some() && config.mode != Mode::Split {
+            return Err(ManifestError::PathTemplateWithoutSplit);
+        }
+        if config.input_as == InputAs::Args && (config.mode != Mode::Split || config.joined) {
+            return Err(ManifestError::ArgsWithoutSplit);
+        }
+        if config.success_codes.is_empty() {
+            return Err(ManifestError::EmptySuccessCodes);
+        }
+        if config.input_as == InputAs::File
+            && !config.command.0.iter().any(|arg| arg.contains(INPUT_FILE))
+        {
+            return Err(ManifestError::FileWithoutPlaceholder);
+        }
         Ok(config)
     }
 
-    fn build_query(config: manifest::Query) -> ManifestResult<Query> {
-        let config = Query::builder()
-            .default_field(Some("value".to_string()))
-            .default_one(false)
-            .build(config)
-            .map_err(QueryConfigError::Builder)?;
+    fn build_query(
+        config: manifest::Query,
+        default_field: Option<Option<String>>,
+        mode: Mode,
+    ) -> ManifestResult<Query> {
+        let default_field = default_field.unwrap_or_else(|| Some("value".to_string()));
+        let mut builder = Query::builder()
+            .default_field(default_field)
+            .default_one(false);
+        if mode == Mode::File {
+            // file mode doesn't execute the query, so the selector is not required
+            builder = builder.default_selector(String::new());
+        }
+        let config = builder.build(config).map_err(QueryConfigError::Builder)?;
         if config.one {
             return Err(QueryConfigError::One.into());
         }
@@ -62,15 +119,30 @@ impl<W: World> PreprocessorDefinition<W::MainWorld> for ShellFactory<W> {
         &self,
         world: &Arc<W::MainWorld>,
         name: String,
-        config: toml::Table,
+        root: Option<PathBuf>,
+        mut config: toml::Table,
         query: manifest::Query,
+        defaults: manifest::JobDefaults,
     ) -> ManifestResult<BoxedPreprocessor<W::MainWorld>> {
-        let world = Arc::new(W::new(world.clone()));
-        let config = Self::parse_config(config)?;
+        defaults.apply_default_index(&mut config, DEFAULT_INDEX);
+        let mut config = Self::parse_config(config)?;
+        if let Some(root) = &root
+            && let Some(index) = &mut config.index
+        {
+            // a job with its own root keeps its index there
+            *index = root.join(&*index);
+        }
+        let output_dir = defaults.output_dir;
+        let world = Arc::new(W::new(world.clone(), &config));
         // index begins as None and is asynchronously populated later
         let index = None;
-        let query = Self::build_query(query)?;
-        let instance = Shell::new(world, name, config, index, query);
+        let mut query = Self::build_query(query, defaults.field, config.mode)?;
+        query.typst = defaults.typst;
+        query.root = root.clone();
+        let mut instance = Shell::new(world, name, root, output_dir, config, index, query);
+        if let Some(log_prefix) = defaults.log_prefix {
+            instance = instance.with_log_prefix(log_prefix);
+        }
         Ok(Box::new(Arc::new(instance)))
     }
 }
@@ -84,6 +84,14 @@ impl Index {
     pub fn update(&mut self, resource: Resource) {
         self.entries.insert(resource.path.clone(), resource);
     }
+
+    /// Takes over the entries of another index, e.g. one that a job has updated in memory since
+    /// this one was read from the same location. Entries only recorded in this index are kept.
+    pub fn merge(&mut self, other: &Index) {
+        for resource in other.entries.values() {
+            self.update(resource.clone());
+        }
+    }
 }
 
 fn serialize_entries<S>(map: &BTreeMap<PathBuf, Resource>, serializer: S) -> Result<S::Ok, S::Error>
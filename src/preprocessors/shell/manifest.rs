@@ -1,30 +1,96 @@
 use std::fmt;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer};
 
+use crate::manifest::{Exclude, FileMode, OnCorrupt};
+
 /// Auxiliary configuration for the preprocessor
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Manifest {
     /// command and arguments to run with this shell preprocessor job
     pub command: Command,
 
+    /// Whether the query result is split into individual items that are processed and saved
+    /// separately, or processed as a whole by a single command invocation.
+    #[serde(default)]
+    pub mode: Mode,
+
+    /// The file to save the command result to in [whole][Mode::Whole] and [file][Mode::File]
+    /// mode. In the default mode, the files to save to are specified in the query result instead.
+    /// With a [split][Format::Split] output format, the files are specified in the command output.
+    ///
+    /// The special path [`-`][STDOUT] writes the result to stdout instead of a file. This is also
+    /// possible for a shared output file given in the query result, but not for individual outputs.
+    pub output: Option<PathBuf>,
+
+    /// A template for the output paths of items in the query result that don't specify a `path`
+    /// in [split][Mode::Split] mode, e.g. `"figs/{data.id}.svg"`. Each `{data.field}` placeholder
+    /// is replaced by that field of the item's data, which must be a string, number or boolean;
+    /// nested fields are written like `{data.meta.id}`, and `{data}` refers to the data itself.
+    /// An item whose data lacks a field of the template is an error.
+    pub path_template: Option<String>,
+
+    /// The file to read the command's input from in [file][Mode::File] mode, relative to the
+    /// root. The file's content is passed to the command verbatim.
+    pub input: Option<PathBuf>,
+
     /// Whether each input should be process by its own command invocation, or all inputs should be
     /// joined and processed by a single command invocation.
     #[serde(default)]
     pub joined: bool,
 
+    /// How each input is passed to its command invocation. Passing inputs as arguments is only
+    /// possible in [split][Mode::Split] mode, without [joined][Manifest::joined] inputs.
+    #[serde(default)]
+    pub input_as: InputAs,
+
+    /// The directory that [input files][InputAs::File] are created in, relative to the
+    /// `typst.toml` file. If not given, the system's temporary directory is used.
+    pub temp_dir: Option<PathBuf>,
+
     /// Whether command invocation can be run concurrently to each other. This only has an effect if
     /// inputs are not joined together for a single command invocation.
     #[serde(default)]
     pub concurrent: bool,
 
+    /// The maximum number of commands to run at the same time if [concurrent][Self::concurrent]
+    /// is set. If not given, the limit given by `--concurrency` is used.
+    #[serde(default)]
+    pub max_concurrent: Option<NonZeroUsize>,
+
+    /// Whether to stop running commands as soon as one of them fails. This only has an effect if
+    /// inputs are not joined together for a single command invocation.
+    ///
+    /// If this is set, the first failure aborts the job without writing any output, not even the
+    /// results of commands that already succeeded. If this is not set, all commands are run even if
+    /// some fail. When saving to individual files, the results of all successful commands are then
+    /// still saved; only the outputs of failed commands are not written. When saving to a shared
+    /// output file, that file is only written if all commands succeeded, regardless of this option.
+    #[serde(default)]
+    pub fail_fast: bool,
+
+    /// The exit codes that indicate a successful command invocation. Some tools use non-zero exit
+    /// codes for results that are not failures, e.g. `grep` exits with `1` if nothing matched. The
+    /// output of a command exiting with any of these codes is processed as usual. The list must not
+    /// be empty.
+    #[serde(default = "default_success_codes")]
+    pub success_codes: Vec<i32>,
+
+    /// Whether a command that produces no output (or only whitespace) is treated as having returned
+    /// `null` instead of failing. This only has an effect if the command's stdout is
+    /// [JSON][Format::Json]; for other formats, empty output is an empty string.
+    #[serde(default)]
+    pub allow_empty: bool,
+
     /// The data formats for sending data in various directions. Typst queries are always
     /// represented as JSON, but command stdin, stdout and the file format to be read by Typst can
     /// be configured.
     ///
-    /// Not all options are always available: [Format::Plain] requires the data to be UTF8 text,
+    /// Not all options are always available: [Format::Plain] requires the data to be UTF8 text
+    /// (except if both stdout and output are plain, in which case the bytes are saved verbatim),
     /// which implies that it can't be used with [joined][Manifest::joined] inputs for stdin or
     /// stdout. Likewise, it can't be used for [output][Formats::output] if
     /// [SharedOutput][super::Output::SharedOutput] is used, since that must also save an array of
@@ -33,12 +99,73 @@ pub struct Manifest {
     pub format: Formats,
 
     /// Change this to true or a file path given as a string to enable the index. If true, the
-    /// default path is "shell-index.toml"; note that if multiple shell jobs are using the same
-    /// index file, this will lead to problems!
+    /// default path is "shell-index.toml", unless the package configures a different one as
+    /// `shell.default_index`. Multiple shell jobs can use the same index file, as each job merges
+    /// its entries into the file's current content when saving it. While an index is saved, it is
+    /// locked using a `.lock` file next to it.
     #[serde(default, deserialize_with = "deserialize_index")]
     pub index: Option<PathBuf>,
+
+    /// What happens if the index file's content is invalid, e.g. after it was edited by hand; see
+    /// [OnCorrupt]. By default, the job fails; `"rebuild"` treats the index as empty instead. The
+    /// `--repair-index` flag applies `"rebuild"` to all jobs.
+    #[serde(default)]
+    pub on_corrupt: OnCorrupt,
+
+    /// Glob patterns for output paths that should not be processed. Matching items are removed
+    /// from the query result before running any commands. This only applies to items with
+    /// individual outputs; a shared output file is never excluded.
+    #[serde(default)]
+    pub exclude: Exclude,
+
+    /// Permissions to give each output file, e.g. `0o755` for generated scripts; see [FileMode].
+    /// This is called `file_mode` since [mode][Manifest::mode] already configures how commands are
+    /// run. It doesn't apply to output written to stdout. If not given, files are created with the
+    /// operating system's default permissions.
+    #[serde(default)]
+    pub file_mode: Option<FileMode>,
+}
+
+fn default_success_codes() -> Vec<i32> {
+    vec![0]
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// The query result is split into items, each with data and/or a path to save results to.
+    /// Depending on [joined][Manifest::joined], one or many commands are run for the data.
+    #[default]
+    Split,
+    /// The complete query result is passed to a single command verbatim, and the result is saved
+    /// to the manifest's [output][Manifest::output] file.
+    Whole,
+    /// No query is executed. Instead, the manifest's [input][Manifest::input] file is passed to a
+    /// single command verbatim, and the result is saved to the manifest's [output][Manifest::output]
+    /// file.
+    File,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputAs {
+    /// The input is written to the command's stdin, in the [stdin format][Formats::stdin].
+    #[default]
+    Stdin,
+    /// The input, which must be a string or an array of strings, is appended to the command's
+    /// arguments. Nothing is written to the command's stdin.
+    Args,
+    /// The input is written to a temporary file in the [stdin format][Formats::stdin], and the
+    /// [`{input_file}`][INPUT_FILE] placeholder in the command's arguments is replaced by its path.
+    /// Nothing is written to the command's stdin. The file is removed once the command has
+    /// finished, regardless of whether it succeeded.
+    File,
 }
 
+/// The placeholder in a command's arguments that is replaced by the path of its
+/// [input file][InputAs::File]
+pub const INPUT_FILE: &str = "{input_file}";
+
 #[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
 pub struct Formats {
     #[serde(default)]
@@ -47,6 +174,40 @@ pub struct Formats {
     pub stdout: Format,
     #[serde(default)]
     pub output: Format,
+    /// How trailing newlines of the output are handled. This only has an effect if the output is
+    /// saved as [plain][Format::Plain] text.
+    #[serde(default)]
+    pub trim: Trim,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trim {
+    /// The output is saved verbatim.
+    #[default]
+    None,
+    /// All trailing newlines are removed from the output.
+    Trailing,
+    /// All trailing newlines are replaced by a single one, which is added if there is none.
+    EnsureFinalNewline,
+}
+
+impl Trim {
+    /// Applies this newline handling to the given output.
+    pub fn apply(self, mut output: Vec<u8>) -> Vec<u8> {
+        if self == Self::None {
+            return output;
+        }
+        let len = output
+            .iter()
+            .rposition(|byte| !matches!(byte, b'\n' | b'\r'))
+            .map_or(0, |index| index + 1);
+        output.truncate(len);
+        if self == Self::EnsureFinalNewline {
+            output.push(b'\n');
+        }
+        output
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -56,10 +217,27 @@ pub struct Command(pub Vec<String>);
 #[serde(rename_all = "lowercase")]
 pub enum Format {
     Plain,
+    /// When saving JSON, the keys of all objects are sorted, so that the saved files don't depend
+    /// on the order in which the command emitted them and stay stable under version control.
     #[default]
     Json,
+    /// Only available as the [output format][Formats::output] in [whole][Mode::Whole] and
+    /// [file][Mode::File] mode: the command output must be a JSON object mapping file paths to
+    /// their contents, and each file is saved separately. String contents are saved as plain text
+    /// (subject to [trim][Formats::trim]), all other contents as JSON.
+    ///
+    /// The paths are interpreted like any other output path, i.e. relative to the root or output
+    /// directory. If any path escapes the root, or if multiple paths refer to the same file (e.g.
+    /// `a.json` and `./a.json`), no file is saved at all.
+    Split,
 }
 
+/// The output path that refers to stdout instead of a file
+pub const STDOUT: &str = "-";
+
+/// The index location that is used if the `index` config is `true`
+pub const DEFAULT_INDEX: &str = "shell-index.toml";
+
 /// Deserializes the `index` config: if given, must be either a boolean or string.
 fn deserialize_index<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
 where
@@ -78,7 +256,7 @@ where
         where
             E: de::Error,
         {
-            Ok(v.then(|| "shell-index.toml".into()))
+            Ok(v.then(|| DEFAULT_INDEX.into()))
         }
 
         fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
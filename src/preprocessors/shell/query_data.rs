@@ -1,9 +1,12 @@
 use std::fmt;
 use std::path::PathBuf;
 
+use itertools::Itertools;
 use serde::Deserialize;
 use serde::de::{self, Deserializer, MapAccess, Visitor};
 
+use crate::manifest::Exclude;
+
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct InputItem {
     path: PathBuf,
@@ -19,12 +22,63 @@ pub enum QueryData {
     IndividualOutput(Vec<InputItem>),
 }
 
+/// An item of the query result when output paths are given by a path template: its path is
+/// optional
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct TemplatedItem {
+    #[serde(default)]
+    path: Option<PathBuf>,
+    data: serde_json::Value,
+}
+
 pub enum Output {
     SharedOutput(PathBuf),
     IndividualOutput(Vec<PathBuf>),
 }
 
 impl QueryData {
+    /// Creates individual outputs from items whose paths are rendered from the template, unless
+    /// they are given explicitly. Fails with the item index and field if the template references
+    /// a field that an item's data doesn't have.
+    pub fn from_template(
+        items: Vec<TemplatedItem>,
+        template: &str,
+    ) -> Result<Self, (usize, String)> {
+        let items = items
+            .into_iter()
+            .enumerate()
+            .map(|(index, TemplatedItem { path, data })| {
+                let path = match path {
+                    Some(path) => path,
+                    None => render_path_template(template, &data)
+                        .map_err(|field| (index, field))?
+                        .into(),
+                };
+                Ok(InputItem { path, data })
+            })
+            .try_collect()?;
+        Ok(QueryData::IndividualOutput(items))
+    }
+
+    /// Removes the items whose output paths are excluded. A shared output is never excluded.
+    pub fn exclude(self, exclude: &Exclude) -> Self {
+        match self {
+            QueryData::IndividualOutput(mut input_items) => {
+                input_items.retain(|item| !exclude.is_excluded(&item.path));
+                QueryData::IndividualOutput(input_items)
+            }
+            shared => shared,
+        }
+    }
+
+    /// Whether there are no inputs at all.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            QueryData::SharedOutput { inputs, .. } => inputs.is_empty(),
+            QueryData::IndividualOutput(input_items) => input_items.is_empty(),
+        }
+    }
+
     pub fn split(self) -> (Output, Vec<serde_json::Value>) {
         match self {
             QueryData::SharedOutput { path, inputs } => (Output::SharedOutput(path), inputs),
@@ -39,6 +93,37 @@ impl QueryData {
     }
 }
 
+/// Replaces the `{data.field}` placeholders in a path template by the fields of the data. If a
+/// field is missing or not a scalar value, the placeholder's field is returned as the error. An
+/// unterminated placeholder is kept verbatim.
+fn render_path_template(template: &str, data: &serde_json::Value) -> Result<String, String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let field = &rest[start + 1..start + end];
+        let mut segments = field.split('.');
+        let value = match segments.next() {
+            Some("data") => segments.try_fold(data, |value, segment| value.get(segment)),
+            _ => None,
+        };
+        let value = match value {
+            Some(serde_json::Value::String(value)) => value.clone(),
+            Some(value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) => {
+                value.to_string()
+            }
+            _ => return Err(field.to_string()),
+        };
+        result.push_str(&rest[..start]);
+        result.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
 impl<'de> Deserialize<'de> for QueryData {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -1,14 +1,21 @@
+use std::env;
 use std::ffi::OsStr;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use async_trait::async_trait;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use tokio::process;
 
+use crate::manifest::{FileMode, OnCorrupt};
+use crate::world::{FileLock, World as _};
+
 use super::index::Index;
+use super::manifest::{Manifest, STDOUT};
 use super::{CommandError, FileError, IndexError};
 
 /// The context for executing a Shell job. Defines how downloading and saving files work, and thus
@@ -18,8 +25,8 @@ use super::{CommandError, FileError, IndexError};
 pub trait World: Send + Sync + 'static {
     type MainWorld: crate::world::World;
 
-    /// Creates a new shell world based on the given main world.
-    fn new(main: Arc<Self::MainWorld>) -> Self;
+    /// Creates a new shell world based on the given main world, for a job with the given manifest.
+    fn new(main: Arc<Self::MainWorld>, manifest: &Manifest) -> Self;
 
     /// Accesses the main world.
     fn main(&self) -> &Arc<Self::MainWorld>;
@@ -30,27 +37,90 @@ pub trait World: Send + Sync + 'static {
     /// Writes the shell index to its location.
     async fn write_index(&self, index: &Index) -> Result<(), IndexError>;
 
-    /// Runs a shell command.
+    /// Locks the shell index at the given path, interpreted relative to the typst.toml file,
+    /// until the returned lock is dropped. The lock is held on a separate file next to the index,
+    /// named like the index with `.lock` appended.
+    async fn lock_index(&self, path: &Path) -> Result<FileLock, IndexError>;
+
+    /// Runs a shell command. A non-zero exit code results in an [`CommandError::ExitStatus`] error
+    /// that still carries the command's output; which codes count as success is decided by the
+    /// caller.
     async fn run_command<S>(&self, command: &[S], input: &[u8]) -> Result<Vec<u8>, CommandError>
     where
         S: AsRef<OsStr> + std::fmt::Debug + Send + Sync + 'static;
 
-    /// Writes a command's result to a file.
+    /// Creates a temporary file with the given content, to pass a command's input in.
+    async fn create_input_file(&self, content: &[u8]) -> io::Result<PathBuf>;
+
+    /// Removes a temporary input file created by [World::create_input_file].
+    async fn remove_input_file(&self, location: &Path) -> io::Result<()>;
+
+    /// Reads a command's input from a file.
+    async fn read_input(&self, location: &Path) -> io::Result<Vec<u8>>;
+
+    /// Writes a command's result to a file, or to stdout if the location is [`-`][super::STDOUT].
     async fn write_output(&self, location: &Path, output: &[u8]) -> Result<(), FileError>;
 }
 
+/// Higher-level operations on a shell [World], implemented in terms of its methods
+#[async_trait]
+pub trait WorldExt: World {
+    /// Reads the shell index at the given path, lets `f` modify it, and writes it back, all
+    /// while holding the [index lock][World::lock_index]. Unlike a separate
+    /// [read][World::read_index] and [write][World::write_index], this doesn't lose changes that
+    /// other jobs or processes sharing the index made in the meantime. Returns the written index.
+    async fn with_index<F>(&self, path: &Path, f: F) -> Result<Index, IndexError>
+    where
+        F: FnOnce(&mut Index) + Send,
+    {
+        let _lock = self.lock_index(path).await?;
+        let mut index = self.read_index(path).await?;
+        f(&mut index);
+        self.write_index(&index).await?;
+        Ok(index)
+    }
+}
+
+#[async_trait]
+impl<T: World> WorldExt for T {}
+
 /// The default context, accessing the real web and filesystem.
 #[derive(Clone)]
 pub struct DefaultWorld {
     main: Arc<crate::world::DefaultWorld>,
+    temp_dir: Option<PathBuf>,
+    file_mode: Option<FileMode>,
+    on_corrupt: OnCorrupt,
+}
+
+/// Distinguishes the input files created by this process
+static INPUT_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl DefaultWorld {
+    /// Returns the location of the index at the given path, interpreted relative to the
+    /// typst.toml file.
+    async fn index_location(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(self.main().manifest_dir().await?.join(path))
+    }
 }
 
 #[async_trait]
 impl World for DefaultWorld {
     type MainWorld = crate::world::DefaultWorld;
 
-    fn new(main: Arc<Self::MainWorld>) -> Self {
-        Self { main }
+    fn new(main: Arc<Self::MainWorld>, manifest: &Manifest) -> Self {
+        let temp_dir = manifest.temp_dir.clone();
+        let on_corrupt = if main.arguments().repair_index {
+            OnCorrupt::Rebuild
+        } else {
+            manifest.on_corrupt
+        };
+        Self {
+            main,
+            temp_dir,
+            file_mode: manifest.file_mode,
+            on_corrupt,
+        }
     }
 
     fn main(&self) -> &Arc<Self::MainWorld> {
@@ -58,17 +128,27 @@ impl World for DefaultWorld {
     }
 
     async fn read_index(&self, path: &Path) -> Result<Index, IndexError> {
-        let mut location = self.main().resolve_typst_toml().await?;
-        let result = location.pop();
-        assert!(
-            result,
-            "the path should have had a final filename component"
-        );
-        location.push(path);
+        let location = self.index_location(path).await?;
 
         let index = if fs::try_exists(&location).await.unwrap_or(false) {
             // read the existing index
-            Index::read(location).await?
+            match Index::read(location.clone()).await {
+                Err(error)
+                    if error.is_corrupt()
+                        && self.on_corrupt == OnCorrupt::Rebuild
+                        && !self.main.arguments().strict =>
+                {
+                    // the index is only an optimization, so start over
+                    let mut l = self.main.log();
+                    let location_str = location.to_string_lossy();
+                    log!(
+                        l,
+                        "warning: {location_str} is corrupt and will be rebuilt: {error}"
+                    );
+                    Index::new(location)
+                }
+                result => result?,
+            }
         } else {
             // generate an empty index
             Index::new(location)
@@ -81,24 +161,36 @@ impl World for DefaultWorld {
         Ok(())
     }
 
+    async fn lock_index(&self, path: &Path) -> Result<FileLock, IndexError> {
+        let mut location = self.index_location(path).await?.into_os_string();
+        location.push(".lock");
+        let lock = FileLock::acquire(location.into()).await?;
+        Ok(lock)
+    }
+
     async fn run_command<S>(&self, command: &[S], input: &[u8]) -> Result<Vec<u8>, CommandError>
     where
         S: AsRef<OsStr> + Send + Sync,
     {
-        let mut root = self.main().resolve_typst_toml().await?;
-        // remove the file name
-        let result = root.pop();
-        assert!(
-            result,
-            "the path should have had a final component of `typst.toml`"
-        );
+        let root = self.main().manifest_dir().await?;
 
         let mut child = process::Command::new(&command[0])
             .args(&command[1..])
             .current_dir(root)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .spawn()?;
+            // if the command is not awaited to completion, e.g. because another command failed,
+            // don't leave it running
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|error| {
+                if error.kind() == io::ErrorKind::NotFound {
+                    let executable = command[0].as_ref().to_string_lossy().into_owned();
+                    CommandError::NotFound(executable)
+                } else {
+                    error.into()
+                }
+            })?;
 
         let mut stdin = child
             .stdin
@@ -110,20 +202,55 @@ impl World for DefaultWorld {
 
         let output = child.wait_with_output().await?;
         if !output.status.success() {
-            return Err(CommandError::ExitStatus(output.status));
+            return Err(CommandError::ExitStatus(output.status, output.stdout));
         }
         let output = output.stdout;
 
         Ok(output)
     }
 
+    async fn create_input_file(&self, content: &[u8]) -> io::Result<PathBuf> {
+        let mut location = match &self.temp_dir {
+            Some(temp_dir) => {
+                let location = self.main().manifest_dir().await?.join(temp_dir);
+                fs::create_dir_all(&location).await?;
+                location
+            }
+            None => env::temp_dir(),
+        };
+        let counter = INPUT_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        location.push(format!("prequery-input-{}-{counter}", std::process::id()));
+        let mut file = fs::File::create_new(&location).await?;
+        file.write_all(content).await?;
+        file.flush().await?;
+        Ok(location)
+    }
+
+    async fn remove_input_file(&self, location: &Path) -> io::Result<()> {
+        fs::remove_file(location).await
+    }
+
+    async fn read_input(&self, location: &Path) -> io::Result<Vec<u8>> {
+        fs::read(location).await
+    }
+
     async fn write_output(&self, location: &Path, output: &[u8]) -> Result<(), FileError> {
+        if location == Path::new(STDOUT) {
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(output).await?;
+            stdout.flush().await?;
+            return Ok(());
+        }
         if let Some(parent) = location.parent() {
             fs::create_dir_all(parent).await?;
         }
         let mut file = fs::File::create(&location).await?;
         file.write_all(output).await?;
         file.flush().await?;
+        drop(file);
+        if let Some(mode) = self.file_mode {
+            mode.apply(location).await?;
+        }
         Ok(())
     }
 }
@@ -1,35 +1,54 @@
 //! The `web-resource` preprocessor
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 use async_trait::async_trait;
 use derive_more::Debug;
-use tokio::sync::Mutex;
+use serde::Serialize;
+use tokio::sync::{Mutex, Semaphore};
 
-use crate::preprocessor::{DynError, Preprocessor};
+use crate::lockfile;
+use crate::preprocessor::{self, ConfigResult, DynError, Preprocessor};
 use crate::query::{self, Query};
 use crate::utils;
 use crate::world::{World as _, WorldExt as _};
 
+mod archive;
+#[cfg(not(feature = "test"))]
+mod credentials;
+#[cfg(feature = "test")]
+pub mod credentials;
 mod error;
 mod factory;
 #[cfg(not(feature = "test"))]
 mod index;
 #[cfg(feature = "test")]
 pub mod index;
+#[cfg(not(feature = "test"))]
 mod manifest;
+#[cfg(feature = "test")]
+pub mod manifest;
+#[cfg(not(feature = "test"))]
+mod proxy;
+#[cfg(feature = "test")]
+pub mod proxy;
 mod query_data;
 mod world;
 
 use index::*;
 use manifest::*;
 use query_data::*;
-use world::World;
+use world::{World, WorldExt as _};
 
 pub use error::*;
 pub use factory::WebResourceFactory;
+pub use world::ProgressEvent;
 #[cfg(feature = "test")]
 pub use world::{__mock_MockWorld_World::__new::Context as MockWorld_NewContext, MockWorld};
 
@@ -39,9 +58,32 @@ pub struct WebResource<W: World> {
     #[debug(skip)]
     world: Arc<W>,
     name: String,
+    log_prefix: String,
+    root: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
     manifest: Manifest,
-    index: Option<Mutex<Index>>,
+    /// The index, if one is in use. This is populated when the job starts running.
+    index: Mutex<Option<Index>>,
     query: Query,
+    /// The resources that are present after running the job, with their resolved locations
+    resources: Mutex<Vec<(Resource, PathBuf)>>,
+    summary: Mutex<DownloadSummary>,
+    /// Whether the query found no resources at all, before any were excluded
+    found_nothing: Mutex<bool>,
+    /// The URLs minted for keyed resources during the current run, by key
+    minted: Mutex<HashMap<String, String>>,
+}
+
+/// The outcome of a web-resource job: which resources were downloaded, skipped or failed. Paths are
+/// given as in the query result, i.e. relative to the root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DownloadSummary {
+    /// The resources that were downloaded
+    pub downloaded: Vec<PathBuf>,
+    /// The resources that were not downloaded because an up-to-date file already existed
+    pub skipped: Vec<PathBuf>,
+    /// The resources that could not be downloaded
+    pub failed: Vec<PathBuf>,
 }
 
 /// The state of the file: if and how the existing file corresponds to the desired web resource.
@@ -49,28 +91,70 @@ pub struct WebResource<W: World> {
 enum ResourceState {
     /// No local file exists.
     Missing,
+    /// No local file exists, although the index records one: it was deleted outside prequery.
+    MissingTracked,
     /// A re-download is forced despite the file existing.
     Forced,
     /// The file seems to be up-to-date: the URL hasn't changed, or no index is kept.
     Existing,
     /// The file seems is not up-to-date: the URL has changed according to the index.
     ChangedResource,
+    /// The file may not be up-to-date: the index records a hash computed with a different
+    /// algorithm than the configured one.
+    ChangedHashAlgorithm,
+    /// The file is up-to-date: it was downloaded within the configured maximum age.
+    Fresh,
+    /// The file may not be up-to-date: it was downloaded before the configured maximum age.
+    Expired,
+    /// The file is up-to-date: the server reports the same content as when it was downloaded.
+    UnchangedContent,
+    /// The file is not up-to-date: the server reports different content than when it was
+    /// downloaded.
+    ChangedContent,
+    /// The file may not be up-to-date: the server doesn't support checking the content.
+    Unchecked,
 }
 
 impl ResourceState {
     pub fn download(self) -> bool {
         match self {
-            Self::Missing | Self::Forced | Self::ChangedResource => true,
-            Self::Existing => false,
+            Self::Missing
+            | Self::MissingTracked
+            | Self::Forced
+            | Self::ChangedResource
+            | Self::ChangedHashAlgorithm
+            | Self::Expired
+            | Self::ChangedContent
+            | Self::Unchecked => true,
+            Self::Existing | Self::Fresh | Self::UnchangedContent => false,
         }
     }
 
+    /// Whether a file in this state may be copied from the cache instead of being downloaded:
+    /// this is not the case if the remote content may have changed.
+    pub fn cacheable(self) -> bool {
+        matches!(
+            self,
+            Self::Missing
+                | Self::MissingTracked
+                | Self::ChangedResource
+                | Self::ChangedHashAlgorithm
+        )
+    }
+
     pub fn reason(self) -> Option<&'static str> {
         match self {
             Self::Missing => None,
+            Self::MissingTracked => Some("tracked file is missing"),
             Self::Forced => Some("overwrite of existing files was forced"),
             Self::ChangedResource => Some("URL has changed"),
+            Self::ChangedHashAlgorithm => Some("hash algorithm has changed"),
             Self::Existing => Some("file exists"),
+            Self::Fresh => Some("file is within max age"),
+            Self::Expired => Some("file exceeded max age"),
+            Self::UnchangedContent => Some("remote content is unchanged"),
+            Self::ChangedContent => Some("remote content has changed"),
+            Self::Unchecked => Some("server doesn't support HEAD requests"),
         }
     }
 
@@ -112,117 +196,631 @@ impl<W: World> WebResource<W> {
     pub(crate) fn new(
         world: Arc<W>,
         name: String,
+        root: Option<PathBuf>,
+        output_dir: Option<PathBuf>,
         manifest: Manifest,
-        index: Option<Mutex<Index>>,
+        index: Option<Index>,
         query: Query,
     ) -> Self {
         Self {
             world,
+            log_prefix: format!("[{name}]"),
             name,
-            index,
+            root,
+            output_dir,
+            index: Mutex::new(index),
             manifest,
             query,
+            resources: Mutex::default(),
+            summary: Mutex::default(),
+            found_nothing: Mutex::default(),
+            minted: Mutex::default(),
         }
     }
 
-    async fn populate_index(&mut self) -> Result<(), IndexError> {
-        if let Some(path) = self.manifest.index.as_ref() {
+    /// Returns the outcome of this job's downloads so far. After the job has run, this contains
+    /// all resources of the query result, except excluded ones.
+    pub async fn summary(&self) -> DownloadSummary {
+        let mut summary = self.summary.lock().await.clone();
+        // downloads finish in arbitrary order
+        summary.downloaded.sort();
+        summary.skipped.sort();
+        summary.failed.sort();
+        summary
+    }
+
+    /// Replaces the default `[name]` log prefix.
+    pub(crate) fn with_log_prefix(mut self, log_prefix: String) -> Self {
+        self.log_prefix = log_prefix;
+        self
+    }
+
+    /// Resolves an output path relative to this job's root, or the output directory within it if
+    /// the path is not absolute. Fails if the path escapes the root.
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let main = self.world.main();
+        let root = self.root.as_deref().unwrap_or_else(|| main.resolve_root());
+        let path = match &self.output_dir {
+            Some(output_dir) if !path.has_root() => Cow::Owned(output_dir.join(path)),
+            _ => Cow::Borrowed(path),
+        };
+        main.try_resolve_in(root, &path)
+    }
+
+    /// The current time, in seconds since the Unix epoch
+    fn now(&self) -> u64 {
+        self.world
+            .main()
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+
+    async fn populate_index(&self) -> Result<(), IndexError> {
+        let index = if let Some(config) = self.manifest.index.as_ref()
+            && !self.world.main().arguments().no_index
+        {
             // an index is in use
-            let index = self.world.read_index(path).await?;
-            self.index = Some(Mutex::new(index));
+            let mut index = self.world.read_index(&config.write).await?;
+            for path in &config.read {
+                index.add_base(self.world.read_index(path).await?);
+            }
+            Some(index)
         } else {
-            // no index is in use
-            self.index = None;
-        }
+            // no index is in use, or it is ignored because of `--no-index`
+            None
+        };
+        *self.index.lock().await = index;
 
         Ok(())
     }
 
     async fn query(&self) -> query::Result<QueryData> {
-        let data = self.world.main().query(&self.query).await?;
+        let data = self.query.execute(self.world.main().as_ref()).await?;
         Ok(data)
     }
 
-    async fn download(self: Arc<Self>, resource: Resource) -> Result<(), DownloadError> {
+    /// Queries the resources to download, with `${env:NAME}` placeholders in their URLs and paths
+    /// expanded and relative URLs resolved, without excluded ones, and with their paths adjusted
+    /// according to the destination directory and flattening.
+    async fn resources(&self) -> ExecutionResult<BTreeMap<PathBuf, QueriedResource>> {
+        let mut data = self.query().await?;
+        data = data.expand_env(|name| self.world.main().env_var(name))?;
+        data = data.resolve_urls(self.manifest.base_url.as_ref())?;
+        *self.found_nothing.lock().await = data.resources.is_empty();
+        if self.manifest.url_command.is_none()
+            && let Some((path, _)) = data.resources.iter().find(|(_, resource)| resource.keyed)
+        {
+            return Err(ExecutionError::NoUrlCommand(path.clone()));
+        }
+        data.resources
+            .retain(|path, _| !self.manifest.exclude.is_excluded(path));
+        if !self.manifest.allow_any_scheme {
+            // URLs were already checked to be valid when resolving them. Keys are not URLs
+            let unsupported = data
+                .resources
+                .iter()
+                .filter(|(_, resource)| !resource.keyed)
+                .find(|(_, QueriedResource { url, .. })| {
+                    !(url.starts_with("http://") || url.starts_with("https://"))
+                });
+            if let Some((path, resource)) = unsupported {
+                return Err(ExecutionError::UnsupportedScheme {
+                    path: path.clone(),
+                    url: resource.url.clone(),
+                });
+            }
+        }
+        if self.manifest.dest_dir.is_some() || self.manifest.flatten {
+            let dest_dir = self.manifest.dest_dir.as_deref().unwrap_or(Path::new(""));
+            data = data.relocate(dest_dir, self.manifest.flatten)?;
+        }
+        Ok(data.resources)
+    }
+
+    async fn download(
+        self: Arc<Self>,
+        resource: Resource,
+        options: ResourceOptions,
+        keyed: bool,
+    ) -> Result<(), DownloadError> {
+        let path = resource.path.clone();
+        let result = Arc::clone(&self)
+            .download_impl(resource, options, keyed)
+            .await;
+        let mut summary = self.summary.lock().await;
+        match result {
+            Ok(true) => summary.downloaded.push(path),
+            Ok(false) => summary.skipped.push(path),
+            Err(_) => summary.failed.push(path),
+        }
+        let downloaded = summary.downloaded.len();
+        drop(summary);
+
+        if let Some(every) = self.manifest.checkpoint_every
+            && matches!(result, Ok(true))
+            && downloaded % every == 0
+        {
+            self.checkpoint_index().await;
+        }
+        result.map(|_| ())
+    }
+
+    /// Saves the index that is in use. Entries that other jobs or processes sharing the index
+    /// have added since it was read are kept.
+    async fn save_index(&self, index: &Index) -> Result<(), IndexError> {
+        let config = self.manifest.index.as_ref();
+        let config = config.expect("an index is only in use if it is configured");
+        self.world
+            .with_index(&config.write, |written| written.merge(index))
+            .await?;
+        Ok(())
+    }
+
+    /// Saves the index while downloads are still running. A failure is only logged, as the index
+    /// is saved again at the end of the job.
+    async fn checkpoint_index(&self) {
+        // holding the lock keeps other downloads from updating the index while it's written
+        let index = self.index.lock().await;
+        if let Some(index) = &*index
+            && let Err(error) = self.save_index(index).await
+        {
+            let mut l = self.world.main().log();
+            let prefix = &self.log_prefix;
+            log!(
+                l,
+                "{prefix} warning: saving an index checkpoint failed: {error}"
+            );
+        }
+    }
+
+    /// Checks that a URL's host is one of the [allowed hosts][Manifest::allowed_hosts], if they are
+    /// restricted. `resource` names the resource in the error: its URL, or its key if the URL was
+    /// minted.
+    fn check_host(&self, resource: &str, url: &str) -> Result<(), DownloadError> {
+        let Some(allowed_hosts) = &self.manifest.allowed_hosts else {
+            return Ok(());
+        };
+        let url = reqwest::Url::parse(url).ok();
+        let host = url.as_ref().and_then(|url| url.host_str()).unwrap_or("");
+        if !host.is_empty()
+            && allowed_hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        {
+            return Ok(());
+        }
+        Err(DownloadError::HostNotAllowed {
+            resource: resource.to_string(),
+            host: host.to_string(),
+        })
+    }
+
+    /// Returns the URL to download a resource from: the given URL, or if the resource is keyed, the
+    /// URL minted from the key. A key's URL is only minted once per run.
+    async fn remote_url(&self, url: &str, keyed: bool) -> Result<String, DownloadError> {
+        if !keyed {
+            return Ok(url.to_string());
+        }
+        let command = self.manifest.url_command.as_ref();
+        let command = command.expect("keyed resources require a url_command, as checked before");
+        // holding the lock keeps concurrent downloads from minting the same key twice
+        let mut minted = self.minted.lock().await;
+        if let Some(minted) = minted.get(url) {
+            return Ok(minted.clone());
+        }
+        let result = async {
+            let minted = self.world.mint_url(command, url).await?;
+            reqwest::Url::parse(&minted).map_err(|_| UrlCommandError::InvalidUrl)?;
+            Ok(minted)
+        };
+        let remote = result
+            .await
+            .map_err(|error| DownloadError::UrlCommand(url.to_string(), error))?;
+        // the host of a minted URL is only known now
+        self.check_host(url, &remote)?;
+        minted.insert(url.to_string(), remote.clone());
+        Ok(remote)
+    }
+
+    /// Downloads a resource if necessary. Returns whether the resource was actually downloaded.
+    async fn download_impl(
+        self: Arc<Self>,
+        resource: Resource,
+        options: ResourceOptions,
+        keyed: bool,
+    ) -> Result<bool, DownloadError> {
         let mut l = self.world.main().log();
 
-        let name = self.name();
-        let Resource { url, path } = &resource;
+        let prefix = &self.log_prefix;
+        let Resource { url, path, .. } = &resource;
+        let now = self.now();
 
         let path_str = path.to_string_lossy();
-        let resolved_path = self
-            .world
-            .main()
-            .resolve(path)
-            .ok_or_else(|| {
-                let msg = format!("{path_str} is outside the project root");
-                io::Error::new(io::ErrorKind::PermissionDenied, msg)
-            })
-            .inspect_err(|error| {
-                log!(l, "[{name}] Can't download to {path_str}: {error}");
-            })?;
+        let resolved_path = self.resolve(path).inspect_err(|error| {
+            log!(l, "{prefix} Can't download to {path_str}: {error}");
+        })?;
+        // the host is checked even if the resource ends up not being downloaded or is copied from
+        // the cache; a keyed resource's host is only known from its minted URL
+        let checked = if !keyed {
+            self.check_host(url, url)
+        } else if self.manifest.allowed_hosts.is_some() {
+            self.remote_url(url, keyed).await.map(drop)
+        } else {
+            Ok(())
+        };
+        checked.inspect_err(|error| {
+            log!(l, "{prefix} Can't download to {path_str}: {error}");
+        })?;
         let path_str = resolved_path.to_string_lossy();
 
         let exists = self.world.resource_exists(&resolved_path).await;
-        let state = if !exists {
-            ResourceState::Missing
-        } else if self.manifest.overwrite {
+        let index = self.index.lock().await;
+        // resources that are not tracked are handled as if no index was kept
+        let tracked = index.as_ref().filter(|_| options.indexed());
+        let mut state = if !exists {
+            match tracked {
+                Some(index) if index.get(path).is_some() => ResourceState::MissingTracked,
+                _ => ResourceState::Missing,
+            }
+        } else if options.overwrite(self.manifest.overwrite) {
             ResourceState::Forced
-        } else if let Some(index) = &self.index {
-            let index = index.lock().await;
-            if index.is_up_to_date(path, url) {
-                ResourceState::Existing
-            } else {
+        } else if let Some(index) = tracked {
+            if !index.is_up_to_date(path, url) {
                 ResourceState::ChangedResource
+            } else if index.has_other_hash_algorithm(path, self.manifest.hash) {
+                ResourceState::ChangedHashAlgorithm
+            } else if let Some(max_age) = self.manifest.max_age {
+                if index.is_fresh(path, now, max_age.as_secs()) {
+                    ResourceState::Fresh
+                } else {
+                    ResourceState::Expired
+                }
+            } else {
+                ResourceState::Existing
             }
         } else {
             ResourceState::Existing
         };
+        let recorded = match tracked {
+            Some(index) if state == ResourceState::Existing => index.get(path).cloned(),
+            _ => None,
+        };
+        drop(index);
 
-        log!(l, "[{name}] {}", state.on(url, &path_str));
+        let check = match self.manifest.check {
+            Check::Url => false,
+            Check::Head => recorded.is_some(),
+            Check::Always => state == ResourceState::Existing,
+        };
+        if check {
+            let result = async {
+                let remote = self.remote_url(url, keyed).await?;
+                let Some(current) = self.world.head(&remote).await? else {
+                    return Ok(ResourceState::Unchecked);
+                };
+                let unchanged = match &recorded {
+                    Some(recorded) => current.matches(&recorded.validators),
+                    // without a record, the local file is all there is to compare
+                    None => {
+                        let (size, modified) = self.world.resource_metadata(&resolved_path).await?;
+                        current.matches_file(size, modified)
+                    }
+                };
+                Ok(if unchanged {
+                    ResourceState::UnchangedContent
+                } else {
+                    ResourceState::ChangedContent
+                })
+            };
+            state = result.await.inspect_err(|error: &DownloadError| {
+                log!(l, "{prefix} Checking {path_str} failed: {error}");
+            })?;
+        }
+
+        log!(l, "{prefix} {}", state.on(url, &path_str));
 
         if state.download() {
-            self.world
-                .download(&resolved_path, url)
-                .await
-                .inspect_err(|error| {
-                    log!(l, "[{name}] Downloading to {path_str} failed: {error}");
-                })?;
+            let cache = self.world.main().arguments().cache_dir.as_deref();
+            let cached = match cache {
+                Some(cache) if state.cacheable() => self
+                    .world
+                    .copy_from_cache(cache, url, &resolved_path)
+                    .await
+                    .unwrap_or_else(|error| {
+                        log!(
+                            l,
+                            "{prefix} warning: copying {path_str} from the cache failed: {error}"
+                        );
+                        false
+                    }),
+                _ => false,
+            };
+            let validators = if cached {
+                // the server was not contacted, so nothing is known about the content
+                Validators::default()
+            } else {
+                let download = async {
+                    let remote = self.remote_url(url, keyed).await?;
+                    let validate = self.manifest.validate.clone();
+                    self.world.download(&resolved_path, &remote, validate).await
+                };
+                download.await.inspect_err(|error| match error {
+                    DownloadError::Validation(_, error) => {
+                        log!(l, "{prefix} Validating {path_str} failed: {error}");
+                    }
+                    error => {
+                        log!(l, "{prefix} Downloading to {path_str} failed: {error}");
+                    }
+                })?
+            };
+
+            if let Some(cache) = cache
+                && !cached
+                && let Err(error) = self.world.copy_to_cache(cache, url, &resolved_path).await
+            {
+                log!(
+                    l,
+                    "{prefix} warning: adding {path_str} to the cache failed: {error}"
+                );
+            }
+
+            // the index records the content's hash, prefixed by the algorithm used
+            let hash = if options.indexed() && self.index.lock().await.is_some() {
+                let hash = self
+                    .world
+                    .hash_resource(&resolved_path, self.manifest.hash)
+                    .await
+                    .inspect_err(|error| {
+                        log!(l, "{prefix} Hashing {path_str} failed: {error}");
+                    })?;
+                Some(hash)
+            } else {
+                None
+            };
+            if let Some(index) = &mut *self.index.lock().await
+                && options.indexed()
+            {
+                index.update(Resource {
+                    timestamp: Some(now),
+                    validators,
+                    hash,
+                    ..resource.clone()
+                });
+            }
+            if cached {
+                log!(l, "{prefix} Copied {path_str} from the cache");
+            } else {
+                log!(l, "{prefix} Downloading to {path_str} finished");
+            }
 
-            if let Some(index) = &self.index {
-                let mut index = index.lock().await;
-                index.update(resource.clone());
+            if let Some(extract) = &self.manifest.extract {
+                self.extract(path, &resolved_path, extract)
+                    .await
+                    .map_err(|error| {
+                        log!(l, "{prefix} Extracting {path_str} failed: {error}");
+                        DownloadError::Extract(resolved_path.clone(), error)
+                    })?;
             }
-            log!(l, "[{name}] Downloading to {path_str} finished");
         }
 
-        Ok(())
-    }
+        let mut resources = self.resources.lock().await;
+        resources.push((resource, resolved_path));
 
-    async fn run_impl(self: &mut Arc<Self>) -> ExecutionResult<()> {
-        Arc::get_mut(self)
-            .expect("web-resource ref count should be one before starting the processing")
-            .populate_index()
-            .await?;
+        Ok(state.download())
+    }
 
-        let downloads = self
-            .query()
-            .await?
-            .resources
+    /// Extracts a downloaded archive into the directory containing it. All extracted paths are
+    /// checked to stay inside that directory and the root before any file is written.
+    async fn extract(
+        &self,
+        path: &Path,
+        location: &Path,
+        extract: &Extract,
+    ) -> Result<(), ExtractError> {
+        let data = self.world.read_resource(location).await?;
+        let entries = archive::entries(location, &data, extract)?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let entries = entries
             .into_iter()
-            .map(|(path, url)| Arc::clone(self).download(Resource { path, url }));
-        let errors = utils::spawn_set(downloads).await;
+            .map(|entry| {
+                let location = self.resolve(&dir.join(&entry.path))?;
+                Ok((location, entry.content))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        for (location, content) in &entries {
+            self.world.write_extracted(location, content).await?;
+        }
 
-        if let Some(index) = &self.index {
-            let index = index.lock().await;
-            self.world.write_index(&index).await?;
+        let mut l = self.world.main().log();
+        log!(
+            l,
+            "{} Extracted {} files from {}",
+            self.log_prefix,
+            entries.len(),
+            location.display(),
+        );
+        Ok(())
+    }
+
+    async fn run_impl(self: &Arc<Self>) -> ExecutionResult<DownloadSummary> {
+        // a retried job starts over
+        self.resources.lock().await.clear();
+        self.minted.lock().await.clear();
+        *self.summary.lock().await = DownloadSummary::default();
+        self.populate_index().await?;
+
+        let semaphore = Arc::new(Semaphore::new(self.world.main().concurrency().get()));
+        let downloads = self.resources().await?.into_iter().map(|(path, queried)| {
+            let resource = Resource {
+                path,
+                url: queried.url,
+                timestamp: None,
+                validators: Validators::default(),
+                hash: None,
+            };
+            let download = Arc::clone(self).download(resource, queried.options, queried.keyed);
+            utils::with_permit(Arc::clone(&semaphore), download)
+        });
+        let (_, errors) = utils::spawn_set(downloads).await;
+
+        if let Some(index) = &*self.index.lock().await {
+            self.save_index(index).await?;
         }
 
         if !errors.is_empty() {
             return Err(error::MultipleDownloadError::new(errors).into());
         }
 
-        Ok::<_, ExecutionError>(())
+        if let Some(path) = &self.manifest.emit_manifest {
+            self.write_asset_list(path).await?;
+        }
+
+        Ok(self.summary().await)
+    }
+
+    /// Writes the list of this job's resources, sorted by path, to the given file.
+    async fn write_asset_list(&self, path: &Path) -> ExecutionResult<()> {
+        #[derive(Serialize)]
+        struct Asset<'a> {
+            url: &'a str,
+            path: &'a Path,
+        }
+
+        let resources = self.resources.lock().await;
+        let mut assets: Vec<_> = resources
+            .iter()
+            .map(|(Resource { url, path, .. }, _)| Asset { url, path })
+            .collect();
+        assets.sort_by_key(|asset| asset.path);
+        let content = serde_json::to_vec(&assets).expect("serializing assets should not fail");
+
+        let error = |error| ExecutionError::AssetList(path.to_path_buf(), error);
+        let location = self.resolve(path).map_err(error)?;
+        self.world
+            .write_asset_list(&location, &content)
+            .await
+            .map_err(error)?;
+
+        let mut l = self.world.main().log();
+        log!(
+            l,
+            "{} Saved asset manifest to {}",
+            self.log_prefix,
+            location.display(),
+        );
+        Ok(())
+    }
+
+    /// Removes all resources tracked by the index, and then the index itself. If any resource can't
+    /// be removed, the index is kept.
+    async fn clean_impl(&self) -> ExecutionResult<()> {
+        let mut l = self.world.main().log();
+        let prefix = &self.log_prefix;
+
+        let Some(config) = &self.manifest.index else {
+            log!(l, "{prefix} warning: no index is kept, can't clean");
+            return Ok(());
+        };
+        // only the written index's resources belong to this job
+        let index = self.world.read_index(&config.write).await?;
+
+        let mut first_error = None;
+        for path in index.entries.keys() {
+            let path_str = path.to_string_lossy();
+            let result = match self.resolve(path) {
+                Ok(location) => self.world.remove_resource(&location).await,
+                Err(error) => Err(error),
+            };
+            match result {
+                Ok(()) => log!(l, "{prefix} Removed {path_str}"),
+                Err(error) => {
+                    log!(l, "{prefix} Removing {path_str} failed: {error}");
+                    first_error.get_or_insert((path.clone(), error));
+                }
+            }
+        }
+        if let Some((path, error)) = first_error {
+            return Err(ExecutionError::Remove(path, error));
+        }
+
+        self.world.remove_index(&index).await?;
+        log!(l, "{prefix} Removed index {}", index.location().display());
+
+        Ok(())
+    }
+
+    /// Compares the index with the current query result, without downloading anything. Entries
+    /// that are no longer queried are reported, but they would stay in the index.
+    async fn diff_index_impl(&self) -> ExecutionResult<()> {
+        let mut l = self.world.main().log();
+        let prefix = &self.log_prefix;
+
+        let Some(config) = &self.manifest.index else {
+            log!(l, "{prefix} warning: no index is kept, can't diff");
+            return Ok(());
+        };
+        // read-only indexes don't change
+        let index = self.world.read_index(&config.write).await?;
+        let resources = self.resources().await?;
+
+        let mut unchanged = true;
+        for (path, QueriedResource { url, options, .. }) in &resources {
+            if !options.indexed() {
+                // untracked resources don't change the index
+                continue;
+            }
+            let path_str = path.to_string_lossy();
+            match index.get(path) {
+                None => log!(l, "{prefix} + {path_str}: {url}"),
+                Some(resource) if &resource.url != url => {
+                    log!(l, "{prefix} ~ {path_str}: {} -> {url}", resource.url);
+                }
+                Some(_) if index.has_other_hash_algorithm(path, self.manifest.hash) => {
+                    log!(
+                        l,
+                        "{prefix} ~ {path_str}: {url} (hash algorithm has changed)"
+                    );
+                }
+                Some(_) => continue,
+            }
+            unchanged = false;
+        }
+        for (path, resource) in &index.entries {
+            if !resources.contains_key(path) {
+                let path_str = path.to_string_lossy();
+                log!(
+                    l,
+                    "{prefix} - {path_str}: {} (no longer queried)",
+                    resource.url
+                );
+                unchanged = false;
+            }
+        }
+        if unchanged {
+            log!(l, "{prefix} index is up to date");
+        }
+
+        Ok(())
+    }
+
+    async fn locked_resources_impl(&self) -> ExecutionResult<Vec<lockfile::Resource>> {
+        let resources = self.resources.lock().await;
+        let mut locked = Vec::with_capacity(resources.len());
+        for (Resource { path, url, .. }, location) in resources.iter() {
+            let hash = self
+                .world
+                .hash_resource(location, self.manifest.hash)
+                .await
+                .map_err(|error| ExecutionError::Hash(location.clone(), error))?;
+            locked.push(lockfile::Resource {
+                path: path.clone(),
+                url: url.clone(),
+                hash,
+            });
+        }
+        Ok(locked)
     }
 }
 
@@ -236,8 +834,66 @@ impl<W: World> Preprocessor<W::MainWorld> for Arc<WebResource<W>> {
         &self.name
     }
 
+    fn log_prefix(&self) -> &str {
+        &self.log_prefix
+    }
+
+    fn validate(&self) -> ConfigResult<()> {
+        let paths = [
+            ("dest_dir", &self.manifest.dest_dir),
+            ("emit_manifest", &self.manifest.emit_manifest),
+        ];
+        for (option, path) in paths {
+            if let Some(path) = path {
+                self.resolve(path).map_err(|error| {
+                    let error = ManifestError::OutsideRoot(option, error);
+                    preprocessor::ManifestError::new("web-resource".into(), error)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
     async fn run(&mut self) -> Result<(), DynError> {
-        self.run_impl().await.map_err(Box::new)?;
+        let summary = self.run_impl().await.map_err(Box::new)?;
+        let mut l = self.world().log();
+        if summary.downloaded.is_empty() && !summary.skipped.is_empty() {
+            // nothing was downloaded, which would otherwise be easy to miss between skipped items
+            log!(
+                l,
+                "{} up to date (0 of {} changed)",
+                self.log_prefix(),
+                summary.skipped.len(),
+            );
+        }
+        if self.world().arguments().verbose {
+            log!(
+                l,
+                "{} {} resources downloaded, {} skipped",
+                self.log_prefix(),
+                summary.downloaded.len(),
+                summary.skipped.len(),
+            );
+        }
+        Ok(())
+    }
+
+    async fn locked_resources(&mut self) -> Result<Vec<lockfile::Resource>, DynError> {
+        let resources = self.locked_resources_impl().await.map_err(Box::new)?;
+        Ok(resources)
+    }
+
+    async fn found_nothing(&mut self) -> bool {
+        *self.found_nothing.lock().await
+    }
+
+    async fn clean(&mut self) -> Result<(), DynError> {
+        self.clean_impl().await.map_err(Box::new)?;
+        Ok(())
+    }
+
+    async fn diff_index(&mut self) -> Result<(), DynError> {
+        self.diff_index_impl().await.map_err(Box::new)?;
         Ok(())
     }
 }
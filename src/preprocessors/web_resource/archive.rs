@@ -0,0 +1,147 @@
+//! Reading the regular files of zip, tar and gzip-compressed tar archives
+
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+
+use super::{Extract, ExtractError};
+
+/// A regular file contained in an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The file's path within the archive, with leading components already stripped. It only
+    /// consists of normal components, i.e. it is relative and contains no `.` or `..`.
+    pub path: String,
+    /// The file's uncompressed content
+    pub content: Vec<u8>,
+}
+
+/// Reads the regular files contained in an archive. The archive format is determined by the file
+/// name: `.zip`, `.tar`, `.tar.gz` or `.tgz`. The configured number of leading path components is
+/// removed from each file's path; files that don't have more components than that are skipped.
+/// Archives with more files or more decompressed content than configured are rejected, as are
+/// archives containing files whose paths lead outside the directory they are extracted to.
+pub fn entries(
+    location: &Path,
+    data: &[u8],
+    extract: &Extract,
+) -> Result<Vec<Entry>, ExtractError> {
+    let Extract {
+        strip_components,
+        max_files,
+        max_size,
+    } = *extract;
+    let name = location
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let entries = if name.ends_with(".zip") {
+        zip(data, max_files, max_size)?
+    } else if name.ends_with(".tar") {
+        tar(data, max_files, max_size)?
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        // the whole tar file is limited, so that no part of it, e.g. a huge header, exhausts memory
+        let data = read_with_limit(GzDecoder::new(data), max_size)?
+            .ok_or(ExtractError::TooLarge(max_size))?;
+        tar(&data, max_files, max_size)?
+    } else {
+        return Err(ExtractError::Unsupported(location.to_path_buf()));
+    };
+
+    let mut result = Vec::new();
+    for (name, content) in entries {
+        let mut components = Vec::new();
+        let parts = name
+            .split(['/', '\\'])
+            .filter(|c| !c.is_empty() && *c != ".")
+            .skip(strip_components);
+        for part in parts {
+            if part == ".." {
+                // even within the root, a file must not replace e.g. the project's typst.toml
+                components
+                    .pop()
+                    .ok_or_else(|| ExtractError::OutsideDirectory(name.clone()))?;
+            } else {
+                components.push(part);
+            }
+        }
+        if !components.is_empty() {
+            let path = components.join("/");
+            result.push(Entry { path, content });
+        }
+    }
+    Ok(result)
+}
+
+/// Reads all data from the reader, or returns `None` if there are more than `limit` bytes. Unless
+/// the limit is exceeded, the reader is read to the end, so that decompressing readers check the
+/// data's checksum.
+fn read_with_limit(reader: impl Read, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    let take = u64::try_from(limit).unwrap_or(u64::MAX).saturating_add(1);
+    reader.take(take).read_to_end(&mut data)?;
+    Ok((data.len() <= limit).then_some(data))
+}
+
+/// Reads the regular files of a tar archive, including ones stored with GNU or pax long names.
+/// Directories, links and other special entries are skipped, but count towards `max_files`.
+fn tar(
+    data: &[u8],
+    max_files: usize,
+    max_size: usize,
+) -> Result<Vec<(String, Vec<u8>)>, ExtractError> {
+    let mut archive = tar::Archive::new(data);
+    let mut entries = Vec::new();
+    let mut files = 0;
+    // the decompressed size still allowed for the remaining files
+    let mut remaining = max_size;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        files += 1;
+        if files > max_files {
+            return Err(ExtractError::TooManyFiles(max_files));
+        }
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_contiguous() {
+            continue;
+        }
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let content = read_with_limit(entry, remaining)
+            .map_err(|error| ExtractError::Entry(name.clone(), error))?
+            .ok_or(ExtractError::TooLarge(max_size))?;
+        remaining -= content.len();
+        entries.push((name, content));
+    }
+    Ok(entries)
+}
+
+/// Reads the regular files of a zip archive, checking their checksums. Directories and symbolic
+/// links are skipped, but count towards `max_files`.
+fn zip(
+    data: &[u8],
+    max_files: usize,
+    max_size: usize,
+) -> Result<Vec<(String, Vec<u8>)>, ExtractError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    if archive.len() > max_files {
+        return Err(ExtractError::TooManyFiles(max_files));
+    }
+
+    let mut entries = Vec::new();
+    // the decompressed size still allowed for the remaining files
+    let mut remaining = max_size;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        if !file.is_file() {
+            continue;
+        }
+        let name = file.name()?.into_owned();
+        let content = read_with_limit(file, remaining)
+            .map_err(|error| ExtractError::Entry(name.clone(), error))?
+            .ok_or(ExtractError::TooLarge(max_size))?;
+        remaining -= content.len();
+        entries.push((name, content));
+    }
+    Ok(entries)
+}
@@ -0,0 +1,75 @@
+use std::env;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+use tokio::fs;
+
+/// Login credentials for a single host
+#[derive(Clone, PartialEq, Eq)]
+struct Login {
+    login: String,
+    password: Option<String>,
+}
+
+/// Credentials for basic authentication, as read from a netrc file. This type intentionally does
+/// not implement `Debug`, so that credentials can't end up in logs.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    hosts: Vec<(String, Login)>,
+    default: Option<Login>,
+}
+
+impl Credentials {
+    /// Returns the location of the user's netrc file: the `NETRC` environment variable if set,
+    /// otherwise `.netrc` in the home directory.
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("NETRC").filter(|path| !path.is_empty()) {
+            return Some(path.into());
+        }
+        let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+        Some(Path::new(&home).join(".netrc"))
+    }
+
+    /// Parses the contents of a netrc file.
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let netrc = netrc::Netrc::parse(Cursor::new(content)).map_err(|error| match error {
+            netrc::Error::Io(error) => error,
+            netrc::Error::Parse(msg, line) => {
+                let msg = format!("invalid netrc file at line {line}: {msg}");
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            }
+        })?;
+        let login = |machine: netrc::Machine| Login {
+            login: machine.login,
+            password: machine.password,
+        };
+        Ok(Self {
+            hosts: netrc
+                .hosts
+                .into_iter()
+                .map(|(host, machine)| (host.to_ascii_lowercase(), login(machine)))
+                .collect(),
+            default: netrc.default.map(login),
+        })
+    }
+
+    /// Reads a netrc file. If it doesn't exist, no credentials are returned.
+    pub async fn read(path: &Path) -> io::Result<Self> {
+        if !fs::try_exists(path).await.unwrap_or(false) {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    /// Returns the login and password to use for the given host, if any. Hosts without their own
+    /// entry use the `default` entry, if it exists.
+    pub fn for_host(&self, host: &str) -> Option<(&str, Option<&str>)> {
+        let host = host.to_ascii_lowercase();
+        self.hosts
+            .iter()
+            .find_map(|(name, login)| (*name == host).then_some(login))
+            .or(self.default.as_ref())
+            .map(|login| (login.login.as_str(), login.password.as_deref()))
+    }
+}
@@ -1,11 +1,14 @@
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
 
+use reqwest::StatusCode;
 use thiserror::Error;
 use tokio::task::JoinError;
 
 use crate::query;
-use crate::reporting::{ErrorExt, WriteExt};
+use crate::reporting::write_error_list;
 
 /// An error in the configuration of the job's query
 #[derive(Error, Debug)]
@@ -27,6 +30,21 @@ pub enum ManifestError {
     /// An error in the configuration of the job's query
     #[error(transparent)]
     Query(#[from] QueryConfigError),
+    /// `max_age` was given, but the index is disabled
+    #[error("web-resource max_age requires the index to be enabled")]
+    MaxAgeWithoutIndex,
+    /// `check = "head"` was given, but the index is disabled
+    #[error("web-resource HEAD checks require the index to be enabled")]
+    HeadCheckWithoutIndex,
+    /// `checkpoint_every` was given, but the index is disabled
+    #[error("web-resource checkpoint_every requires the index to be enabled")]
+    CheckpointWithoutIndex,
+    /// `url_command` was given, but is empty
+    #[error("web-resource url_command must not be empty")]
+    EmptyUrlCommand,
+    /// A configured path escapes the job's root
+    #[error("web-resource {0} is invalid")]
+    OutsideRoot(&'static str, #[source] io::Error),
 }
 
 /// A problem with using the index of downloaded resources
@@ -44,6 +62,97 @@ pub enum IndexError {
     /// Error writing new index file contents
     #[error("web-resource index: TOML writing error")]
     Write(#[from] toml::ser::Error),
+    /// Error decompressing the index file's contents
+    #[error("web-resource index file could not be decompressed")]
+    Gzip(#[source] io::Error),
+}
+
+impl IndexError {
+    /// Checks whether this error means that the index file exists but its content is invalid.
+    /// An index of an unsupported version is not considered corrupt.
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            Self::Io(error) => error.kind() == io::ErrorKind::InvalidData,
+            Self::Parse(_) | Self::Gzip(_) => true,
+            Self::Version(_) | Self::Write(_) => false,
+        }
+    }
+}
+
+/// A problem with extracting a downloaded archive
+#[derive(Error, Debug)]
+pub enum ExtractError {
+    /// The archive's file name doesn't indicate a supported format
+    #[error(
+        "the archive format of {} is not supported; expected .zip, .tar, .tar.gz or .tgz",
+        .0.display()
+    )]
+    Unsupported(PathBuf),
+    /// The zip archive is not valid or uses unsupported features
+    #[error("the zip archive could not be read")]
+    Zip(#[from] zip::result::ZipError),
+    /// The archive contains more files than allowed by `max_files`
+    #[error("the archive contains more than {0} files")]
+    TooManyFiles(usize),
+    /// A file in the archive could not be read, e.g. because it doesn't match its checksum
+    #[error("the archive entry {0} could not be read")]
+    Entry(String, #[source] io::Error),
+    /// A file in the archive would be extracted outside the directory containing the archive
+    #[error("the archive entry {0} would be extracted outside the archive's directory")]
+    OutsideDirectory(String),
+    /// The archive's files are larger than allowed by `max_size`
+    #[error("the archive's files are larger than {0} bytes in total")]
+    TooLarge(usize),
+    /// An error reading or decompressing the archive, or writing an extracted file
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A downloaded file's content that did not pass the configured check
+#[derive(Error, Debug)]
+pub enum ValidationError {
+    /// The file is empty
+    #[error("the file is empty")]
+    Empty,
+    /// The file is not valid JSON
+    #[error("the file is not valid JSON")]
+    Json(#[from] serde_json::Error),
+    /// The file doesn't contain the required key
+    #[error("the file doesn't contain the key `{0}`")]
+    MissingKey(String),
+    /// An error reading or removing the downloaded file
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A problem with minting a URL for a keyed resource using the `url_command`
+#[derive(Error, Debug)]
+pub enum UrlCommandError {
+    /// An error running or communicating with the command
+    #[error(transparent)]
+    Process(#[from] io::Error),
+    /// The command's executable could not be found
+    #[error("the `{0}` executable could not be found")]
+    NotFound(String),
+    /// The command exited unsuccessfully
+    #[error("the command failed: {0}")]
+    ExitStatus(ExitStatus),
+    /// The command's output is not a valid URL. As minted URLs often contain credentials, the
+    /// output is not included.
+    #[error("the command did not print a valid URL")]
+    InvalidUrl,
+}
+
+/// An error loading the custom CA certificate bundle given by
+/// [`--ca-cert`][crate::args::CliArguments::ca_cert]
+#[derive(Error, Debug)]
+pub enum CaCertError {
+    /// An error reading the bundle
+    #[error("CA certificate bundle {} could not be read", .0.display())]
+    Read(PathBuf, #[source] io::Error),
+    /// The bundle does not contain valid PEM certificates
+    #[error("CA certificate bundle {} is not valid", .0.display())]
+    Invalid(PathBuf, #[source] reqwest::Error),
 }
 
 /// An error during downloading a resource from the web
@@ -52,9 +161,51 @@ pub enum DownloadError {
     /// A network error during the download
     #[error(transparent)]
     Network(#[from] reqwest::Error),
+    /// The server responded with an error status
+    #[error(
+        "the server responded with {status}{}",
+        .body.as_deref().map(|body| format!(": {body}")).unwrap_or_default()
+    )]
+    Status {
+        /// The response's status code
+        status: StatusCode,
+        /// The (possibly truncated) response body, if it was captured and not empty
+        body: Option<String>,
+    },
+    /// The response body did not have the length announced by the server's `Content-Length`
+    #[error("the download was truncated: expected {expected} bytes, got {got}")]
+    Truncated {
+        /// The length announced by the server
+        expected: u64,
+        /// The number of bytes actually received
+        got: u64,
+    },
     /// An error accessing the local file for the resource
     #[error(transparent)]
     File(#[from] io::Error),
+    /// An error validating the downloaded file's content
+    #[error("validating {} failed", .0.display())]
+    Validation(PathBuf, #[source] ValidationError),
+    /// An error extracting the downloaded archive
+    #[error("extracting {} failed", .0.display())]
+    Extract(PathBuf, #[source] ExtractError),
+    /// An error loading the custom CA certificate bundle
+    #[error(transparent)]
+    CaCert(#[from] CaCertError),
+    /// An error reading the netrc file for authentication
+    #[error("netrc file could not be read")]
+    Netrc(#[source] io::Error),
+    /// An error minting the URL for a keyed resource
+    #[error("minting a URL for the key `{0}` failed")]
+    UrlCommand(String, #[source] UrlCommandError),
+    /// The host of a resource's URL is not one of the allowed hosts
+    #[error("the host `{host}` of {resource} is not allowed")]
+    HostNotAllowed {
+        /// The resource's URL, or its key if the URL was minted
+        resource: String,
+        /// The URL's host, or an empty string if it has none
+        host: String,
+    },
     /// An error while waiting for the download to finish
     #[error("waiting for a download task failed")]
     Join(#[from] JoinError),
@@ -75,15 +226,7 @@ impl MultipleDownloadError {
 
 impl fmt::Display for MultipleDownloadError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use fmt::Write;
-
-        let mut w = f.hanging_indent("  ");
-        write!(w, "at least one download failed:")?;
-        for error in &self.errors {
-            writeln!(w)?;
-            write!(w, "{}", error.error_chain())?;
-        }
-        Ok(())
+        write_error_list(f, "at least one download failed:", &self.errors)
     }
 }
 
@@ -99,6 +242,58 @@ pub enum ExecutionError {
     /// An error during downloading a resource from the web
     #[error(transparent)]
     Download(#[from] MultipleDownloadError),
+    /// A resource's URL uses a scheme other than `http` and `https`
+    #[error("the URL {url} for {} does not use http or https", .path.display())]
+    UnsupportedScheme {
+        /// The resource's path
+        path: PathBuf,
+        /// The resource's URL
+        url: String,
+    },
+    /// An environment variable referenced by a resource's URL or path is not set
+    #[error("the environment variable `{var}` for {} is not set", .path.display())]
+    MissingEnv {
+        /// The resource's path, before expanding environment variables
+        path: PathBuf,
+        /// The name of the variable
+        var: String,
+    },
+    /// A resource's URL is malformed after expanding environment variables, or is relative and
+    /// can't be resolved
+    #[error("invalid URL `{url}` for {}: {error}", .path.display())]
+    InvalidUrl {
+        /// The resource's path
+        path: PathBuf,
+        /// The resource's URL, before expanding environment variables and resolving it
+        url: String,
+        /// The reason the URL is malformed
+        error: String,
+    },
+    /// A resource is given as a key, but the job has no `url_command` to mint its URL
+    #[error("resource {} is given as a key, but no url_command is configured", .0.display())]
+    NoUrlCommand(PathBuf),
+    /// A resource's URL has no file name to save it under when flattening
+    #[error("the URL {0} does not end in a file name")]
+    NoFileName(String),
+    /// Two resources would be saved to the same file after flattening
+    #[error("resources {first} and {second} would both be saved to {}", .path.display())]
+    FlattenCollision {
+        /// The file both resources would be saved to
+        path: PathBuf,
+        /// The URL of one resource
+        first: String,
+        /// The URL of the other resource
+        second: String,
+    },
+    /// An error while hashing a downloaded resource for the lockfile
+    #[error("resource {} could not be hashed", .0.display())]
+    Hash(PathBuf, #[source] io::Error),
+    /// An error while writing the list of resources
+    #[error("the asset manifest {} could not be written", .0.display())]
+    AssetList(PathBuf, #[source] io::Error),
+    /// An error while removing a resource during cleaning
+    #[error("resource {} could not be removed", .0.display())]
+    Remove(PathBuf, #[source] io::Error),
 }
 
 /// A result with a config error in it
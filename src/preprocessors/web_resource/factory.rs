@@ -1,17 +1,24 @@
 use std::borrow::Cow;
 use std::marker::PhantomData;
+use std::path::PathBuf;
 use std::sync::Arc;
 
+use tokio::sync::mpsc::UnboundedSender;
+
 use crate::manifest;
 use crate::preprocessor::{BoxedPreprocessor, PreprocessorDefinition};
 use crate::query::Query;
 
 use super::world::{DefaultWorld, World};
-use super::{Manifest, ManifestError, ManifestResult, QueryConfigError, WebResource};
+use super::{
+    Check, DEFAULT_INDEX, Manifest, ManifestError, ManifestResult, ProgressEvent, QueryConfigError,
+    WebResource,
+};
 
 /// The `web-resource` preprocessor factory
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct WebResourceFactory<W> {
+    progress: Option<UnboundedSender<ProgressEvent>>,
     _w: PhantomData<W>,
 }
 
@@ -24,17 +31,44 @@ impl Default for WebResourceFactory<DefaultWorld> {
 impl<W: World> WebResourceFactory<W> {
     /// Creates a factory with the given world.
     pub fn new() -> Self {
-        Self { _w: PhantomData }
+        Self {
+            progress: None,
+            _w: PhantomData,
+        }
+    }
+
+    /// Sets a sink for download progress. While web resources are downloaded, events with the
+    /// number of bytes received so far are sent to it, e.g. for displaying progress bars. Without
+    /// a sink, progress is not tracked.
+    pub fn with_progress(mut self, progress: UnboundedSender<ProgressEvent>) -> Self {
+        self.progress = Some(progress);
+        self
     }
 
     fn parse_config(config: toml::Table) -> ManifestResult<Manifest> {
-        let config = config.try_into()?;
+        let config: Manifest = config.try_into()?;
+        if config.max_age.is_some() && config.index.is_none() {
+            return Err(ManifestError::MaxAgeWithoutIndex);
+        }
+        if config.check == Check::Head && config.index.is_none() {
+            return Err(ManifestError::HeadCheckWithoutIndex);
+        }
+        if config.checkpoint_every.is_some() && config.index.is_none() {
+            return Err(ManifestError::CheckpointWithoutIndex);
+        }
+        if config.url_command.as_ref().is_some_and(Vec::is_empty) {
+            return Err(ManifestError::EmptyUrlCommand);
+        }
         Ok(config)
     }
 
-    fn build_query(config: manifest::Query) -> ManifestResult<Query> {
+    fn build_query(
+        config: manifest::Query,
+        default_field: Option<Option<String>>,
+    ) -> ManifestResult<Query> {
+        let default_field = default_field.unwrap_or_else(|| Some("value".to_string()));
         let config = Query::builder()
-            .default_field(Some("value".to_string()))
+            .default_field(default_field)
             .default_one(false)
             .default_selector("<web-resource>".to_string())
             .build(config)
@@ -58,15 +92,33 @@ impl<W: World> PreprocessorDefinition<W::MainWorld> for WebResourceFactory<W> {
         &self,
         world: &Arc<W::MainWorld>,
         name: String,
-        config: toml::Table,
+        root: Option<PathBuf>,
+        mut config: toml::Table,
         query: manifest::Query,
+        defaults: manifest::JobDefaults,
     ) -> ManifestResult<BoxedPreprocessor<W::MainWorld>> {
-        let world = Arc::new(W::new(world.clone()));
-        let config = Self::parse_config(config)?;
+        defaults.apply_default_index(&mut config, DEFAULT_INDEX);
+        let mut config = Self::parse_config(config)?;
+        if let Some(root) = &root
+            && let Some(index) = &mut config.index
+        {
+            // a job with its own root keeps its indexes there
+            index.write = root.join(&index.write);
+            for path in &mut index.read {
+                *path = root.join(&*path);
+            }
+        }
+        let output_dir = defaults.output_dir;
+        let world = Arc::new(W::new(world.clone(), &config, self.progress.clone()));
         // index begins as None and is asynchronously populated later
         let index = None;
-        let query = Self::build_query(query)?;
-        let instance = WebResource::new(world, name, config, index, query);
+        let mut query = Self::build_query(query, defaults.field)?;
+        query.typst = defaults.typst;
+        query.root = root.clone();
+        let mut instance = WebResource::new(world, name, root, output_dir, config, index, query);
+        if let Some(log_prefix) = defaults.log_prefix {
+            instance = instance.with_log_prefix(log_prefix);
+        }
         Ok(Box::new(Arc::new(instance)))
     }
 }
@@ -1,17 +1,24 @@
 use std::borrow::Borrow;
 use std::collections::BTreeMap;
 use std::fmt;
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
 use super::IndexError;
+use crate::lockfile::HashAlgorithm;
 
-/// Represents an index of resources.
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Represents an index of resources. Indexes are equal if they would be written the same way,
+/// regardless of their read-only layers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Index {
     #[serde(skip)]
     location: PathBuf,
@@ -26,6 +33,10 @@ pub struct Index {
         skip_serializing_if = "BTreeMap::is_empty"
     )]
     pub entries: BTreeMap<PathBuf, Resource>,
+    /// Entries of read-only indexes layered below this one. They are consulted when looking up
+    /// resources, but never written.
+    #[serde(skip)]
+    base: BTreeMap<PathBuf, Resource>,
 }
 
 /// A resource that should be downloaded
@@ -35,20 +46,146 @@ pub struct Resource {
     pub path: PathBuf,
     /// The URL to download from.
     pub url: String,
+    /// When the resource was last downloaded, in seconds since the Unix epoch. This is only
+    /// recorded in the index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// What the server reported about the content when the resource was last downloaded. This is
+    /// only recorded in the index.
+    #[serde(flatten)]
+    pub validators: Validators,
+    /// The hash of the content when the resource was last downloaded, in the form
+    /// `algorithm:hex-digest`. This is only recorded in the index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Response headers that identify the content behind a URL, used to detect changed content
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Validators {
+    /// The `ETag` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// The `Last-Modified` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// The `Content-Length` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_length: Option<u64>,
+}
+
+impl Validators {
+    /// Whether the content seems unchanged: at least one header is known in both, and all headers
+    /// known in both are equal.
+    pub fn matches(&self, other: &Self) -> bool {
+        fn compare<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> Option<bool> {
+            Some(a.as_ref()? == b.as_ref()?)
+        }
+
+        let comparisons = [
+            compare(&self.etag, &other.etag),
+            compare(&self.last_modified, &other.last_modified),
+            compare(&self.content_length, &other.content_length),
+        ];
+        let mut known = comparisons.into_iter().flatten().peekable();
+        known.peek().is_some() && known.all(|equal| equal)
+    }
+
+    /// Whether a local file seems to have the content described by these headers: at least one of
+    /// `Content-Length` and `Last-Modified` is known, the length is the file's size, and the file
+    /// was modified no earlier than the content.
+    pub fn matches_file(&self, size: u64, modified: SystemTime) -> bool {
+        let last_modified = self.last_modified.as_deref().map(parse_http_date);
+        let comparisons = [
+            self.content_length.map(|length| length == size),
+            last_modified.map(|last_modified| last_modified.is_some_and(|time| time <= modified)),
+        ];
+        let mut known = comparisons.into_iter().flatten().peekable();
+        known.peek().is_some() && known.all(|equal| equal)
+    }
+}
+
+/// Parses an HTTP date in the preferred format, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`. Returns
+/// `None` for other formats.
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (_weekday, date) = date.split_once(", ")?;
+    let [day, month, year, time, "GMT"] = *date.split(' ').collect::<Vec<_>>() else {
+        return None;
+    };
+    let [hour, minute, second] = *time.split(':').collect::<Vec<_>>() else {
+        return None;
+    };
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let year: u64 = year.parse().ok().filter(|year| *year >= 1970)?;
+    let (hour, minute, second): (u64, u64, u64) = (
+        hour.parse().ok()?,
+        minute.parse().ok()?,
+        second.parse().ok()?,
+    );
+
+    // days since the epoch of the proleptic Gregorian calendar date, counting years from March
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146097 + day_of_era).checked_sub(719468)?;
+
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
 }
 
+impl PartialEq for Index {
+    fn eq(&self, other: &Self) -> bool {
+        self.location == other.location
+            && self.version == other.version
+            && self.entries == other.entries
+    }
+}
+
+impl Eq for Index {}
+
 impl Index {
     pub fn new(location: PathBuf) -> Self {
         Self {
             location,
             version: 1,
             entries: BTreeMap::new(),
+            base: BTreeMap::new(),
         }
     }
 
-    /// Reads an index from a file.
+    /// The location this index is read from and written to
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// Whether the index at this location is gzip-compressed, i.e. its file name ends in `.gz`
+    pub fn is_compressed(location: &Path) -> bool {
+        location.extension().is_some_and(|ext| ext == "gz")
+    }
+
+    /// Reads an index from a file. If the file name ends in `.gz`, the file is decompressed first.
     pub async fn read(location: PathBuf) -> Result<Self, IndexError> {
-        let index = fs::read_to_string(&location).await?;
+        let mut index = fs::read(&location).await?;
+        if Self::is_compressed(&location) {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(index.as_slice())
+                .read_to_end(&mut decompressed)
+                .map_err(IndexError::Gzip)?;
+            index = decompressed;
+        }
+        let index = String::from_utf8(index)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
         let mut index: Self = toml::from_str(&index)?;
         if index.version != 1 {
             return Err(IndexError::Version(index.version));
@@ -57,20 +194,34 @@ impl Index {
         Ok(index)
     }
 
-    /// Writes the index to a file.
+    /// Writes the index to a file. If the file name ends in `.gz`, the file is compressed.
     pub async fn write(&self) -> Result<(), IndexError> {
         let mut file = fs::File::create(&self.location).await?;
-        let index = toml::to_string(self)?;
-        file.write_all(index.as_bytes()).await?;
+        let mut index = toml::to_string(self)?.into_bytes();
+        if Self::is_compressed(&self.location) {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&index)?;
+            index = encoder.finish()?;
+        }
+        file.write_all(&index).await?;
+        file.flush().await?;
         Ok(())
     }
 
+    /// Layers a read-only index below this one. Its entries are only used for resources that
+    /// neither this index nor previously added ones record.
+    pub fn add_base(&mut self, index: Index) {
+        for (path, resource) in index.entries.into_iter().chain(index.base) {
+            self.base.entry(path).or_insert(resource);
+        }
+    }
+
     pub fn get<P>(&self, path: &P) -> Option<&Resource>
     where
         PathBuf: Borrow<P>,
         P: Ord + ?Sized,
     {
-        self.entries.get(path)
+        self.entries.get(path).or_else(|| self.base.get(path))
     }
 
     pub fn is_up_to_date<P>(&self, path: &P, url: &str) -> bool
@@ -81,9 +232,44 @@ impl Index {
         self.get(path).is_some_and(|res| res.url == url)
     }
 
+    /// Checks whether the resource at the given path has a recorded hash that was computed with a
+    /// different algorithm than the given one, so that it can't be compared to the current
+    /// content's hash. Entries without a hash are never mismatched.
+    pub fn has_other_hash_algorithm<P>(&self, path: &P, algorithm: HashAlgorithm) -> bool
+    where
+        PathBuf: Borrow<P>,
+        P: Ord + ?Sized,
+    {
+        self.get(path)
+            .and_then(|res| res.hash.as_deref())
+            .is_some_and(|hash| {
+                hash.split_once(':').map(|(name, _)| name) != Some(algorithm.name())
+            })
+    }
+
+    /// Checks whether the resource at the given path was downloaded less than `max_age` seconds
+    /// before `now`. Resources without a recorded timestamp are never fresh.
+    pub fn is_fresh<P>(&self, path: &P, now: u64, max_age: u64) -> bool
+    where
+        PathBuf: Borrow<P>,
+        P: Ord + ?Sized,
+    {
+        self.get(path)
+            .and_then(|res| res.timestamp)
+            .is_some_and(|timestamp| now.saturating_sub(timestamp) < max_age)
+    }
+
     pub fn update(&mut self, resource: Resource) {
         self.entries.insert(resource.path.clone(), resource);
     }
+
+    /// Takes over the entries of another index, e.g. one that a job has updated in memory since
+    /// this one was read from the same location. Entries only recorded in this index are kept.
+    pub fn merge(&mut self, other: &Index) {
+        for resource in other.entries.values() {
+            self.update(resource.clone());
+        }
+    }
 }
 
 fn serialize_entries<S>(map: &BTreeMap<PathBuf, Resource>, serializer: S) -> Result<S::Ok, S::Error>
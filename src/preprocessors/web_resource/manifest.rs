@@ -1,9 +1,15 @@
 use std::fmt;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer};
 
+use super::ValidationError;
+use crate::lockfile::HashAlgorithm;
+use crate::manifest::{Exclude, FileMode, OnCorrupt};
+
 /// Auxiliary configuration for the preprocessor
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Manifest {
@@ -13,36 +19,362 @@ pub struct Manifest {
     pub overwrite: bool,
 
     /// Change this to true or a file path given as a string to enable the index. If true, the
-    /// default path is "web-resource-index.toml"; note that if multiple web-resource jobs are using
-    /// the same index file, this will lead to problems!
+    /// default path is "web-resource-index.toml", unless the package configures a different one
+    /// as `web_resource.default_index`. Multiple web-resource jobs can use the same index file, as
+    /// each job merges its entries into the file's current content when saving it; however,
+    /// cleaning one of these jobs removes the files of all of them. While an index is saved, it is
+    /// locked using a `.lock` file next to it. If the file name ends in `.gz`, e.g.
+    /// "web-resource-index.toml.gz", the index is stored gzip-compressed.
+    ///
+    /// Alternatively, a table such as `{ read = ["base.toml"], write = "local.toml" }` layers
+    /// read-only indexes (e.g. shared in a workspace) below the index that is written; see
+    /// [IndexConfig].
     #[serde(default, deserialize_with = "deserialize_index")]
-    pub index: Option<PathBuf>,
+    pub index: Option<IndexConfig>,
+
+    /// What happens if an index file's content is invalid, e.g. after it was edited by hand; see
+    /// [OnCorrupt]. By default, the job fails; `"rebuild"` treats the index as empty instead. The
+    /// `--repair-index` flag applies `"rebuild"` to all jobs.
+    #[serde(default)]
+    pub on_corrupt: OnCorrupt,
 
     /// Change this to true to delete files no longer needed by the document this requires the index
     /// to be enabled.
     #[serde(default)]
     pub evict: bool,
+
+    /// Hosts that should be connected to directly, even if a proxy is configured. These are used
+    /// in addition to the hosts in the `NO_PROXY` environment variable. An entry matches the host
+    /// itself and all its subdomains; IP addresses may be given with a subnet mask, e.g.
+    /// `10.0.0.0/8`.
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+
+    /// Change this to true to authenticate downloads using the credentials in the user's netrc
+    /// file. This can also be enabled for all jobs using the `--netrc` command line flag.
+    #[serde(default)]
+    pub netrc: bool,
+
+    /// Glob patterns for resource paths that should not be downloaded. Matching resources are
+    /// removed from the query result before any downloads, e.g. to skip already vendored files.
+    #[serde(default)]
+    pub exclude: Exclude,
+
+    /// A URL that relative resource URLs are resolved against, e.g. "https://example.com/assets/";
+    /// absolute resource URLs are used as-is. Resolution works like for links in a web page: for a
+    /// relative URL to be appended to the base URL's path, the base URL must end in a `/`.
+    #[serde(default, deserialize_with = "deserialize_base_url")]
+    pub base_url: Option<reqwest::Url>,
+
+    /// A command and its arguments, e.g. `["./presign.sh", "--expires", "1h"]`, that mints the
+    /// URLs of resources given by a `key` instead of a `url` in the query result, such as object
+    /// keys in S3-style storage whose presigned URLs expire. Before such a resource is downloaded
+    /// or checked, the command is run in the directory containing the `typst.toml` file, with the
+    /// key appended as its last argument. It must exit successfully and print the URL to stdout;
+    /// surrounding whitespace is ignored. Each key's URL is minted at most once per run.
+    ///
+    /// As minted URLs change, the key stands in for the URL everywhere else: it is what is
+    /// recorded in the index, lockfile and asset manifest, and what is logged.
+    #[serde(default)]
+    pub url_command: Option<Vec<String>>,
+
+    /// A directory that resources are placed in. Each resource's path is interpreted relative to
+    /// this directory, unless it is absolute or [flatten][Self::flatten] is set.
+    #[serde(default)]
+    pub dest_dir: Option<PathBuf>,
+
+    /// Change this to true to ignore the resources' paths and instead name each file after the last
+    /// segment of its URL, placed directly in the [destination directory][Self::dest_dir] (or the
+    /// root, if none is given). Resources whose file names coincide are rejected.
+    #[serde(default)]
+    pub flatten: bool,
+
+    /// Change this to true to allow URLs with schemes other than `http` and `https`. By default,
+    /// such resources are rejected before downloading anything.
+    #[serde(default)]
+    pub allow_any_scheme: bool,
+
+    /// The hosts that resources may be downloaded from, e.g. `["example.com", "cdn.example.com"]`.
+    /// Hosts are compared exactly (ignoring case), so subdomains must be listed separately. A
+    /// resource from any other host is rejected before anything is downloaded or copied from the
+    /// cache, even if it is up to date. Keyed resources have their URL minted for this check. If
+    /// not given, all hosts are allowed.
+    #[serde(default)]
+    pub allowed_hosts: Option<Vec<String>>,
+
+    /// The maximum number of downloads from the same host that run at the same time. Downloads
+    /// from different hosts are not limited by this. If not given, the limit given by
+    /// `--concurrency` is used. Regardless of this, that limit also applies to the number of the
+    /// job's downloads overall.
+    #[serde(default)]
+    pub per_host_concurrency: Option<NonZeroUsize>,
+
+    /// The maximum number of requests per second sent to the same host. Requests from different
+    /// hosts are not limited by this. If not given, requests are not throttled.
+    #[serde(default)]
+    pub rate_limit: Option<NonZeroU32>,
+
+    /// The maximum number of bytes of a response body that are included in the error message when
+    /// a download fails with an error status. Hosts often explain the problem there. Set this to
+    /// zero to not include response bodies in error messages.
+    #[serde(default = "default_error_body_limit")]
+    pub error_body_limit: usize,
+
+    /// How long a downloaded file stays fresh, e.g. `"24h"`. Existing files that were downloaded
+    /// longer ago are downloaded again; fresher files are skipped. This requires the index to be
+    /// enabled, as it records when files were downloaded. If not given, existing files are only
+    /// downloaded again if their URL changes.
+    #[serde(default, deserialize_with = "deserialize_max_age")]
+    pub max_age: Option<Duration>,
+
+    /// How existing files whose URL hasn't changed are checked for changes. With `"head"`, a
+    /// `HEAD` request is sent and the response's `ETag`, `Last-Modified` and `Content-Length` are
+    /// compared with those recorded in the index when the file was downloaded. This requires the
+    /// index to be enabled. With `"always"`, files that are not recorded in an index are checked
+    /// as well, by comparing the response with the local file; see [Check::Always].
+    #[serde(default)]
+    pub check: Check,
+
+    /// If given, the index is also saved after every this many downloads, so that the progress of
+    /// a long-running job is not lost if it is interrupted. Otherwise, the index is only saved once
+    /// all downloads have finished. This requires the index to be enabled.
+    #[serde(default)]
+    pub checkpoint_every: Option<NonZeroUsize>,
+
+    /// The algorithm used for the content hashes of this job's resources in the lockfile and the
+    /// index: one of `sha256` (the default), `sha512` or `blake3`. Hashes are stored with the
+    /// algorithm as a prefix, e.g. `sha256:...`; an index entry hashed with a different algorithm
+    /// is treated as outdated, and the resource is downloaded again.
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+
+    /// A file to write a list of this job's resources to after a successful run, so that the
+    /// document can read it using `json()`. The file contains an array of objects with the `url`
+    /// and `path` of each resource, as given in the query result. Like resource paths, this path is
+    /// relative to the root.
+    pub emit_manifest: Option<PathBuf>,
+
+    /// Change this to true or a table such as `{ strip_components = 1 }` to unpack resources that
+    /// are archives after downloading them; see [Extract].
+    #[serde(default, deserialize_with = "deserialize_extract")]
+    pub extract: Option<Extract>,
+
+    /// A check that each downloaded file's content must pass, e.g. to catch an HTML error page
+    /// served instead of the expected data; see [Validate]. The content is checked before it
+    /// replaces an existing file, so content that fails the check is discarded, an existing file
+    /// is kept, and the download fails. Files copied from the cache are not checked again. If not
+    /// given, any content is accepted.
+    #[serde(default)]
+    pub validate: Option<Validate>,
+
+    /// Permissions to give each downloaded file, e.g. `0o644`; see [FileMode]. This also applies
+    /// to files copied from the cache, but not to files extracted from archives. If not given,
+    /// files are created with the operating system's default permissions.
+    #[serde(default)]
+    pub file_mode: Option<FileMode>,
+}
+
+/// How the content of downloaded files is checked. In the manifest, this is either `"non-empty"`,
+/// `"json"`, or a table such as `{ key = "data.items" }`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Validate {
+    /// The file must not be empty.
+    NonEmpty,
+    /// The file must be valid JSON.
+    Json,
+    /// The file must be a JSON object containing the given key. Nested keys are separated by
+    /// dots, e.g. `"data.items"` requires the `items` key in the object at the `data` key.
+    Key(String),
 }
 
-/// Deserializes the `index` config: if given, must be either a boolean or string.
-fn deserialize_index<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+impl Validate {
+    /// Checks the content of a downloaded file.
+    pub fn check(&self, content: &[u8]) -> Result<(), ValidationError> {
+        let key = match self {
+            Self::NonEmpty if content.is_empty() => return Err(ValidationError::Empty),
+            Self::NonEmpty => return Ok(()),
+            Self::Json => None,
+            Self::Key(key) => Some(key),
+        };
+        let value: serde_json::Value = serde_json::from_slice(content)?;
+        if let Some(key) = key {
+            key.split('.')
+                .try_fold(&value, |value, part| value.get(part))
+                .ok_or_else(|| ValidationError::MissingKey(key.clone()))?;
+        }
+        Ok(())
+    }
+}
+
+/// How downloaded archives are unpacked. The archive format is determined by the resource's file
+/// name: `.zip`, `.tar`, `.tar.gz` or `.tgz`; other resources can't be extracted.
+///
+/// Each archive is kept at its path, and its files are extracted into the directory containing
+/// it, replacing existing files. The extracted paths must not leave that directory, e.g. using
+/// `../`; if any of them would, nothing is extracted from that archive. Only regular files are
+/// extracted; directories are created as needed, and links are skipped.
+///
+/// The index only tracks the archive itself: its files are extracted whenever the archive is
+/// downloaded, and not if the archive is skipped. Extracted files are not removed when cleaning.
+///
+/// To guard against archives that decompress to enormous amounts of data ("zip bombs"), the
+/// number and total size of the extracted files are limited; an archive exceeding a limit is not
+/// extracted at all.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Extract {
+    /// The number of leading path components removed from the archive's files, e.g. `1` to
+    /// extract `fonts-1.0/a.ttf` as `a.ttf`. Files with no more components than this are skipped.
+    #[serde(default)]
+    pub strip_components: usize,
+
+    /// The maximum number of files in an archive. Directories and links count towards this limit
+    /// as well. Defaults to 10000.
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+
+    /// The maximum total size of an archive's files after decompression, in bytes. Defaults to
+    /// 1 GiB.
+    #[serde(default = "default_max_size")]
+    pub max_size: usize,
+}
+
+impl Default for Extract {
+    fn default() -> Self {
+        Self {
+            strip_components: 0,
+            max_files: default_max_files(),
+            max_size: default_max_size(),
+        }
+    }
+}
+
+/// How existing files are checked for changes
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Check {
+    /// Existing files are only downloaded again if their URL has changed according to the index.
+    #[default]
+    Url,
+    /// Existing files are also downloaded again if the server reports different content for the
+    /// URL. If the server doesn't support `HEAD` requests, the file is downloaded again.
+    Head,
+    /// Like [Head][Self::Head], but this doesn't require an index: files not recorded in an index
+    /// are downloaded again unless the server reports a `Content-Length` equal to the file's size
+    /// and a `Last-Modified` time no later than the file's modification time. Nothing about the
+    /// check is stored, so this costs one `HEAD` request per existing file on every run.
+    Always,
+}
+
+fn default_error_body_limit() -> usize {
+    1024
+}
+
+fn default_max_files() -> usize {
+    10_000
+}
+
+fn default_max_size() -> usize {
+    1 << 30
+}
+
+/// Deserializes the `max_age` config: if given, must be a duration string such as `"24h"`.
+fn deserialize_max_age<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let max_age = String::deserialize(deserializer)?;
+    let max_age = humantime::parse_duration(&max_age).map_err(de::Error::custom)?;
+    Ok(Some(max_age))
+}
+
+/// Deserializes the `base_url` config: if given, must be an absolute URL.
+fn deserialize_base_url<'de, D>(deserializer: D) -> Result<Option<reqwest::Url>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let base_url = String::deserialize(deserializer)?;
+    let base_url = reqwest::Url::parse(&base_url).map_err(de::Error::custom)?;
+    Ok(Some(base_url))
+}
+
+/// Deserializes the `extract` config: if given, must be either a boolean or table.
+fn deserialize_extract<'de, D>(deserializer: D) -> Result<Option<Extract>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct ExtractVisitor;
+
+    impl<'de> Visitor<'de> for ExtractVisitor {
+        type Value = Option<Extract>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a boolean or table")
+        }
+
+        fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v.then(Extract::default))
+        }
+
+        fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::MapAccess<'de>,
+        {
+            let config = Extract::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            Ok(Some(config))
+        }
+    }
+
+    deserializer.deserialize_any(ExtractVisitor)
+}
+
+/// The index files used by a job
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexConfig {
+    /// Additional indexes that are consulted to detect existing files, but never written. If
+    /// multiple indexes record the same file, the written index takes precedence, followed by these
+    /// in the given order.
+    #[serde(default)]
+    pub read: Vec<PathBuf>,
+    /// The index that is read and updated with downloaded files
+    pub write: PathBuf,
+}
+
+impl IndexConfig {
+    /// A configuration with a single index that is read and written.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            read: Vec::new(),
+            write: path,
+        }
+    }
+}
+
+/// The index location that is used if the `index` config is `true`
+pub const DEFAULT_INDEX: &str = "web-resource-index.toml";
+
+/// Deserializes the `index` config: if given, must be either a boolean, string or table.
+fn deserialize_index<'de, D>(deserializer: D) -> Result<Option<IndexConfig>, D::Error>
 where
     D: Deserializer<'de>,
 {
     struct IndexVisitor;
 
-    impl Visitor<'_> for IndexVisitor {
-        type Value = Option<PathBuf>;
+    impl<'de> Visitor<'de> for IndexVisitor {
+        type Value = Option<IndexConfig>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a boolean or string`")
+            formatter.write_str("a boolean, string or table")
         }
 
         fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            Ok(v.then(|| "web-resource-index.toml".into()))
+            Ok(v.then(|| IndexConfig::new(DEFAULT_INDEX.into())))
         }
 
         fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -56,7 +388,15 @@ where
         where
             E: de::Error,
         {
-            Ok(Some(v.into()))
+            Ok(Some(IndexConfig::new(v.into())))
+        }
+
+        fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::MapAccess<'de>,
+        {
+            let config = IndexConfig::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            Ok(Some(config))
         }
 
         fn visit_none<E>(self) -> Result<Self::Value, E>
@@ -0,0 +1,68 @@
+use reqwest::{NoProxy, Proxy};
+
+/// Proxy configuration for downloads. This mirrors the usual proxy environment variables
+/// (`HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY`), and additionally allows adding hosts
+/// that bypass the proxy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// The proxy to use for `http` URLs
+    pub http: Option<String>,
+    /// The proxy to use for `https` URLs
+    pub https: Option<String>,
+    /// The proxy to use for URLs that don't have a scheme-specific proxy
+    pub all: Option<String>,
+    /// Hosts that are connected to directly, in the format of `NO_PROXY` (see
+    /// [NoProxy::from_string]). An entry matches the host itself and all its subdomains; IP
+    /// addresses may be given with a subnet mask, and `*` matches all hosts.
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    /// Reads the proxy configuration from the environment, using the given function to look up
+    /// variables. Lowercase variables take precedence.
+    pub fn from_env(env_var: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |name: &str| {
+            [name.to_lowercase(), name.to_uppercase()]
+                .into_iter()
+                .find_map(|name| env_var(&name))
+                .filter(|value| !value.is_empty())
+        };
+
+        let no_proxy = var("no_proxy")
+            .map(|hosts| hosts.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        Self {
+            http: var("http_proxy"),
+            https: var("https_proxy"),
+            all: var("all_proxy"),
+            no_proxy,
+        }
+    }
+
+    /// Adds hosts that should be connected to directly.
+    pub fn with_no_proxy(mut self, hosts: impl IntoIterator<Item = String>) -> Self {
+        self.no_proxy.extend(hosts);
+        self
+    }
+
+    /// Converts this configuration into the proxies for a [reqwest::Client], in the order in which
+    /// they are tried. None of them is used for the [no_proxy][Self::no_proxy] hosts.
+    pub fn into_proxies(self) -> reqwest::Result<Vec<Proxy>> {
+        let no_proxy = NoProxy::from_string(&self.no_proxy.join(","));
+        let mut proxies = Vec::new();
+        if let Some(http) = &self.http {
+            proxies.push(Proxy::http(http.as_str())?);
+        }
+        if let Some(https) = &self.https {
+            proxies.push(Proxy::https(https.as_str())?);
+        }
+        if let Some(all) = &self.all {
+            proxies.push(Proxy::all(all.as_str())?);
+        }
+        let proxies = proxies
+            .into_iter()
+            .map(|proxy| proxy.no_proxy(no_proxy.clone()))
+            .collect();
+        Ok(proxies)
+    }
+}
@@ -1,16 +1,193 @@
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry;
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use serde::Deserialize;
 use serde::de::{self, Deserializer, Error, Unexpected, Visitor};
 
-use super::Resource;
+use super::{ExecutionError, ExecutionResult};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct QueryData {
-    pub resources: BTreeMap<PathBuf, String>,
+    pub resources: BTreeMap<PathBuf, QueriedResource>,
+}
+
+/// A resource in the query result: its URL, and the options the document sets for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueriedResource {
+    /// The resource's URL, or its key if [keyed][Self::keyed]
+    pub url: String,
+    /// Whether the resource was given as a key instead of a URL. The URL to download from is
+    /// minted from the key using the job's `url_command`; the key stands in for the URL everywhere
+    /// else, e.g. in the index.
+    pub keyed: bool,
+    pub options: ResourceOptions,
+}
+
+/// Options of a single resource that override the job's configuration. Unset options are
+/// inherited from the job.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceOptions {
+    /// Whether the resource is downloaded and overwritten even if it exists, overriding the job's
+    /// `overwrite` setting
+    #[serde(default)]
+    pub overwrite: Option<bool>,
+    /// Whether the resource is tracked in the index, if one is kept. Untracked resources are
+    /// handled as if no index was kept.
+    #[serde(default)]
+    pub index: Option<bool>,
+}
+
+impl ResourceOptions {
+    /// Whether the resource is forced to be downloaded, given the job's `overwrite` setting.
+    pub fn overwrite(&self, job: bool) -> bool {
+        self.overwrite.unwrap_or(job)
+    }
+
+    /// Whether the resource is tracked in the index.
+    pub fn indexed(&self) -> bool {
+        self.index.unwrap_or(true)
+    }
+}
+
+/// The prefix of placeholders for environment variables in resource URLs and paths
+const ENV_PREFIX: &str = "${env:";
+
+impl QueryData {
+    /// Checks the resources' URLs and makes them absolute: relative URLs are resolved against the
+    /// base URL, if one is given. URLs containing environment variables must already have been
+    /// [expanded][Self::expand_env]. Keys are left as they are.
+    pub fn resolve_urls(self, base: Option<&reqwest::Url>) -> ExecutionResult<Self> {
+        let mut resources = BTreeMap::new();
+        for (path, mut resource) in self.resources {
+            if resource.keyed {
+                resources.insert(path, resource);
+                continue;
+            }
+            let resolved = match base {
+                Some(base) => base.join(&resource.url),
+                None => reqwest::Url::parse(&resource.url),
+            };
+            let resolved = resolved.map_err(|error| ExecutionError::InvalidUrl {
+                path: path.clone(),
+                // expanded environment variables may contain secrets, so they're not shown
+                url: resource.url.clone(),
+                error: error.to_string(),
+            })?;
+            resource.url = String::from(resolved);
+            resources.insert(path, resource);
+        }
+        Ok(Self { resources })
+    }
+
+    /// Replaces `${env:NAME}` placeholders in the resources' URLs and paths with the values of the
+    /// named environment variables, as returned by `var`. It's an error if a variable is not set
+    /// or if two resources with different URLs end up with the same path.
+    pub fn expand_env(self, var: impl Fn(&str) -> Option<String>) -> ExecutionResult<Self> {
+        let mut resources = BTreeMap::<_, QueriedResource>::new();
+        for (
+            path,
+            QueriedResource {
+                url,
+                keyed,
+                options,
+            },
+        ) in self.resources
+        {
+            let missing = |name: &str| ExecutionError::MissingEnv {
+                path: path.clone(),
+                var: name.to_string(),
+            };
+            let expanded_path = match path.to_str() {
+                Some(path) if path.contains(ENV_PREFIX) => {
+                    PathBuf::from(expand_env(path, &var).map_err(missing)?)
+                }
+                _ => path.clone(),
+            };
+            let expanded_url = if url.contains(ENV_PREFIX) {
+                expand_env(&url, &var).map_err(missing)?
+            } else {
+                url
+            };
+            match resources.entry(expanded_path) {
+                Entry::Occupied(entry) if entry.get().url != expanded_url => {
+                    return Err(ExecutionError::FlattenCollision {
+                        path: entry.key().clone(),
+                        first: entry.get().url.clone(),
+                        second: expanded_url,
+                    });
+                }
+                Entry::Occupied(_) => {}
+                Entry::Vacant(entry) => {
+                    entry.insert(QueriedResource {
+                        url: expanded_url,
+                        keyed,
+                        options,
+                    });
+                }
+            }
+        }
+        Ok(Self { resources })
+    }
+
+    /// Moves all resources into the given directory. If `flatten` is set, the resources' paths are
+    /// replaced by the last segment of their URLs (or keys, split at `/`), and no two resources may
+    /// end up with the same path.
+    pub fn relocate(self, dest_dir: &Path, flatten: bool) -> ExecutionResult<Self> {
+        let mut resources = BTreeMap::<_, QueriedResource>::new();
+        for (path, resource) in self.resources {
+            let path = if flatten {
+                let url = &resource.url;
+                let file_name = if resource.keyed {
+                    url.rsplit('/').next().map(str::to_string)
+                } else {
+                    reqwest::Url::parse(url)
+                        .ok()
+                        .and_then(|url| Some(url.path_segments()?.next_back()?.to_string()))
+                };
+                let file_name = file_name
+                    .filter(|file_name| !file_name.is_empty())
+                    .ok_or_else(|| ExecutionError::NoFileName(url.clone()))?;
+                dest_dir.join(file_name)
+            } else {
+                dest_dir.join(path)
+            };
+            match resources.entry(path) {
+                Entry::Occupied(entry) => {
+                    // without flattening, this only happens for absolute paths that coincide with
+                    // paths in the destination directory
+                    return Err(ExecutionError::FlattenCollision {
+                        path: entry.key().clone(),
+                        first: entry.get().url.clone(),
+                        second: resource.url,
+                    });
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(resource);
+                }
+            }
+        }
+        Ok(Self { resources })
+    }
+}
+
+/// Expands the `${env:NAME}` placeholders in a string. If a variable is not set, its name is
+/// returned as the error. An unterminated placeholder is kept verbatim.
+fn expand_env(value: &str, var: impl Fn(&str) -> Option<String>) -> Result<String, &str> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find(ENV_PREFIX) {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + ENV_PREFIX.len()..start + end];
+        result.push_str(&rest[..start]);
+        result.push_str(&var(name).ok_or(name)?);
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
 }
 
 impl<'de> Deserialize<'de> for QueryData {
@@ -18,10 +195,22 @@ impl<'de> Deserialize<'de> for QueryData {
     where
         D: Deserializer<'de>,
     {
+        /// A resource as it appears in the query result, with either a URL or a key
+        #[derive(Deserialize)]
+        struct Item {
+            path: PathBuf,
+            #[serde(default)]
+            url: Option<String>,
+            #[serde(default)]
+            key: Option<String>,
+            #[serde(flatten)]
+            options: ResourceOptions,
+        }
+
         struct FieldVisitor;
 
         impl<'de> Visitor<'de> for FieldVisitor {
-            type Value = BTreeMap<PathBuf, String>;
+            type Value = BTreeMap<PathBuf, QueriedResource>;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
                 formatter
@@ -33,20 +222,57 @@ impl<'de> Deserialize<'de> for QueryData {
                 A: de::SeqAccess<'de>,
             {
                 let mut resources = Self::Value::new();
-                while let Some(Resource { path, url }) = seq.next_element()? {
+                while let Some(item) = seq.next_element()? {
+                    let Item {
+                        path,
+                        url,
+                        key,
+                        options,
+                    } = item;
+                    let (url, keyed) = match (url, key) {
+                        (Some(url), None) => (url, false),
+                        (None, Some(key)) => (key, true),
+                        _ => {
+                            return Err(Error::custom(format!(
+                                "resource {} must have either a `url` or a `key`",
+                                path.display()
+                            )));
+                        }
+                    };
+                    // reject malformed URLs here, where it's clear which resource they belong to.
+                    // URLs with environment variables are only checked once these are expanded,
+                    // and relative URLs once they are resolved against the base URL. Keys can be
+                    // anything
+                    let url = match reqwest::Url::parse(&url) {
+                        _ if keyed => url,
+                        Ok(url) => String::from(url),
+                        Err(url::ParseError::RelativeUrlWithoutBase) => url,
+                        Err(_) if url.contains(ENV_PREFIX) => url,
+                        Err(error) => {
+                            return Err(Error::custom(format!(
+                                "invalid URL `{url}` for {}: {error}",
+                                path.display()
+                            )));
+                        }
+                    };
                     let entry = resources.entry(path);
                     match entry {
                         Entry::Occupied(entry) => {
-                            // the entry is either ok, or we error here
-                            if entry.get().as_str() != url {
+                            // the entry is either ok, or we error here. Options are taken from
+                            // the first occurrence
+                            if entry.get().url != url {
                                 return Err(Error::invalid_value(
-                                    Unexpected::Str(entry.get()),
+                                    Unexpected::Str(&entry.get().url),
                                     &self,
                                 ));
                             }
                         }
                         Entry::Vacant(entry) => {
-                            entry.insert(url);
+                            entry.insert(QueriedResource {
+                                url,
+                                keyed,
+                                options,
+                            });
                         }
                     }
                 }
@@ -1,12 +1,29 @@
-use std::path::Path;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::io;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
+use tokio::process;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
 
-use super::index::Index;
-use super::{DownloadError, IndexError};
+use crate::lockfile::HashAlgorithm;
+use crate::manifest::{FileMode, OnCorrupt};
+use crate::world::{FileLock, World as _, WorldExt as _};
+
+use super::credentials::Credentials;
+use super::index::{Index, Validators};
+use super::manifest::{Manifest, Validate};
+use super::proxy::ProxyConfig;
+use super::{CaCertError, DownloadError, IndexError, UrlCommandError};
 
 /// The context for executing a WebResource job. Defines how downloading and saving files work, and
 /// thus allows mocking.
@@ -15,8 +32,13 @@ use super::{DownloadError, IndexError};
 pub trait World: Send + Sync + 'static {
     type MainWorld: crate::world::World;
 
-    /// Creates a new web resource world based on the given main world.
-    fn new(main: Arc<Self::MainWorld>) -> Self;
+    /// Creates a new web resource world based on the given main world, for a job with the given
+    /// manifest. If a progress sink is given, download progress is sent to it.
+    fn new(
+        main: Arc<Self::MainWorld>,
+        manifest: &Manifest,
+        progress: Option<UnboundedSender<ProgressEvent>>,
+    ) -> Self;
 
     /// Accesses the main world.
     fn main(&self) -> &Arc<Self::MainWorld>;
@@ -27,25 +49,301 @@ pub trait World: Send + Sync + 'static {
     /// Writes the web resource index to its location.
     async fn write_index(&self, index: &Index) -> Result<(), IndexError>;
 
+    /// Locks the web resource index at the given path, interpreted relative to the typst.toml file,
+    /// until the returned lock is dropped. The lock is held on a separate file next to the index,
+    /// named like the index with `.lock` appended.
+    async fn lock_index(&self, path: &Path) -> Result<FileLock, IndexError>;
+
     /// Checks whether a resource at the given path exists.
     async fn resource_exists(&self, location: &Path) -> bool;
 
-    /// Performs the download of a URL's contents to a file.
-    async fn download(&self, location: &Path, url: &str) -> Result<(), DownloadError>;
+    /// Returns the size and modification time of the existing resource at the given path.
+    async fn resource_metadata(&self, location: &Path) -> io::Result<(u64, SystemTime)>;
+
+    /// Performs the download of a URL's contents to a file. Returns what the server reported
+    /// about the content. The content is first saved next to the file and only moved into its
+    /// place if exactly as many bytes as announced by the server were received and the content
+    /// passes the given check, if any. Otherwise, the download fails and an existing file is
+    /// left untouched.
+    async fn download(
+        &self,
+        location: &Path,
+        url: &str,
+        validate: Option<Validate>,
+    ) -> Result<Validators, DownloadError>;
+
+    /// Asks the server what the content behind a URL currently is, without downloading it.
+    /// Returns `None` if the server doesn't support `HEAD` requests.
+    async fn head(&self, url: &str) -> Result<Option<Validators>, DownloadError>;
+
+    /// Runs the given `url_command` with the key appended, in the directory containing the
+    /// typst.toml file, and returns its output with surrounding whitespace removed.
+    async fn mint_url(&self, command: &[String], key: &str) -> Result<String, UrlCommandError>;
+
+    /// Copies the file downloaded from a URL from the given cache directory to the location.
+    /// Returns whether the cache contained such a file.
+    async fn copy_from_cache(&self, cache: &Path, url: &str, location: &Path) -> io::Result<bool>;
+
+    /// Adds the file at the location to the given cache directory, as downloaded from a URL. An
+    /// existing entry for the URL is replaced.
+    async fn copy_to_cache(&self, cache: &Path, url: &str, location: &Path) -> io::Result<()>;
+
+    /// Computes the hash of a resource's content, in the form `algorithm:hex-digest`.
+    async fn hash_resource(&self, location: &Path, algorithm: HashAlgorithm) -> io::Result<String>;
+
+    /// Writes the list of a job's resources to a file.
+    async fn write_asset_list(&self, location: &Path, content: &[u8]) -> io::Result<()>;
+
+    /// Reads a downloaded resource, to validate or extract it.
+    async fn read_resource(&self, location: &Path) -> io::Result<Vec<u8>>;
+
+    /// Writes a file extracted from an archive, replacing any existing file.
+    async fn write_extracted(&self, location: &Path, content: &[u8]) -> io::Result<()>;
+
+    /// Removes the resource at the given path. A resource that doesn't exist is not an error.
+    async fn remove_resource(&self, location: &Path) -> io::Result<()>;
+
+    /// Removes the web resource index from its location.
+    async fn remove_index(&self, index: &Index) -> Result<(), IndexError>;
+}
+
+/// Higher-level operations on a web resource [World], implemented in terms of its methods
+#[async_trait]
+pub trait WorldExt: World {
+    /// Reads the web resource index at the given path, lets `f` modify it, and writes it back, all
+    /// while holding the [index lock][World::lock_index]. Unlike a separate
+    /// [read][World::read_index] and [write][World::write_index], this doesn't lose changes that
+    /// other jobs or processes sharing the index made in the meantime. Returns the written index.
+    async fn with_index<F>(&self, path: &Path, f: F) -> Result<Index, IndexError>
+    where
+        F: FnOnce(&mut Index) + Send,
+    {
+        let _lock = self.lock_index(path).await?;
+        let mut index = self.read_index(path).await?;
+        f(&mut index);
+        self.write_index(&index).await?;
+        Ok(index)
+    }
+}
+
+#[async_trait]
+impl<T: World> WorldExt for T {}
+
+/// The progress of a download, as reported to a
+/// [progress sink][super::WebResourceFactory::with_progress] while the response is received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressEvent {
+    /// The path the resource is downloaded to
+    pub path: PathBuf,
+    /// The number of bytes received so far
+    pub bytes_done: u64,
+    /// The size of the resource, if the server reported it
+    pub bytes_total: Option<u64>,
 }
 
 /// The default context, accessing the real web and filesystem.
 #[derive(Clone)]
 pub struct DefaultWorld {
     main: Arc<crate::world::DefaultWorld>,
+    progress: Option<UnboundedSender<ProgressEvent>>,
+    proxy: ProxyConfig,
+    client: OnceCell<reqwest::Client>,
+    netrc: bool,
+    credentials: OnceCell<Credentials>,
+    error_body_limit: usize,
+    per_host_concurrency: NonZeroUsize,
+    rate_limit: Option<NonZeroU32>,
+    file_mode: Option<FileMode>,
+    on_corrupt: OnCorrupt,
+    /// The limits of each host that was requested so far
+    hosts: Arc<Mutex<HashMap<String, Arc<HostLimit>>>>,
+}
+
+/// The state of the per-host limits for a single host
+#[derive(Debug)]
+struct HostLimit {
+    /// Limits the number of concurrent downloads
+    semaphore: Arc<Semaphore>,
+    /// The earliest time the next request may be sent
+    next_request: tokio::sync::Mutex<Instant>,
+}
+
+impl DefaultWorld {
+    /// Returns the HTTP client shared by all downloads of this job. The client is created on first
+    /// use, trusting the certificates of the [CA bundle][crate::args::CliArguments::ca_cert], if
+    /// one was given. The bundle is thus only read by jobs that download something.
+    async fn client(&self) -> Result<&reqwest::Client, DownloadError> {
+        self.client
+            .get_or_try_init(|| async {
+                // the proxies are only taken from the configuration, not read by reqwest itself
+                let mut builder = reqwest::Client::builder().no_proxy();
+                for proxy in self.proxy.clone().into_proxies()? {
+                    builder = builder.proxy(proxy);
+                }
+                if let Some(path) = &self.main.arguments().ca_cert {
+                    for certificate in read_ca_certificates(path).await? {
+                        builder = builder.add_root_certificate(certificate);
+                    }
+                }
+                let client = builder.build()?;
+                Ok(client)
+            })
+            .await
+    }
+
+    /// Reads the start of an error response's body, up to the configured limit. Returns `None` if
+    /// the body is empty, could not be read, or capturing it is disabled.
+    async fn error_body(&self, mut response: reqwest::Response) -> Option<String> {
+        let limit = self.error_body_limit;
+        if limit == 0 {
+            return None;
+        }
+        let mut body = Vec::new();
+        while body.len() <= limit {
+            let Ok(Some(chunk)) = response.chunk().await else {
+                break;
+            };
+            body.extend_from_slice(&chunk);
+        }
+        let truncated = body.len() > limit;
+        body.truncate(limit);
+        let mut body = String::from_utf8_lossy(&body).trim().to_string();
+        if body.is_empty() {
+            return None;
+        }
+        if truncated {
+            body.push_str("...");
+        }
+        Some(body)
+    }
+
+    /// Waits until a request to the given URL's host is allowed by the per-host limits. The
+    /// returned permit, if any, must be held until the download is finished.
+    async fn throttle(&self, url: &str) -> Option<OwnedSemaphorePermit> {
+        // invalid URLs are reported when sending the request
+        let host = reqwest::Url::parse(url).ok()?.host_str()?.to_string();
+        let limit = {
+            let mut hosts = self.hosts.lock().expect("host limits lock was poisoned");
+            let limit = hosts.entry(host).or_insert_with(|| {
+                Arc::new(HostLimit {
+                    semaphore: Arc::new(Semaphore::new(self.per_host_concurrency.get())),
+                    next_request: tokio::sync::Mutex::new(Instant::now()),
+                })
+            });
+            Arc::clone(limit)
+        };
+
+        let permit = Arc::clone(&limit.semaphore)
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        if let Some(rate_limit) = self.rate_limit {
+            // requests to the same host are spaced out evenly
+            let interval = Duration::from_secs(1) / rate_limit.get();
+            let mut next_request = limit.next_request.lock().await;
+            let now = Instant::now();
+            if *next_request > now {
+                time::sleep_until(*next_request).await;
+            }
+            *next_request = (*next_request).max(now) + interval;
+        }
+        Some(permit)
+    }
+
+    /// Builds a request to the given URL, authenticated if there are netrc credentials for it.
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+    ) -> Result<reqwest::RequestBuilder, DownloadError> {
+        let mut request = self.client().await?.request(method, url);
+        if let Some(credentials) = self.credentials().await.map_err(DownloadError::Netrc)? {
+            // invalid URLs are reported when sending the request
+            let url = reqwest::Url::parse(url).ok();
+            let login = url
+                .as_ref()
+                .and_then(|url| url.host_str())
+                .and_then(|host| credentials.for_host(host));
+            if let Some((login, password)) = login {
+                request = request.basic_auth(login, password);
+            }
+        }
+        Ok(request)
+    }
+
+    /// Extracts the validators from a successful response's headers.
+    fn validators(response: &reqwest::Response) -> Validators {
+        let header = |name| {
+            let value = response.headers().get(name)?.to_str().ok()?;
+            Some(value.to_string())
+        };
+        Validators {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+            content_length: header(reqwest::header::CONTENT_LENGTH)
+                .and_then(|value| value.parse().ok()),
+        }
+    }
+
+    /// Returns the credentials from the user's netrc file, or `None` if netrc authentication is
+    /// not enabled. The file is read on first use.
+    async fn credentials(&self) -> io::Result<Option<&Credentials>> {
+        if !self.netrc {
+            return Ok(None);
+        }
+        let credentials = self
+            .credentials
+            .get_or_try_init(|| async {
+                match Credentials::default_path() {
+                    Some(path) => Credentials::read(&path).await,
+                    None => Ok(Credentials::default()),
+                }
+            })
+            .await?;
+        Ok(Some(credentials))
+    }
+
+    /// Returns the location of the index at the given path, interpreted relative to the
+    /// typst.toml file.
+    async fn index_location(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(self.main().manifest_dir().await?.join(path))
+    }
 }
 
 #[async_trait]
 impl World for DefaultWorld {
     type MainWorld = crate::world::DefaultWorld;
 
-    fn new(main: Arc<Self::MainWorld>) -> Self {
-        Self { main }
+    fn new(
+        main: Arc<Self::MainWorld>,
+        manifest: &Manifest,
+        progress: Option<UnboundedSender<ProgressEvent>>,
+    ) -> Self {
+        let proxy = ProxyConfig::from_env(|name| main.env_var(name))
+            .with_no_proxy(manifest.no_proxy.iter().cloned());
+        let netrc = manifest.netrc || main.arguments().netrc;
+        let per_host_concurrency = manifest
+            .per_host_concurrency
+            .unwrap_or_else(|| main.concurrency());
+        let on_corrupt = if main.arguments().repair_index {
+            OnCorrupt::Rebuild
+        } else {
+            manifest.on_corrupt
+        };
+        Self {
+            main,
+            progress,
+            proxy,
+            client: OnceCell::new(),
+            netrc,
+            credentials: OnceCell::new(),
+            error_body_limit: manifest.error_body_limit,
+            per_host_concurrency,
+            rate_limit: manifest.rate_limit,
+            file_mode: manifest.file_mode,
+            on_corrupt,
+            hosts: Arc::default(),
+        }
     }
 
     fn main(&self) -> &Arc<Self::MainWorld> {
@@ -53,17 +351,27 @@ impl World for DefaultWorld {
     }
 
     async fn read_index(&self, path: &Path) -> Result<Index, IndexError> {
-        let mut location = self.main().resolve_typst_toml().await?;
-        let result = location.pop();
-        assert!(
-            result,
-            "the path should have had a final filename component"
-        );
-        location.push(path);
+        let location = self.index_location(path).await?;
 
         let index = if fs::try_exists(&location).await.unwrap_or(false) {
             // read the existing index
-            Index::read(location).await?
+            match Index::read(location.clone()).await {
+                Err(error)
+                    if error.is_corrupt()
+                        && self.on_corrupt == OnCorrupt::Rebuild
+                        && !self.main.arguments().strict =>
+                {
+                    // the index is only an optimization, so start over
+                    let mut l = self.main.log();
+                    let location_str = location.to_string_lossy();
+                    log!(
+                        l,
+                        "warning: {location_str} is corrupt and will be rebuilt: {error}"
+                    );
+                    Index::new(location)
+                }
+                result => result?,
+            }
         } else {
             // generate an empty index
             Index::new(location)
@@ -76,20 +384,215 @@ impl World for DefaultWorld {
         Ok(())
     }
 
+    async fn lock_index(&self, path: &Path) -> Result<FileLock, IndexError> {
+        let mut location = self.index_location(path).await?.into_os_string();
+        location.push(".lock");
+        let lock = FileLock::acquire(location.into()).await?;
+        Ok(lock)
+    }
+
     async fn resource_exists(&self, location: &Path) -> bool {
         fs::try_exists(location).await.unwrap_or(false)
     }
 
-    async fn download(&self, location: &Path, url: &str) -> Result<(), DownloadError> {
+    async fn resource_metadata(&self, location: &Path) -> io::Result<(u64, SystemTime)> {
+        let metadata = fs::metadata(location).await?;
+        Ok((metadata.len(), metadata.modified()?))
+    }
+
+    async fn download(
+        &self,
+        location: &Path,
+        url: &str,
+        validate: Option<Validate>,
+    ) -> Result<Validators, DownloadError> {
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let _permit = self.throttle(url).await;
+        let request = self.request(reqwest::Method::GET, url).await?;
+        let mut response = request.send().await?;
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            let body = self.error_body(response).await;
+            return Err(DownloadError::Status { status, body });
+        }
+        let validators = Self::validators(&response);
+        let bytes_total = response.content_length();
+
+        // an existing file is only replaced once the new content is complete and valid
+        let temp = partial_location(location);
+        let result = async {
+            let mut bytes_done = 0;
+            let mut file = fs::File::create(&temp).await?;
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).await?;
+                bytes_done += chunk.len() as u64;
+                if let Some(progress) = &self.progress {
+                    // a dropped receiver just means nobody is interested in progress anymore
+                    let _ = progress.send(ProgressEvent {
+                        path: location.to_path_buf(),
+                        bytes_done,
+                        bytes_total,
+                    });
+                }
+            }
+            file.flush().await?;
+            drop(file);
+            if let Some(expected) = bytes_total
+                && bytes_done != expected
+            {
+                // a connection that was dropped early doesn't necessarily result in an error
+                return Err(DownloadError::Truncated {
+                    expected,
+                    got: bytes_done,
+                });
+            }
+            if let Some(validate) = &validate {
+                let content = fs::read(&temp).await?;
+                validate
+                    .check(&content)
+                    .map_err(|error| DownloadError::Validation(location.to_path_buf(), error))?;
+            }
+            if let Some(mode) = self.file_mode {
+                mode.apply(&temp).await?;
+            }
+            fs::rename(&temp, location).await?;
+            Ok(())
+        };
+        if let Err(error) = result.await {
+            // the download's error is more relevant than one removing the partial file
+            let _ = self.remove_resource(&temp).await;
+            return Err(error);
+        }
+        Ok(validators)
+    }
+
+    async fn head(&self, url: &str) -> Result<Option<Validators>, DownloadError> {
+        let _permit = self.throttle(url).await;
+        let request = self.request(reqwest::Method::HEAD, url).await?;
+        let response = request.send().await?;
+        let status = response.status();
+        if status == reqwest::StatusCode::METHOD_NOT_ALLOWED
+            || status == reqwest::StatusCode::NOT_IMPLEMENTED
+        {
+            return Ok(None);
+        }
+        if status.is_client_error() || status.is_server_error() {
+            return Err(DownloadError::Status { status, body: None });
+        }
+        Ok(Some(Self::validators(&response)))
+    }
+
+    async fn mint_url(&self, command: &[String], key: &str) -> Result<String, UrlCommandError> {
+        let dir = self.main().manifest_dir().await?;
+
+        let output = process::Command::new(&command[0])
+            .args(&command[1..])
+            .arg(key)
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|error| {
+                if error.kind() == io::ErrorKind::NotFound {
+                    UrlCommandError::NotFound(command[0].clone())
+                } else {
+                    error.into()
+                }
+            })?;
+        if !output.status.success() {
+            return Err(UrlCommandError::ExitStatus(output.status));
+        }
+        let url = String::from_utf8(output.stdout).map_err(|_| UrlCommandError::InvalidUrl)?;
+        Ok(url.trim().to_string())
+    }
+
+    async fn copy_from_cache(&self, cache: &Path, url: &str, location: &Path) -> io::Result<bool> {
+        let entry = cache_entry(cache, url);
+        if !fs::try_exists(&entry).await? {
+            return Ok(false);
+        }
         if let Some(parent) = location.parent() {
             fs::create_dir_all(parent).await?;
         }
-        let mut response = reqwest::get(url).await?.error_for_status()?;
-        let mut file = fs::File::create(&location).await?;
-        while let Some(chunk) = response.chunk().await? {
-            file.write_all(&chunk).await?;
+        // a hard link would be faster, but overwriting the project's file would change the cache
+        fs::copy(&entry, location).await?;
+        if let Some(mode) = self.file_mode {
+            mode.apply(location).await?;
         }
-        file.flush().await?;
+        Ok(true)
+    }
+
+    async fn copy_to_cache(&self, cache: &Path, url: &str, location: &Path) -> io::Result<()> {
+        fs::create_dir_all(cache).await?;
+        let entry = cache_entry(cache, url);
+        // other processes may use the same cache, so entries are replaced atomically
+        let temp = entry.with_extension(format!("tmp-{}", std::process::id()));
+        fs::copy(location, &temp).await?;
+        fs::rename(&temp, &entry).await
+    }
+
+    async fn hash_resource(&self, location: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+        let content = fs::read(location).await?;
+        Ok(algorithm.digest(&content))
+    }
+
+    async fn write_asset_list(&self, location: &Path, content: &[u8]) -> io::Result<()> {
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(location, content).await
+    }
+
+    async fn read_resource(&self, location: &Path) -> io::Result<Vec<u8>> {
+        fs::read(location).await
+    }
+
+    async fn write_extracted(&self, location: &Path, content: &[u8]) -> io::Result<()> {
+        if let Some(parent) = location.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(location, content).await
+    }
+
+    async fn remove_resource(&self, location: &Path) -> io::Result<()> {
+        match fs::remove_file(location).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    async fn remove_index(&self, index: &Index) -> Result<(), IndexError> {
+        self.remove_resource(index.location()).await?;
         Ok(())
     }
 }
+
+/// Returns the location of the file downloaded from a URL in the cache directory. Entries are
+/// named after the hash of the URL.
+fn cache_entry(cache: &Path, url: &str) -> PathBuf {
+    let hash = HashAlgorithm::Sha256.digest(url.as_bytes());
+    cache.join(hash.replace(':', "-"))
+}
+
+/// Returns a unique location next to the given one, that a download is saved to before it is moved
+/// into place. Files are named like the location with `.part-<unique>` appended.
+fn partial_location(location: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut name = location.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".part-{}-{unique}", std::process::id()));
+    location.with_file_name(name)
+}
+
+/// Reads the certificates of a PEM bundle.
+async fn read_ca_certificates(path: &Path) -> Result<Vec<reqwest::Certificate>, CaCertError> {
+    let pem = fs::read(path)
+        .await
+        .map_err(|error| CaCertError::Read(path.to_path_buf(), error))?;
+    reqwest::Certificate::from_pem_bundle(&pem)
+        .map_err(|error| CaCertError::Invalid(path.to_path_buf(), error))
+}
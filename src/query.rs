@@ -1,14 +1,21 @@
 //! Executing `typst query` commands
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use itertools::Itertools;
+use serde::Deserialize;
 
 use crate::manifest;
+use crate::world::{World, WorldExt};
 
 pub use error::*;
 
 /// A query that can be run against a Typst document. This is usually configured from a
 /// [manifest::Query] using a [QueryBuilder].
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Query {
     /// The selector to be queried, e.g. `<label>`
     pub selector: String,
@@ -18,8 +25,21 @@ pub struct Query {
     /// Whether only one (`--one`) query result is expected and should be returned
     pub one: bool,
     /// Any additional inputs (`--input`) to be given to the queried document. Regardless of these
-    /// settings, `prequery-fallback` is always set to `true` during queries.
+    /// settings, `prequery-fallback` is set according to [fallback][Self::fallback]. Values of the
+    /// form `${env:NAME}` are [expanded][Self::expand_inputs] when the query is executed.
     pub inputs: HashMap<String, String>,
+    /// The document to query. If not given, the input file given on the command line is queried.
+    pub document: Option<PathBuf>,
+    /// Whether the `prequery-fallback=true` input is given to the queried document
+    pub fallback: bool,
+    /// Additional arguments for `typst query`, passed verbatim before the document and selector
+    pub extra_args: Vec<String>,
+    /// The Typst executable to run the query with. If not given, the executable given on the
+    /// command line is used.
+    pub typst: Option<PathBuf>,
+    /// The project root (`--root`) to run the query with. If not given, the root given on the
+    /// command line is used.
+    pub root: Option<PathBuf>,
 }
 
 impl Query {
@@ -27,6 +47,147 @@ impl Query {
     pub fn builder() -> QueryBuilder {
         QueryBuilder::default()
     }
+
+    /// Executes this query against the given world and returns the result parsed into the desired
+    /// type from JSON. This is the way preprocessors should run their queries; it is equivalent to
+    /// [WorldExt::query].
+    pub async fn execute<T>(&self, world: &impl World) -> Result<T>
+    where
+        T: for<'a> Deserialize<'a>,
+    {
+        world.query(self).await
+    }
+
+    /// Returns the label pattern if the selector is a label containing the wildcards `*` (any
+    /// number of characters) or `?` (a single character), e.g. `<*-data>` for the pattern
+    /// `*-data`. As `typst query` doesn't support such selectors, these queries are run as a
+    /// [broad query][Self::broad_query] for all metadata elements, and the results are then
+    /// filtered by label. [field][Self::field] and [one][Self::one] are applied to the filtered
+    /// results, in document order.
+    pub fn label_pattern(&self) -> Option<&str> {
+        let label = self.selector.strip_prefix('<')?.strip_suffix('>')?;
+        label.contains(['*', '?']).then_some(label)
+    }
+
+    /// Returns the query that is actually run for a [label pattern][Self::label_pattern]: it
+    /// selects all metadata elements, without a field and allowing any number of results.
+    pub fn broad_query(&self) -> Self {
+        Self {
+            selector: "metadata".to_string(),
+            field: None,
+            one: false,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the key identifying this query's result in a [QueryCache]: the selector followed by
+    /// the `typst query` arguments that affect the result, separated by spaces. Inputs are sorted
+    /// and not [expanded][Self::expand_inputs], so that no secrets end up in the cache file.
+    pub fn cache_key(&self) -> String {
+        let mut key = vec![self.selector.clone()];
+        if let Some(field) = &self.field {
+            key.push(format!("--field {field}"));
+        }
+        if self.one {
+            key.push("--one".to_string());
+        }
+        for (name, value) in self.inputs.iter().sorted() {
+            key.push(format!("--input {name}={value}"));
+        }
+        if self.fallback {
+            key.push("--input prequery-fallback=true".to_string());
+        }
+        key.extend(self.extra_args.iter().cloned());
+        if let Some(document) = &self.document {
+            key.push(document.display().to_string());
+        }
+        if let Some(root) = &self.root {
+            key.push(format!("--root {}", root.display()));
+        }
+        key.join(" ")
+    }
+
+    /// Returns this query with the [inputs][Self::inputs] of the form `${env:NAME}` replaced by
+    /// the value of the named environment variable, as returned by `var`. Fails if a variable is
+    /// not set.
+    pub fn expand_inputs(&self, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut query = self.clone();
+        for (key, value) in &mut query.inputs {
+            let Some(name) = value
+                .strip_prefix("${env:")
+                .and_then(|name| name.strip_suffix('}'))
+            else {
+                continue;
+            };
+            *value = var(name).ok_or_else(|| Error::MissingEnv {
+                input: key.clone(),
+                var: name.to_string(),
+            })?;
+        }
+        Ok(query)
+    }
+}
+
+/// Recorded query results, stored in a JSON file as an object that maps each query's [cache
+/// key][Query::cache_key] to its result. This is a development aid; see
+/// [query_cache_file][crate::args::CliArguments::query_cache_file].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryCache {
+    location: PathBuf,
+    /// The recorded results, by cache key
+    pub entries: BTreeMap<String, serde_json::Value>,
+}
+
+impl QueryCache {
+    /// Creates an empty query cache stored at the given location.
+    pub fn new(location: PathBuf) -> Self {
+        Self {
+            location,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Reads the query cache from the given location. If the file doesn't exist, the cache is
+    /// empty.
+    pub async fn read(location: PathBuf) -> Result<Self> {
+        let content = match tokio::fs::read(&location).await {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::new(location));
+            }
+            Err(error) => return Err(Error::QueryCacheIo(location, error)),
+        };
+        let entries = match serde_json::from_slice(&content) {
+            Ok(entries) => entries,
+            Err(error) => return Err(Error::QueryCacheInvalid(location, error)),
+        };
+        Ok(Self { location, entries })
+    }
+
+    /// Writes the query cache to its location.
+    pub async fn write(&self) -> Result<()> {
+        let mut content =
+            serde_json::to_vec_pretty(&self.entries).expect("serializing JSON should not fail");
+        content.push(b'\n');
+        tokio::fs::write(&self.location, content)
+            .await
+            .map_err(|error| Error::QueryCacheIo(self.location.clone(), error))
+    }
+
+    /// Returns the recorded result of the query, as JSON. Fails if the query was not recorded.
+    pub fn get(&self, query: &Query) -> Result<Vec<u8>> {
+        let key = query.cache_key();
+        let value = self.entries.get(&key).ok_or(Error::NotCached(key))?;
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    /// Records the result of the query, replacing an earlier result. Fails if the result is not
+    /// valid JSON.
+    pub fn insert(&mut self, query: &Query, output: &[u8]) -> Result<()> {
+        let value = serde_json::from_slice(output)?;
+        self.entries.insert(query.cache_key(), value);
+        Ok(())
+    }
 }
 
 /// A query builder. Default values for the various configs can be set. If a setting is missing from
@@ -69,23 +230,251 @@ impl QueryBuilder {
             .selector
             .or(self.selector)
             .ok_or(QueryBuilderError::Selector)?;
+        let selector = expand_vars(&selector, &config.vars)?;
         let field = config
             .field
             .or(self.field)
             .ok_or(QueryBuilderError::Field)?;
         let one = config.one.or(self.one).ok_or(QueryBuilderError::One)?;
         let inputs = config.inputs;
+        let document = config.document;
+        let fallback = config.fallback.unwrap_or(true);
+        let extra_args = config.extra_args;
+        let typst = None;
+        let root = None;
         Ok(Query {
             selector,
             field,
             one,
             inputs,
+            document,
+            fallback,
+            extra_args,
+            typst,
+            root,
         })
     }
 }
 
+/// Replaces the `${name}` placeholders in a selector by the given variables' values. Fails if a
+/// variable is not defined or a placeholder is not closed.
+pub(crate) fn expand_vars(
+    selector: &str,
+    vars: &HashMap<String, String>,
+) -> Result<String, QueryBuilderError> {
+    let mut result = String::with_capacity(selector.len());
+    let mut rest = selector;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 2..];
+        let end = rest
+            .find('}')
+            .ok_or(QueryBuilderError::UnterminatedVariable)?;
+        let name = &rest[..end];
+        let value = vars
+            .get(name)
+            .ok_or_else(|| QueryBuilderError::UndefinedVariable(name.to_string()))?;
+        result.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Filters the result of a [broad query][Query::broad_query] down to the elements whose labels
+/// match the pattern, then applies the original query's field and `one` setting.
+pub(crate) fn filter_labels(query: &Query, pattern: &str, output: &[u8]) -> Result<Vec<u8>> {
+    let elements: Vec<serde_json::Value> = serde_json::from_slice(output)?;
+    let mut results = Vec::new();
+    for mut element in elements {
+        let Some(label) = element.get("label").and_then(serde_json::Value::as_str) else {
+            continue;
+        };
+        let Some(label) = label.strip_prefix('<').and_then(|l| l.strip_suffix('>')) else {
+            continue;
+        };
+        if !glob_match(pattern, label) {
+            continue;
+        }
+        let label = label.to_string();
+        let result = match &query.field {
+            Some(field) => match element.get_mut(field) {
+                Some(value) => value.take(),
+                None => {
+                    return Err(Error::MissingField {
+                        label,
+                        field: field.clone(),
+                    });
+                }
+            },
+            None => element,
+        };
+        results.push(result);
+    }
+
+    let output = if query.one {
+        if results.len() != 1 {
+            return Err(Error::NotOne {
+                selector: query.selector.clone(),
+                count: results.len(),
+            });
+        }
+        serde_json::to_vec(&results[0])?
+    } else {
+        serde_json::to_vec(&results)?
+    };
+    Ok(output)
+}
+
+/// Matches a label against a pattern, where `*` matches any number of characters and `?` matches
+/// a single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // the position after the last `*`, and the text position it was matched up to
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` match one more character
+                Some((bp, bt)) => {
+                    p = bp;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl Error {
+    /// Creates a [Schema][Error::Schema] error for a query result that could not be deserialized.
+    /// If the result is an array, the item containing the error's position is reported; otherwise
+    /// the start of the result.
+    pub(crate) fn schema(selector: &str, output: &[u8], error: serde_json::Error) -> Self {
+        const SNIPPET_LEN: usize = 80;
+
+        let output = String::from_utf8_lossy(output);
+        let offset = output
+            .split_inclusive('\n')
+            .take(error.line().saturating_sub(1))
+            .map(str::len)
+            .sum::<usize>()
+            + error.column().saturating_sub(1);
+        let (item, snippet) = match locate_item(&output, offset) {
+            Some((index, item)) => (Some(index), item),
+            None => (None, output.as_ref()),
+        };
+
+        // pretty-printed JSON is collapsed into a single line
+        let snippet = snippet.split_whitespace().join(" ");
+        let snippet = match snippet.char_indices().nth(SNIPPET_LEN) {
+            Some((end, _)) => format!("{}...", &snippet[..end]),
+            None => snippet,
+        };
+        Self::Schema {
+            selector: selector.to_string(),
+            item,
+            snippet,
+            error,
+        }
+    }
+}
+
+/// Finds the item of a top-level JSON array that contains the given byte offset. Returns the
+/// item's index and text, or `None` if the JSON value is not an array or the offset is outside it.
+fn locate_item(json: &str, offset: usize) -> Option<(usize, &str)> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut index = 0;
+    let mut start = None;
+    for (pos, byte) in json.bytes().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                if depth == 0 {
+                    if byte != b'[' {
+                        return None;
+                    }
+                    start = Some(pos + 1);
+                }
+                depth += 1;
+            }
+            b']' | b'}' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    let item = json[start?..pos].trim();
+                    return (!item.is_empty()).then_some((index, item));
+                }
+            }
+            b',' if depth == 1 => {
+                if offset <= pos {
+                    return Some((index, json[start?..pos].trim()));
+                }
+                index += 1;
+                start = Some(pos + 1);
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A Typst version, as reported by `typst --version`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TypstVersion(pub u64, pub u64, pub u64);
+
+impl TypstVersion {
+    /// The oldest Typst version that supports all `typst query` features used by prequery
+    pub const MINIMUM: Self = Self(0, 11, 0);
+
+    /// Parses the output of `typst --version`, e.g. `typst 0.13.1 (8ace67d9)`. Pre-release
+    /// suffixes are ignored.
+    pub fn parse(output: &str) -> Option<Self> {
+        let version = output.split_whitespace().nth(1)?;
+        let mut parts = version.split('.').map(|part| {
+            let end = part
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(part.len());
+            part[..end].parse().ok()
+        });
+        let major = parts.next()??;
+        let minor = parts.next()??;
+        let patch = parts.next()??;
+        Some(Self(major, minor, patch))
+    }
+}
+
+impl fmt::Display for TypstVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
 mod error {
     use std::io;
+    use std::path::PathBuf;
     use std::process::ExitStatus;
 
     use thiserror::Error;
@@ -97,6 +486,32 @@ mod error {
         /// Reading command output failed
         #[error("reading from the `typst query` child process failed")]
         Io(#[from] io::Error),
+        /// The Typst executable could not be found
+        #[error(
+            "the `{}` executable could not be found; set --typst or install Typst",
+            .0.display()
+        )]
+        TypstNotFound(PathBuf),
+        /// An environment variable referenced by an input is not set
+        #[error("the environment variable `{var}` for the input `{input}` is not set")]
+        MissingEnv {
+            /// The input referencing the variable
+            input: String,
+            /// The name of the variable
+            var: String,
+        },
+        /// The document to query does not exist
+        #[error("the document to query does not exist: {}", .0.display())]
+        MissingDocument(PathBuf),
+        /// The query cache file could not be read or written
+        #[error("the query cache file {} could not be read or written", .0.display())]
+        QueryCacheIo(PathBuf, #[source] io::Error),
+        /// The query cache file's content is not valid
+        #[error("the query cache file {} is not a valid JSON object", .0.display())]
+        QueryCacheInvalid(PathBuf, #[source] serde_json::Error),
+        /// The query's result is not recorded in the query cache file
+        #[error("the query `{0}` is not recorded in the query cache file")]
+        NotCached(String),
         /// The subprocess failed
         #[error("query command failed: {status}\n\n\t{command:?}")]
         Failure {
@@ -105,9 +520,40 @@ mod error {
             /// The status code with which the command failed
             status: ExitStatus,
         },
+        /// An element matching a label pattern doesn't have the queried field
+        #[error("the element labelled `<{label}>` has no field `{field}`")]
+        MissingField {
+            /// The label of the element
+            label: String,
+            /// The queried field
+            field: String,
+        },
+        /// A query with `one` and a label pattern did not match exactly one element
+        #[error("expected exactly one element matching `{selector}`, but found {count}")]
+        NotOne {
+            /// The selector that was queried
+            selector: String,
+            /// The number of matching elements
+            count: usize,
+        },
         /// The response to the query was not valid
         #[error("query response was not valid JSON or did not fit the expected schema")]
         Json(#[from] serde_json::Error),
+        /// The response to the query was valid JSON, but did not fit the expected schema
+        #[error(
+            "query result for `{selector}` did not fit the expected schema{}: {error}\n\n\t{snippet}",
+            item.map(|item| format!(" in item {item}")).unwrap_or_default(),
+        )]
+        Schema {
+            /// The selector that was queried
+            selector: String,
+            /// The index of the offending item, if the result is an array
+            item: Option<usize>,
+            /// The offending part of the result, shortened if necessary
+            snippet: String,
+            /// The deserialization error
+            error: serde_json::Error,
+        },
     }
 
     /// Error in the query builder: a required configuration is missing
@@ -122,6 +568,12 @@ mod error {
         /// `one` is missing
         #[error("`one` was not specified but is required")]
         One,
+        /// The selector references a variable that is not defined
+        #[error("the selector references the undefined variable `{0}`")]
+        UndefinedVariable(String),
+        /// The selector contains a `${` without a matching `}`
+        #[error("the selector contains an unterminated `${{` placeholder")]
+        UnterminatedVariable,
     }
 
     /// Result type alias that defaults error to [enum@Error].
@@ -2,21 +2,68 @@
 
 use std::error::Error;
 use std::fmt;
-use std::io;
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 
 #[macro_export]
-/// Logs preprocessor progress to the given logger
+/// Logs preprocessor progress to the given logger. If the current task belongs to a job whose
+/// output is buffered (`--ordered-output`), the message is written to the job's buffer instead.
 macro_rules! log {
-    ($dst:expr, $($arg:tt)*) => {
-        use ::std::io::Write;
-        ::std::writeln!($dst, $($arg)*).expect("logging should not fail");
-    };
+    ($dst:expr, $($arg:tt)*) => {{
+        $crate::__write_log(&mut $dst, ::std::format_args!($($arg)*))
+            .expect("logging should not fail");
+    }};
 }
 
 pub trait Log: io::Write + Send + Sync {}
 
 impl<T: io::Write + Send + Sync> Log for T {}
 
+tokio::task_local! {
+    static JOB_LOG: JobLog;
+}
+
+/// A buffer for the log output of a single job. While a future runs in the buffer's
+/// [scope][JobLog::scope], all messages logged via [log!] are collected in the buffer, so that
+/// they can be written out later, e.g. in the order in which jobs are defined.
+#[derive(Debug, Clone, Default)]
+pub struct JobLog(Arc<Mutex<Vec<u8>>>);
+
+impl JobLog {
+    /// Runs the future with this buffer collecting its log output.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        JOB_LOG.scope(self, future).await
+    }
+
+    /// Returns the buffer of the current task, if any. Tasks spawned by a job don't inherit the
+    /// buffer automatically; they need to be run in its [scope][JobLog::scope] explicitly.
+    pub fn current() -> Option<Self> {
+        JOB_LOG.try_with(Clone::clone).ok()
+    }
+
+    /// Removes and returns the output collected so far.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().expect("lock JobLog"))
+    }
+}
+
+/// Writes a log message; implementation of [log!].
+#[doc(hidden)]
+pub fn write_log(dst: &mut impl io::Write, args: fmt::Arguments<'_>) -> io::Result<()> {
+    match JobLog::current() {
+        Some(log) => {
+            let mut buffer = log.0.lock().expect("lock JobLog");
+            buffer.write_fmt(args)?;
+            buffer.write_all(b"\n")
+        }
+        None => {
+            dst.write_fmt(args)?;
+            dst.write_all(b"\n")
+        }
+    }
+}
+
 pub trait ErrorExt: Error {
     fn error_chain(&self) -> ErrorChain<&Self> {
         ErrorChain(self)
@@ -25,6 +72,38 @@ pub trait ErrorExt: Error {
 
 impl<T: Error + ?Sized> ErrorExt for T {}
 
+/// Writes a header line followed by a list of errors, each with its chain of sources, with a
+/// hanging indent. Identical errors are only written once, with the number of occurrences appended
+/// as e.g. `(x50)`.
+pub fn write_error_list<E: Error>(
+    f: &mut fmt::Formatter<'_>,
+    header: &str,
+    errors: &[E],
+) -> fmt::Result {
+    use fmt::Write;
+
+    // count identical messages, keeping the order of first occurrence
+    let mut messages: Vec<(String, usize)> = Vec::new();
+    for error in errors {
+        let message = error.error_chain().to_string();
+        match messages.iter_mut().find(|(m, _)| *m == message) {
+            Some((_, count)) => *count += 1,
+            None => messages.push((message, 1)),
+        }
+    }
+
+    let mut w = f.hanging_indent("  ");
+    write!(w, "{header}")?;
+    for (message, count) in messages {
+        writeln!(w)?;
+        write!(w, "{message}")?;
+        if count > 1 {
+            write!(w, " (x{count})")?;
+        }
+    }
+    Ok(())
+}
+
 pub trait WriteExt: fmt::Write {
     fn indents<F, H>(&mut self, first: F, hanging: H) -> IndentWriter<'_, F, H, Self>
     where
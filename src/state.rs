@@ -0,0 +1,108 @@
+//! The state of incremental runs, recording when each job last ran successfully
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+pub use error::*;
+
+/// The name of the state file, which is stored next to the `typst.toml` file
+pub const STATE_FILE: &str = ".prequery-state.toml";
+
+/// The state of incremental runs: when each job last ran successfully.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    #[serde(skip)]
+    location: PathBuf,
+    /// a file format version number. Should be 1.
+    pub version: usize,
+    /// The start of each job's last successful run, in seconds since the Unix epoch, by job name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub jobs: BTreeMap<String, u64>,
+}
+
+impl State {
+    /// Creates an empty state that will be written to the given location
+    pub fn new(location: PathBuf) -> Self {
+        Self {
+            location,
+            version: 1,
+            jobs: BTreeMap::new(),
+        }
+    }
+
+    /// The location this state is read from and written to
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// Reads the state from a file.
+    pub async fn read(location: PathBuf) -> Result<Self> {
+        let state = fs::read_to_string(&location).await?;
+        let mut state: Self = toml::from_str(&state)?;
+        if state.version != 1 {
+            return Err(Error::Version(state.version));
+        }
+        state.location = location;
+        Ok(state)
+    }
+
+    /// Writes the state to a file.
+    pub async fn write(&self) -> Result<()> {
+        let mut file = fs::File::create(&self.location).await?;
+        let state = toml::to_string(self)?;
+        file.write_all(state.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Whether the job's last successful run started after all of the given files were last
+    /// modified. A file modified in the same second as the run started counts as changed.
+    pub fn is_up_to_date(&self, job: &str, modified: &[SystemTime]) -> bool {
+        let Some(&last_run) = self.jobs.get(job) else {
+            return false;
+        };
+        modified
+            .iter()
+            .all(|modified| to_secs(*modified) < last_run)
+    }
+
+    /// Records a successful run of the job that started at the given time.
+    pub fn record(&mut self, job: String, started: SystemTime) {
+        self.jobs.insert(job, to_secs(started));
+    }
+}
+
+fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+mod error {
+    use std::io;
+
+    use thiserror::Error;
+
+    /// A problem with using the state of incremental runs
+    #[derive(Error, Debug)]
+    pub enum Error {
+        /// I/O error while accessing the state file
+        #[error("state file could not be read or written")]
+        Io(#[from] io::Error),
+        /// Unexpected version: must be 1
+        #[error("expected state file version 1, was {0}")]
+        Version(usize),
+        /// Error parsing the state file's contents
+        #[error("invalid state file content")]
+        Parse(#[from] toml::de::Error),
+        /// Error writing new state file contents
+        #[error("state file: TOML writing error")]
+        Write(#[from] toml::ser::Error),
+    }
+
+    /// Result type alias that defaults error to [enum@Error].
+    pub type Result<T, E = Error> = std::result::Result<T, E>;
+}
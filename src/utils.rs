@@ -1,30 +1,60 @@
 use std::collections::HashMap;
 use std::future::Future;
+use std::sync::Arc;
 
+use tokio::sync::Semaphore;
 use tokio::task::{JoinError, JoinSet};
 
-pub async fn spawn_set<I, F, E>(futures: I) -> Vec<E>
+use crate::reporting::JobLog;
+
+/// Runs the future once the semaphore grants a permit, which is held until the future finishes.
+pub async fn with_permit<F: Future>(semaphore: Arc<Semaphore>, future: F) -> F::Output {
+    let _permit = semaphore
+        .acquire_owned()
+        .await
+        .expect("semaphore is never closed");
+    future.await
+}
+
+pub async fn spawn_set<I, F, T, E>(futures: I) -> (Vec<T>, Vec<E>)
 where
     I: Iterator<Item = F>,
-    F: Future<Output = Result<(), E>> + Send + 'static,
+    F: Future<Output = Result<T, E>> + Send + 'static,
+    T: Send + 'static,
     E: From<JoinError> + Send + 'static,
 {
-    spawn_set_with_id(futures.map(|f| ((), f)), |_, error| E::from(error)).await
+    spawn_set_with_id(futures.map(|f| ((), f)), |_, error| E::from(error), false).await
 }
 
-pub async fn spawn_set_with_id<I, Id, F, E>(futures: I, to_error: fn(Id, JoinError) -> E) -> Vec<E>
+/// Spawns all futures and waits for them to finish, collecting their results. If `fail_fast` is
+/// set, the remaining futures are aborted as soon as one fails, and only that error is returned.
+pub async fn spawn_set_with_id<I, Id, F, T, E>(
+    futures: I,
+    to_error: fn(Id, JoinError) -> E,
+    fail_fast: bool,
+) -> (Vec<T>, Vec<E>)
 where
     I: Iterator<Item = (Id, F)>,
-    F: Future<Output = Result<(), E>> + Send + 'static,
+    F: Future<Output = Result<T, E>> + Send + 'static,
+    T: Send + 'static,
     E: Send + 'static,
 {
     let mut set = JoinSet::new();
     let mut ids = HashMap::new();
+    // spawned tasks log to the same job buffer as the spawning task
+    let log = JobLog::current();
     for (id, future) in futures {
-        let handle = set.spawn(future);
+        let log = log.clone();
+        let handle = set.spawn(async move {
+            match log {
+                Some(log) => log.scope(future).await,
+                None => future.await,
+            }
+        });
         ids.insert(handle.id(), id);
     }
 
+    let mut values = Vec::new();
     let mut errors = Vec::new();
     while let Some(result) = set.join_next().await {
         match result {
@@ -33,8 +63,12 @@ where
                 errors.push(to_error(id, error));
             }
             Ok(Err(error)) => errors.push(error),
-            Ok(Ok(())) => {}
+            Ok(Ok(value)) => values.push(value),
+        }
+        if fail_fast && !errors.is_empty() {
+            set.shutdown().await;
+            break;
         }
     }
-    errors
+    (values, errors)
 }
@@ -2,11 +2,15 @@
 //! The world mediates access to the file system, the network, and more high-level resources
 //! such as the project manifest
 
+use std::collections::HashSet;
+use std::env;
 use std::fmt::Write;
 use std::io;
+use std::num::NonZeroUsize;
 use std::path::{self, Component, Path, PathBuf};
 use std::process::Stdio;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
 
 use async_trait::async_trait;
 use clap::Parser;
@@ -14,13 +18,16 @@ use itertools::{Either, Itertools};
 use serde::Deserialize;
 use tokio::fs;
 use tokio::process::Command;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, OnceCell};
 
 use crate::args::CliArguments;
 use crate::error::MultiplePreprocessorConfigError;
-use crate::manifest::{self, PrequeryManifest};
-use crate::preprocessor::{BoxedPreprocessor, PreprocessorMap};
-use crate::query::{self, Query};
+use crate::lockfile::{self, Lockfile};
+use crate::manifest::{self, JobDefaults, PrequeryManifest};
+use crate::preprocessor::{BoxedPreprocessor, PreprocessorDefinition, PreprocessorMap};
+use crate::query::{self, Query, QueryCache, TypstVersion};
 use crate::reporting::Log;
+use crate::state::{self, STATE_FILE, State};
 
 /// The context for executing preprocessors.
 #[cfg_attr(feature = "test", mockall::automock(type Logger = crate::test_utils::VecLog;))]
@@ -40,18 +47,72 @@ pub trait World: Send + Sync + 'static {
     /// logger.
     fn log(&self) -> Self::Logger;
 
+    /// The current time. All timestamps, e.g. in the lockfile, should use this clock, so that
+    /// they can be controlled in tests.
+    fn now(&self) -> SystemTime;
+
+    /// Returns the directory containing the `typst.toml` file that is closest to the input file,
+    /// or the one set using [set_manifest_dir][Self::set_manifest_dir]. Relative paths in the
+    /// manifest, such as index locations, are resolved against it.
+    async fn manifest_dir(&self) -> io::Result<PathBuf>;
+
+    /// Sets the directory relative paths in the manifest are resolved against, instead of
+    /// looking for a `typst.toml` file. This is used when the manifest is given explicitly.
+    fn set_manifest_dir(&self, dir: PathBuf);
+
     /// Reads the `typst.toml` file that is closest to the input file.
     async fn read_typst_toml(&self) -> manifest::Result<PrequeryManifest>;
 
+    /// Reads the unparsed content of the `typst.toml` file that is closest to the input file,
+    /// together with its location.
+    async fn read_typst_toml_source(&self) -> io::Result<(PathBuf, String)>;
+
+    /// Writes the unparsed content of the `typst.toml` file at the given location.
+    async fn write_typst_toml_source(&self, location: &Path, content: &str) -> io::Result<()>;
+
     /// Executes the query. This builds the necessary command line, runs the command, and returns
     /// the command's stdout.
     async fn query_impl(&self, query: &Query) -> query::Result<Vec<u8>>;
+
+    /// Returns the version of the given Typst executable, or `None` if it could not be determined.
+    async fn typst_version(&self, typst: &Path) -> Option<TypstVersion>;
+
+    /// Reads the lockfile at the given path. If it doesn't exist, an empty lockfile is returned.
+    async fn read_lockfile(&self, path: &Path) -> lockfile::Result<Lockfile>;
+
+    /// Writes the lockfile to its location.
+    async fn write_lockfile(&self, lockfile: &Lockfile) -> lockfile::Result<()>;
+
+    /// Reads the state of incremental runs, stored in the given directory: usually the one
+    /// containing the `typst.toml` file. If it doesn't exist, an empty state is returned.
+    async fn read_state(&self, dir: &Path) -> state::Result<State>;
+
+    /// Writes the state of incremental runs to its location.
+    async fn write_state(&self, state: &State) -> state::Result<()>;
+
+    /// Returns when the file at the given path was last modified.
+    async fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+
+    /// Reads the source of a Typst document.
+    async fn read_source(&self, path: &Path) -> io::Result<String>;
+
+    /// Returns the value of the given environment variable, or `None` if it is not set or not
+    /// valid Unicode.
+    fn env_var(&self, name: &str) -> Option<String>;
 }
 
 /// The context for executing preprocessors; provided methods that don't need to be customized
 /// between environments.
 #[async_trait]
 pub trait WorldExt: World {
+    /// The default limit for how many tasks run at the same time; see
+    /// [concurrency][CliArguments::concurrency].
+    fn concurrency(&self) -> NonZeroUsize {
+        self.arguments()
+            .concurrency
+            .unwrap_or_else(|| std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN))
+    }
+
     /// returns the root path. This is either the explicitly given root or the directory in which
     /// the input file is located. If the input file path only consists of a file name, the current
     /// directory (`"."`) is the root. In general, this function does not return an absolute path.
@@ -74,7 +135,15 @@ pub trait WorldExt: World {
     /// Returns `None` if the path lexically escapes the root. The path might
     /// still escape through symlinks.
     fn resolve(&self, path: &Path) -> Option<PathBuf> {
-        let root = self.resolve_root();
+        self.resolve_in(self.resolve_root(), path)
+    }
+
+    /// Resolve the virtual path relative to the given root instead of the one returned by
+    /// [WorldExt::resolve_root]. This is used for jobs that override the root.
+    ///
+    /// Returns `None` if the path lexically escapes the root. The path might
+    /// still escape through symlinks.
+    fn resolve_in(&self, root: &Path, path: &Path) -> Option<PathBuf> {
         let root_len = root.as_os_str().len();
         let mut out = root.to_path_buf();
         for component in path.components() {
@@ -94,17 +163,69 @@ pub trait WorldExt: World {
         Some(out)
     }
 
-    /// Tries to configure all preprocessors in this manifest. Fails if any preprocessors can not be
+    /// Resolve the virtual path relative to the given root, like [WorldExt::resolve_in]. If the
+    /// path lexically escapes the root, a [PermissionDenied][io::ErrorKind::PermissionDenied] error
+    /// is returned.
+    fn try_resolve_in(&self, root: &Path, path: &Path) -> io::Result<PathBuf> {
+        self.resolve_in(root, path).ok_or_else(|| {
+            let msg = format!("{} is outside the project root", path.display());
+            io::Error::new(io::ErrorKind::PermissionDenied, msg)
+        })
+    }
+
+    /// Removes the jobs whose [condition][manifest::Job::when] is not met from the manifest,
+    /// logging that they are skipped, and returns their names. The conditions' environment
+    /// variables are read through [World::env_var].
+    fn retain_enabled_jobs(&self, manifest: &mut PrequeryManifest) -> HashSet<String> {
+        let mut l = self.log();
+        let mut disabled = HashSet::new();
+        manifest.jobs.retain(|job| {
+            let enabled = job.is_enabled(|name| self.env_var(name));
+            if !enabled {
+                let prefix = job
+                    .render_log_prefix()
+                    .unwrap_or_else(|| format!("[{}]", job.name));
+                let when = job
+                    .when
+                    .as_ref()
+                    .expect("a job without condition is enabled");
+                log!(l, "{prefix} skipped: condition `{when}` is not met");
+                disabled.insert(job.name.clone());
+            }
+            enabled
+        });
+        disabled
+    }
+
+    /// Tries to configure all preprocessors in this manifest. Disabled jobs should already have
+    /// been [removed][WorldExt::retain_enabled_jobs]. Fails if any preprocessors can not be
     /// configured.
     fn get_preprocessors(
         self: &Arc<Self>,
-        manifest: PrequeryManifest,
+        mut manifest: PrequeryManifest,
     ) -> Result<Vec<BoxedPreprocessor<Self>>, MultiplePreprocessorConfigError>
     where
         Self: Sized,
     {
-        let (jobs, errors): (Vec<_>, Vec<_>) = manifest.jobs.into_iter().partition_map(|job| {
-            match self.preprocessors().get(self, job) {
+        let defaults = JobDefaults {
+            field: manifest.default_field.clone(),
+            output_dir: manifest.output_dir.clone(),
+            log_prefix: None,
+            typst: None,
+            index: None,
+        };
+        let fallback = manifest.fallback;
+        let pass_job_name = manifest.pass_job_name;
+        let jobs = std::mem::take(&mut manifest.jobs);
+        let (jobs, errors): (Vec<_>, Vec<_>) = jobs.into_iter().partition_map(|mut job| {
+            job.query.fallback = job.query.fallback.or(fallback);
+            job.query.pass_job_name = job.query.pass_job_name.or(pass_job_name);
+            let kind_defaults = manifest.kind_defaults(&job.kind);
+            let defaults = JobDefaults {
+                index: kind_defaults.and_then(|kind| kind.default_index.clone()),
+                ..defaults.clone()
+            };
+            match self.preprocessors().get(self, job, defaults) {
                 Ok(value) => Either::Left(value),
                 Err(err) => Either::Right(err),
             }
@@ -117,14 +238,31 @@ pub trait WorldExt: World {
         Ok(jobs)
     }
 
+    /// Executes the query and returns the unparsed JSON result. If the selector is a [label
+    /// pattern][Query::label_pattern], all metadata elements are queried and then filtered by
+    /// their labels; the result has the same shape as if `typst query` supported the pattern.
+    async fn query_raw(&self, query: &Query) -> query::Result<Vec<u8>> {
+        let Some(pattern) = query.label_pattern() else {
+            return self.query_impl(query).await;
+        };
+        let output = self.query_impl(&query.broad_query()).await?;
+        query::filter_labels(query, pattern, &output)
+    }
+
     /// Executes the query. This builds the necessary command line, runs the command, and returns
     /// the result parsed into the desired type from JSON.
     async fn query<T>(&self, query: &Query) -> query::Result<T>
     where
         T: for<'a> Deserialize<'a>,
     {
-        let output = self.query_impl(query).await?;
-        let value = serde_json::from_slice(&output)?;
+        let output = self.query_raw(query).await?;
+        let value = serde_json::from_slice(&output).map_err(|error| {
+            if error.is_data() {
+                query::Error::schema(&query.selector, &output, error)
+            } else {
+                error.into()
+            }
+        })?;
         Ok(value)
     }
 }
@@ -136,6 +274,12 @@ impl<T: World> WorldExt for T {}
 pub struct DefaultWorld {
     preprocessors: PreprocessorMap<Self>,
     arguments: CliArguments,
+    /// The query cache file's content, once it was read
+    query_cache: Mutex<Option<QueryCache>>,
+    /// The location of the `typst.toml` file, once it was found
+    typst_toml: OnceCell<PathBuf>,
+    /// The manifest directory, if it was set instead of looking for `typst.toml`
+    manifest_dir: OnceLock<PathBuf>,
 }
 
 impl Default for DefaultWorld {
@@ -145,23 +289,68 @@ impl Default for DefaultWorld {
 }
 
 impl DefaultWorld {
-    /// Creates the default world.
+    /// Creates the default world, using the process's command line arguments.
     pub fn new() -> Self {
+        Self::with_arguments(CliArguments::parse())
+    }
+
+    /// Creates the default world with the given arguments.
+    pub fn with_arguments(arguments: CliArguments) -> Self {
         let mut preprocessors = PreprocessorMap::default();
         preprocessors.register(crate::web_resource::WebResourceFactory::default());
         preprocessors.register(crate::shell::ShellFactory::default());
-        let arguments = CliArguments::parse();
         Self {
             preprocessors,
             arguments,
+            query_cache: Mutex::default(),
+            typst_toml: OnceCell::new(),
+            manifest_dir: OnceLock::new(),
+        }
+    }
+
+    /// Registers a preprocessor definition, replacing a default one of the same name. This allows
+    /// e.g. registering a [WebResourceFactory][crate::web_resource::WebResourceFactory] with a
+    /// [progress sink][crate::web_resource::WebResourceFactory::with_progress].
+    pub fn with_preprocessor<T>(mut self, preprocessor: T) -> Self
+    where
+        T: PreprocessorDefinition<Self> + Send + Sync + 'static,
+    {
+        self.preprocessors.register(preprocessor);
+        self
+    }
+
+    /// Locks the query cache, reading it from the given location the first time.
+    async fn query_cache(
+        &self,
+        location: &Path,
+    ) -> query::Result<MappedMutexGuard<'_, QueryCache>> {
+        let mut cache = self.query_cache.lock().await;
+        if cache.is_none() {
+            *cache = Some(QueryCache::read(location.to_path_buf()).await?);
         }
+        Ok(MutexGuard::map(cache, |cache| {
+            cache.as_mut().expect("the query cache was just read")
+        }))
     }
 
-    /// Returns the path of the `typst.toml` file that is closest to the input file.
+    /// Returns the path of the `typst.toml` file that is closest to the input file. The search
+    /// does not leave the workspace, i.e. it stops at the first directory containing the
+    /// [workspace boundary][CliArguments::workspace_boundary]. The file is only searched for until
+    /// it was found once; the location is cached.
     pub async fn resolve_typst_toml(&self) -> io::Result<PathBuf> {
+        let typst_toml = self
+            .typst_toml
+            .get_or_try_init(|| self.find_typst_toml())
+            .await?;
+        Ok(typst_toml.clone())
+    }
+
+    /// Searches for the `typst.toml` file; see [resolve_typst_toml][Self::resolve_typst_toml].
+    async fn find_typst_toml(&self) -> io::Result<PathBuf> {
         const TYPST_TOML: &str = "typst.toml";
 
         let input = path::absolute(&self.arguments().input)?;
+        let boundary = &self.arguments().workspace_boundary;
         let mut p = input.clone();
 
         // the input path needs to refer to a file. refer to typst.toml instead
@@ -174,6 +363,16 @@ impl DefaultWorld {
                 result,
                 "the path should have had a final component of `{TYPST_TOML}`"
             );
+            // if this directory is the workspace boundary, don't search any further
+            if !boundary.as_os_str().is_empty()
+                && fs::try_exists(p.join(boundary)).await.unwrap_or(false)
+            {
+                let input_str = input.to_string_lossy();
+                let msg = format!(
+                    "no {TYPST_TOML} file found within the workspace for input file {input_str}"
+                );
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+            }
             // go one level up
             let result = p.pop();
             if !result {
@@ -205,45 +404,202 @@ impl World for DefaultWorld {
         io::stderr()
     }
 
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    async fn manifest_dir(&self) -> io::Result<PathBuf> {
+        if let Some(dir) = self.manifest_dir.get() {
+            return Ok(dir.clone());
+        }
+        let typst_toml = self.resolve_typst_toml().await?;
+        let dir = typst_toml
+            .parent()
+            .expect("the path should have had a final component of `typst.toml`");
+        Ok(dir.to_path_buf())
+    }
+
+    fn set_manifest_dir(&self, dir: PathBuf) {
+        // a world is only used for one manifest
+        let _ = self.manifest_dir.set(dir);
+    }
+
     async fn read_typst_toml(&self) -> manifest::Result<PrequeryManifest> {
         let typst_toml = self
             .resolve_typst_toml()
             .await
             .map_err(manifest::Error::from)?;
-        let config = fs::read_to_string(typst_toml).await?;
-        let config = PrequeryManifest::parse(&config)?;
+        let dir = self.manifest_dir().await.map_err(manifest::Error::from)?;
+        let config = fs::read_to_string(&typst_toml).await?;
+        let mut config = PrequeryManifest::parse(&config)?;
+        config.resolve_paths(&dir);
+        config.merge_inputs_files().await?;
         Ok(config)
     }
 
+    async fn read_typst_toml_source(&self) -> io::Result<(PathBuf, String)> {
+        let typst_toml = self.resolve_typst_toml().await?;
+        let content = fs::read_to_string(&typst_toml).await?;
+        Ok((typst_toml, content))
+    }
+
+    async fn write_typst_toml_source(&self, location: &Path, content: &str) -> io::Result<()> {
+        fs::write(location, content).await
+    }
+
     async fn query_impl(&self, query: &Query) -> query::Result<Vec<u8>> {
-        let mut cmd = Command::new(&self.arguments().typst);
-        cmd.arg("query");
-        if let Some(root) = &self.arguments().root {
-            cmd.arg("--root").arg(root);
-        }
-        if let Some(field) = &query.field {
-            cmd.arg("--field").arg(field);
+        let arguments = self.arguments();
+        if let Some(location) = &arguments.query_cache_file
+            && !arguments.record_queries
+        {
+            return self.query_cache(location).await?.get(query);
         }
-        if query.one {
-            cmd.arg("--one");
-        }
-        let mut input = String::new();
-        for (key, value) in &query.inputs {
-            input.clear();
-            write!(&mut input, "{key}={value}").expect("writing to a string failed");
-            cmd.arg("--input").arg(&input);
+
+        let typst = query.typst.as_ref().unwrap_or(&self.arguments().typst);
+        let document = query.document.as_ref().unwrap_or(&self.arguments().input);
+        if !fs::try_exists(document).await? {
+            return Err(query::Error::MissingDocument(document.clone()));
         }
-        cmd.arg("--input").arg("prequery-fallback=true");
-        cmd.arg(&self.arguments().input).arg(&query.selector);
 
+        // the expanded query may contain secrets, so only the original query is used in errors
+        let expanded = query.expand_inputs(|name| self.env_var(name))?;
+        let mut cmd = build_query_command(self.arguments(), &expanded);
         cmd.stderr(Stdio::inherit());
-        let output = cmd.output().await?;
+        let output = cmd.output().await.map_err(|error| {
+            if error.kind() == io::ErrorKind::NotFound {
+                query::Error::TypstNotFound(typst.clone())
+            } else {
+                error.into()
+            }
+        })?;
         if !output.status.success() {
-            let command = Box::new(cmd);
+            let command = Box::new(build_query_command(self.arguments(), query));
             let status = output.status;
             Err(query::Error::Failure { command, status })?;
         }
 
+        if let Some(location) = &arguments.query_cache_file {
+            // the cache is locked until it's written, so that no recorded results are lost
+            let mut cache = self.query_cache(location).await?;
+            cache.insert(query, &output.stdout)?;
+            cache.write().await?;
+        }
+
         Ok(output.stdout)
     }
+
+    async fn typst_version(&self, typst: &Path) -> Option<TypstVersion> {
+        let output = Command::new(typst)
+            .arg("--version")
+            .stderr(Stdio::null())
+            .output()
+            .await
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        TypstVersion::parse(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    async fn read_lockfile(&self, path: &Path) -> lockfile::Result<Lockfile> {
+        let lockfile = if fs::try_exists(path).await.unwrap_or(false) {
+            // read the existing lockfile
+            Lockfile::read(path.to_path_buf()).await?
+        } else {
+            // generate an empty lockfile
+            Lockfile::new(path.to_path_buf())
+        };
+        Ok(lockfile)
+    }
+
+    async fn write_lockfile(&self, lockfile: &Lockfile) -> lockfile::Result<()> {
+        lockfile.write().await?;
+        Ok(())
+    }
+
+    async fn read_state(&self, dir: &Path) -> state::Result<State> {
+        let path = dir.join(STATE_FILE);
+        let state = if fs::try_exists(&path).await.unwrap_or(false) {
+            State::read(path).await?
+        } else {
+            State::new(path)
+        };
+        Ok(state)
+    }
+
+    async fn write_state(&self, state: &State) -> state::Result<()> {
+        state.write().await
+    }
+
+    async fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        fs::metadata(path).await?.modified()
+    }
+
+    async fn read_source(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path).await
+    }
+
+    fn env_var(&self, name: &str) -> Option<String> {
+        env::var(name).ok()
+    }
+}
+
+/// An exclusive lock on a file, held until it is dropped. This keeps concurrent jobs and processes
+/// from interleaving their reads and writes of shared files such as indexes. The default value
+/// doesn't lock anything, which is useful in tests.
+#[derive(Debug, Default)]
+pub struct FileLock {
+    /// The locked file; closing it releases the lock
+    _file: Option<std::fs::File>,
+}
+
+impl FileLock {
+    /// Locks the given lock file, creating it and its directory if necessary. Waits until no one
+    /// else holds the lock.
+    pub async fn acquire(location: PathBuf) -> io::Result<Self> {
+        let lock = tokio::task::spawn_blocking(move || {
+            if let Some(parent) = location.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&location)?;
+            file.lock()?;
+            Ok::<_, io::Error>(Self { _file: Some(file) })
+        });
+        lock.await.map_err(io::Error::other)?
+    }
+}
+
+/// Builds the `typst query` command for the given query, without running it. The queried document
+/// is the query's [document][Query::document] if given, otherwise the input file; likewise for the
+/// [Typst executable][Query::typst] and the [root][Query::root].
+pub fn build_query_command(arguments: &CliArguments, query: &Query) -> Command {
+    let document = query.document.as_ref().unwrap_or(&arguments.input);
+    let typst = query.typst.as_ref().unwrap_or(&arguments.typst);
+    let mut cmd = Command::new(typst);
+    cmd.arg("query");
+    if let Some(root) = query.root.as_ref().or(arguments.root.as_ref()) {
+        cmd.arg("--root").arg(root);
+    }
+    if let Some(field) = &query.field {
+        cmd.arg("--field").arg(field);
+    }
+    if query.one {
+        cmd.arg("--one");
+    }
+    let mut input = String::new();
+    for (key, value) in &query.inputs {
+        input.clear();
+        write!(&mut input, "{key}={value}").expect("writing to a string failed");
+        cmd.arg("--input").arg(&input);
+    }
+    if query.fallback {
+        cmd.arg("--input").arg("prequery-fallback=true");
+    }
+    cmd.args(&query.extra_args);
+    cmd.arg(document).arg(&query.selector);
+    cmd
 }
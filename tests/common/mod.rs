@@ -1,20 +1,32 @@
 #![allow(dead_code)]
 
+use std::time::{Duration, UNIX_EPOCH};
+
 use clap::Parser;
 
 use mockall::predicate::eq;
 use prequery_preprocess::VecLog;
 use prequery_preprocess::args::CliArguments;
-use prequery_preprocess::entry::run;
+use prequery_preprocess::entry::{run, run_with_manifest};
 use prequery_preprocess::error::Result;
 use prequery_preprocess::manifest::PrequeryManifest;
 use prequery_preprocess::preprocessor::PreprocessorMap;
-use prequery_preprocess::query::Query;
+use prequery_preprocess::query::{Query, TypstVersion};
 use prequery_preprocess::world::MockWorld;
 
+/// The fixed time returned by the mocked world's clock, in seconds since the Unix epoch
+pub const NOW: u64 = 1_700_000_000;
+
+/// The input document's source returned by the mocked world, unless a test replaces it
+pub const SOURCE: &str = "#import \"@preview/prequery:0.1.0\"\n";
+
 pub struct PreprocessorTest {
     pub world: MockWorld,
     pub log: VecLog,
+    /// The source of every document read by the preprocessor
+    pub source: &'static str,
+    /// The version of every Typst executable, or `None` if it can't be determined
+    pub typst_version: Option<TypstVersion>,
 }
 
 impl PreprocessorTest {
@@ -36,6 +48,9 @@ impl PreprocessorTest {
             .expect_arguments()
             .return_const(CliArguments::parse_from(args));
         world.expect_log().return_const(log.clone());
+        world
+            .expect_now()
+            .return_const(UNIX_EPOCH + Duration::from_secs(NOW));
         world
             .expect_read_typst_toml()
             .returning(|| PrequeryManifest::parse(manifest));
@@ -45,14 +60,57 @@ impl PreprocessorTest {
             .with(eq(query))
             .returning(|_| Ok(query_result.to_vec()));
 
-        Self { world, log }
+        Self {
+            world,
+            log,
+            source: SOURCE,
+            typst_version: Some(TypstVersion::MINIMUM),
+        }
+    }
+
+    fn expect_read_source(&mut self) {
+        let source = self.source;
+        self.world
+            .expect_read_source()
+            .returning(move |_| Ok(source.to_string()));
     }
 
-    pub async fn run(self) -> RunResult {
+    fn expect_typst_version(&mut self) {
+        self.world
+            .expect_typst_version()
+            .return_const(self.typst_version);
+    }
+
+    /// Variables that the test didn't set up itself are read from the actual environment.
+    fn expect_env_var(&mut self) {
+        self.world
+            .expect_env_var()
+            .returning(|name| std::env::var(name).ok());
+    }
+
+    pub async fn run(mut self) -> RunResult {
+        self.expect_read_source();
+        self.expect_typst_version();
+        self.expect_env_var();
         let result = run(self.world).await;
         let log = self.log;
         RunResult { result, log }
     }
+
+    /// Runs with the given manifest instead of the one read from `typst.toml`.
+    pub async fn run_with_manifest(mut self, manifest: &str) -> RunResult {
+        self.expect_read_source();
+        self.expect_typst_version();
+        self.expect_env_var();
+        self.world
+            .expect_set_manifest_dir()
+            .once()
+            .with(eq(std::env::current_dir().unwrap()))
+            .return_const(());
+        let result = run_with_manifest(self.world, manifest).await;
+        let log = self.log;
+        RunResult { result, log }
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,389 @@
+//! Downloads using the actual web resource world, from a local HTTP server. Query results are
+//! replayed from `download/query-cache.json`, so Typst is not needed.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use prequery_preprocess::args::CliArguments;
+use prequery_preprocess::entry::run_with_manifest;
+use prequery_preprocess::error::{Error, Result};
+use prequery_preprocess::web_resource::WebResourceFactory;
+use prequery_preprocess::web_resource::proxy::ProxyConfig;
+use prequery_preprocess::world::DefaultWorld;
+
+/// The directory containing the files served by [Server]
+const SERVED: &str = "tests/download/served";
+
+/// A minimal HTTP server that serves the files in [SERVED], ignoring query strings other than
+/// `truncated`, for which the connection is closed before the announced length is sent. It keeps
+/// track of how many requests it handled at the same time.
+struct Server {
+    base_url: String,
+    /// The most requests that were handled at the same time
+    max_in_flight: Arc<AtomicUsize>,
+}
+
+impl Server {
+    /// Starts the server; every response is delayed by the given duration.
+    async fn start(delay: Duration) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let max = Arc::clone(&max_in_flight);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let in_flight = Arc::clone(&in_flight);
+                let max = Arc::clone(&max);
+                tokio::spawn(async move {
+                    let count = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max.fetch_max(count, Ordering::SeqCst);
+                    tokio::time::sleep(delay).await;
+                    Self::respond(stream).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Self {
+            base_url,
+            max_in_flight,
+        }
+    }
+
+    async fn respond(mut stream: TcpStream) {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let target = request.split_whitespace().nth(1).unwrap_or("/");
+        let (name, query) = target
+            .trim_start_matches('/')
+            .split_once('?')
+            .unwrap_or((target.trim_start_matches('/'), ""));
+        let response = match tokio::fs::read(Path::new(SERVED).join(name)).await {
+            Ok(body) => {
+                let length = body.len() + if query == "truncated" { 16 } else { 0 };
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {length}\r\nConnection: close\r\n\r\n",
+                )
+                .into_bytes();
+                response.extend(body);
+                response
+            }
+            Err(_) => {
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+            }
+        };
+        stream.write_all(&response).await.unwrap();
+        stream.shutdown().await.unwrap();
+    }
+}
+
+/// Returns an empty directory for the test's downloads.
+async fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("download")
+        .join(name);
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    dir
+}
+
+/// Runs a web resource job with the given additional configuration, downloading from the server
+/// into the given root.
+async fn run(world: DefaultWorld, server: &Server, job: &str) -> Result<()> {
+    let manifest = format!(
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        base_url = "{}"
+        {job}
+        "#,
+        server.base_url,
+    );
+    run_with_manifest(world, &manifest).await
+}
+
+/// Creates a world that replays the query results and downloads into the root.
+fn world(root: &Path, args: &[&str]) -> DefaultWorld {
+    let root = root.to_str().unwrap();
+    let args = [
+        "prequery-preprocess",
+        "--query-cache-file",
+        "tests/download/query-cache.json",
+    ]
+    .into_iter()
+    .chain(["--root", root])
+    .chain(args.iter().copied())
+    .chain(["input.typ"]);
+    DefaultWorld::with_arguments(CliArguments::parse_from(args))
+}
+
+/// The file served for the resources
+async fn served() -> Vec<u8> {
+    tokio::fs::read(Path::new(SERVED).join("example.txt"))
+        .await
+        .unwrap()
+}
+
+/// Returns the partial downloads left behind in the root.
+async fn partial_files(root: &Path) -> Vec<PathBuf> {
+    let mut partial = Vec::new();
+    let mut entries = tokio::fs::read_dir(root).await.unwrap();
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+        if entry.file_name().to_string_lossy().contains(".part-") {
+            partial.push(entry.path());
+        }
+    }
+    partial
+}
+
+/// Downloads with a custom CA bundle, which is loaded in addition to the system's certificates.
+#[tokio::test]
+async fn download_ca_cert() {
+    let server = Server::start(Duration::ZERO).await;
+    let root = scratch_dir("ca-cert").await;
+
+    let world = world(&root, &["--ca-cert", "tests/download/ca-cert.pem"]);
+    run(world, &server, "")
+        .await
+        .expect("download with a custom CA bundle should succeed");
+    let downloaded = tokio::fs::read(root.join("example.txt")).await.unwrap();
+    assert_eq!(downloaded, served().await);
+}
+
+/// A CA bundle that doesn't exist or isn't valid fails the web resource job's downloads.
+#[tokio::test]
+async fn download_ca_cert_failed() {
+    let server = Server::start(Duration::ZERO).await;
+
+    for (name, ca_cert, message) in [
+        (
+            "ca-cert-missing",
+            "tests/download/ca-cert-missing.pem",
+            "CA certificate bundle tests/download/ca-cert-missing.pem could not be read",
+        ),
+        (
+            "ca-cert-invalid",
+            "tests/download/ca-cert-invalid.pem",
+            "CA certificate bundle tests/download/ca-cert-invalid.pem is not valid",
+        ),
+    ] {
+        let root = scratch_dir(name).await;
+        let world = world(&root, &["--ca-cert", ca_cert]);
+        let error = run(world, &server, "")
+            .await
+            .expect_err("download with a broken CA bundle should fail");
+        assert!(matches!(error, Error::PreprocessorExecution(_)), "{error}");
+        assert!(error.to_string().contains(message), "{error}");
+        assert!(!root.join("example.txt").exists());
+    }
+}
+
+/// Download progress is reported to the sink, ending with the whole file.
+#[tokio::test]
+async fn download_progress() {
+    let server = Server::start(Duration::ZERO).await;
+    let root = scratch_dir("progress").await;
+
+    let (progress, mut events) = mpsc::unbounded_channel();
+    let factory = WebResourceFactory::default().with_progress(progress);
+    let world = world(&root, &[]).with_preprocessor(factory);
+    run(world, &server, "")
+        .await
+        .expect("download should succeed");
+
+    let served = served().await;
+    let size = served.len() as u64;
+    let mut last = None;
+    while let Ok(event) = events.try_recv() {
+        assert_eq!(event.path, root.join("example.txt"));
+        assert_eq!(event.bytes_total, Some(size));
+        assert!(event.bytes_done <= size);
+        last = Some(event.bytes_done);
+    }
+    assert_eq!(
+        last,
+        Some(size),
+        "the last event should cover the whole file"
+    );
+}
+
+/// Requests use the configured proxy, unless the host matches `no_proxy`, which also supports IP
+/// ranges.
+#[tokio::test]
+async fn download_no_proxy() {
+    let server = Server::start(Duration::ZERO).await;
+    let url = format!("{}example.txt", server.base_url);
+    // a proxy that refuses all connections
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+
+    let get = |no_proxy: &[&str]| {
+        let config = ProxyConfig {
+            http: Some(proxy.clone()),
+            ..Default::default()
+        }
+        .with_no_proxy(no_proxy.iter().map(|host| host.to_string()));
+        let mut builder = reqwest::Client::builder().no_proxy();
+        for proxy in config.into_proxies().unwrap() {
+            builder = builder.proxy(proxy);
+        }
+        builder.build().unwrap().get(&url).send()
+    };
+
+    get(&[]).await.expect_err("the proxy should be used");
+    get(&["127.0.0.2/32"])
+        .await
+        .expect_err("the proxy should be used for other addresses");
+    let response = get(&["127.0.0.0/8"])
+        .await
+        .expect("the proxy should be bypassed");
+    assert_eq!(response.bytes().await.unwrap(), served().await);
+}
+
+/// The resources of the `<web-resource-many>` query, all from the same host
+const MANY: [&str; 3] = ["one.txt", "two.txt", "three.txt"];
+
+/// Without a per-host limit, downloads from the same host run at the same time.
+#[tokio::test]
+async fn download_concurrent() {
+    let server = Server::start(Duration::from_millis(200)).await;
+    let root = scratch_dir("concurrent").await;
+
+    let world = world(&root, &["--concurrency", "3"]);
+    run(world, &server, r#"query.selector = "<web-resource-many>""#)
+        .await
+        .expect("downloads should succeed");
+    assert_eq!(server.max_in_flight.load(Ordering::SeqCst), MANY.len());
+}
+
+/// With a per-host concurrency limit, downloads from the same host wait for each other.
+#[tokio::test]
+async fn download_per_host_concurrency() {
+    let server = Server::start(Duration::from_millis(200)).await;
+    let root = scratch_dir("per-host-concurrency").await;
+
+    let world = world(&root, &["--concurrency", "3"]);
+    run(
+        world,
+        &server,
+        r#"
+        query.selector = "<web-resource-many>"
+        per_host_concurrency = 1
+        "#,
+    )
+    .await
+    .expect("downloads should succeed");
+    assert_eq!(server.max_in_flight.load(Ordering::SeqCst), 1);
+    for path in MANY {
+        let downloaded = tokio::fs::read(root.join(path)).await.unwrap();
+        assert_eq!(downloaded, served().await);
+    }
+}
+
+/// With a rate limit, requests to the same host are spaced out evenly.
+#[tokio::test]
+async fn download_rate_limit() {
+    let server = Server::start(Duration::ZERO).await;
+    let root = scratch_dir("rate-limit").await;
+
+    let world = world(&root, &["--concurrency", "3"]);
+    let started = Instant::now();
+    run(
+        world,
+        &server,
+        r#"
+        query.selector = "<web-resource-many>"
+        rate_limit = 5
+        "#,
+    )
+    .await
+    .expect("downloads should succeed");
+    // the first request is sent immediately, each other one 200ms after the previous
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(400),
+        "took only {elapsed:?}"
+    );
+}
+
+/// Content that fails validation doesn't replace the existing file, and no partial file is left.
+#[tokio::test]
+async fn download_validation_keeps_existing() {
+    let server = Server::start(Duration::ZERO).await;
+    let root = scratch_dir("validation").await;
+    tokio::fs::write(root.join("example.txt"), b"{}")
+        .await
+        .unwrap();
+
+    let world = world(&root, &[]);
+    let error = run(
+        world,
+        &server,
+        r#"
+        overwrite = true
+        validate = "json"
+        "#,
+    )
+    .await
+    .expect_err("download of invalid content should fail");
+    assert!(
+        error.to_string().contains("the file is not valid JSON"),
+        "{error}"
+    );
+    assert_eq!(
+        tokio::fs::read(root.join("example.txt")).await.unwrap(),
+        b"{}"
+    );
+    assert_eq!(partial_files(&root).await, Vec::<PathBuf>::new());
+}
+
+/// A connection that is closed early while refreshing a file doesn't replace the existing file, and
+/// no partial file is left.
+#[tokio::test]
+async fn download_truncated_keeps_existing() {
+    let server = Server::start(Duration::ZERO).await;
+    let root = scratch_dir("truncated").await;
+    tokio::fs::write(root.join("example.txt"), b"previous")
+        .await
+        .unwrap();
+
+    let world = world(&root, &[]);
+    run(
+        world,
+        &server,
+        r#"
+        query.selector = "<web-resource-truncated>"
+        overwrite = true
+        "#,
+    )
+    .await
+    .expect_err("truncated download should fail");
+    assert_eq!(
+        tokio::fs::read(root.join("example.txt")).await.unwrap(),
+        b"previous"
+    );
+    assert_eq!(partial_files(&root).await, Vec::<PathBuf>::new());
+}
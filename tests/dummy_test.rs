@@ -1,8 +1,22 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use clap::Parser;
 use mockall::predicate::{always, eq};
-use prequery_preprocess::log;
-use prequery_preprocess::preprocessor::{MockPreprocessor, MockPreprocessorDefinition};
-use prequery_preprocess::query::Query;
+use prequery_preprocess::args::CliArguments;
+use prequery_preprocess::entry::run_job;
+use prequery_preprocess::error::Error;
+use prequery_preprocess::lockfile::{LockedResource, Lockfile};
+use prequery_preprocess::manifest::{Job, JobDefaults};
+use prequery_preprocess::preprocessor::{
+    ManifestError, MockPreprocessor, MockPreprocessorDefinition, PreprocessorMap,
+};
+use prequery_preprocess::query::{Query, TypstVersion};
+use prequery_preprocess::state::{STATE_FILE, State};
 use prequery_preprocess::world::{MockWorld, World};
+use prequery_preprocess::{VecLog, lockfile, log};
 
 mod common;
 
@@ -14,18 +28,33 @@ async fn run_dummy() {
     dummy
         .expect_configure()
         .once()
-        .with(always(), eq("test".to_string()), always(), always())
-        .returning(|world, name, _manifest, _query| {
+        .with(
+            always(),
+            eq("test".to_string()),
+            eq(None),
+            always(),
+            always(),
+            eq(JobDefaults::default()),
+        )
+        .returning(|world, name, _root, _manifest, _query, _defaults| {
             let world = world.clone();
             // when run, the preprocessor only logs something
             let mut preprocessor = MockPreprocessor::new();
             preprocessor.expect_world().return_const(world.clone());
             preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
             preprocessor.expect_run().once().returning(move || {
                 let mut l = world.log();
                 log!(l, "[{name}] this is a dummy preprocessor");
                 Ok(())
             });
+            preprocessor
+                .expect_found_nothing()
+                .once()
+                .return_const(false);
             Ok(Box::new(preprocessor))
         });
 
@@ -56,12 +85,910 @@ async fn run_dummy() {
             selector: Default::default(),
             field: Default::default(),
             one: Default::default(),
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    )
+    .run()
+    .await
+    .expect_ok("dummy job should succeed")
+    .expect_log(include_str!("dummy/run.txt"));
+}
+
+/// A manifest that is given explicitly is used instead of `typst.toml`.
+#[tokio::test]
+async fn run_dummy_with_manifest() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    // only the job from the given manifest is configured
+    dummy
+        .expect_configure()
+        .once()
+        .with(
+            always(),
+            eq("test".to_string()),
+            eq(None),
+            always(),
+            always(),
+            eq(JobDefaults::default()),
+        )
+        .returning(|world, name, _root, _manifest, _query, _defaults| {
+            let world = world.clone();
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            preprocessor.expect_run().once().returning(move || {
+                let mut l = world.log();
+                log!(l, "[{name}] this is a dummy preprocessor");
+                Ok(())
+            });
+            preprocessor
+                .expect_found_nothing()
+                .once()
+                .return_const(false);
+            Ok(Box::new(preprocessor))
+        });
+
+    common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "unused"
+        kind = "dummy"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    )
+    .run_with_manifest(
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test"
+        kind = "dummy"
+        "#,
+    )
+    .await
+    .expect_ok("dummy job should succeed")
+    .expect_log(include_str!("dummy/run.txt"));
+}
+
+/// A job that fails once is run again if it allows retries.
+#[tokio::test]
+async fn run_dummy_retries() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().once().returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let world = world.clone();
+            // when run, the preprocessor fails the first time only
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            let mut attempts = 0;
+            preprocessor.expect_run().times(2).returning(move || {
+                attempts += 1;
+                if attempts == 1 {
+                    return Err("transient failure".into());
+                }
+                let mut l = world.log();
+                log!(l, "[{name}] this is a dummy preprocessor");
+                Ok(())
+            });
+            preprocessor
+                .expect_found_nothing()
+                .once()
+                .return_const(false);
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test"
+        kind = "dummy"
+        retries = 2
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    )
+    .run()
+    .await
+    .expect_ok("dummy job should succeed on retry")
+    .expect_log(include_str!("dummy/retries.txt"));
+}
+
+/// If any job's validation fails, no job is run.
+#[tokio::test]
+async fn run_dummy_validate_failure() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().times(2).returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            // only the second job is invalid
+            let invalid = name == "test2";
+            preprocessor.expect_validate().once().returning(move || {
+                if invalid {
+                    let error = io::Error::other("output is outside the project root");
+                    return Err(ManifestError::new("dummy".into(), error).into());
+                }
+                Ok(())
+            });
+            preprocessor.expect_run().never();
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test1"
+        kind = "dummy"
+
+        [[tool.prequery.jobs]]
+        name = "test2"
+        kind = "dummy"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    )
+    .run()
+    .await
+    .expect_err("dummy job should fail validation")
+    .expect_log(include_str!("dummy/validate-failure.txt"));
+}
+
+/// With --fail-fast, only the first failure is reported; the results of other jobs that finished
+/// in the meantime are discarded.
+#[tokio::test]
+async fn run_dummy_fail_fast() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().times(2).returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            preprocessor
+                .expect_run()
+                .once()
+                .returning(move || Err(format!("{name} failed").into()));
+            preprocessor.expect_found_nothing().never();
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "--fail-fast", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test1"
+        kind = "dummy"
+
+        [[tool.prequery.jobs]]
+        name = "test2"
+        kind = "dummy"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
         b"",
     )
     .run()
     .await
+    .expect_err("dummy jobs should fail")
+    .expect_log(include_str!("dummy/fail-fast.txt"));
+}
+
+/// With --incremental, a job that ran successfully after its inputs were last modified is
+/// skipped; other jobs are run and recorded.
+#[tokio::test]
+async fn run_dummy_incremental() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().times(2).returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let world = world.clone();
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            // only the job that didn't run before is run now
+            let times = if name == "changed" { 1 } else { 0 };
+            preprocessor.expect_run().times(times).returning(move || {
+                let mut l = world.log();
+                log!(l, "[{name}] this is a dummy preprocessor");
+                Ok(())
+            });
+            preprocessor
+                .expect_found_nothing()
+                .times(times)
+                .return_const(false);
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    let mut test = common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "--incremental", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "unchanged"
+        kind = "dummy"
+
+        [[tool.prequery.jobs]]
+        name = "changed"
+        kind = "dummy"
+        query.document = "other.typ"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    );
+
+    let last_run = common::NOW - 100;
+    test.world
+        .expect_manifest_dir()
+        .returning(|| Ok(PathBuf::from("project")));
+    test.world
+        .expect_read_state()
+        .once()
+        .with(eq(Path::new("project")))
+        .returning(move |_| {
+            let mut state = State::new(STATE_FILE.into());
+            state.jobs.insert("unchanged".to_string(), last_run);
+            state.jobs.insert("changed".to_string(), last_run);
+            Ok(state)
+        });
+    test.world
+        .expect_read_typst_toml_source()
+        .returning(|| Ok(("typst.toml".into(), String::new())));
+    // the second job's document was modified after its last run
+    test.world.expect_modified().returning(move |path| {
+        let modified = if path == Path::new("other.typ") {
+            last_run + 10
+        } else {
+            last_run - 10
+        };
+        Ok(UNIX_EPOCH + Duration::from_secs(modified))
+    });
+    test.world
+        .expect_write_state()
+        .once()
+        .withf(move |state| {
+            state.jobs["unchanged"] == last_run && state.jobs["changed"] == common::NOW
+        })
+        .returning(|_| Ok(()));
+
+    test.run()
+        .await
+        .expect_ok("dummy jobs should succeed")
+        .expect_log(include_str!("dummy/incremental.txt"));
+}
+
+/// With --incremental and an explicitly given manifest, the job is run, and the state is recorded
+/// in the current directory instead of next to a `typst.toml` file.
+#[tokio::test]
+async fn run_dummy_incremental_with_manifest() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().once().returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let world = world.clone();
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            preprocessor.expect_run().once().returning(move || {
+                let mut l = world.log();
+                log!(l, "[{name}] this is a dummy preprocessor");
+                Ok(())
+            });
+            preprocessor
+                .expect_found_nothing()
+                .once()
+                .return_const(false);
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    let mut test = common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "--incremental", "input.typ"],
+        // unused
+        "",
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    );
+
+    // there is no typst.toml file to put the state next to
+    test.world.expect_manifest_dir().never();
+    test.world.expect_read_typst_toml_source().never();
+    test.world
+        .expect_read_state()
+        .once()
+        .with(eq(Path::new("")))
+        .returning(|dir| {
+            let mut state = State::new(dir.join(STATE_FILE));
+            // a previous run doesn't matter without a modification time
+            state.jobs.insert("test".to_string(), common::NOW - 100);
+            Ok(state)
+        });
+    test.world
+        .expect_write_state()
+        .once()
+        .withf(|state| state.jobs["test"] == common::NOW)
+        .returning(|_| Ok(()));
+
+    test.run_with_manifest(
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test"
+        kind = "dummy"
+        "#,
+    )
+    .await
     .expect_ok("dummy job should succeed")
     .expect_log(include_str!("dummy/run.txt"));
 }
+
+/// A job whose condition is not met is skipped without being configured.
+#[tokio::test]
+async fn run_dummy_when() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().never();
+
+    let mut test = common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test"
+        kind = "dummy"
+        when = "env.PREQUERY_TEST_UNSET == 'true'"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    );
+    // the condition's variable is read through the world
+    test.world
+        .expect_env_var()
+        .with(eq("PREQUERY_TEST_UNSET"))
+        .return_const(None);
+
+    test.run()
+        .await
+        .expect_ok("skipping a job should succeed")
+        .expect_log(include_str!("dummy/when.txt"));
+}
+
+/// A job whose condition is not met keeps its lockfile entries, so `--locked` still succeeds.
+#[tokio::test]
+async fn run_dummy_when_lockfile() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().never();
+
+    let mut test = common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "--locked", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        lockfile = true
+
+        [[tool.prequery.jobs]]
+        name = "test"
+        kind = "dummy"
+        when = "env.PREQUERY_TEST_UNSET == 'true'"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    );
+    test.world
+        .expect_env_var()
+        .with(eq("PREQUERY_TEST_UNSET"))
+        .return_const(None);
+    test.world
+        .expect_read_lockfile()
+        .once()
+        .with(eq(PathBuf::from("prequery.lock")))
+        .returning(|location| {
+            let mut lockfile = Lockfile::new(location.to_path_buf());
+            lockfile.entries.push(LockedResource {
+                job: "test".to_string(),
+                path: PathBuf::from("assets/example.png"),
+                url: "https://example.com/example.png".to_string(),
+                hash: "sha256:1234".to_string(),
+                timestamp: 0,
+            });
+            Ok(lockfile)
+        });
+    test.world.expect_write_lockfile().never();
+
+    test.run()
+        .await
+        .expect_ok("skipping a locked job should succeed")
+        .expect_log(include_str!("dummy/when.txt"));
+}
+
+/// A job whose condition is met is configured and run like any other job.
+#[tokio::test]
+async fn run_dummy_when_met() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().once().returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let world = world.clone();
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            preprocessor.expect_run().once().returning(move || {
+                let mut l = world.log();
+                log!(l, "[{name}] this is a dummy preprocessor");
+                Ok(())
+            });
+            preprocessor
+                .expect_found_nothing()
+                .once()
+                .return_const(false);
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    let mut test = common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test"
+        kind = "dummy"
+        when = "env.PREQUERY_TEST_UNSET == 'true'"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    );
+    // the variable is only set in the world, and the condition is only evaluated once
+    test.world
+        .expect_env_var()
+        .once()
+        .with(eq("PREQUERY_TEST_UNSET"))
+        .return_const(Some("true".to_string()));
+
+    test.run()
+        .await
+        .expect_ok("dummy job should succeed")
+        .expect_log(include_str!("dummy/run.txt"));
+}
+
+/// A Typst executable whose version can't be determined is only a warning; the job still runs.
+/// The executable is the one configured for the job.
+#[tokio::test]
+async fn run_dummy_typst_version_warning() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().once().returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let world = world.clone();
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            preprocessor.expect_run().once().returning(|| Ok(()));
+            preprocessor
+                .expect_found_nothing()
+                .once()
+                .return_const(false);
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    let mut test = common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test"
+        kind = "dummy"
+        typst = "typst-old"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    );
+    test.typst_version = None;
+    test.run()
+        .await
+        .expect_ok("dummy job should succeed")
+        .expect_log(include_str!("dummy/typst-version-warning.txt"));
+}
+
+/// In strict mode, an outdated Typst executable is an error, and no job is run.
+#[tokio::test]
+async fn run_dummy_typst_version_strict() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().once().returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            preprocessor.expect_run().never();
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    let mut test = common::PreprocessorTest::new(
+        |preprocessors| {
+            preprocessors.register(dummy);
+        },
+        &["prequery-preprocess", "--strict", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "test"
+        kind = "dummy"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    );
+    test.typst_version = Some(TypstVersion(0, 10, 0));
+    test.run()
+        .await
+        .expect_err("an outdated Typst version should fail in strict mode")
+        .expect_log(include_str!("dummy/typst-version-strict.txt"));
+}
+
+/// A job constructed in memory is run without reading `typst.toml`, and returns its resources.
+#[tokio::test]
+async fn run_dummy_single_job() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy
+        .expect_configure()
+        .once()
+        .with(
+            always(),
+            eq("generated".to_string()),
+            eq(None),
+            always(),
+            always(),
+            eq(JobDefaults::default()),
+        )
+        .returning(|world, name, _root, _manifest, _query, _defaults| {
+            let world = world.clone();
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            preprocessor.expect_run().once().returning(move || {
+                let mut l = world.log();
+                log!(l, "[{name}] this is a dummy preprocessor");
+                Ok(())
+            });
+            preprocessor
+                .expect_found_nothing()
+                .once()
+                .return_const(false);
+            preprocessor
+                .expect_locked_resources()
+                .once()
+                .returning(|| Ok(vec![resource()]));
+            Ok(Box::new(preprocessor))
+        });
+
+    fn resource() -> lockfile::Resource {
+        lockfile::Resource {
+            path: "assets/example.png".into(),
+            url: "https://example.com/example.png".to_string(),
+            hash: "sha256:0123".to_string(),
+        }
+    }
+
+    let log = VecLog::new();
+    let mut world = MockWorld::new();
+    world.expect_preprocessors().return_const({
+        let mut preprocessors = PreprocessorMap::new();
+        preprocessors.register(dummy);
+        preprocessors
+    });
+    world.expect_log().return_const(log.clone());
+    world
+        .expect_now()
+        .return_const(UNIX_EPOCH + Duration::from_secs(common::NOW));
+    world.expect_read_typst_toml().never();
+    world
+        .expect_arguments()
+        .return_const(CliArguments::parse_from([
+            "prequery-preprocess",
+            "input.typ",
+        ]));
+    // without a job-specific executable, the one from the arguments is checked
+    world
+        .expect_typst_version()
+        .once()
+        .with(eq(PathBuf::from("typst")))
+        .return_const(Some(TypstVersion::MINIMUM));
+    let world = Arc::new(world);
+
+    let job = Job {
+        name: "generated".to_string(),
+        kind: "dummy".to_string(),
+        root: None,
+        query: Default::default(),
+        log_prefix: None,
+        retries: None,
+        when: None,
+        typst: None,
+        manifest: Default::default(),
+    };
+    let resources = run_job(&world, job, JobDefaults::default())
+        .await
+        .expect("dummy job should succeed");
+    assert_eq!(resources, vec![resource()]);
+    assert_eq!(
+        log.get_lossy(),
+        "[generated] beginning job...\n\
+         [generated] this is a dummy preprocessor\n\
+         [generated] job finished in 0.0s\n"
+    );
+}
+
+/// A job constructed in memory is not run if its Typst executable is outdated in strict mode.
+#[tokio::test]
+async fn run_dummy_single_job_typst_version_strict() {
+    let mut dummy = MockPreprocessorDefinition::<MockWorld>::new();
+    dummy.expect_name().return_const("dummy");
+    dummy.expect_configure().once().returning(
+        |world, name, _root, _manifest, _query, _defaults| {
+            let mut preprocessor = MockPreprocessor::new();
+            preprocessor.expect_world().return_const(world.clone());
+            preprocessor.expect_name().return_const(name.clone());
+            preprocessor
+                .expect_log_prefix()
+                .return_const(format!("[{name}]"));
+            preprocessor.expect_validate().once().returning(|| Ok(()));
+            preprocessor.expect_run().never();
+            Ok(Box::new(preprocessor))
+        },
+    );
+
+    let log = VecLog::new();
+    let mut world = MockWorld::new();
+    world.expect_preprocessors().return_const({
+        let mut preprocessors = PreprocessorMap::new();
+        preprocessors.register(dummy);
+        preprocessors
+    });
+    world.expect_log().return_const(log.clone());
+    world.expect_read_typst_toml().never();
+    world
+        .expect_arguments()
+        .return_const(CliArguments::parse_from([
+            "prequery-preprocess",
+            "--strict",
+            "input.typ",
+        ]));
+    // the job's own executable is checked instead of the one from the arguments
+    world
+        .expect_typst_version()
+        .once()
+        .with(eq(PathBuf::from("typst-0.10")))
+        .return_const(Some(TypstVersion(0, 10, 0)));
+    let world = Arc::new(world);
+
+    let job = Job {
+        name: "generated".to_string(),
+        kind: "dummy".to_string(),
+        root: None,
+        query: Default::default(),
+        log_prefix: None,
+        retries: None,
+        when: None,
+        typst: Some(PathBuf::from("typst-0.10")),
+        manifest: Default::default(),
+    };
+    let error = run_job(&world, job, JobDefaults::default())
+        .await
+        .expect_err("an outdated Typst version should fail in strict mode");
+    assert!(
+        matches!(error, Error::UnsupportedTypstVersion { .. }),
+        "{error}"
+    );
+}
@@ -0,0 +1,88 @@
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use mockall::predicate::{always, eq};
+use prequery_preprocess::VecLog;
+use prequery_preprocess::args::CliArguments;
+use prequery_preprocess::entry::run;
+use prequery_preprocess::init::EXAMPLE;
+use prequery_preprocess::manifest::PrequeryManifest;
+use prequery_preprocess::world::MockWorld;
+
+const TYPST_TOML: &str = r#"[package]
+name = "test"
+version = "0.0.1"
+entrypoint = "main.typ"
+"#;
+
+fn init_world(content: &'static str, log: &VecLog) -> MockWorld {
+    let mut world = MockWorld::new();
+    world
+        .expect_arguments()
+        .return_const(CliArguments::parse_from([
+            "prequery-preprocess",
+            "--init",
+            "input.typ",
+        ]));
+    world.expect_log().return_const(log.clone());
+    world
+        .expect_read_typst_toml_source()
+        .once()
+        .returning(move || Ok((PathBuf::from("typst.toml"), content.to_string())));
+    // no jobs are run
+    world.expect_read_typst_toml().never();
+    world
+}
+
+/// The example configuration is appended to a typst.toml file without prequery configuration.
+#[tokio::test]
+async fn init_appends_example() {
+    let log = VecLog::new();
+    let mut world = init_world(TYPST_TOML, &log);
+    world
+        .expect_write_typst_toml_source()
+        .once()
+        .with(
+            eq(Path::new("typst.toml")),
+            eq(format!("{TYPST_TOML}{EXAMPLE}")),
+        )
+        .returning(|_, _| Ok(()));
+
+    run(world).await.expect("init should succeed");
+    PrequeryManifest::parse(&format!("{TYPST_TOML}{EXAMPLE}"))
+        .expect("the example configuration should be valid");
+    assert_eq!(
+        log.get_lossy(),
+        "added an example prequery configuration to typst.toml\n"
+    );
+}
+
+/// Existing prequery configuration is not changed.
+#[tokio::test]
+async fn init_already_configured() {
+    let log = VecLog::new();
+    let mut world = init_world(
+        r#"[package]
+name = "test"
+version = "0.0.1"
+entrypoint = "main.typ"
+
+[[tool.prequery.jobs]]
+name = "download"
+kind = "web-resource"
+"#,
+        &log,
+    );
+    world
+        .expect_write_typst_toml_source()
+        .with(always(), always())
+        .never();
+
+    run(world)
+        .await
+        .expect_err("init should fail for existing configuration");
+    assert_eq!(
+        log.get_lossy(),
+        "typst.toml already contains a `tool.prequery` section, it was not changed\n"
+    );
+}
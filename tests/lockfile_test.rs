@@ -0,0 +1,19 @@
+use prequery_preprocess::lockfile::HashAlgorithm;
+
+/// Hashes are prefixed with the algorithm's name.
+#[test]
+fn hash_algorithm_digest() {
+    assert_eq!(
+        HashAlgorithm::Sha256.digest(b""),
+        "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+    );
+    assert_eq!(
+        HashAlgorithm::Sha512.digest(b""),
+        "sha512:cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce\
+         47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e",
+    );
+    assert_eq!(
+        HashAlgorithm::Blake3.digest(b""),
+        "blake3:af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+    );
+}
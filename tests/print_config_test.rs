@@ -0,0 +1,53 @@
+use prequery_preprocess::query::Query;
+
+mod common;
+
+/// Print the configuration of the jobs instead of running them. Package-wide defaults and selector
+/// variables are applied to the jobs.
+#[tokio::test]
+async fn print_config() {
+    common::PreprocessorTest::new(
+        |_preprocessors| {},
+        &["prequery-preprocess", "--print-config", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        default_field = false
+        fallback = false
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        query.selector = "<${label}>"
+        query.vars.label = "web-resource"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+        query.selector = "<python>"
+        query.field = "value"
+        query.fallback = true
+        query.inputs.mode = "draft"
+        command = ["python", "-c"]
+        format.stdout = "plain"
+        "#,
+        // unused
+        Query {
+            selector: Default::default(),
+            field: Default::default(),
+            one: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        b"",
+    )
+    .run()
+    .await
+    .expect_ok("printing the config should succeed")
+    .expect_log(include_str!("print-config/jobs.txt"));
+}
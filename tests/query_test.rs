@@ -0,0 +1,584 @@
+use std::ffi::OsStr;
+
+use clap::Parser;
+use mockall::predicate::eq;
+use prequery_preprocess::args::CliArguments;
+use prequery_preprocess::manifest;
+use prequery_preprocess::query::{self, Query, QueryBuilderError, TypstVersion};
+use prequery_preprocess::world::{MockWorld, build_query_command};
+use serde::Deserialize;
+
+fn query() -> Query {
+    Query {
+        selector: "<web-resource>".to_string(),
+        field: Some("value".to_string()),
+        fallback: true,
+        ..Default::default()
+    }
+}
+
+fn query_args(query: &Query) -> Vec<String> {
+    query_args_with(&["prequery-preprocess", "input.typ"], query)
+}
+
+fn query_args_with(args: &[&str], query: &Query) -> Vec<String> {
+    let arguments = CliArguments::parse_from(args);
+    let cmd = build_query_command(&arguments, query);
+    cmd.as_std()
+        .get_args()
+        .map(OsStr::to_string_lossy)
+        .map(String::from)
+        .collect()
+}
+
+/// The `prequery-fallback` input is given by default, but can be disabled.
+#[test]
+fn query_command_fallback() {
+    assert_eq!(
+        query_args(&query()),
+        [
+            "query",
+            "--field",
+            "value",
+            "--input",
+            "prequery-fallback=true",
+            "input.typ",
+            "<web-resource>",
+        ],
+    );
+    assert_eq!(
+        query_args(&Query {
+            fallback: false,
+            ..query()
+        }),
+        ["query", "--field", "value", "input.typ", "<web-resource>"],
+    );
+}
+
+/// Extra arguments are passed in order, before the document and selector.
+#[test]
+fn query_command_extra_args() {
+    assert_eq!(
+        query_args(&Query {
+            extra_args: vec![
+                "--features".to_string(),
+                "html".to_string(),
+                "--ppi=300".to_string(),
+            ],
+            ..query()
+        }),
+        [
+            "query",
+            "--field",
+            "value",
+            "--input",
+            "prequery-fallback=true",
+            "--features",
+            "html",
+            "--ppi=300",
+            "input.typ",
+            "<web-resource>",
+        ],
+    );
+}
+
+/// A job's own Typst executable replaces the one given on the command line.
+#[test]
+fn query_command_typst() {
+    let arguments =
+        CliArguments::parse_from(["prequery-preprocess", "--typst", "typst-0.13", "input.typ"]);
+    let cmd = build_query_command(&arguments, &query());
+    assert_eq!(cmd.as_std().get_program(), "typst-0.13");
+    let query = Query {
+        typst: Some("bin/typst-0.11".into()),
+        ..query()
+    };
+    let cmd = build_query_command(&arguments, &query);
+    assert_eq!(cmd.as_std().get_program(), "bin/typst-0.11");
+}
+
+/// All flags are given in a fixed order: root, field, one, inputs, fallback, extra arguments,
+/// and finally the document and selector.
+#[test]
+fn query_command_order() {
+    assert_eq!(
+        query_args_with(
+            &["prequery-preprocess", "--root", "..", "input.typ"],
+            &Query {
+                one: true,
+                inputs: [("a".to_string(), "1".to_string())].into_iter().collect(),
+                document: Some("other.typ".into()),
+                extra_args: vec!["--ppi=300".to_string()],
+                ..query()
+            },
+        ),
+        [
+            "query",
+            "--root",
+            "..",
+            "--field",
+            "value",
+            "--one",
+            "--input",
+            "a=1",
+            "--input",
+            "prequery-fallback=true",
+            "--ppi=300",
+            "other.typ",
+            "<web-resource>",
+        ],
+    );
+    assert_eq!(
+        query_args(&Query {
+            field: None,
+            fallback: false,
+            ..query()
+        }),
+        ["query", "input.typ", "<web-resource>"],
+    );
+}
+
+fn build_selector(selector: &str, vars: &[(&str, &str)]) -> Result<String, QueryBuilderError> {
+    let config = manifest::Query {
+        selector: Some(selector.to_string()),
+        vars: vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        ..Default::default()
+    };
+    let query = Query::builder()
+        .default_field(Some("value".to_string()))
+        .default_one(false)
+        .build(config)?;
+    Ok(query.selector)
+}
+
+fn build_field(
+    field: Option<Option<&str>>,
+    default: Option<Option<&str>>,
+) -> Result<Option<String>, QueryBuilderError> {
+    let to_owned = |field: Option<Option<&str>>| field.map(|field| field.map(str::to_string));
+    let config = manifest::Query {
+        selector: Some("<label>".to_string()),
+        field: to_owned(field),
+        ..Default::default()
+    };
+    let mut builder = Query::builder().default_one(false);
+    if let Some(default) = to_owned(default) {
+        builder = builder.default_field(default);
+    }
+    Ok(builder.build(config)?.field)
+}
+
+/// A field of `false` means no field: it overrides a default field, and as a default it applies to
+/// queries that don't specify a field. Such queries don't pass `--field` to `typst query`.
+#[test]
+fn query_builder_no_field() {
+    assert_eq!(build_field(Some(None), Some(Some("value"))).unwrap(), None);
+    assert_eq!(build_field(None, Some(None)).unwrap(), None);
+    assert_eq!(
+        build_field(Some(Some("meta")), Some(None)).unwrap(),
+        Some("meta".to_string()),
+    );
+    assert!(matches!(
+        build_field(None, None),
+        Err(QueryBuilderError::Field),
+    ));
+
+    let config: manifest::Query = toml::from_str("field = false").unwrap();
+    assert_eq!(config.field, Some(None));
+    let query = Query::builder()
+        .default_field(Some("value".to_string()))
+        .default_one(false)
+        .default_selector("<label>".to_string())
+        .build(config)
+        .unwrap();
+    assert_eq!(
+        query_args(&query),
+        [
+            "query",
+            "--input",
+            "prequery-fallback=true",
+            "input.typ",
+            "<label>"
+        ],
+    );
+}
+
+/// Variables in the selector are expanded; undefined and unterminated placeholders are errors.
+#[test]
+fn query_builder_selector_vars() {
+    assert_eq!(
+        build_selector("<${name}-data>", &[("name", "figure")]).unwrap(),
+        "<figure-data>",
+    );
+    assert_eq!(
+        build_selector("<${a}-${b}>", &[("a", "x"), ("b", "y")]).unwrap(),
+        "<x-y>",
+    );
+    assert_eq!(build_selector("<label>", &[]).unwrap(), "<label>");
+    assert!(matches!(
+        build_selector("<${name}-data>", &[]),
+        Err(QueryBuilderError::UndefinedVariable(name)) if name == "name",
+    ));
+    assert!(matches!(
+        build_selector("<${name-data>", &[("name", "figure")]),
+        Err(QueryBuilderError::UnterminatedVariable),
+    ));
+}
+
+/// Inputs of the form `${env:NAME}` are expanded from the environment; missing variables are errors.
+#[test]
+fn query_expand_inputs() {
+    let env = |name: &str| (name == "SECRET").then(|| "hunter2".to_string());
+    let with_secret = Query {
+        inputs: [
+            ("token".to_string(), "${env:SECRET}".to_string()),
+            ("mode".to_string(), "draft".to_string()),
+        ]
+        .into(),
+        ..query()
+    };
+
+    let expanded = with_secret.expand_inputs(env).unwrap();
+    assert_eq!(expanded.inputs["token"], "hunter2");
+    assert_eq!(expanded.inputs["mode"], "draft");
+    // the original query is not changed
+    assert_eq!(with_secret.inputs["token"], "${env:SECRET}");
+
+    let with_missing = Query {
+        inputs: [("token".to_string(), "${env:MISSING}".to_string())].into(),
+        ..query()
+    };
+    assert!(matches!(
+        with_missing.expand_inputs(env),
+        Err(query::Error::MissingEnv { input, var }) if input == "token" && var == "MISSING",
+    ));
+}
+
+#[tokio::test]
+async fn query_execute() {
+    let mut world = MockWorld::new();
+    world
+        .expect_query_impl()
+        .with(eq(query()))
+        .returning(|_| Ok(br#"["a", "b"]"#.to_vec()));
+
+    let data: Vec<String> = query().execute(&world).await.unwrap();
+    assert_eq!(data, ["a", "b"]);
+}
+
+#[tokio::test]
+async fn query_execute_label_pattern() {
+    const ELEMENTS: &[u8] = br#"[
+  {"func": "metadata", "value": "a", "label": "<figure-data>"},
+  {"func": "metadata", "value": "b"},
+  {"func": "metadata", "value": "c", "label": "<table-data>"},
+  {"func": "metadata", "value": "d", "label": "<table-data-old>"},
+  {"func": "metadata", "value": "e", "label": "<data>"}
+]"#;
+
+    let broad = Query {
+        selector: "metadata".to_string(),
+        field: None,
+        ..query()
+    };
+    let mut world = MockWorld::new();
+    world
+        .expect_query_impl()
+        .with(eq(broad))
+        .returning(|_| Ok(ELEMENTS.to_vec()));
+
+    let execute = |selector: &str, one: bool| {
+        let query = Query {
+            selector: selector.to_string(),
+            one,
+            ..query()
+        };
+        let world = &world;
+        async move { query.execute::<serde_json::Value>(world).await }
+    };
+
+    let data = execute("<*-data>", false).await.unwrap();
+    assert_eq!(data, serde_json::json!(["a", "c"]));
+    let data = execute("<?????-data*>", false).await.unwrap();
+    assert_eq!(data, serde_json::json!(["c", "d"]));
+    let data = execute("<fig*>", true).await.unwrap();
+    assert_eq!(data, serde_json::json!("a"));
+    let error = execute("<*data*>", true).await.unwrap_err();
+    assert!(matches!(error, query::Error::NotOne { count: 4, .. }));
+}
+
+#[tokio::test]
+async fn query_execute_schema_error() {
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Item {
+        url: String,
+        path: String,
+    }
+
+    let mut world = MockWorld::new();
+    world.expect_query_impl().returning(|_| {
+        Ok(br#"[
+  {"url": "https://example.com/a.png", "path": "a.png"},
+  {"path": "b.png"},
+  {"url": "https://example.com/c.png", "path": "c.png"}
+]"#
+        .to_vec())
+    });
+
+    let error = query().execute::<Vec<Item>>(&world).await.unwrap_err();
+    assert!(matches!(
+        &error,
+        query::Error::Schema { selector, item: Some(1), snippet, .. }
+            if selector == "<web-resource>" && snippet == r#"{"path": "b.png"}"#,
+    ));
+}
+
+#[tokio::test]
+async fn manifest_inputs_file() {
+    let dir = std::env::temp_dir().join(format!("prequery-inputs-file-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("inputs.json"),
+        r#"{"theme": "dark", "draft": true, "lang": "de"}"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("inputs.toml"), "sizes = [1, 2]\n").unwrap();
+
+    let mut config = manifest::PrequeryManifest::parse(
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "json"
+        kind = "web-resource"
+        query.inputs_file = "inputs.json"
+        query.inputs.lang = "en"
+
+        [[tool.prequery.jobs]]
+        name = "toml"
+        kind = "web-resource"
+        query.inputs_file = "inputs.toml"
+
+        [[tool.prequery.jobs]]
+        name = "missing"
+        kind = "web-resource"
+        query.inputs_file = "missing.json"
+        "#,
+    )
+    .unwrap();
+    config.resolve_paths(&dir);
+    let missing = config.jobs.pop().unwrap();
+    let result = config.merge_inputs_files().await;
+
+    config.jobs.push(missing);
+    let missing_result = config.merge_inputs_files().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    result.expect("inputs files should be merged");
+    let json = &config.jobs[0].query.inputs;
+    assert_eq!(json["theme"], "dark");
+    assert_eq!(json["draft"], "true");
+    // explicit inputs take precedence
+    assert_eq!(json["lang"], "en");
+    assert_eq!(config.jobs[1].query.inputs["sizes"], "[1,2]");
+
+    assert!(matches!(
+        missing_result,
+        Err(manifest::Error::InputsFileRead(path, _)) if path == dir.join("missing.json"),
+    ));
+}
+
+/// Job conditions support presence checks, comparisons and boolean operators.
+#[test]
+fn manifest_when() {
+    let var = |name: &str| match name {
+        "CI" => Some("true".to_string()),
+        "EMPTY" => Some(String::new()),
+        _ => None,
+    };
+    let evaluate = |source: &str| {
+        manifest::When::new(source.to_string())
+            .unwrap()
+            .evaluate(var)
+    };
+
+    assert!(evaluate("env.CI == 'true'"));
+    assert!(evaluate(r#""true" == env.CI"#));
+    assert!(!evaluate("env.CI != 'true'"));
+    assert!(evaluate("env.EMPTY"));
+    assert!(!evaluate("env.MISSING"));
+    assert!(!evaluate("env.MISSING == env.OTHER"));
+    assert!(evaluate("env.MISSING != 'true'"));
+    assert!(evaluate("!env.MISSING && env.CI"));
+    assert!(evaluate(
+        "env.MISSING || env.CI == 'true' && env.EMPTY == ''"
+    ));
+    assert!(!evaluate("(env.MISSING || env.CI) && env.CI == 'false'"));
+
+    assert!(manifest::When::new("env.CI ==".to_string()).is_err());
+    assert!(manifest::When::new("CI == 'true'".to_string()).is_err());
+    assert!(manifest::When::new("'true'".to_string()).is_err());
+    assert!(manifest::When::new("(env.CI".to_string()).is_err());
+    assert!(manifest::When::new("env.CI = 'true'".to_string()).is_err());
+}
+
+/// The output of `typst --version` is parsed into a version, ignoring pre-release suffixes.
+#[test]
+fn typst_version_parse() {
+    assert_eq!(
+        TypstVersion::parse("typst 0.13.1 (8ace67d9)"),
+        Some(TypstVersion(0, 13, 1)),
+    );
+    assert_eq!(
+        TypstVersion::parse("typst 0.14.0-rc.1 (b3b8a1c2)\n"),
+        Some(TypstVersion(0, 14, 0)),
+    );
+    assert_eq!(TypstVersion::parse("typst 0.13"), None);
+    assert_eq!(TypstVersion::parse("typst"), None);
+    assert_eq!(TypstVersion::parse(""), None);
+    assert_eq!(TypstVersion::parse("typst x.y.z"), None);
+
+    assert!(TypstVersion(0, 10, 0) < TypstVersion::MINIMUM);
+    assert!(TypstVersion(0, 13, 1) > TypstVersion::MINIMUM);
+}
+
+/// A manifest can require a minimum version of prequery-preprocess.
+#[test]
+fn manifest_min_version() {
+    let parse = |min_version: &str| {
+        manifest::PrequeryManifest::parse(&format!(
+            r#"
+            [package]
+            name = "test"
+            version = "0.0.1"
+            entrypoint = "main.typ"
+
+            [tool.prequery]
+            min_version = "{min_version}"
+            jobs = []
+            "#
+        ))
+    };
+
+    assert!(parse("0.1").is_ok());
+    assert!(parse(env!("CARGO_PKG_VERSION")).is_ok());
+    // a pre-release is older than its release
+    assert!(parse(&format!("{}-rc.1", env!("CARGO_PKG_VERSION"))).is_ok());
+    assert!(matches!(
+        parse("999.0.0-rc.1"),
+        Err(manifest::Error::MinVersion { required, .. }) if required == "999.0.0-rc.1",
+    ));
+    assert!(matches!(
+        parse("999.0.0"),
+        Err(manifest::Error::MinVersion { required, .. }) if required == "999.0.0",
+    ));
+    assert!(matches!(
+        parse("latest"),
+        Err(manifest::Error::InvalidMinVersion(version)) if version == "latest",
+    ));
+}
+
+/// Job names must be unique within a manifest.
+#[test]
+fn manifest_duplicate_job_name() {
+    let result = manifest::PrequeryManifest::parse(
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+
+        [[tool.prequery.jobs]]
+        name = "render"
+        kind = "shell"
+        command = "true"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+    );
+    assert!(matches!(
+        result,
+        Err(manifest::Error::DuplicateJobName { name, first: 1, second: 3 }) if name == "download",
+    ));
+}
+
+/// Query results can be recorded in a query cache file and read back.
+#[tokio::test]
+async fn query_cache_round_trip() {
+    let dir = std::env::temp_dir().join(format!("prequery-query-cache-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let location = dir.join("queries.json");
+    std::fs::write(dir.join("invalid.json"), "[]").unwrap();
+
+    let mut query = query();
+    query.inputs = [
+        ("b".to_string(), "2".to_string()),
+        ("a".to_string(), "1".to_string()),
+    ]
+    .into();
+    let other = Query {
+        selector: "<other>".to_string(),
+        ..query.clone()
+    };
+    assert_eq!(
+        query.cache_key(),
+        "<web-resource> --field value --input a=1 --input b=2 --input prequery-fallback=true",
+    );
+
+    // a missing file is an empty cache
+    let mut cache = query::QueryCache::read(location.clone()).await.unwrap();
+    assert!(cache.entries.is_empty());
+    cache
+        .insert(&query, br#"[{"url": "https://example.com/example.png"}]"#)
+        .unwrap();
+    cache.write().await.unwrap();
+
+    let read = query::QueryCache::read(location.clone()).await;
+    let invalid = query::QueryCache::read(dir.join("invalid.json")).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let cache = read.unwrap();
+    assert_eq!(
+        cache.get(&query).unwrap(),
+        br#"[{"url":"https://example.com/example.png"}]"#,
+    );
+    assert!(matches!(
+        cache.get(&other),
+        Err(query::Error::NotCached(key)) if key == other.cache_key(),
+    ));
+    assert!(matches!(
+        invalid,
+        Err(query::Error::QueryCacheInvalid(path, _)) if path == dir.join("invalid.json"),
+    ));
+}
+
+/// A job's root takes precedence over the root given on the command line.
+#[test]
+fn query_command_job_root() {
+    let args = ["prequery-preprocess", "--root", "project", "input.typ"];
+    assert_eq!(
+        query_args_with(&args, &query())[..3],
+        ["query", "--root", "project"],
+    );
+    let query = Query {
+        root: Some("project/assets".into()),
+        ..query()
+    };
+    assert_eq!(
+        query_args_with(&args, &query)[..3],
+        ["query", "--root", "project/assets"],
+    );
+}
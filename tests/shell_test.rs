@@ -1,9 +1,13 @@
 use std::io;
+use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
+use std::process::ExitStatus;
 
+use mockall::Sequence;
 use mockall::predicate::eq;
 use prequery_preprocess::query::Query;
 use prequery_preprocess::shell::{MockWorld, MockWorld_NewContext, ShellFactory};
+use prequery_preprocess::world::FileLock;
 use serial_test::serial;
 
 mod common;
@@ -22,9 +26,12 @@ impl ShellTest {
         cfg_world: impl Fn(&mut MockWorld) + Send + 'static,
     ) -> Self {
         let ctx = MockWorld::new_context();
-        ctx.expect().returning(move |main| {
+        ctx.expect().returning(move |main, _manifest| {
             let mut world = MockWorld::default();
             world.expect_main().return_const(main);
+            world
+                .expect_lock_index()
+                .returning(|_| Ok(FileLock::default()));
             cfg_world(&mut world);
             world
         });
@@ -72,8 +79,8 @@ async fn run_shell_python_snippets() {
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
         br#"[{"path": "out.json"}, {"data": "print(\"Hello World\")"}, {"data": "print(\"Hello Prequery\")"}]"#,
         |world| {
@@ -114,11 +121,11 @@ async fn run_shell_python_snippets() {
     .expect_log(include_str!("shell/python.txt"));
 }
 
-/// Run the shell preprocessor with two separate commands, saved to separate files.
-/// All data is passed as plain text
+/// Run the shell preprocessor with two separate commands, saved to one file.
+/// The commands return objects with their keys in different orders, which are saved sorted
 #[tokio::test]
 #[serial(shell)]
-async fn run_shell_python_snippets_separate_files() {
+async fn run_shell_python_sorted_keys() {
     ShellTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -134,51 +141,38 @@ async fn run_shell_python_snippets_separate_files() {
         query.selector = "<python>"
 
         command = "python"
-        format.stdin = "plain"
-        format.stdout = "plain"
-        format.output = "plain"
         "#,
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "out2.json", "data": "print(\"Hello Prequery\")"}]"#,
+        br#"[{"path": "out.json"}, {"data": "a"}, {"data": "b"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
             world.expect_write_index().never();
 
             // two code snippets
-            world.expect_run_command()
+            world
+                .expect_run_command()
                 .once()
-                .with(
-                    eq(["python".to_string()]),
-                    eq(*br#"print("Hello World")"#),
-                )
-                .returning(|_, _| Ok(br#"Hello World\n"#.to_vec()));
-            world.expect_run_command()
+                .with(eq(["python".to_string()]), eq(*br#""a""#))
+                .returning(|_, _| Ok(br#"{"b": 1, "a": {"d": 2, "c": 3}}"#.to_vec()));
+            world
+                .expect_run_command()
                 .once()
-                .with(
-                    eq(["python".to_string()]),
-                    eq(*br#"print("Hello Prequery")"#),
-                )
-                .returning(|_, _| Ok(br#"Hello Prequery\n"#.to_vec()));
+                .with(eq(["python".to_string()]), eq(*br#""b""#))
+                .returning(|_, _| Ok(br#"{"a": 4, "b": 5}"#.to_vec()));
 
-            // separate output files
-            world
-                .expect_write_output()
-                .with(
-                    eq(PathBuf::from("out1.json")),
-                    eq(*br#"Hello World\n"#),
-                )
-                .returning(|_, _| Ok(()));
+            // one combined output file, with all keys sorted
             world
                 .expect_write_output()
+                .once()
                 .with(
-                    eq(PathBuf::from("out2.json")),
-                    eq(*br#"Hello Prequery\n"#),
+                    eq(PathBuf::from("out.json")),
+                    eq(*br#"[{"a":{"c":3,"d":2},"b":1},{"a":4,"b":5}]"#),
                 )
                 .returning(|_, _| Ok(()));
         },
@@ -186,13 +180,14 @@ async fn run_shell_python_snippets_separate_files() {
     .run()
     .await
     .expect_ok("shell job should succeed")
-    .expect_log(include_str!("shell/python-separate.txt"));
+    .expect_log(include_str!("shell/python.txt"));
 }
 
-/// Run the shell preprocessor with two joined commands, saved to one file.
+/// Run the shell preprocessor with two separate commands, saved to separate files.
+/// The commands return nested objects with unordered keys, which are saved sorted
 #[tokio::test]
 #[serial(shell)]
-async fn run_shell_python_joined_snippets() {
+async fn run_shell_python_sorted_keys_separate_files() {
     ShellTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -207,16 +202,15 @@ async fn run_shell_python_joined_snippets() {
 
         query.selector = "<python>"
 
-        command = ["python", "exec.py"]
-        joined = true
+        command = "python"
         "#,
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"path": "out.json"}, {"data": "x = 1\nprint(x)"}, {"data": "y = x + 1\nprint(y)"}]"#,
+        br#"[{"path": "out1.json", "data": "a"}, {"path": "out2.json", "data": "b"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
@@ -226,31 +220,44 @@ async fn run_shell_python_joined_snippets() {
             world
                 .expect_run_command()
                 .once()
+                .with(eq(["python".to_string()]), eq(*br#""a""#))
+                .returning(|_, _| Ok(br#"{"z": [{"y": 1, "x": 2}], "a": null}"#.to_vec()));
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""b""#))
+                .returning(|_, _| Ok(br#"{"b": {"d": true, "c": false}, "a": 0}"#.to_vec()));
+
+            // separate output files, with all keys sorted
+            world
+                .expect_write_output()
+                .once()
                 .with(
-                    eq(["python".to_string(), "exec.py".to_string()]),
-                    eq(*br#"["x = 1\nprint(x)","y = x + 1\nprint(y)"]"#),
+                    eq(PathBuf::from("out1.json")),
+                    eq(*br#"{"a":null,"z":[{"x":2,"y":1}]}"#),
                 )
-                .returning(|_, _| Ok(br#"["1\n","2\n"]"#.to_vec()));
-
-            // one combined output file
+                .returning(|_, _| Ok(()));
             world
                 .expect_write_output()
                 .once()
-                .with(eq(PathBuf::from("out.json")), eq(*br#"["1\n","2\n"]"#))
+                .with(
+                    eq(PathBuf::from("out2.json")),
+                    eq(*br#"{"a":0,"b":{"c":false,"d":true}}"#),
+                )
                 .returning(|_, _| Ok(()));
         },
     )
     .run()
     .await
     .expect_ok("shell job should succeed")
-    .expect_log(include_str!("shell/joined-python.txt"));
+    .expect_log(include_str!("shell/python-separate.txt"));
 }
 
-/// Run the shell preprocessor with two joined commands, saved to separate files.
-/// Files are saved as plain text
+/// Run the shell preprocessor with two separate commands, saved to separate files.
+/// All data is passed as plain text
 #[tokio::test]
 #[serial(shell)]
-async fn run_shell_python_joined_snippets_separate_files() {
+async fn run_shell_python_snippets_separate_files() {
     ShellTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -265,45 +272,52 @@ async fn run_shell_python_joined_snippets_separate_files() {
 
         query.selector = "<python>"
 
-        command = ["python", "exec.py"]
-        joined = true
+        command = "python"
+        format.stdin = "plain"
+        format.stdout = "plain"
         format.output = "plain"
         "#,
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"path": "out1.json", "data": "x = 1\nprint(x)"}, {"path": "out2.json", "data": "y = x + 1\nprint(y)"}]"#,
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "out2.json", "data": "print(\"Hello Prequery\")"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
             world.expect_write_index().never();
 
             // two code snippets
-            world
-                .expect_run_command()
+            world.expect_run_command()
                 .once()
                 .with(
-                    eq(["python".to_string(), "exec.py".to_string()]),
-                    eq(*br#"["x = 1\nprint(x)","y = x + 1\nprint(y)"]"#),
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello World")"#),
                 )
-                .returning(|_, _| Ok(br#"["1\n","2\n"]"#.to_vec()));
+                .returning(|_, _| Ok(br#"Hello World\n"#.to_vec()));
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello Prequery")"#),
+                )
+                .returning(|_, _| Ok(br#"Hello Prequery\n"#.to_vec()));
 
             // separate output files
             world
                 .expect_write_output()
                 .with(
                     eq(PathBuf::from("out1.json")),
-                    eq(*b"1\n"),
+                    eq(*br#"Hello World\n"#),
                 )
                 .returning(|_, _| Ok(()));
             world
                 .expect_write_output()
                 .with(
                     eq(PathBuf::from("out2.json")),
-                    eq(*b"2\n"),
+                    eq(*br#"Hello Prequery\n"#),
                 )
                 .returning(|_, _| Ok(()));
         },
@@ -311,13 +325,14 @@ async fn run_shell_python_joined_snippets_separate_files() {
     .run()
     .await
     .expect_ok("shell job should succeed")
-    .expect_log(include_str!("shell/joined-python-separate.txt"));
+    .expect_log(include_str!("shell/python-separate.txt"));
 }
 
-/// Run the shell preprocessor, but the command fails.
+/// Run the shell preprocessor with two concurrent commands, limited to one at a time. The
+/// commands thus run in order
 #[tokio::test]
 #[serial(shell)]
-async fn run_shell_python_failed_process() {
+async fn run_shell_python_max_concurrent() {
     ShellTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -332,46 +347,74 @@ async fn run_shell_python_failed_process() {
 
         query.selector = "<python>"
 
-        command = ["python"]
+        command = "python"
+        format.stdin = "plain"
+        format.stdout = "plain"
+        format.output = "plain"
+        concurrent = true
+        max_concurrent = 1
         "#,
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"path": "out.json", "data": ""}]"#,
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "out2.json", "data": "print(\"Hello Prequery\")"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
             world.expect_write_index().never();
 
-            // one command, fails
-            world
-                .expect_run_command()
+            // two code snippets, in order
+            let mut seq = Sequence::new();
+            world.expect_run_command()
                 .once()
-                .with(eq(["python".to_string()]), eq(*br#""""#))
-                .returning(|_, _| {
-                    Err(prequery_preprocess::shell::CommandError::Process(
-                        io::ErrorKind::Other.into(),
-                    ))
-                });
+                .in_sequence(&mut seq)
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello World")"#),
+                )
+                .returning(|_, _| Ok(br#"Hello World\n"#.to_vec()));
+            world.expect_run_command()
+                .once()
+                .in_sequence(&mut seq)
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello Prequery")"#),
+                )
+                .returning(|_, _| Ok(br#"Hello Prequery\n"#.to_vec()));
 
-            world.expect_write_output().never();
+            // separate output files
+            world
+                .expect_write_output()
+                .with(
+                    eq(PathBuf::from("out1.json")),
+                    eq(*br#"Hello World\n"#),
+                )
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_output()
+                .with(
+                    eq(PathBuf::from("out2.json")),
+                    eq(*br#"Hello Prequery\n"#),
+                )
+                .returning(|_, _| Ok(()));
         },
     )
     .run()
     .await
-    .expect_err("shell job should fail")
-    .expect_log(include_str!("shell/python-failed-process.txt"));
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-separate.txt"));
 }
 
-/// Run the shell preprocessor with one command, but the command doesn't return JSON.
+/// Run the shell preprocessor with two concurrent commands, limited to one at a time by the
+/// global `--concurrency`. The commands thus run in order
 #[tokio::test]
 #[serial(shell)]
-async fn run_shell_python_invalid_output() {
+async fn run_shell_python_global_concurrency() {
     ShellTest::new(
-        &["prequery-preprocess", "input.typ"],
+        &["prequery-preprocess", "--concurrency", "1", "input.typ"],
         r#"
         [package]
         name = "test"
@@ -385,37 +428,70 @@ async fn run_shell_python_invalid_output() {
         query.selector = "<python>"
 
         command = "python"
+        format.stdin = "plain"
+        format.stdout = "plain"
+        format.output = "plain"
+        concurrent = true
         "#,
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"path": "out.json", "data": ""}]"#,
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "out2.json", "data": "print(\"Hello Prequery\")"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
             world.expect_write_index().never();
 
-            // one code snippet
-            world
-                .expect_run_command()
+            // two code snippets, in order
+            let mut seq = Sequence::new();
+            world.expect_run_command()
                 .once()
-                .with(eq(["python".to_string()]), eq(*br#""""#))
-                .returning(|_, _| Ok(br#"not JSON"#.to_vec()));
+                .in_sequence(&mut seq)
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello World")"#),
+                )
+                .returning(|_, _| Ok(br#"Hello World\n"#.to_vec()));
+            world.expect_run_command()
+                .once()
+                .in_sequence(&mut seq)
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello Prequery")"#),
+                )
+                .returning(|_, _| Ok(br#"Hello Prequery\n"#.to_vec()));
+
+            // separate output files
+            world
+                .expect_write_output()
+                .with(
+                    eq(PathBuf::from("out1.json")),
+                    eq(*br#"Hello World\n"#),
+                )
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_output()
+                .with(
+                    eq(PathBuf::from("out2.json")),
+                    eq(*br#"Hello Prequery\n"#),
+                )
+                .returning(|_, _| Ok(()));
         },
     )
     .run()
     .await
-    .expect_err("shell job should fail")
-    .expect_log(include_str!("shell/python-failed-invalid-output.txt"));
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-separate.txt"));
 }
 
-/// Run the shell preprocessor, but the command for the joined inputs returns an array of the wrong length.
+/// Run the shell preprocessor with two separate commands, saved to separate files.
+/// The inputs are passed as command line arguments instead of stdin
 #[tokio::test]
 #[serial(shell)]
-async fn run_shell_python_joined_wrong_length() {
+async fn run_shell_python_snippets_as_args() {
     ShellTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -430,42 +506,76 @@ async fn run_shell_python_joined_wrong_length() {
 
         query.selector = "<python>"
 
-        command = ["python", "exec.py"]
-        joined = true
+        command = ["python", "-c"]
+        input_as = "args"
+        format.stdout = "plain"
+        format.output = "plain"
         "#,
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"path": "out.json"}, {"data": ""}]"#,
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "out2.json", "data": ["import sys; print(sys.argv[1])", "Prequery"]}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
             world.expect_write_index().never();
 
-            // one code snippet, returns as if there were two
-            world
-                .expect_run_command()
+            // two code snippets, with nothing on stdin
+            world.expect_run_command()
                 .once()
                 .with(
-                    eq(["python".to_string(), "exec.py".to_string()]),
-                    eq(*br#"[""]"#),
+                    eq([
+                        "python".to_string(),
+                        "-c".to_string(),
+                        r#"print("Hello World")"#.to_string(),
+                    ]),
+                    eq(*b""),
                 )
-                .returning(|_, _| Ok(br#"["",""]"#.to_vec()));
+                .returning(|_, _| Ok(br#"Hello World\n"#.to_vec()));
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq([
+                        "python".to_string(),
+                        "-c".to_string(),
+                        "import sys; print(sys.argv[1])".to_string(),
+                        "Prequery".to_string(),
+                    ]),
+                    eq(*b""),
+                )
+                .returning(|_, _| Ok(br#"Prequery\n"#.to_vec()));
+
+            // separate output files
+            world
+                .expect_write_output()
+                .with(
+                    eq(PathBuf::from("out1.json")),
+                    eq(*br#"Hello World\n"#),
+                )
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_output()
+                .with(
+                    eq(PathBuf::from("out2.json")),
+                    eq(*br#"Prequery\n"#),
+                )
+                .returning(|_, _| Ok(()));
         },
     )
     .run()
     .await
-    .expect_err("shell job should fail")
-    .expect_log(include_str!("shell/joined-python-failed-length.txt"));
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-args.txt"));
 }
 
-/// Run the shell preprocessor with two separate commands, saved to one file.
+/// Run the shell preprocessor with two separate commands, saved to separate files.
+/// The inputs are passed in temporary files, which are removed even if the command fails
 #[tokio::test]
 #[serial(shell)]
-async fn run_shell_python_output_outside_root() {
+async fn run_shell_python_input_file() {
     ShellTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -480,37 +590,91 @@ async fn run_shell_python_output_outside_root() {
 
         query.selector = "<python>"
 
-        command = "python"
+        command = ["python", "{input_file}"]
+        input_as = "file"
+        format.stdin = "plain"
+        format.stdout = "plain"
+        format.output = "plain"
         "#,
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"path": "../out.json"}, {"data": "print(\"Hello World\")"}, {"data": "print(\"Hello Prequery\")"}]"#,
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "out2.json", "data": "exit(1)"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
             world.expect_write_index().never();
 
-            // two code snippets
-            world.expect_run_command::<String>().never();
+            // each input is written to its own file
+            world
+                .expect_create_input_file()
+                .once()
+                .with(eq(*br#"print("Hello World")"#))
+                .returning(|_| Ok(PathBuf::from("/tmp/input-1")));
+            world
+                .expect_create_input_file()
+                .once()
+                .with(eq(*b"exit(1)"))
+                .returning(|_| Ok(PathBuf::from("/tmp/input-2")));
 
-            // one combined output file
-            world.expect_write_output().never();
-        },
-    )
-    .run()
-    .await
+            // the file paths are passed as arguments, with nothing on stdin
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "/tmp/input-1".to_string()]),
+                    eq(*b""),
+                )
+                .returning(|_, _| Ok(br#"Hello World\n"#.to_vec()));
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "/tmp/input-2".to_string()]),
+                    eq(*b""),
+                )
+                .returning(|_, _| {
+                    Err(prequery_preprocess::shell::CommandError::Process(
+                        io::ErrorKind::Other.into(),
+                    ))
+                });
+
+            // both files are removed
+            world
+                .expect_remove_input_file()
+                .once()
+                .with(eq(PathBuf::from("/tmp/input-1")))
+                .returning(|_| Ok(()));
+            world
+                .expect_remove_input_file()
+                .once()
+                .with(eq(PathBuf::from("/tmp/input-2")))
+                .returning(|_| Ok(()));
+
+            // the successful output is still saved
+            world
+                .expect_write_output()
+                .once()
+                .with(
+                    eq(PathBuf::from("out1.json")),
+                    eq(*br#"Hello World\n"#),
+                )
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
     .expect_err("shell job should fail")
-    .expect_log(include_str!("shell/python-failed-outside-root.txt"));
+    .expect_log(include_str!("shell/python-failed-input-file.txt"));
 }
 
-/// Run the shell preprocessor with two separate commands, saved to one file.
+/// Run the shell preprocessor with two joined commands, saved to one file.
 #[tokio::test]
 #[serial(shell)]
-async fn run_shell_python_joined_plain_text_input() {
+async fn run_shell_python_joined_snippets() {
     ShellTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -527,13 +691,12 @@ async fn run_shell_python_joined_plain_text_input() {
 
         command = ["python", "exec.py"]
         joined = true
-        format.stdin = "plain"
         "#,
         Query {
             selector: "<python>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
         br#"[{"path": "out.json"}, {"data": "x = 1\nprint(x)"}, {"data": "y = x + 1\nprint(y)"}]"#,
         |world| {
@@ -542,16 +705,1704 @@ async fn run_shell_python_joined_plain_text_input() {
             world.expect_write_index().never();
 
             // two code snippets
-            world.expect_run_command::<String>().never();
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "exec.py".to_string()]),
+                    eq(*br#"["x = 1\nprint(x)","y = x + 1\nprint(y)"]"#),
+                )
+                .returning(|_, _| Ok(br#"["1\n","2\n"]"#.to_vec()));
 
             // one combined output file
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out.json")), eq(*br#"["1\n","2\n"]"#))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/joined-python.txt"));
+}
+
+/// Run the shell preprocessor with two joined commands, saved to separate files.
+/// Files are saved as plain text
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_joined_snippets_separate_files() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "exec.py"]
+        joined = true
+        format.output = "plain"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out1.json", "data": "x = 1\nprint(x)"}, {"path": "out2.json", "data": "y = x + 1\nprint(y)"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // two code snippets
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "exec.py".to_string()]),
+                    eq(*br#"["x = 1\nprint(x)","y = x + 1\nprint(y)"]"#),
+                )
+                .returning(|_, _| Ok(br#"["1\n","2\n"]"#.to_vec()));
+
+            // separate output files
+            world
+                .expect_write_output()
+                .with(
+                    eq(PathBuf::from("out1.json")),
+                    eq(*b"1\n"),
+                )
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_output()
+                .with(
+                    eq(PathBuf::from("out2.json")),
+                    eq(*b"2\n"),
+                )
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/joined-python-separate.txt"));
+}
+
+/// Run the shell preprocessor, but the command fails.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_failed_process() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python"]
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json", "data": ""}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one command, fails
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""""#))
+                .returning(|_, _| {
+                    Err(prequery_preprocess::shell::CommandError::Process(
+                        io::ErrorKind::Other.into(),
+                    ))
+                });
+
             world.expect_write_output().never();
         },
     )
     .run()
     .await
     .expect_err("shell job should fail")
-    .expect_log(include_str!(
-        "shell/python-failed-joined-plain-text-input.txt"
-    ));
+    .expect_log(include_str!("shell/python-failed-process.txt"));
+}
+
+/// Run the shell preprocessor with one command that exits with a non-zero code, which is
+/// configured as a success code.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_success_codes() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python"]
+        success_codes = [0, 1]
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json", "data": ""}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one command, exits with code 1
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""""#))
+                .returning(|_, _| {
+                    Err(prequery_preprocess::shell::CommandError::ExitStatus(
+                        ExitStatus::from_raw(1 << 8),
+                        br#""Hello World""#.to_vec(),
+                    ))
+                });
+
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out.json")), eq(*br#""Hello World""#))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-success-codes.txt"));
+}
+
+/// Run the shell preprocessor with one command that exits with code 0, which is not configured as
+/// a success code.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_failed_success_codes() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python"]
+        success_codes = [1]
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json", "data": ""}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one command, exits with code 0
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""""#))
+                .returning(|_, _| Ok(br#""Hello World""#.to_vec()));
+
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-success-codes.txt"));
+}
+
+/// Run the shell preprocessor with two separate commands, saved to separate files. The command
+/// exiting with the configured success code is saved, the one exiting with code 0 fails.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_separate_failed_success_codes() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        success_codes = [1]
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out1.json", "data": "exit(1)"}, {"path": "out2.json", "data": "exit(0)"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // two code snippets, one exits with code 1, the other with code 0
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""exit(1)""#))
+                .returning(|_, _| {
+                    Err(prequery_preprocess::shell::CommandError::ExitStatus(
+                        ExitStatus::from_raw(1 << 8),
+                        br#""Hello World""#.to_vec(),
+                    ))
+                });
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""exit(0)""#))
+                .returning(|_, _| Ok(br#""Hello Prequery""#.to_vec()));
+
+            // only the result of the configured success code is saved
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out1.json")), eq(*br#""Hello World""#))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!(
+        "shell/python-failed-partial-success-codes.txt"
+    ));
+}
+
+/// Run the shell preprocessor without any success codes.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_empty_success_codes() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python"]
+        success_codes = []
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world.expect_run_command::<String>().never();
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-empty-success-codes.txt"));
+}
+
+/// Run the shell preprocessor with one command, but the command doesn't return JSON.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_invalid_output() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json", "data": ""}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one code snippet
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""""#))
+                .returning(|_, _| Ok(br#"not JSON"#.to_vec()));
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-invalid-output.txt"));
+}
+
+/// Run the shell preprocessor with one command, but the command doesn't return anything.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_empty_output() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json", "data": ""}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one code snippet
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""""#))
+                .returning(|_, _| Ok(Vec::new()));
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-empty-output.txt"));
+}
+
+/// Run the shell preprocessor with one command, but the command doesn't return anything.
+/// Empty output is allowed and saved as `null`.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_empty_output_allowed() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        allow_empty = true
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json", "data": ""}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one code snippet
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#""""#))
+                .returning(|_, _| Ok(Vec::new()));
+
+            // the empty output is saved as null
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out.json")), eq(*b"null"))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-empty-output.txt"));
+}
+
+/// Run the shell preprocessor, but the command for the joined inputs returns an array of the wrong length.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_joined_wrong_length() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "exec.py"]
+        joined = true
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json"}, {"data": ""}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one code snippet, returns as if there were two
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "exec.py".to_string()]),
+                    eq(*br#"[""]"#),
+                )
+                .returning(|_, _| Ok(br#"["",""]"#.to_vec()));
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/joined-python-failed-length.txt"));
+}
+
+/// Run the shell preprocessor with two separate commands, saved to one file.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_output_outside_root() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "../out.json"}, {"data": "print(\"Hello World\")"}, {"data": "print(\"Hello Prequery\")"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // two code snippets
+            world.expect_run_command::<String>().never();
+
+            // one combined output file
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-outside-root.txt"));
+}
+
+/// Run the shell preprocessor with two separate commands, saved to one file.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_joined_plain_text_input() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "exec.py"]
+        joined = true
+        format.stdin = "plain"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json"}, {"data": "x = 1\nprint(x)"}, {"data": "y = x + 1\nprint(y)"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // two code snippets
+            world.expect_run_command::<String>().never();
+
+            // one combined output file
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!(
+        "shell/python-failed-joined-plain-text-input.txt"
+    ));
+}
+
+/// Run the shell preprocessor with two separate commands, saved to separate files, one of which is
+/// outside the root.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_separate_output_outside_root() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "../out2.json", "data": "print(\"Hello Prequery\")"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no commands are run because an output path is invalid
+            world.expect_run_command::<String>().never();
+
+            // no outputs are written, not even the valid one
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-separate-outside-root.txt"));
+}
+
+/// Run the shell preprocessor with two separate commands, one of which is saved to stdout.
+/// This is not allowed, as only a single output can be written to stdout.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_separate_output_stdout() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "-", "data": "print(\"Hello Prequery\")"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no commands are run because stdout is only for a single output
+            world.expect_run_command::<String>().never();
+
+            // no outputs are written, not even the valid one
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-separate-stdout.txt"));
+}
+
+/// Run the shell preprocessor with two separate commands, saved to separate files.
+/// One command fails, but the other command's result is still saved.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_separate_partial_failure() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "out2.json", "data": "exit(1)"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // two code snippets, one fails
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#""print(\"Hello World\")""#),
+                )
+                .returning(|_, _| Ok(br#""Hello World\n""#.to_vec()));
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#""exit(1)""#),
+                )
+                .returning(|_, _| {
+                    Err(prequery_preprocess::shell::CommandError::Process(
+                        io::ErrorKind::Other.into(),
+                    ))
+                });
+
+            // only the successful result is saved
+            world
+                .expect_write_output()
+                .once()
+                .with(
+                    eq(PathBuf::from("out1.json")),
+                    eq(*br#""Hello World\n""#),
+                )
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-partial.txt"));
+}
+
+/// Run the shell preprocessor with two separate commands, saved to separate files.
+/// The first command fails, and the second is not run at all.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_separate_fail_fast() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        fail_fast = true
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out1.json", "data": "exit(1)"}, {"path": "out2.json", "data": "print(\"Hello World\")"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // two code snippets, the first fails
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#""exit(1)""#),
+                )
+                .returning(|_, _| {
+                    Err(prequery_preprocess::shell::CommandError::Process(
+                        io::ErrorKind::Other.into(),
+                    ))
+                });
+            world.expect_run_command()
+                .never()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#""print(\"Hello World\")""#),
+                );
+
+            // nothing is saved
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-fail-fast.txt"));
+}
+
+/// Run the shell preprocessor with one command processing the whole query result.
+/// The query result is passed on verbatim.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_whole() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "transform.py"]
+        mode = "whole"
+        output = "out.json"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"b": 1, "a": 2}, "x"]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one command for everything
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "transform.py".to_string()]),
+                    eq(*br#"[{"b": 1, "a": 2}, "x"]"#),
+                )
+                .returning(|_, _| Ok(br#"{"count": 2}"#.to_vec()));
+
+            // one output file from the manifest
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out.json")), eq(*br#"{"count":2}"#))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/whole-python.txt"));
+}
+
+/// A shell job with `field = false` queries whole elements and passes them to the command verbatim.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_whole_no_field() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+        query.field = false
+
+        command = ["python", "transform.py"]
+        mode = "whole"
+        output = "out.json"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: None,
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"func": "metadata", "value": "x", "label": "<python>"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // the elements are passed with their labels
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "transform.py".to_string()]),
+                    eq(*br#"[{"func": "metadata", "value": "x", "label": "<python>"}]"#),
+                )
+                .returning(|_, _| Ok(br#"{"count": 1}"#.to_vec()));
+
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out.json")), eq(*br#"{"count":1}"#))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/whole-python.txt"));
+}
+
+/// A shell job whose output would escape the root fails validation, before the query is run.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_whole_output_outside_root() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "transform.py"]
+        mode = "whole"
+        output = "../out.json"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"b": 1, "a": 2}, "x"]"#,
+        |world| {
+            // nothing is run or written
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+            world.expect_run_command::<String>().never();
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail validation")
+    .expect_log(include_str!("shell/whole-python-failed-outside-root.txt"));
+}
+
+/// Run the shell preprocessor with one command on the whole query result, written to stdout.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_whole_stdout() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "transform.py"]
+        mode = "whole"
+        output = "-"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"b": 1, "a": 2}, "x"]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one command for everything
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "transform.py".to_string()]),
+                    eq(*br#"[{"b": 1, "a": 2}, "x"]"#),
+                )
+                .returning(|_, _| Ok(br#"{"count": 2}"#.to_vec()));
+
+            // stdout is not resolved relative to the root
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("-")), eq(*br#"{"count":2}"#))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/whole-python-stdout.txt"));
+}
+
+/// Run the shell preprocessor with one command on the whole query result, whose output is split
+/// into multiple files.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_whole_split_output() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "transform.py"]
+        mode = "whole"
+        format.output = "split"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"b": 1, "a": 2}, "x"]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one command for everything
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "transform.py".to_string()]),
+                    eq(*br#"[{"b": 1, "a": 2}, "x"]"#),
+                )
+                .returning(|_, _| Ok(br#"{"a.json": {"count": 2}, "b.txt": "x"}"#.to_vec()));
+
+            // one output file per key of the command output
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("a.json")), eq(*br#"{"count":2}"#))
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("b.txt")), eq(*b"x"))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/whole-python-split.txt"));
+}
+
+/// Run the shell preprocessor with one command on the whole query result, whose output is split
+/// into multiple files, two of which are the same. No file should be saved.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_whole_split_output_collision() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "transform.py"]
+        mode = "whole"
+        format.output = "split"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"b": 1, "a": 2}, "x"]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one command for everything
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "transform.py".to_string()]),
+                    eq(*br#"[{"b": 1, "a": 2}, "x"]"#),
+                )
+                .returning(|_, _| Ok(br#"{"a.json": {"count": 2}, "./a.json": "x"}"#.to_vec()));
+
+            // the collision is detected before saving anything
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!(
+        "shell/whole-python-failed-split-collision.txt"
+    ));
+}
+
+/// Run the shell preprocessor with one command processing an input file instead of a query result.
+/// The file's content is passed on verbatim, and no query is executed.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_file() {
+    let mut test = ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        command = ["python", "transform.py"]
+        mode = "file"
+        input = "config.json"
+        output = "out.json"
+        "#,
+        Query {
+            selector: "".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // the input file from the manifest
+            world
+                .expect_read_input()
+                .once()
+                .with(eq(PathBuf::from("config.json")))
+                .returning(|_| Ok(br#"{"b": 1, "a": 2}"#.to_vec()));
+
+            // one command for the input file
+            world
+                .expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string(), "transform.py".to_string()]),
+                    eq(*br#"{"b": 1, "a": 2}"#),
+                )
+                .returning(|_, _| Ok(br#"{"count": 2}"#.to_vec()));
+
+            // one output file from the manifest
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out.json")), eq(*br#"{"count":2}"#))
+                .returning(|_, _| Ok(()));
+        },
+    );
+    // no query in file mode
+    test.test.world.expect_query_impl().never();
+    test.run()
+        .await
+        .expect_ok("shell job should succeed")
+        .expect_log(include_str!("shell/file-python.txt"));
+}
+
+/// Run the shell preprocessor in whole mode, but without an output file.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_whole_without_output() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["python", "transform.py"]
+        mode = "whole"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world.expect_run_command::<String>().never();
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/whole-python-failed-without-output.txt"));
+}
+
+/// Run the shell preprocessor with two separate commands, saved to separate files.
+/// Trailing newlines of the outputs are normalized to exactly one
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_separate_trim() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        format.stdin = "plain"
+        format.stdout = "plain"
+        format.output = "plain"
+        format.trim = "ensure-final-newline"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out1.txt", "data": "print(\"Hello World\")"}, {"path": "out2.txt", "data": "print(\"Hello Prequery\", end=\"\")"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // two code snippets
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello World")"#),
+                )
+                .returning(|_, _| Ok(b"Hello World\n\n".to_vec()));
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello Prequery", end="")"#),
+                )
+                .returning(|_, _| Ok(b"Hello Prequery".to_vec()));
+
+            // separate output files, each ending in exactly one newline
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out1.txt")), eq(*b"Hello World\n"))
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out2.txt")), eq(*b"Hello Prequery\n"))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-separate.txt"));
+}
+
+/// Run the shell preprocessor, but the command's executable does not exist.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_command_not_found() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = ["pyhton"]
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.json", "data": ""}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // one command, can't be started
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["pyhton".to_string()]), eq(*br#""""#))
+                .returning(|_, _| {
+                    Err(prequery_preprocess::shell::CommandError::NotFound(
+                        "pyhton".to_string(),
+                    ))
+                });
+
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-not-found.txt"));
+}
+
+/// Run the shell preprocessor with three separate commands, saved to separate files. One item is
+/// excluded and should not be processed.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_separate_exclude() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        format.stdin = "plain"
+        format.stdout = "plain"
+        format.output = "plain"
+        exclude = ["vendored/*.json"]
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out1.json", "data": "print(\"Hello World\")"}, {"path": "vendored/out.json", "data": "print(\"Hello Vendor\")"}, {"path": "out2.json", "data": "print(\"Hello Prequery\")"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // two code snippets; the excluded one is not run
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello World")"#),
+                )
+                .returning(|_, _| Ok(br#"Hello World\n"#.to_vec()));
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"print("Hello Prequery")"#),
+                )
+                .returning(|_, _| Ok(br#"Hello Prequery\n"#.to_vec()));
+
+            // separate output files
+            world
+                .expect_write_output()
+                .once()
+                .with(
+                    eq(PathBuf::from("out1.json")),
+                    eq(*br#"Hello World\n"#),
+                )
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_output()
+                .once()
+                .with(
+                    eq(PathBuf::from("out2.json")),
+                    eq(*br#"Hello Prequery\n"#),
+                )
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-separate.txt"));
+}
+
+/// Run the shell preprocessor with two separate commands, whose output paths are rendered from a
+/// path template unless given explicitly.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_path_template() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        path_template = "figs/{data.meta.id}-{data.meta.n}.txt"
+        format.stdout = "plain"
+        format.output = "plain"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"data": {"meta": {"id": "hello", "n": 1}}}, {"path": "out.txt", "data": {"meta": {}}}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world.expect_run_command()
+                .once()
+                .with(
+                    eq(["python".to_string()]),
+                    eq(*br#"{"meta":{"id":"hello","n":1}}"#),
+                )
+                .returning(|_, _| Ok(b"Hello World\n".to_vec()));
+            world.expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#"{"meta":{}}"#))
+                .returning(|_, _| Ok(b"Hello Prequery\n".to_vec()));
+
+            // the first path is rendered, the second one is explicit
+            world
+                .expect_write_output()
+                .once()
+                .with(
+                    eq(PathBuf::from("figs/hello-1.txt")),
+                    eq(*b"Hello World\n"),
+                )
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_output()
+                .once()
+                .with(eq(PathBuf::from("out.txt")), eq(*b"Hello Prequery\n"))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-separate.txt"));
+}
+
+/// Run the shell preprocessor with a path template referencing a field one item doesn't have.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_path_template_undefined_field() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        path_template = "figs/{data.id}.txt"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"data": {"id": "hello"}}, {"data": {"name": "prequery"}}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no commands are run because an output path can't be rendered
+            world.expect_run_command::<String>().never();
+            world.expect_write_output().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("shell job should fail")
+    .expect_log(include_str!("shell/python-failed-path-template.txt"));
+}
+
+/// Run the shell preprocessor with a command that returns binary data which is not valid UTF8.
+/// Since the output is plain, the bytes are saved verbatim.
+#[tokio::test]
+#[serial(shell)]
+async fn run_shell_python_binary_plain_output() {
+    ShellTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "python"
+        kind = "shell"
+
+        query.selector = "<python>"
+
+        command = "python"
+        format.stdin = "plain"
+        format.stdout = "plain"
+        format.output = "plain"
+        "#,
+        Query {
+            selector: "<python>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"path": "out.png", "data": "print(\"binary\")"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_run_command()
+                .once()
+                .with(eq(["python".to_string()]), eq(*br#"print("binary")"#))
+                .returning(|_, _| Ok(b"\x89PNG\r\n\x1a\n\xff\xfe\x00\n".to_vec()));
+
+            // the bytes are saved unchanged
+            world
+                .expect_write_output()
+                .once()
+                .with(
+                    eq(PathBuf::from("out.png")),
+                    eq(*b"\x89PNG\r\n\x1a\n\xff\xfe\x00\n"),
+                )
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("shell job should succeed")
+    .expect_log(include_str!("shell/python-binary.txt"));
 }
@@ -1,10 +1,28 @@
-use std::io;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
-use mockall::predicate::eq;
+use clap::Parser;
+
+use mockall::Sequence;
+use mockall::predicate::{eq, function};
+use prequery_preprocess::VecLog;
+use prequery_preprocess::args::CliArguments;
+use prequery_preprocess::lockfile::{HashAlgorithm, LockedResource, Lockfile};
+use prequery_preprocess::manifest::{self, FileMode, JobDefaults};
+use prequery_preprocess::preprocessor::PreprocessorDefinition;
 use prequery_preprocess::query::Query;
-use prequery_preprocess::web_resource::index::{Index, Resource};
-use prequery_preprocess::web_resource::{MockWorld, MockWorld_NewContext, WebResourceFactory};
+use prequery_preprocess::web_resource::credentials::Credentials;
+use prequery_preprocess::web_resource::index::{Index, Resource, Validators};
+use prequery_preprocess::web_resource::manifest::{Manifest, Validate};
+use prequery_preprocess::web_resource::proxy::ProxyConfig;
+use prequery_preprocess::web_resource::{
+    DownloadError, MockWorld, MockWorld_NewContext, MultipleDownloadError, WebResourceFactory,
+};
+use prequery_preprocess::world::FileLock;
+use reqwest::StatusCode;
 use serial_test::serial;
 
 mod common;
@@ -23,10 +41,17 @@ impl WebResourceTest {
         cfg_world: impl Fn(&mut MockWorld) + Send + 'static,
     ) -> Self {
         let ctx = MockWorld::new_context();
-        ctx.expect().returning(move |main| {
+        ctx.expect().returning(move |main, _manifest, _progress| {
             let mut world = MockWorld::default();
             world.expect_main().return_const(main);
+            world
+                .expect_lock_index()
+                .returning(|_| Ok(FileLock::default()));
             cfg_world(&mut world);
+            // downloaded resources are hashed for the index, unless a test expects otherwise
+            world
+                .expect_hash_resource()
+                .returning(|_, algorithm| Ok(format!("{}:00", algorithm.name())));
             world
         });
 
@@ -68,8 +93,128 @@ async fn run_web_resource_no_resources_no_index() {
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no resources in the query result
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/no-resources.txt"));
+}
+
+/// Run the web resource preprocessor on a document that doesn't import prequery.
+/// The query finds nothing, and a warning about the missing import should be logged.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_no_prequery_import() {
+    let mut test = WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    );
+    test.test.source = "#image(\"example.png\")\n";
+    test.run()
+        .await
+        .expect_ok("download job should succeed")
+        .expect_log(include_str!("web-resource/no-prequery-import.txt"));
+}
+
+/// Run the web resource preprocessor in strict mode on a document that doesn't import prequery.
+/// The missing import should be an error instead of a warning.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_no_prequery_import_strict() {
+    let mut test = WebResourceTest::new(
+        &["prequery-preprocess", "--strict", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    );
+    test.test.source = "#image(\"example.png\")\n";
+    test.run()
+        .await
+        .expect_err("download job should fail")
+        .expect_log(include_str!(
+            "web-resource/failed-no-prequery-import-strict.txt"
+        ));
+}
+
+/// Run the web resource preprocessor with its own Typst executable.
+/// The query should be run using that executable.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_job_typst() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        typst = "typst-0.11"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            typst: Some("typst-0.11".into()),
+            ..Default::default()
         },
         br#"[]"#,
         |world| {
@@ -109,14 +254,14 @@ async fn run_web_resource_no_resources_with_index() {
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
         br#"[]"#,
         |world| {
             world
                 .expect_read_index()
-                .once()
+                .times(2)
                 .with(eq(PathBuf::from("web-resource-index.toml")))
                 .returning(|location| Ok(Index::new(location.to_path_buf())));
             world
@@ -156,8 +301,8 @@ async fn run_web_resource_download_not_found() {
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
         br#"[{"url": "https://example.com/exampl.png", "path": "assets/example.png"}]"#,
         |world| {
@@ -176,8 +321,11 @@ async fn run_web_resource_download_not_found() {
                 .with(
                     eq(PathBuf::from("assets/example.png")),
                     eq("https://example.com/exampl.png"),
+                    eq(None),
                 )
-                .returning(|_, _| Err(io::Error::new(io::ErrorKind::NotFound, "not found").into()));
+                .returning(|_, _, _| {
+                    Err(io::Error::new(io::ErrorKind::NotFound, "not found").into())
+                });
         },
     )
     .run()
@@ -187,10 +335,10 @@ async fn run_web_resource_download_not_found() {
 }
 
 /// Run the web resource preprocessor with one resource and no index.
-/// The resource is outside the root and should not be downloaded.
+/// The server responds with an error status, which should be reported with the response body.
 #[tokio::test]
 #[serial(web_resource)]
-async fn run_web_resource_download_outside_root() {
+async fn run_web_resource_download_error_status() {
     WebResourceTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -206,30 +354,47 @@ async fn run_web_resource_download_outside_root() {
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"url": "https://example.com/example.png", "path": "../example.png"}]"#,
+        br#"[{"url": "https://example.com/private.png", "path": "assets/example.png"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
             world.expect_write_index().never();
 
-            world.expect_resource_exists().never();
-            world.expect_download().never();
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/private.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| {
+                    Err(DownloadError::Status {
+                        status: StatusCode::FORBIDDEN,
+                        body: Some("access token expired".to_string()),
+                    })
+                });
         },
     )
     .run()
     .await
-    .expect_err("access to file outside root should be denied")
-    .expect_log(include_str!("web-resource/fail-outside-root.txt"));
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/fail-status.txt"));
 }
 
 /// Run the web resource preprocessor with one resource and no index.
-/// The resource does not exist locally and should be downloaded.
+/// The connection is dropped early, so fewer bytes than announced are received.
 #[tokio::test]
 #[serial(web_resource)]
-async fn run_web_resource_no_index_missing() {
+async fn run_web_resource_download_truncated() {
     WebResourceTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -245,8 +410,8 @@ async fn run_web_resource_no_index_missing() {
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
         br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
         |world| {
@@ -265,21 +430,26 @@ async fn run_web_resource_no_index_missing() {
                 .with(
                     eq(PathBuf::from("assets/example.png")),
                     eq("https://example.com/example.png"),
+                    eq(None),
                 )
-                .returning(|_, _| Ok(()));
+                .returning(|_, _, _| {
+                    Err(DownloadError::Truncated {
+                        expected: 2048,
+                        got: 1024,
+                    })
+                });
         },
     )
     .run()
     .await
-    .expect_ok("download job should succeed")
-    .expect_log(include_str!("web-resource/success.txt"));
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/fail-truncated.txt"));
 }
 
-/// Run the web resource preprocessor with one resource and no index.
-/// The resource exists locally and should not be downloaded.
+/// A login page is served instead of the expected JSON, so the download fails.
 #[tokio::test]
 #[serial(web_resource)]
-async fn run_web_resource_no_index_existing() {
+async fn run_web_resource_download_validation() {
     WebResourceTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -291,14 +461,15 @@ async fn run_web_resource_no_index_existing() {
         [[tool.prequery.jobs]]
         name = "download"
         kind = "web-resource"
+        validate = { key = "data.items" }
         "#,
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        br#"[{"url": "https://example.com/data.json", "path": "assets/data.json"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
@@ -307,22 +478,35 @@ async fn run_web_resource_no_index_existing() {
             world
                 .expect_resource_exists()
                 .once()
-                .with(eq(PathBuf::from("assets/example.png")))
-                .return_const(true);
-            world.expect_download().never();
+                .with(eq(PathBuf::from("assets/data.json")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/data.json")),
+                    eq("https://example.com/data.json"),
+                    eq(Some(Validate::Key("data.items".to_string()))),
+                )
+                .returning(|location, _, validate| {
+                    // the world checks the content before moving it into place
+                    let content = b"<html><body>Please log in</body></html>";
+                    let error = validate.as_ref().unwrap().check(content).unwrap_err();
+                    Err(DownloadError::Validation(location.to_path_buf(), error))
+                });
         },
     )
     .run()
     .await
-    .expect_ok("download job should succeed")
-    .expect_log(include_str!("web-resource/success-existing.txt"));
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/fail-validation.txt"));
 }
 
 /// Run the web resource preprocessor with one resource and no index.
-/// The resource exists locally and should be re-downloaded according to the manifest.
+/// The resource is outside the root and should not be downloaded.
 #[tokio::test]
 #[serial(web_resource)]
-async fn run_web_resource_no_index_existing_forced() {
+async fn run_web_resource_download_outside_root() {
     WebResourceTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -334,47 +518,34 @@ async fn run_web_resource_no_index_existing_forced() {
         [[tool.prequery.jobs]]
         name = "download"
         kind = "web-resource"
-        overwrite = true
         "#,
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        br#"[{"url": "https://example.com/example.png", "path": "../example.png"}]"#,
         |world| {
             // no index specified in the manifest
             world.expect_read_index().never();
             world.expect_write_index().never();
 
-            world
-                .expect_resource_exists()
-                .once()
-                .with(eq(PathBuf::from("assets/example.png")))
-                .return_const(true);
-            world
-                .expect_download()
-                .once()
-                .with(
-                    eq(PathBuf::from("assets/example.png")),
-                    eq("https://example.com/example.png"),
-                )
-                .returning(|_, _| Ok(()));
+            world.expect_resource_exists().never();
+            world.expect_download().never();
         },
     )
     .run()
     .await
-    .expect_ok("download job should succeed")
-    .expect_log(include_str!("web-resource/success-forced.txt"));
+    .expect_err("access to file outside root should be denied")
+    .expect_log(include_str!("web-resource/fail-outside-root.txt"));
 }
 
-/// Run the web resource preprocessor with one resource and an index.
-/// The resource does not exist locally and should be downloaded.
-/// The index should be saved with the downloaded resource in it.
+/// Run the web resource preprocessor with two resources, one of which is not from an allowed host.
+/// The other resource should still be downloaded.
 #[tokio::test]
 #[serial(web_resource)]
-async fn run_web_resource_with_index_missing() {
+async fn run_web_resource_host_not_allowed() {
     WebResourceTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -386,34 +557,21 @@ async fn run_web_resource_with_index_missing() {
         [[tool.prequery.jobs]]
         name = "download"
         kind = "web-resource"
-        index = true
+        allowed_hosts = ["Example.com"]
         "#,
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}, {"url": "https://cdn.example.com/other.png", "path": "assets/other.png"}]"#,
         |world| {
-            world
-                .expect_read_index()
-                .once()
-                .with(eq(PathBuf::from("web-resource-index.toml")))
-                .returning(|location| Ok(Index::new(location.to_path_buf())));
-            world
-                .expect_write_index()
-                .once()
-                .with(eq({
-                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
-                    index.update(Resource {
-                        path: PathBuf::from("assets/example.png"),
-                        url: "https://example.com/example.png".to_string(),
-                    });
-                    index
-                }))
-                .returning(|_| Ok(()));
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
 
+            // the resource from the subdomain is rejected before anything else
             world
                 .expect_resource_exists()
                 .once()
@@ -425,22 +583,22 @@ async fn run_web_resource_with_index_missing() {
                 .with(
                     eq(PathBuf::from("assets/example.png")),
                     eq("https://example.com/example.png"),
+                    eq(None),
                 )
-                .returning(|_, _| Ok(()));
+                .returning(|_, _, _| Ok(Default::default()));
         },
     )
     .run()
     .await
-    .expect_ok("download job should succeed")
-    .expect_log(include_str!("web-resource/success.txt"));
+    .expect_err("download from a host that is not allowed should fail")
+    .expect_log(include_str!("web-resource/fail-host-not-allowed.txt"));
 }
 
-/// Run the web resource preprocessor with one resource and an index.
-/// The resource exists locally and should not be downloaded.
-/// The index should be saved with the downloaded resource in it (no change).
+/// Run the web resource preprocessor with a malformed URL.
+/// The job should fail without downloading anything.
 #[tokio::test]
 #[serial(web_resource)]
-async fn run_web_resource_with_index_existing() {
+async fn run_web_resource_invalid_url() {
     WebResourceTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -452,61 +610,34 @@ async fn run_web_resource_with_index_existing() {
         [[tool.prequery.jobs]]
         name = "download"
         kind = "web-resource"
-        index = true
         "#,
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        br#"[{"url": "example.com/example.png", "path": "assets/example.png"}]"#,
         |world| {
-            world
-                .expect_read_index()
-                .once()
-                .with(eq(PathBuf::from("web-resource-index.toml")))
-                .returning(|location| {
-                    let mut index = Index::new(location.to_path_buf());
-                    index.update(Resource {
-                        path: PathBuf::from("assets/example.png"),
-                        url: "https://example.com/example.png".to_string(),
-                    });
-                    Ok(index)
-                });
-            world
-                .expect_write_index()
-                .once()
-                .with(eq({
-                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
-                    index.update(Resource {
-                        path: PathBuf::from("assets/example.png"),
-                        url: "https://example.com/example.png".to_string(),
-                    });
-                    index
-                }))
-                .returning(|_| Ok(()));
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
 
-            world
-                .expect_resource_exists()
-                .once()
-                .with(eq(PathBuf::from("assets/example.png")))
-                .return_const(true);
+            world.expect_resource_exists().never();
             world.expect_download().never();
         },
     )
     .run()
     .await
-    .expect_ok("download job should succeed")
-    .expect_log(include_str!("web-resource/success-existing.txt"));
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/failed-invalid-url.txt"));
 }
 
-/// Run the web resource preprocessor with one resource and an index.
-/// The resource exists locally and should be re-downloaded according to the manifest.
-/// The index should be saved with the downloaded resource in it (no change).
+/// Run the web resource preprocessor with environment variables in a resource's URL and path.
+/// The resource should be downloaded using the expanded URL and path.
 #[tokio::test]
 #[serial(web_resource)]
-async fn run_web_resource_with_index_existing_forced() {
+async fn run_web_resource_env_expansion() {
     WebResourceTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -518,69 +649,3504 @@ async fn run_web_resource_with_index_existing_forced() {
         [[tool.prequery.jobs]]
         name = "download"
         kind = "web-resource"
-        index = true
-        overwrite = true
         "#,
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
-        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        // Cargo sets this variable when running tests
+        br#"[{"url": "https://${env:CARGO_PKG_NAME}.example.com/example.png", "path": "assets/${env:CARGO_PKG_NAME}.png"}]"#,
         |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
             world
-                .expect_read_index()
+                .expect_resource_exists()
                 .once()
-                .with(eq(PathBuf::from("web-resource-index.toml")))
-                .returning(|location| {
-                    let mut index = Index::new(location.to_path_buf());
-                    index.update(Resource {
-                        path: PathBuf::from("assets/example.png"),
-                        url: "https://example.com/example.png".to_string(),
-                    });
-                    Ok(index)
-                });
+                .with(eq(PathBuf::from("assets/prequery-preprocess.png")))
+                .return_const(false);
             world
-                .expect_write_index()
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/prequery-preprocess.png")),
+                    eq("https://prequery-preprocess.example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-env.txt"));
+}
+
+/// Run the web resource preprocessor with an unset environment variable in a resource's URL.
+/// The job should fail without downloading anything.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_env_missing() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://${env:PREQUERY_TEST_UNSET}/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/failed-env-missing.txt"));
+}
+
+/// Run the web resource preprocessor with a base URL and one relative and one absolute URL.
+/// The relative URL should be resolved against the base URL, the absolute one used as-is.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_base_url() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        base_url = "https://example.com/assets/"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[
+            {"url": "images/example.png", "path": "assets/example.png"},
+            {"url": "https://example.org/other.png", "path": "assets/other.png"}
+        ]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world.expect_resource_exists().times(2).return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/assets/images/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/other.png")),
+                    eq("https://example.org/other.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-base-url.txt"));
+}
+
+/// Run the web resource preprocessor with a URL that doesn't use HTTP.
+/// The job should fail without downloading anything.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_unsupported_scheme() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "ftp://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/failed-unsupported-scheme.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and no index.
+/// The resource does not exist locally and should be downloaded.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_no_index_missing() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Builds a tar archive with the given entries, each with its name, type flag and content.
+fn tar(entries: &[(&str, u8, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (name, kind, content) in entries {
+        let mut header = tar::Header::new_ustar();
+        header.set_path(name).unwrap();
+        header.set_size(content.len() as u64);
+        header.set_entry_type(tar::EntryType::new(*kind));
+        header.set_cksum();
+        builder.append(&header, *content).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+/// Builds a zip archive with the given uncompressed entries, each with its name and content,
+/// followed by symbolic links with the given names.
+fn zip(entries: &[(&str, &[u8])], links: &[&str]) -> Vec<u8> {
+    let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    let options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, content) in entries {
+        writer.start_file(*name, options).unwrap();
+        writer.write_all(content).unwrap();
+    }
+    for name in links {
+        writer.add_symlink(*name, "target", options).unwrap();
+    }
+    writer.finish().unwrap().into_inner()
+}
+
+/// Run the web resource preprocessor with one archive and no index.
+/// The archive is downloaded and its files are extracted next to it, without their common
+/// top-level directory.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_extract_tar() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        extract.strip_components = 1
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/fonts.tar", "path": "fonts/fonts.tar"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("fonts/fonts.tar")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("fonts/fonts.tar")),
+                    eq("https://example.com/fonts.tar"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_read_resource()
+                .once()
+                .with(eq(PathBuf::from("fonts/fonts.tar")))
+                .returning(|_| {
+                    Ok(tar(&[
+                        ("README", b'0', b"not extracted"),
+                        ("fonts-1.0/", b'5', b""),
+                        ("fonts-1.0/a.ttf", b'0', b"a"),
+                        ("fonts-1.0/link.ttf", b'2', b""),
+                        ("fonts-1.0/sub/b.ttf", b'0', b"b"),
+                        ("fonts-1.0/c.ttf", b'7', b"c"),
+                    ]))
+                });
+
+            // only regular (including contiguous) files below the stripped directory are extracted
+            world
+                .expect_write_extracted()
+                .once()
+                .with(eq(PathBuf::from("fonts/a.ttf")), eq(*b"a"))
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_extracted()
+                .once()
+                .with(eq(PathBuf::from("fonts/sub/b.ttf")), eq(*b"b"))
+                .returning(|_, _| Ok(()));
+            world
+                .expect_write_extracted()
+                .once()
+                .with(eq(PathBuf::from("fonts/c.ttf")), eq(*b"c"))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-extract.txt"));
+}
+
+/// Run the web resource preprocessor with one archive and no index.
+/// One of the archive's files would be outside the root, so nothing is extracted.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_extract_zip_outside_root() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        extract = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/assets.zip", "path": "assets/assets.zip"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/assets.zip")),
+                    eq("https://example.com/assets.zip"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_read_resource()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .returning(|_| Ok(zip(&[("ok.txt", b"ok"), ("../../evil.txt", b"evil")], &[])));
+
+            world.expect_write_extracted().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/fail-extract-outside-root.txt"));
+}
+
+/// Run the web resource preprocessor with one archive and no index.
+/// One of the archive's files would be outside the archive's directory, though inside the root,
+/// so nothing is extracted.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_extract_zip_outside_directory() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        extract = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/assets.zip", "path": "assets/assets.zip"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/assets.zip")),
+                    eq("https://example.com/assets.zip"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_read_resource()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .returning(|_| Ok(zip(&[("ok.txt", b"ok"), ("../typst.toml", b"evil")], &[])));
+
+            world.expect_write_extracted().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!(
+        "web-resource/fail-extract-outside-directory.txt"
+    ));
+}
+
+/// Run the web resource preprocessor with one archive and no index.
+/// The archive's regular file is extracted, while its symbolic link is skipped.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_extract_zip() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        extract = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/assets.zip", "path": "assets/assets.zip"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/assets.zip")),
+                    eq("https://example.com/assets.zip"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_read_resource()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .returning(|_| Ok(zip(&[("ok.txt", b"ok")], &["link.txt"])));
+
+            world
+                .expect_write_extracted()
+                .once()
+                .with(eq(PathBuf::from("assets/ok.txt")), eq(*b"ok"))
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-extract-zip.txt"));
+}
+
+/// Run the web resource preprocessor with one archive and no index.
+/// One of the archive's files doesn't match its checksum, so nothing is extracted.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_extract_zip_corrupt() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        extract = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/assets.zip", "path": "assets/assets.zip"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/assets.zip")),
+                    eq("https://example.com/assets.zip"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_read_resource()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .returning(|_| {
+                    let mut archive = zip(&[("ok.txt", b"ok"), ("bad.txt", b"good")], &[]);
+                    // the stored content is changed after the checksum was computed
+                    let pos = archive.windows(4).position(|w| w == b"good").unwrap();
+                    archive[pos..pos + 4].copy_from_slice(b"evil");
+                    Ok(archive)
+                });
+
+            world.expect_write_extracted().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/fail-extract-corrupt.txt"));
+}
+
+/// Run the web resource preprocessor with one archive containing more files than allowed.
+/// Nothing is extracted.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_extract_zip_too_many_files() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        extract.max_files = 2
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/assets.zip", "path": "assets/assets.zip"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/assets.zip")),
+                    eq("https://example.com/assets.zip"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_read_resource()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.zip")))
+                .returning(|_| {
+                    Ok(zip(
+                        &[("a.txt", b"a"), ("b.txt", b"b"), ("c.txt", b"c")],
+                        &[],
+                    ))
+                });
+
+            world.expect_write_extracted().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/fail-extract-too-many-files.txt"));
+}
+
+/// Run the web resource preprocessor with one archive whose contents are larger than allowed.
+/// Nothing is extracted.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_extract_tar_too_large() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        extract.max_size = 4
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/assets.tar", "path": "assets/assets.tar"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.tar")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/assets.tar")),
+                    eq("https://example.com/assets.tar"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_read_resource()
+                .once()
+                .with(eq(PathBuf::from("assets/assets.tar")))
+                .returning(|_| Ok(tar(&[("a.txt", b'0', b"abc"), ("b.txt", b'0', b"def")])));
+
+            world.expect_write_extracted().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/fail-extract-too-large.txt"));
+}
+
+/// Run the web resource preprocessor with one resource, flattened into a destination directory.
+/// The resource should be downloaded to a file named after its URL.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_flatten() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        dest_dir = "assets"
+        flatten = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/images/example.png?size=large", "path": "images/large.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/images/example.png?size=large"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-flatten.txt"));
+}
+
+/// Run the web resource preprocessor with two resources that are flattened to the same file.
+/// The job should fail without downloading anything.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_flatten_collision() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        dest_dir = "assets"
+        flatten = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/a/example.png", "path": "a.png"}, {"url": "https://example.com/b/example.png", "path": "b.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/failed-flatten-collision.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and no index.
+/// The resource exists locally and should not be downloaded.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_no_index_existing() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-existing.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and no index.
+/// The job has a custom log prefix.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_log_prefix() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        log_prefix = "[{kind}:{name}]"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-log-prefix.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and no index.
+/// The resource exists locally and should be re-downloaded according to the manifest.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_no_index_existing_forced() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        overwrite = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-forced.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index.
+/// The resource does not exist locally and should be downloaded.
+/// The index should be saved with the downloaded resource in it.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_missing() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with two resources and an index, with per-resource options.
+/// The existing resource that sets `overwrite` should be re-downloaded, and the missing resource
+/// that opts out of the index should be downloaded without being recorded in the index.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_resource_options() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[
+            {"url": "https://example.com/example.png", "path": "assets/example.png", "overwrite": true},
+            {"url": "https://example.com/other.png", "path": "assets/other.png", "index": false}
+        ]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/other.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/other.png")),
+                    eq("https://example.com/other.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-resource-options.txt"));
+}
+
+/// Run the web resource preprocessor with two resources given by the same key, and an index.
+/// The URL should be minted once using the URL command, both resources should be downloaded from
+/// it, and the key should be recorded in the index.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_url_command() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        url_command = ["./presign.sh", "--expires", "1h"]
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[
+            {"key": "assets/example.png", "path": "assets/example.png"},
+            {"key": "assets/example.png", "path": "assets/copy.png"}
+        ]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    for path in ["assets/copy.png", "assets/example.png"] {
+                        index.update(Resource {
+                            path: PathBuf::from(path),
+                            url: "assets/example.png".to_string(),
+                            timestamp: Some(common::NOW),
+                            validators: Default::default(),
+                            hash: Some("sha256:00".to_string()),
+                        });
+                    }
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world.expect_resource_exists().times(2).return_const(false);
+            world
+                .expect_mint_url()
+                .once()
+                .with(
+                    eq(vec![
+                        "./presign.sh".to_string(),
+                        "--expires".to_string(),
+                        "1h".to_string(),
+                    ]),
+                    eq("assets/example.png"),
+                )
+                .returning(|_, _| Ok("https://example.com/example.png?signature=abc".to_string()));
+            world
+                .expect_download()
+                .times(2)
+                .with(
+                    function(|location: &Path| {
+                        location == Path::new("assets/example.png")
+                            || location == Path::new("assets/copy.png")
+                    }),
+                    eq("https://example.com/example.png?signature=abc"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-url-command.txt"));
+}
+
+/// Run the web resource preprocessor with a resource given by a key, but no URL command.
+/// The job should fail before downloading anything.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_key_without_url_command() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"key": "assets/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+            world.expect_resource_exists().never();
+            world.expect_mint_url().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("download job should fail")
+    .expect_log(include_str!("web-resource/failed-no-url-command.txt"));
+}
+
+/// Run the web resource preprocessor with one resource, an index and index checkpoints.
+/// The index should be saved after the download, and again at the end.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_checkpoint() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        checkpoint_every = 1
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // once before the downloads, and again for each write
+            world
+                .expect_read_index()
+                .times(3)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            // once as a checkpoint after the download, once at the end
+            world
+                .expect_write_index()
+                .times(2)
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index.
+/// The resource exists locally and should not be downloaded.
+/// The index should be saved with the downloaded resource in it (no change).
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_existing() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: None,
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: None,
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-existing.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index that is ignored because of
+/// `--no-index`. The resource exists locally, so it should not be downloaded.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_no_index_flag() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--no-index", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // the index specified in the manifest is ignored
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-existing.txt"));
+}
+
+/// Run the web resource preprocessor with a cache directory that contains the missing resource.
+/// The resource should be copied from the cache instead of being downloaded.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_cache_hit() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--cache-dir", "cache", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_copy_from_cache()
+                .once()
+                .with(
+                    eq(PathBuf::from("cache")),
+                    eq("https://example.com/example.png"),
+                    eq(PathBuf::from("assets/example.png")),
+                )
+                .returning(|_, _, _| Ok(true));
+            world.expect_download().never();
+            world.expect_copy_to_cache().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-cached.txt"));
+}
+
+/// Run the web resource preprocessor with a keyed resource that is in the cache, but whose minted
+/// URL is not from an allowed host. The resource should not be copied from the cache.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_cache_hit_host_not_allowed() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--cache-dir", "cache", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        url_command = ["./presign.sh"]
+        allowed_hosts = ["example.com"]
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"key": "assets/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_mint_url()
+                .once()
+                .with(
+                    eq(vec!["./presign.sh".to_string()]),
+                    eq("assets/example.png"),
+                )
+                .returning(|_, _| Ok("https://cdn.example.com/example.png".to_string()));
+            world.expect_resource_exists().never();
+            world.expect_copy_from_cache().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("copying from a host that is not allowed should fail")
+    .expect_log(include_str!(
+        "web-resource/fail-cached-host-not-allowed.txt"
+    ));
+}
+
+/// Run the web resource preprocessor with a cache directory that doesn't contain the missing
+/// resource. The resource should be downloaded and added to the cache.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_cache_miss() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--cache-dir", "cache", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_copy_from_cache()
+                .once()
+                .returning(|_, _, _| Ok(false));
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_copy_to_cache()
+                .once()
+                .with(
+                    eq(PathBuf::from("cache")),
+                    eq("https://example.com/example.png"),
+                    eq(PathBuf::from("assets/example.png")),
+                )
+                .returning(|_, _, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and a layered index.
+/// The resource exists locally and is recorded in the read-only index, so it should not be
+/// downloaded. The written index should stay empty.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_layered_index_existing() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = { read = ["base.toml"], write = "local.toml" }
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("local.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            world
+                .expect_read_index()
+                .once()
+                .with(eq(PathBuf::from("base.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: None,
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq(Index::new(PathBuf::from("local.toml"))))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-existing.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index that another job changes while
+/// the resource is downloaded. The index should be saved with both jobs' resources in it.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_changed_concurrently() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            fn other() -> Resource {
+                Resource {
+                    path: PathBuf::from("assets/other.png"),
+                    url: "https://example.com/other.png".to_string(),
+                    timestamp: Some(common::NOW),
+                    validators: Default::default(),
+                    hash: None,
+                }
+            }
+
+            let mut seq = Sequence::new();
+            world
+                .expect_read_index()
+                .once()
+                .in_sequence(&mut seq)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            // when the index is saved, it contains the other job's resource
+            world
+                .expect_read_index()
+                .once()
+                .in_sequence(&mut seq)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(other());
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(other());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index.
+/// The resource is in the index but does not exist locally, and should be downloaded again.
+/// The index should be saved with the downloaded resource in it.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_tracked_missing() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: None,
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-tracked-missing.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index.
+/// The resource exists locally and should be re-downloaded according to the manifest.
+/// The index should be saved with the downloaded resource in it (no change).
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_existing_forced() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        overwrite = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: None,
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-forced.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index.
+/// The resource exists locally and should be re-downloaded because the URL has changed.
+/// The index should be saved with the downloaded resource in it (changed URL).
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_outdated() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example-old.png".to_string(),
+                        timestamp: None,
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-changed.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and no index, using a job-specific root.
+/// The resource does not exist locally and should be downloaded into the job's root.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_job_root() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        root = "assets"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            root: Some("assets".into()),
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and no index, using a job-specific root.
+/// The resource is outside the job's root (but not the global root) and should not be downloaded.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_download_outside_job_root() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        root = "assets"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            root: Some("assets".into()),
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "../example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_err("access to file outside the job's root should be denied")
+    .expect_log(include_str!("web-resource/fail-outside-root.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index, using a non-default hash
+/// algorithm. The resource should be downloaded, and its hash recorded in the index with the
+/// algorithm as a prefix.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_hash() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        hash = "sha512"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let index = Index::new(location.to_path_buf());
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha512:abcd".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_hash_resource()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq(HashAlgorithm::Sha512),
+                )
+                .returning(|_, _| Ok("sha512:abcd".to_string()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one existing resource whose index entry was hashed with a
+/// different algorithm than the configured one. The entry is stale, so the resource should be
+/// downloaded again and rehashed.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_hash_algorithm_changed() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        hash = "sha512"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW - 3600),
+                        validators: Default::default(),
+                        hash: Some("sha256:aaaa".to_string()),
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha512:abcd".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_hash_resource()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq(HashAlgorithm::Sha512),
+                )
+                .returning(|_, _| Ok("sha512:abcd".to_string()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!(
+        "web-resource/success-hash-algorithm-changed.txt"
+    ));
+}
+
+/// Run the web resource preprocessor with one resource and an index, using a job-specific root.
+/// The query should be run with the job's root, and the index should be placed in it.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_job_root_index() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--root", "project", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        root = "assets"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            root: Some("assets".into()),
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("assets/web-resource-index.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("assets/web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index, using a package-wide output
+/// directory. The resource and the index should be placed in the output directory.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_output_dir() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        output_dir = "generated"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("generated/web-resource-index.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("generated/web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("generated/assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("generated/assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-output-dir.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and an index, using a package-wide default
+/// index and output directory. The default index should be used as-is, not placed in the output
+/// directory, and the default of the other preprocessor kind should not apply.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_default_index() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        output_dir = "generated"
+        web_resource.default_index = "assets.index.toml"
+        shell.default_index = "shell.index.toml"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("assets.index.toml")))
+                .returning(|location| Ok(Index::new(location.to_path_buf())));
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("assets.index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("generated/assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("generated/assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-output-dir.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and no index, using a package-wide output
+/// directory. The resource's path is absolute, so it should be placed relative to the root instead.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_output_dir_absolute() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        output_dir = "generated"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "/assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and no index, emitting an asset manifest.
+/// The resource should be downloaded and listed in the asset manifest.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_emit_manifest() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        emit_manifest = "assets.json"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+
+            world
+                .expect_write_asset_list()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets.json")),
+                    eq(*br#"[{"url":"https://example.com/example.png","path":"assets/example.png"}]"#),
+                )
+                .returning(|_, _| Ok(()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-emit-manifest.txt"));
+}
+
+/// Run the web resource preprocessor without any resources and no index, querying a document other
+/// than the input file.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_custom_document() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        query.document = "meta.typ"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            document: Some(PathBuf::from("meta.typ")),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no resources in the query result
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/no-resources.txt"));
+}
+
+/// Run the web resource preprocessor without any resources and no index, with a package-wide
+/// default field.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_default_field() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        default_field = "meta"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("meta".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no resources in the query result
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/no-resources.txt"));
+}
+
+/// Run the web resource preprocessor with `field = false`, overriding a package-wide default field.
+/// Whole elements are queried; fields besides the URL and path are ignored.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_no_field() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        default_field = "meta"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        query.field = false
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: None,
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{
+            "func": "metadata",
+            "url": "https://example.com/example.png",
+            "path": "assets/example.png",
+            "label": "<web-resource>"
+        }]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one resource, an index and a maximum age.
+/// The resource exists locally and was downloaded an hour ago, so it should not be downloaded again.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_fresh() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        max_age = "24h"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW - 3600),
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW - 3600),
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-fresh.txt"));
+}
+
+/// Run the web resource preprocessor with one resource, an index and a maximum age.
+/// The resource exists locally and was downloaded two days ago, so it should be downloaded again.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_expired() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        max_age = "24h"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW - 2 * 24 * 3600),
+                        validators: Default::default(),
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Default::default(),
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-expired.txt"));
+}
+
+/// Run the web resource preprocessor with HEAD checks, for a file whose remote content is unchanged.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_head_unchanged() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        check = "head"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW - 3600),
+                        validators: Validators {
+                            etag: Some("\"v1\"".to_string()),
+                            ..Default::default()
+                        },
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW - 3600),
+                        validators: Validators {
+                            etag: Some("\"v1\"".to_string()),
+                            ..Default::default()
+                        },
+                        hash: None,
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_head()
+                .once()
+                .with(eq("https://example.com/example.png"))
+                .returning(|_| {
+                    Ok(Some(Validators {
+                        etag: Some("\"v1\"".to_string()),
+                        ..Default::default()
+                    }))
+                });
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-head-unchanged.txt"));
+}
+
+/// Run the web resource preprocessor with HEAD checks, for a file whose remote content has changed.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_with_index_head_changed() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        check = "head"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .times(2)
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW - 3600),
+                        validators: Validators {
+                            etag: Some("\"v1\"".to_string()),
+                            ..Default::default()
+                        },
+                        hash: None,
+                    });
+                    Ok(index)
+                });
+            world
+                .expect_write_index()
+                .once()
+                .with(eq({
+                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
+                    index.update(Resource {
+                        path: PathBuf::from("assets/example.png"),
+                        url: "https://example.com/example.png".to_string(),
+                        timestamp: Some(common::NOW),
+                        validators: Validators {
+                            etag: Some("\"v2\"".to_string()),
+                            ..Default::default()
+                        },
+                        hash: Some("sha256:00".to_string()),
+                    });
+                    index
+                }))
+                .returning(|_| Ok(()));
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_head()
+                .once()
+                .with(eq("https://example.com/example.png"))
+                .returning(|_| {
+                    Ok(Some(Validators {
+                        etag: Some("\"v2\"".to_string()),
+                        ..Default::default()
+                    }))
+                });
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| {
+                    Ok(Validators {
+                        etag: Some("\"v2\"".to_string()),
+                        ..Default::default()
+                    })
+                });
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-head-changed.txt"));
+}
+
+/// Run the web resource preprocessor with HEAD checks but no index, for a file that was modified
+/// when the remote content was last modified.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_check_always_unchanged() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        check = "always"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_head()
+                .once()
+                .with(eq("https://example.com/example.png"))
+                .returning(|_| {
+                    // 2023-11-14 22:13:20 UTC is `common::NOW`
+                    Ok(Some(Validators {
+                        last_modified: Some("Tue, 14 Nov 2023 22:13:20 GMT".to_string()),
+                        content_length: Some(1024),
+                        ..Default::default()
+                    }))
+                });
+            world
+                .expect_resource_metadata()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .returning(|_| Ok((1024, UNIX_EPOCH + Duration::from_secs(common::NOW))));
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-head-unchanged.txt"));
+}
+
+/// Run the web resource preprocessor with HEAD checks but no index, for a file that is older than
+/// the remote content.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_check_always_changed() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        check = "always"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
                 .once()
-                .with(eq({
-                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
-                    index.update(Resource {
-                        path: PathBuf::from("assets/example.png"),
-                        url: "https://example.com/example.png".to_string(),
-                    });
-                    index
-                }))
-                .returning(|_| Ok(()));
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(true);
+            world
+                .expect_head()
+                .once()
+                .with(eq("https://example.com/example.png"))
+                .returning(|_| {
+                    // 2023-11-14 22:13:20 UTC is `common::NOW`
+                    Ok(Some(Validators {
+                        last_modified: Some("Tue, 14 Nov 2023 22:13:20 GMT".to_string()),
+                        content_length: Some(1024),
+                        ..Default::default()
+                    }))
+                });
+            world
+                .expect_resource_metadata()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .returning(|_| Ok((1024, UNIX_EPOCH + Duration::from_secs(common::NOW - 1))));
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-head-changed.txt"));
+}
+
+/// Run the web resource preprocessor without any resources and no index, with the fallback input
+/// disabled package-wide.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_no_fallback() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        fallback = false
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: false,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no resources in the query result
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/no-resources.txt"));
+}
+
+/// Run the web resource preprocessor without any resources and no index, with the job name passed
+/// as an input package-wide.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_pass_job_name() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        pass_job_name = true
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            inputs: [("prequery-job".to_string(), "download".to_string())].into(),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no resources in the query result
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/no-resources.txt"));
+}
+
+/// Run the web resource preprocessor without any resources and no index, with the job name passed
+/// as an input, but also given explicitly. The explicit input should be used.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_pass_job_name_explicit_input() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        query.pass_job_name = true
+        query.inputs.prequery-job = "custom"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            inputs: [("prequery-job".to_string(), "custom".to_string())].into(),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // no resources in the query result
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/no-resources.txt"));
+}
+
+/// Run the web resource preprocessor with two resources and no index, one of which is excluded.
+/// Only the other resource should be downloaded.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_exclude() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        exclude = ["vendor/**"]
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}, {"url": "https://example.com/lib.typ", "path": "/vendor/lib/lib.typ"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            // only the resource that is not excluded
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with two resources and no index, in verbose mode.
+/// One resource exists locally, and a summary of the downloads should be logged.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_verbose_summary() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--verbose", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}, {"url": "https://example.com/other.png", "path": "assets/other.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
+
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
+            world
+                .expect_resource_exists()
+                .once()
+                .with(eq(PathBuf::from("assets/other.png")))
+                .return_const(true);
+            world
+                .expect_download()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+        },
+    )
+    .run()
+    .await
+    .expect_ok("download job should succeed")
+    .expect_log(include_str!("web-resource/success-verbose.txt"));
+}
+
+/// Run two web resource jobs with one resource each and no index, with ordered output.
+/// The jobs' log output should not be interleaved.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_ordered_output() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--ordered-output", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download1"
+        kind = "web-resource"
+
+        [[tool.prequery.jobs]]
+        name = "download2"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
+            // no index specified in the manifest
+            world.expect_read_index().never();
+            world.expect_write_index().never();
 
+            // each job downloads the resource
             world
                 .expect_resource_exists()
                 .once()
                 .with(eq(PathBuf::from("assets/example.png")))
-                .return_const(true);
+                .return_const(false);
             world
                 .expect_download()
                 .once()
                 .with(
                     eq(PathBuf::from("assets/example.png")),
                     eq("https://example.com/example.png"),
+                    eq(None),
                 )
-                .returning(|_, _| Ok(()));
+                .returning(|_, _, _| Ok(Default::default()));
         },
     )
     .run()
     .await
-    .expect_ok("download job should succeed")
-    .expect_log(include_str!("web-resource/success-forced.txt"));
+    .expect_ok("download jobs should succeed")
+    .expect_log(include_str!("web-resource/success-ordered.txt"));
 }
 
-/// Run the web resource preprocessor with one resource and an index.
-/// The resource exists locally and should be re-downloaded because the URL has changed.
-/// The index should be saved with the downloaded resource in it (changed URL).
+/// Configure the web resource preprocessor with an unknown hash algorithm.
+/// The job should be rejected before running.
 #[tokio::test]
 #[serial(web_resource)]
-async fn run_web_resource_with_index_outdated() {
+async fn run_web_resource_unknown_hash() {
     WebResourceTest::new(
         &["prequery-preprocess", "input.typ"],
         r#"
@@ -592,58 +4158,561 @@ async fn run_web_resource_with_index_outdated() {
         [[tool.prequery.jobs]]
         name = "download"
         kind = "web-resource"
-        index = true
+        hash = "md5"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |_world| {},
+    )
+    .run()
+    .await
+    .expect_err("download job should be rejected")
+    .expect_log(include_str!("web-resource/fail-unknown-hash.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and a lockfile.
+/// The resource should be downloaded and recorded in the lockfile.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_lockfile() {
+    let mut test = WebResourceTest::new(
+        &["prequery-preprocess", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        lockfile = true
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
         "#,
         Query {
             selector: "<web-resource>".to_string(),
             field: Some("value".to_string()),
-            one: false,
-            inputs: Default::default(),
+            fallback: true,
+            ..Default::default()
         },
         br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
         |world| {
             world
-                .expect_read_index()
+                .expect_resource_exists()
                 .once()
-                .with(eq(PathBuf::from("web-resource-index.toml")))
-                .returning(|location| {
-                    let mut index = Index::new(location.to_path_buf());
-                    index.update(Resource {
-                        path: PathBuf::from("assets/example.png"),
-                        url: "https://example.com/example-old.png".to_string(),
-                    });
-                    Ok(index)
-                });
+                .with(eq(PathBuf::from("assets/example.png")))
+                .return_const(false);
             world
-                .expect_write_index()
+                .expect_download()
                 .once()
-                .with(eq({
-                    let mut index = Index::new(PathBuf::from("web-resource-index.toml"));
-                    index.update(Resource {
-                        path: PathBuf::from("assets/example.png"),
-                        url: "https://example.com/example.png".to_string(),
-                    });
-                    index
-                }))
-                .returning(|_| Ok(()));
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq("https://example.com/example.png"),
+                    eq(None),
+                )
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_hash_resource()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq(HashAlgorithm::Sha256),
+                )
+                .returning(|_, _| Ok("sha256:1234".to_string()));
+        },
+    );
+    test.test
+        .world
+        .expect_read_lockfile()
+        .once()
+        .with(eq(PathBuf::from("prequery.lock")))
+        .returning(|location| Ok(Lockfile::new(location.to_path_buf())));
+    test.test
+        .world
+        .expect_write_lockfile()
+        .once()
+        .with(function(|lockfile: &Lockfile| {
+            let [entry] = lockfile.entries.as_slice() else {
+                return false;
+            };
+            lockfile.location() == Path::new("prequery.lock")
+                && entry.job == "download"
+                && entry.path == Path::new("assets/example.png")
+                && entry.url == "https://example.com/example.png"
+                && entry.hash == "sha256:1234"
+                && entry.timestamp == common::NOW
+        }))
+        .returning(|_| Ok(()));
+    test.run()
+        .await
+        .expect_ok("download job should succeed")
+        .expect_log(include_str!("web-resource/success.txt"));
+}
+
+/// Run the web resource preprocessor with one resource and a lockfile, with `--locked`.
+/// The resource's hash was recorded with a different algorithm, so the run should fail without
+/// updating the lockfile.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_lockfile_outdated() {
+    let mut test = WebResourceTest::new(
+        &["prequery-preprocess", "--locked", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [tool.prequery]
+        lockfile = true
 
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        hash = "sha512"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[{"url": "https://example.com/example.png", "path": "assets/example.png"}]"#,
+        |world| {
             world
                 .expect_resource_exists()
                 .once()
                 .with(eq(PathBuf::from("assets/example.png")))
-                .return_const(true);
+                .return_const(false);
             world
                 .expect_download()
                 .once()
                 .with(
                     eq(PathBuf::from("assets/example.png")),
                     eq("https://example.com/example.png"),
+                    eq(None),
                 )
-                .returning(|_, _| Ok(()));
+                .returning(|_, _, _| Ok(Default::default()));
+            world
+                .expect_hash_resource()
+                .once()
+                .with(
+                    eq(PathBuf::from("assets/example.png")),
+                    eq(HashAlgorithm::Sha512),
+                )
+                .returning(|_, _| Ok("sha512:5678".to_string()));
+        },
+    );
+    test.test
+        .world
+        .expect_read_lockfile()
+        .once()
+        .with(eq(PathBuf::from("prequery.lock")))
+        .returning(|location| {
+            let mut lockfile = Lockfile::new(location.to_path_buf());
+            lockfile.entries.push(LockedResource {
+                job: "download".to_string(),
+                path: PathBuf::from("assets/example.png"),
+                url: "https://example.com/example.png".to_string(),
+                hash: "sha256:1234".to_string(),
+                timestamp: 0,
+            });
+            Ok(lockfile)
+        });
+    test.test.world.expect_write_lockfile().never();
+    test.run()
+        .await
+        .expect_err("download job should fail")
+        .expect_log(include_str!("web-resource/fail-lockfile-outdated.txt"));
+}
+
+/// The proxy configuration is read through the given lookup, preferring lowercase variables and
+/// ignoring empty ones. Hosts from the manifest are added to those in `NO_PROXY`.
+#[test]
+fn web_resource_proxy_from_env() {
+    let env = HashMap::from([
+        ("http_proxy", "http://lower.example.com:8080"),
+        ("HTTP_PROXY", "http://upper.example.com:8080"),
+        ("HTTPS_PROXY", "http://proxy.example.com:8080"),
+        ("all_proxy", ""),
+        ("NO_PROXY", "localhost,10.0.0.0/8"),
+    ]);
+    let proxy = ProxyConfig::from_env(|name| env.get(name).map(|value| value.to_string()))
+        .with_no_proxy([".internal.example.com".to_string()]);
+
+    assert_eq!(
+        proxy,
+        ProxyConfig {
+            http: Some("http://lower.example.com:8080".to_string()),
+            https: Some("http://proxy.example.com:8080".to_string()),
+            all: None,
+            no_proxy: vec![
+                "localhost".to_string(),
+                "10.0.0.0/8".to_string(),
+                ".internal.example.com".to_string(),
+            ],
+        }
+    );
+}
+
+/// Credentials are looked up by host, falling back to the netrc file's default entry.
+#[test]
+fn web_resource_netrc() {
+    let credentials = Credentials::parse(
+        "machine assets.example.com login alice password secret\n\
+         machine token.example.com login bob\n\
+         default login anonymous password guest\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        credentials.for_host("assets.example.com"),
+        Some(("alice", Some("secret")))
+    );
+    assert_eq!(
+        credentials.for_host("Assets.Example.com"),
+        Some(("alice", Some("secret")))
+    );
+    assert_eq!(
+        credentials.for_host("token.example.com"),
+        Some(("bob", None))
+    );
+    assert_eq!(
+        credentials.for_host("example.com"),
+        Some(("anonymous", Some("guest")))
+    );
+
+    // without a default entry, other hosts get no credentials
+    let credentials = Credentials::parse("machine assets.example.com login alice").unwrap();
+    assert_eq!(credentials.for_host("example.com"), None);
+}
+
+/// Identical download errors are only listed once, with their number of occurrences.
+#[test]
+fn web_resource_download_errors_deduplicated() {
+    let not_found = || DownloadError::File(io::Error::new(io::ErrorKind::NotFound, "not found"));
+    let denied = || DownloadError::File(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+    let error = MultipleDownloadError::new(vec![not_found(), denied(), not_found(), not_found()]);
+    assert_eq!(
+        error.to_string(),
+        "at least one download failed:\n  not found (x3)\n  denied",
+    );
+}
+
+/// Clean the web resource preprocessor's files with an index.
+/// All resources in the index and the index itself should be removed; nothing is downloaded.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_clean() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--clean", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .once()
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    for path in ["assets/example.png", "assets/other.png"] {
+                        index.update(Resource {
+                            path: PathBuf::from(path),
+                            url: format!("https://example.com/{path}"),
+                            timestamp: None,
+                            validators: Default::default(),
+                            hash: None,
+                        });
+                    }
+                    Ok(index)
+                });
+            world.expect_write_index().never();
+
+            world
+                .expect_remove_resource()
+                .once()
+                .with(eq(PathBuf::from("assets/example.png")))
+                .returning(|_| Ok(()));
+            world
+                .expect_remove_resource()
+                .once()
+                .with(eq(PathBuf::from("assets/other.png")))
+                .returning(|_| Ok(()));
+            world
+                .expect_remove_index()
+                .once()
+                .withf(|index| index.location() == Path::new("web-resource-index.toml"))
+                .returning(|_| Ok(()));
+
+            world.expect_resource_exists().never();
+            world.expect_download().never();
         },
     )
     .run()
     .await
-    .expect_ok("download job should succeed")
-    .expect_log(include_str!("web-resource/success-changed.txt"));
+    .expect_ok("clean job should succeed")
+    .expect_log(include_str!("web-resource/clean.txt"));
+}
+
+/// Clean the web resource preprocessor's files without an index.
+/// Nothing can be removed, which should only be reported.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_clean_no_index() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--clean", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[]"#,
+        |world| {
+            world.expect_read_index().never();
+            world.expect_remove_resource().never();
+            world.expect_remove_index().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("clean job should succeed")
+    .expect_log(include_str!("web-resource/clean-no-index.txt"));
+}
+
+/// Show how the web resource preprocessor's index would change.
+/// Added, changed and no longer queried entries are reported; nothing is downloaded or written.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_diff_index() {
+    WebResourceTest::new(
+        &["prequery-preprocess", "--diff-index", "input.typ"],
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "download"
+        kind = "web-resource"
+        index = true
+        "#,
+        Query {
+            selector: "<web-resource>".to_string(),
+            field: Some("value".to_string()),
+            fallback: true,
+            ..Default::default()
+        },
+        br#"[
+            {"url": "https://example.com/example.png", "path": "assets/example.png"},
+            {"url": "https://example.com/changed.png", "path": "assets/changed.png"},
+            {"url": "https://example.com/new.png", "path": "assets/new.png"}
+        ]"#,
+        |world| {
+            world
+                .expect_read_index()
+                .once()
+                .with(eq(PathBuf::from("web-resource-index.toml")))
+                .returning(|location| {
+                    let mut index = Index::new(location.to_path_buf());
+                    for (path, url) in [
+                        ("assets/example.png", "https://example.com/example.png"),
+                        ("assets/changed.png", "https://example.com/old.png"),
+                        ("assets/unused.png", "https://example.com/unused.png"),
+                    ] {
+                        index.update(Resource {
+                            path: PathBuf::from(path),
+                            url: url.to_string(),
+                            timestamp: None,
+                            validators: Default::default(),
+                            hash: None,
+                        });
+                    }
+                    Ok(index)
+                });
+            world.expect_write_index().never();
+
+            world.expect_resource_exists().never();
+            world.expect_download().never();
+        },
+    )
+    .run()
+    .await
+    .expect_ok("diff job should succeed")
+    .expect_log(include_str!("web-resource/diff-index.txt"));
+}
+
+/// Run the same web resource preprocessor instance twice, with an index and no resources.
+/// The index is owned by the instance, not its caller, so it should be read and written on every
+/// run.
+#[tokio::test]
+#[serial(web_resource)]
+async fn run_web_resource_repeated() {
+    let ctx = MockWorld::new_context();
+    ctx.expect().returning(|main, _manifest, _progress| {
+        let mut world = MockWorld::default();
+        world.expect_main().return_const(main);
+        world
+            .expect_lock_index()
+            .times(2)
+            .returning(|_| Ok(FileLock::default()));
+        // the index is read again for writing it
+        world
+            .expect_read_index()
+            .times(4)
+            .with(eq(PathBuf::from("web-resource-index.toml")))
+            .returning(|location| Ok(Index::new(location.to_path_buf())));
+        world
+            .expect_write_index()
+            .times(2)
+            .with(eq(Index::new(PathBuf::from("web-resource-index.toml"))))
+            .returning(|_| Ok(()));
+        world.expect_resource_exists().never();
+        world.expect_download().never();
+        world
+    });
+
+    let mut main = prequery_preprocess::world::MockWorld::new();
+    main.expect_arguments()
+        .return_const(CliArguments::parse_from([
+            "prequery-preprocess",
+            "input.typ",
+        ]));
+    main.expect_log().return_const(VecLog::new());
+    main.expect_now()
+        .return_const(UNIX_EPOCH + Duration::from_secs(common::NOW));
+    main.expect_query_impl()
+        .times(2)
+        .returning(|_| Ok(b"[]".to_vec()));
+    let main = Arc::new(main);
+
+    let config = toml::toml! {
+        index = true
+    };
+    let mut job = WebResourceFactory::<MockWorld>::new()
+        .configure(
+            &main,
+            "download".to_string(),
+            None,
+            config,
+            manifest::Query::default(),
+            JobDefaults::default(),
+        )
+        .expect("job should be configured");
+    job.run().await.expect("first run should succeed");
+    job.run().await.expect("second run should succeed");
+}
+
+#[tokio::test]
+async fn web_resource_index_compressed() {
+    let location = std::env::temp_dir().join(format!(
+        "prequery-index-compressed-{}.toml.gz",
+        std::process::id()
+    ));
+
+    let mut index = Index::new(location.clone());
+    index.update(Resource {
+        path: PathBuf::from("assets/example.png"),
+        url: "https://example.com/example.png".to_string(),
+        timestamp: Some(common::NOW),
+        validators: Default::default(),
+        hash: None,
+    });
+    index.write().await.expect("index should be written");
+
+    let raw = std::fs::read(&location).expect("index should exist");
+    let read = Index::read(location.clone()).await;
+    std::fs::remove_file(&location).expect("index should be removed");
+
+    assert_eq!(raw[..2], [0x1f, 0x8b], "index should be gzip-compressed");
+    assert_eq!(read.expect("index should be read"), index);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn web_resource_file_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let manifest: Manifest = toml::from_str(r#"file_mode = "640""#).expect("manifest should parse");
+    let mode = manifest.file_mode.expect("file mode should be set");
+    assert_eq!(mode.bits(), 0o640);
+    let manifest: Manifest = toml::from_str("file_mode = 0o755").expect("manifest should parse");
+    assert_eq!(manifest.file_mode.map(FileMode::bits), Some(0o755));
+    toml::from_str::<Manifest>("file_mode = 0o10000").expect_err("mode should be out of range");
+    toml::from_str::<Manifest>(r#"file_mode = "rw""#).expect_err("mode should not be octal");
+
+    let location =
+        std::env::temp_dir().join(format!("prequery-file-mode-{}.txt", std::process::id()));
+    std::fs::write(&location, "").expect("file should be written");
+    let result = mode.apply(&location).await;
+    let metadata = std::fs::metadata(&location);
+    std::fs::remove_file(&location).expect("file should be removed");
+
+    result.expect("file mode should be applied");
+    let permissions = metadata.expect("file should exist").permissions();
+    assert_eq!(permissions.mode() & 0o7777, 0o640);
+}
+
+#[tokio::test]
+async fn web_resource_index_corrupt() {
+    let location = std::env::temp_dir().join(format!(
+        "prequery-index-corrupt-{}.toml",
+        std::process::id()
+    ));
+    std::fs::write(&location, "version = 1\nentries = [").expect("index should be written");
+    let invalid_toml = Index::read(location.clone()).await;
+    std::fs::write(&location, "version = 2\n").expect("index should be written");
+    let unsupported_version = Index::read(location.clone()).await;
+    std::fs::remove_file(&location).expect("index should be removed");
+
+    let compressed = location.with_extension("toml.gz");
+    std::fs::write(&compressed, "version = 1\n").expect("index should be written");
+    let invalid_gzip = Index::read(compressed.clone()).await;
+    std::fs::remove_file(&compressed).expect("index should be removed");
+
+    assert!(
+        invalid_toml
+            .expect_err("index should be invalid")
+            .is_corrupt()
+    );
+    assert!(
+        invalid_gzip
+            .expect_err("index should be invalid")
+            .is_corrupt()
+    );
+    let error = unsupported_version.expect_err("index version should be unsupported");
+    assert!(!error.is_corrupt());
 }
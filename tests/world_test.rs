@@ -0,0 +1,83 @@
+use std::env;
+use std::path::{self, Path};
+
+use clap::Parser;
+
+use prequery_preprocess::args::CliArguments;
+use prequery_preprocess::entry::run_with_manifest;
+use prequery_preprocess::world::{DefaultWorld, World};
+
+fn world() -> DefaultWorld {
+    DefaultWorld::with_arguments(CliArguments::parse_from([
+        "prequery-preprocess",
+        "tests/world/main.typ",
+    ]))
+}
+
+/// The manifest directory is the one containing the `typst.toml` file closest to the input file.
+#[tokio::test]
+async fn manifest_dir() {
+    let world = world();
+
+    let dir = world
+        .manifest_dir()
+        .await
+        .expect("typst.toml should be found");
+    assert_eq!(dir, path::absolute("tests/world").unwrap());
+}
+
+/// Relative paths in the manifest are resolved against the manifest directory.
+#[tokio::test]
+async fn read_typst_toml_resolves_paths() {
+    let world = world();
+
+    let manifest = world
+        .read_typst_toml()
+        .await
+        .expect("typst.toml should be readable");
+    let dir = world.manifest_dir().await.unwrap();
+    assert_eq!(manifest.jobs[0].root, Some(dir.join("assets")));
+}
+
+/// With an explicitly given manifest, no `typst.toml` is needed: the manifest directory is the
+/// current directory, and shell commands run in it.
+#[tokio::test]
+async fn run_with_manifest_without_typst_toml() {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join("world/run-with-manifest");
+    let _ = tokio::fs::remove_dir_all(&root).await;
+    tokio::fs::create_dir_all(&root).await.unwrap();
+
+    // the input file's directory, the current directory, has no typst.toml
+    let world = DefaultWorld::with_arguments(CliArguments::parse_from([
+        "prequery-preprocess",
+        "--query-cache-file",
+        "tests/world/query-cache.json",
+        "--root",
+        root.to_str().unwrap(),
+        "input.typ",
+    ]));
+    run_with_manifest(
+        world,
+        r#"
+        [package]
+        name = "test"
+        version = "0.0.1"
+        entrypoint = "main.typ"
+
+        [[tool.prequery.jobs]]
+        name = "pwd"
+        kind = "shell"
+        query.selector = "<pwd>"
+        # the input is read so that writing it doesn't fail
+        command = ["sh", "-c", "cat > /dev/null; pwd"]
+        format.stdout = "plain"
+        "#,
+    )
+    .await
+    .expect("shell job should succeed");
+
+    let output = tokio::fs::read(root.join("out.json")).await.unwrap();
+    let output: Vec<String> = serde_json::from_slice(&output).unwrap();
+    let current_dir = env::current_dir().unwrap();
+    assert_eq!(output, [format!("{}\n", current_dir.display())]);
+}